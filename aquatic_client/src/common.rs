@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+
+pub type InfoHash = [u8; 20];
+pub type PeerId = [u8; 20];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+    /// Regular announce, sent without event
+    #[default]
+    None,
+}
+
+/// Announce request parameters, common to all protocols
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    pub bytes_left: u64,
+    pub event: AnnounceEvent,
+    /// Number of peers wanted. If None, tracker default is used.
+    pub numwant: Option<usize>,
+    /// Key allowing tracker to identify peer across IP address changes. Not
+    /// sent to WebTorrent trackers.
+    pub key: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    /// Number of seconds to wait before announcing again
+    pub announce_interval: usize,
+    pub seeders: usize,
+    pub leechers: usize,
+    /// Peer addresses. Always empty for WebTorrent trackers, which forward
+    /// WebRTC offers instead of returning addresses.
+    pub peers: Vec<SocketAddr>,
+    /// Only sent by HTTP trackers
+    pub warning_message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrapeStatistics {
+    pub seeders: usize,
+    pub leechers: usize,
+    /// Number of times torrent has been downloaded. Zero if tracker doesn't
+    /// keep track of this.
+    pub completed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScrapeResponse {
+    pub files: HashMap<InfoHash, ScrapeStatistics>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// Tracker URL could not be parsed or has unsupported scheme
+    InvalidUrl(String),
+    /// Tracker doesn't support scraping (HTTP announce URL doesn't end with
+    /// "announce")
+    ScrapeNotSupported,
+    Io(io::Error),
+    Tls(String),
+    WebSocket(Box<tungstenite::Error>),
+    /// No response before request timeout (HTTP and WebSocket) or last
+    /// retransmission timeout (UDP)
+    Timeout,
+    /// Response could not be parsed or was of unexpected type
+    InvalidResponse(String),
+    /// Tracker responded with failure reason or error message
+    Tracker(String),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUrl(reason) => write!(f, "invalid tracker URL: {}", reason),
+            Self::ScrapeNotSupported => write!(f, "tracker doesn't support scraping"),
+            Self::Io(err) => write!(f, "io error: {}", err),
+            Self::Tls(reason) => write!(f, "tls error: {}", reason),
+            Self::WebSocket(err) => write!(f, "websocket error: {}", err),
+            Self::Timeout => write!(f, "request timed out"),
+            Self::InvalidResponse(reason) => write!(f, "invalid response: {}", reason),
+            Self::Tracker(reason) => write!(f, "tracker error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::WebSocket(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(err: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(err))
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// Time to wait for first UDP response before retransmitting request.
+    /// Doubled for each retransmission, as recommended by BEP 15.
+    pub udp_initial_timeout: Duration,
+    /// Maximum number of UDP retransmissions before giving up. With
+    /// default settings, the last attempt times out after a total of
+    /// 15 * (2^9 - 1) seconds.
+    pub udp_max_retransmissions: u32,
+    /// Time during which a UDP connection id is reused after it was received
+    pub udp_connection_id_max_age: Duration,
+    /// Time to wait for HTTP and WebSocket requests to complete, including
+    /// connection setup
+    pub request_timeout: Duration,
+    /// TLS configuration for HTTPS and secure WebSocket trackers. If None,
+    /// server certificates are verified against Mozilla's root certificates.
+    pub tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            udp_initial_timeout: Duration::from_secs(15),
+            udp_max_retransmissions: 8,
+            udp_connection_id_max_age: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(15),
+            tls_config: None,
+        }
+    }
+}
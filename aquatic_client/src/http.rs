@@ -0,0 +1,342 @@
+//! HTTP(S) tracker protocol
+//!
+//! A new connection is opened for each request. Scrape URLs are derived from
+//! announce URLs by replacing "announce" in the last path segment with
+//! "scrape".
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use aquatic_http_protocol::common::{
+    AnnounceEvent as HttpAnnounceEvent, InfoHash as HttpInfoHash, PeerId as HttpPeerId,
+};
+use aquatic_http_protocol::request::{
+    AnnounceRequest as HttpAnnounceRequest, ScrapeRequest as HttpScrapeRequest,
+};
+use aquatic_http_protocol::response::Response;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::common::*;
+use crate::stream::connect;
+
+pub async fn announce(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, ClientError> {
+    let event = match request.event {
+        AnnounceEvent::Started => HttpAnnounceEvent::Started,
+        AnnounceEvent::Stopped => HttpAnnounceEvent::Stopped,
+        AnnounceEvent::Completed => HttpAnnounceEvent::Completed,
+        AnnounceEvent::None => HttpAnnounceEvent::Empty,
+    };
+
+    let request = HttpAnnounceRequest {
+        info_hash: HttpInfoHash(request.info_hash),
+        peer_id: HttpPeerId(request.peer_id),
+        port: request.port,
+        bytes_uploaded: request.bytes_uploaded as usize,
+        bytes_downloaded: request.bytes_downloaded as usize,
+        bytes_left: request.bytes_left as usize,
+        event,
+        numwant: request.numwant,
+        key: request.key.map(|key| hex::encode(key.to_be_bytes()).into()),
+        tracker_id: None,
+    };
+
+    let mut query_string = Vec::new();
+
+    request.write_query_string(&mut query_string)?;
+
+    match send_request(tls_config, url, &query_string).await? {
+        Response::Announce(r) => {
+            let peers = r
+                .peers
+                .0
+                .into_iter()
+                .map(|peer| SocketAddr::new(peer.ip_address.into(), peer.port))
+                .chain(
+                    r.peers6
+                        .0
+                        .into_iter()
+                        .map(|peer| SocketAddr::new(peer.ip_address.into(), peer.port)),
+                )
+                .collect();
+
+            Ok(AnnounceResponse {
+                announce_interval: r.announce_interval,
+                seeders: r.complete,
+                leechers: r.incomplete,
+                peers,
+                warning_message: r.warning_message,
+            })
+        }
+        Response::Failure(r) => Err(ClientError::Tracker(r.failure_reason.into_owned())),
+        Response::Scrape(_) => Err(ClientError::InvalidResponse(
+            "expected announce response".into(),
+        )),
+    }
+}
+
+pub async fn scrape(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+    info_hashes: &[InfoHash],
+) -> Result<ScrapeResponse, ClientError> {
+    let url = scrape_url(url).ok_or(ClientError::ScrapeNotSupported)?;
+
+    let request = HttpScrapeRequest {
+        info_hashes: info_hashes.iter().copied().map(HttpInfoHash).collect(),
+    };
+
+    let mut query_string = Vec::new();
+
+    request.write_query_string(&mut query_string)?;
+
+    match send_request(tls_config, &url, &query_string).await? {
+        Response::Scrape(r) => {
+            let files = r
+                .files
+                .into_iter()
+                .map(|(info_hash, stats)| {
+                    let stats = ScrapeStatistics {
+                        seeders: stats.complete,
+                        leechers: stats.incomplete,
+                        completed: stats.downloaded,
+                    };
+
+                    (info_hash.0, stats)
+                })
+                .collect();
+
+            Ok(ScrapeResponse { files })
+        }
+        Response::Failure(r) => Err(ClientError::Tracker(r.failure_reason.into_owned())),
+        Response::Announce(_) => Err(ClientError::InvalidResponse(
+            "expected scrape response".into(),
+        )),
+    }
+}
+
+/// Send GET request to URL with query string appended and parse response
+async fn send_request(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+    query_string: &[u8],
+) -> Result<Response, ClientError> {
+    let mut stream = connect(tls_config, url, url.scheme() == "https").await?;
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let mut request_bytes = Vec::new();
+
+    request_bytes.extend_from_slice(b"GET ");
+    request_bytes.extend_from_slice(url.path().as_bytes());
+
+    // Keep existing query parameters, such as passkeys
+    if let Some(query) = url.query() {
+        request_bytes.push(b'?');
+        request_bytes.extend_from_slice(query.as_bytes());
+        request_bytes.push(b'&');
+    } else {
+        request_bytes.push(b'?');
+    }
+
+    request_bytes.extend_from_slice(query_string);
+    request_bytes.extend_from_slice(b" HTTP/1.1\r\nHost: ");
+    request_bytes.extend_from_slice(host.as_bytes());
+    request_bytes.extend_from_slice(b"\r\nConnection: close\r\n\r\n");
+
+    stream.write_all(&request_bytes).await?;
+    stream.flush().await?;
+
+    let mut response_bytes = Vec::new();
+    let mut buffer = [0; 4096];
+
+    // Trackers might keep connection open, so read until response is
+    // complete rather than until end of stream
+    loop {
+        let bytes_read = stream.read(&mut buffer).await?;
+        let eof = bytes_read == 0;
+
+        response_bytes.extend_from_slice(&buffer[..bytes_read]);
+
+        if let Some(response) = parse_response(&response_bytes, eof)? {
+            return Ok(response);
+        }
+    }
+}
+
+/// Parse response bytes. Returns Ok(None) if more bytes are needed and
+/// stream hasn't reached end.
+fn parse_response(bytes: &[u8], eof: bool) -> Result<Option<Response>, ClientError> {
+    let incomplete = |reason: &str| {
+        if eof {
+            Err(ClientError::InvalidResponse(reason.into()))
+        } else {
+            Ok(None)
+        }
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut http_response = httparse::Response::new(&mut headers);
+
+    let body_start = match http_response.parse(bytes) {
+        Ok(httparse::Status::Complete(body_start)) => body_start,
+        Ok(httparse::Status::Partial) => return incomplete("incomplete headers"),
+        Err(err) => {
+            return Err(ClientError::InvalidResponse(format!(
+                "invalid headers: {}",
+                err
+            )));
+        }
+    };
+
+    let status = http_response.code.unwrap_or_default();
+    let mut opt_body = None;
+
+    for header in http_response.headers.iter() {
+        if header.name.eq_ignore_ascii_case("transfer-encoding")
+            && header.value.eq_ignore_ascii_case(b"chunked")
+        {
+            match decode_chunked(&bytes[body_start..]) {
+                Some(body) => opt_body = Some(body),
+                None => return incomplete("incomplete chunked body"),
+            }
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            let content_length = ::std::str::from_utf8(header.value)
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .ok_or_else(|| ClientError::InvalidResponse("invalid content-length".into()))?;
+
+            match bytes[body_start..].get(..content_length) {
+                Some(body) => opt_body = Some(body.to_vec()),
+                None => return incomplete("incomplete body"),
+            }
+        }
+    }
+
+    // Without content length or chunked encoding, body ends with stream
+    let body = match opt_body {
+        Some(body) => body,
+        None if eof => bytes[body_start..].to_vec(),
+        None => return Ok(None),
+    };
+
+    // Trackers may send failure responses with error status codes
+    match Response::from_bytes(&body) {
+        Ok(response) => Ok(Some(response)),
+        Err(_) if status != 200 => Err(ClientError::InvalidResponse(format!(
+            "status code {}",
+            status
+        ))),
+        Err(err) => Err(ClientError::InvalidResponse(format!(
+            "couldn't parse body: {}",
+            err
+        ))),
+    }
+}
+
+/// Decode chunked body. Returns None if it is invalid or incomplete.
+fn decode_chunked(mut bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+
+    loop {
+        let line_end = bytes.windows(2).position(|window| window == b"\r\n")?;
+        let size_str = ::std::str::from_utf8(&bytes[..line_end]).ok()?;
+        // Ignore chunk extensions
+        let size_str = size_str.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_str, 16).ok()?;
+
+        bytes = &bytes[line_end + 2..];
+
+        if size == 0 {
+            return Some(body);
+        }
+
+        body.extend_from_slice(bytes.get(..size)?);
+
+        bytes = bytes.get(size + 2..)?;
+    }
+}
+
+fn scrape_url(announce_url: &Url) -> Option<Url> {
+    let path = announce_url.path();
+    let last_segment_start = path.rfind('/')? + 1;
+    let last_segment = &path[last_segment_start..];
+
+    if !last_segment.starts_with("announce") {
+        return None;
+    }
+
+    let mut scrape_url = announce_url.clone();
+
+    scrape_url.set_path(&format!(
+        "{}scrape{}",
+        &path[..last_segment_start],
+        &last_segment["announce".len()..]
+    ));
+
+    Some(scrape_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape_url =
+            |url: &str| scrape_url(&Url::parse(url).unwrap()).map(|url| url.as_str().to_string());
+
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("https://example.com:8080/abc/announce.php?passkey=x").as_deref(),
+            Some("https://example.com:8080/abc/scrape.php?passkey=x")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let body = b"d14:failure reason4:teste";
+
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        bytes.extend_from_slice(b"5\r\n");
+        bytes.extend_from_slice(&body[..5]);
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(format!("{:x}\r\n", body.len() - 5).as_bytes());
+        bytes.extend_from_slice(&body[5..]);
+        bytes.extend_from_slice(b"\r\n0\r\n\r\n");
+
+        assert!(matches!(
+            parse_response(&bytes[..bytes.len() - 3], false),
+            Ok(None)
+        ));
+
+        match parse_response(&bytes, false) {
+            Ok(Some(Response::Failure(r))) => assert_eq!(r.failure_reason, "test"),
+            _ => panic!("expected failure response"),
+        }
+
+        let bytes = b"HTTP/1.1 200 OK\r\nContent-Length: 25\r\n\r\nd14:failure reason4:testeXYZ";
+
+        assert!(matches!(parse_response(&bytes[..40], false), Ok(None)));
+        assert!(parse_response(&bytes[..40], true).is_err());
+
+        match parse_response(bytes, false) {
+            Ok(Some(Response::Failure(r))) => assert_eq!(r.failure_reason, "test"),
+            _ => panic!("expected failure response"),
+        }
+    }
+}
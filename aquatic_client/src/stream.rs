@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use futures::future::Either;
+use futures_rustls::client::TlsStream;
+use futures_rustls::TlsConnector;
+use tokio::net::TcpStream;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use url::Url;
+
+use crate::common::ClientError;
+
+pub type Stream = Either<Compat<TcpStream>, TlsStream<Compat<TcpStream>>>;
+
+/// Open TCP connection to host in URL, wrapping it in TLS if `tls` is true
+pub async fn connect(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+    tls: bool,
+) -> Result<Stream, ClientError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| ClientError::InvalidUrl("no host".into()))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| ClientError::InvalidUrl("no port".into()))?;
+
+    // IPv6 addresses in URLs are enclosed in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let stream = TcpStream::connect((host, port)).await?;
+
+    stream.set_nodelay(true)?;
+
+    let stream = stream.compat();
+
+    if tls {
+        let server_name = rustls::ServerName::try_from(host)
+            .map_err(|err| ClientError::InvalidUrl(format!("invalid server name: {}", err)))?;
+
+        let stream = TlsConnector::from(tls_config.clone())
+            .connect(server_name, stream)
+            .await
+            .map_err(|err| ClientError::Tls(err.to_string()))?;
+
+        Ok(Either::Right(stream))
+    } else {
+        Ok(Either::Left(stream))
+    }
+}
+
+/// Create TLS configuration verifying server certificates against Mozilla's
+/// root certificates
+pub fn create_default_tls_config() -> rustls::ClientConfig {
+    let mut root_store = rustls::RootCertStore::empty();
+
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+        |trust_anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                trust_anchor.subject,
+                trust_anchor.spki,
+                trust_anchor.name_constraints,
+            )
+        },
+    ));
+
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth()
+}
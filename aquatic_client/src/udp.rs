@@ -0,0 +1,438 @@
+//! UDP tracker protocol (BEP 15)
+//!
+//! Connection ids are cached per tracker address and reused until they
+//! reach the configured maximum age. Each request is sent from a new socket.
+//! Unanswered requests are retransmitted with a timeout that doubles for
+//! each attempt.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use aquatic_udp_protocol::{
+    AnnounceEvent as UdpAnnounceEvent, AnnounceRequest as UdpAnnounceRequest, ConnectRequest,
+    ConnectionId, InfoHash as UdpInfoHash, Ip, NumberOfBytes, NumberOfPeers, PeerId as UdpPeerId,
+    PeerKey, Port, Request, Response, ResponsePeer, ScrapeRequest, TransactionId,
+};
+use tokio::net::UdpSocket;
+use url::Url;
+
+use crate::common::*;
+use crate::config::ClientConfig;
+
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+#[derive(Default)]
+pub struct ConnectionIdCache(Mutex<HashMap<SocketAddr, (ConnectionId, Instant)>>);
+
+impl ConnectionIdCache {
+    fn get(&self, tracker_addr: SocketAddr, max_age: Duration) -> Option<ConnectionId> {
+        let mut connection_ids = self.0.lock().unwrap();
+
+        match connection_ids.get(&tracker_addr) {
+            Some((connection_id, received_at)) if received_at.elapsed() < max_age => {
+                Some(*connection_id)
+            }
+            Some(_) => {
+                connection_ids.remove(&tracker_addr);
+
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, tracker_addr: SocketAddr, connection_id: ConnectionId) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(tracker_addr, (connection_id, Instant::now()));
+    }
+
+    fn remove(&self, tracker_addr: SocketAddr) {
+        self.0.lock().unwrap().remove(&tracker_addr);
+    }
+}
+
+pub async fn announce(
+    config: &ClientConfig,
+    connection_ids: &ConnectionIdCache,
+    url: &Url,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, ClientError> {
+    let tracker_addr = resolve(url).await?;
+    let url_data = url_data(url);
+
+    let event = match request.event {
+        AnnounceEvent::Started => UdpAnnounceEvent::Started,
+        AnnounceEvent::Stopped => UdpAnnounceEvent::Stopped,
+        AnnounceEvent::Completed => UdpAnnounceEvent::Completed,
+        AnnounceEvent::None => UdpAnnounceEvent::None,
+    };
+    let peers_wanted = request
+        .numwant
+        .map(|numwant| numwant.min(i32::MAX as usize) as i32)
+        .unwrap_or(-1);
+
+    let response = send_request(config, connection_ids, tracker_addr, |connection_id| {
+        Request::Announce(UdpAnnounceRequest {
+            connection_id,
+            transaction_id: TransactionId(rand::random()),
+            info_hash: UdpInfoHash(request.info_hash),
+            peer_id: UdpPeerId(request.peer_id),
+            bytes_downloaded: NumberOfBytes(request.bytes_downloaded as i64),
+            bytes_uploaded: NumberOfBytes(request.bytes_uploaded as i64),
+            bytes_left: NumberOfBytes(request.bytes_left as i64),
+            event,
+            ip_address: None,
+            key: PeerKey(request.key.unwrap_or(0)),
+            peers_wanted: NumberOfPeers(peers_wanted),
+            port: Port(request.port),
+            url_data: url_data.clone(),
+        })
+    })
+    .await?;
+
+    match response {
+        Response::AnnounceIpv4(r) => Ok(AnnounceResponse {
+            announce_interval: r.announce_interval.0.max(0) as usize,
+            seeders: r.seeders.0.max(0) as usize,
+            leechers: r.leechers.0.max(0) as usize,
+            peers: convert_peers(r.peers),
+            warning_message: None,
+        }),
+        Response::AnnounceIpv6(r) => Ok(AnnounceResponse {
+            announce_interval: r.announce_interval.0.max(0) as usize,
+            seeders: r.seeders.0.max(0) as usize,
+            leechers: r.leechers.0.max(0) as usize,
+            peers: convert_peers(r.peers),
+            warning_message: None,
+        }),
+        Response::Error(r) => Err(ClientError::Tracker(r.message.into_owned())),
+        _ => Err(ClientError::InvalidResponse(
+            "expected announce response".into(),
+        )),
+    }
+}
+
+pub async fn scrape(
+    config: &ClientConfig,
+    connection_ids: &ConnectionIdCache,
+    url: &Url,
+    info_hashes: &[InfoHash],
+) -> Result<ScrapeResponse, ClientError> {
+    let tracker_addr = resolve(url).await?;
+
+    let response = send_request(config, connection_ids, tracker_addr, |connection_id| {
+        Request::Scrape(ScrapeRequest {
+            connection_id,
+            transaction_id: TransactionId(rand::random()),
+            info_hashes: info_hashes.iter().copied().map(UdpInfoHash).collect(),
+        })
+    })
+    .await?;
+
+    match response {
+        Response::Scrape(r) => {
+            let files = info_hashes
+                .iter()
+                .copied()
+                .zip(r.torrent_stats)
+                .map(|(info_hash, stats)| {
+                    let stats = ScrapeStatistics {
+                        seeders: stats.seeders.0.max(0) as usize,
+                        leechers: stats.leechers.0.max(0) as usize,
+                        completed: stats.completed.0.max(0) as usize,
+                    };
+
+                    (info_hash, stats)
+                })
+                .collect();
+
+            Ok(ScrapeResponse { files })
+        }
+        Response::Error(r) => Err(ClientError::Tracker(r.message.into_owned())),
+        _ => Err(ClientError::InvalidResponse(
+            "expected scrape response".into(),
+        )),
+    }
+}
+
+/// Send request, connecting first if no valid connection id is cached, and
+/// retransmit until a response with matching transaction id is received or
+/// the maximum number of retransmissions is reached
+async fn send_request<F>(
+    config: &ClientConfig,
+    connection_ids: &ConnectionIdCache,
+    tracker_addr: SocketAddr,
+    create_request: F,
+) -> Result<Response, ClientError>
+where
+    F: Fn(ConnectionId) -> Request,
+{
+    let bind_addr = if tracker_addr.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+
+    socket.connect(tracker_addr).await?;
+
+    let mut buffer = vec![0; MAX_RESPONSE_SIZE];
+
+    for attempt in 0..=config.udp_max_retransmissions {
+        let timeout = config
+            .udp_initial_timeout
+            .saturating_mul(2u32.saturating_pow(attempt));
+
+        let opt_connection_id = connection_ids.get(tracker_addr, config.udp_connection_id_max_age);
+
+        let connection_id = match opt_connection_id {
+            Some(connection_id) => connection_id,
+            None => {
+                let request = Request::Connect(ConnectRequest {
+                    transaction_id: TransactionId(rand::random()),
+                });
+
+                match send_and_receive(&socket, &mut buffer, request, timeout).await? {
+                    Some(Response::Connect(r)) => {
+                        connection_ids.insert(tracker_addr, r.connection_id);
+
+                        r.connection_id
+                    }
+                    Some(Response::Error(r)) => {
+                        return Err(ClientError::Tracker(r.message.into_owned()));
+                    }
+                    Some(_) => {
+                        return Err(ClientError::InvalidResponse(
+                            "expected connect response".into(),
+                        ));
+                    }
+                    None => {
+                        ::log::debug!("connect request to {} timed out", tracker_addr);
+
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let request = create_request(connection_id);
+
+        match send_and_receive(&socket, &mut buffer, request, timeout).await? {
+            Some(response) => return Ok(response),
+            None => {
+                ::log::debug!("request to {} timed out", tracker_addr);
+
+                // Tracker might not accept connection id anymore, e.g.,
+                // because it was restarted
+                connection_ids.remove(tracker_addr);
+            }
+        }
+    }
+
+    Err(ClientError::Timeout)
+}
+
+/// Send request and wait for response with same transaction id. Returns None
+/// on timeout.
+async fn send_and_receive(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+    request: Request,
+    timeout: Duration,
+) -> Result<Option<Response>, ClientError> {
+    let transaction_id = request_transaction_id(&request);
+    let ipv4 = socket.peer_addr()?.is_ipv4();
+
+    let mut request_bytes = Vec::new();
+
+    request.write(&mut request_bytes)?;
+
+    socket.send(&request_bytes).await?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let bytes_read = match tokio::time::timeout_at(deadline, socket.recv(buffer)).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(None),
+        };
+
+        match Response::from_bytes(&buffer[..bytes_read], ipv4) {
+            Ok(response) if response_transaction_id(&response) == transaction_id => {
+                return Ok(Some(response));
+            }
+            Ok(_) => {
+                ::log::debug!("ignoring response with unexpected transaction id");
+            }
+            Err(err) => {
+                ::log::debug!("ignoring unparsable response: {:#}", err);
+            }
+        }
+    }
+}
+
+fn request_transaction_id(request: &Request) -> TransactionId {
+    match request {
+        Request::Connect(r) => r.transaction_id,
+        Request::Announce(r) => r.transaction_id,
+        Request::Scrape(r) => r.transaction_id,
+    }
+}
+
+fn response_transaction_id(response: &Response) -> TransactionId {
+    match response {
+        Response::Connect(r) => r.transaction_id,
+        Response::AnnounceIpv4(r) => r.transaction_id,
+        Response::AnnounceIpv6(r) => r.transaction_id,
+        Response::Scrape(r) => r.transaction_id,
+        Response::Error(r) => r.transaction_id,
+    }
+}
+
+fn convert_peers<I: Ip + Into<IpAddr>>(peers: Vec<ResponsePeer<I>>) -> Vec<SocketAddr> {
+    peers
+        .into_iter()
+        .map(|peer| SocketAddr::new(peer.ip_address.into(), peer.port.0))
+        .collect()
+}
+
+async fn resolve(url: &Url) -> Result<SocketAddr, ClientError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| ClientError::InvalidUrl("no host".into()))?;
+    let port = url
+        .port()
+        .ok_or_else(|| ClientError::InvalidUrl("no port".into()))?;
+
+    // IPv6 addresses in URLs are enclosed in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| ClientError::InvalidUrl("host could not be resolved".into()))
+}
+
+/// Path and query of tracker URL, sent as BEP 41 URL data
+fn url_data(url: &Url) -> Vec<u8> {
+    let mut url_data = match url.path() {
+        "" | "/" => String::new(),
+        path => path.to_string(),
+    };
+
+    if let Some(query) = url.query() {
+        if url_data.is_empty() {
+            url_data.push('/');
+        }
+
+        url_data.push('?');
+        url_data.push_str(query);
+    }
+
+    url_data.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_data() {
+        let url_data = |url: &str| String::from_utf8(url_data(&Url::parse(url).unwrap())).unwrap();
+
+        assert_eq!(url_data("udp://localhost:3000"), "");
+        assert_eq!(url_data("udp://localhost:3000/"), "");
+        assert_eq!(url_data("udp://localhost:3000/announce"), "/announce");
+        assert_eq!(
+            url_data("udp://localhost:3000/abc/announce?a=b"),
+            "/abc/announce?a=b"
+        );
+        assert_eq!(url_data("udp://localhost:3000?a=b"), "/?a=b");
+    }
+
+    /// Check that connect requests are retransmitted and that connection
+    /// ids are reused
+    #[tokio::test]
+    async fn test_connect_retransmission_and_caching() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker_socket.local_addr().unwrap();
+
+        let tracker = tokio::spawn(async move {
+            let mut buffer = [0; MAX_RESPONSE_SIZE];
+            let mut requests = Vec::new();
+
+            for _ in 0..4 {
+                let (bytes_read, src) = tracker_socket.recv_from(&mut buffer).await.unwrap();
+                let request = Request::from_bytes(&buffer[..bytes_read], 1).unwrap();
+
+                let response = match &request {
+                    // Drop first connect request
+                    Request::Connect(_) if requests.is_empty() => None,
+                    Request::Connect(r) => {
+                        Some(Response::Connect(aquatic_udp_protocol::ConnectResponse {
+                            connection_id: ConnectionId(123),
+                            transaction_id: r.transaction_id,
+                        }))
+                    }
+                    Request::Scrape(r) => {
+                        Some(Response::Scrape(aquatic_udp_protocol::ScrapeResponse {
+                            transaction_id: r.transaction_id,
+                            torrent_stats: Vec::new(),
+                        }))
+                    }
+                    Request::Announce(_) => panic!("unexpected announce request"),
+                };
+
+                if let Some(response) = response {
+                    let mut bytes = Vec::new();
+
+                    response.write(&mut bytes).unwrap();
+                    tracker_socket.send_to(&bytes, src).await.unwrap();
+                }
+
+                requests.push(request);
+            }
+
+            requests
+        });
+
+        let config = ClientConfig {
+            udp_initial_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let connection_ids = ConnectionIdCache::default();
+
+        for _ in 0..2 {
+            let request = |connection_id| {
+                Request::Scrape(ScrapeRequest {
+                    connection_id,
+                    transaction_id: TransactionId(rand::random()),
+                    info_hashes: vec![UdpInfoHash([0; 20])],
+                })
+            };
+
+            let response = send_request(&config, &connection_ids, tracker_addr, request)
+                .await
+                .unwrap();
+
+            assert!(matches!(response, Response::Scrape(_)));
+        }
+
+        let requests = tracker.await.unwrap();
+
+        assert!(matches!(requests[0], Request::Connect(_)));
+        assert!(matches!(requests[1], Request::Connect(_)));
+
+        for request in requests[2..].iter() {
+            match request {
+                Request::Scrape(r) => assert_eq!(r.connection_id, ConnectionId(123)),
+                _ => panic!("expected scrape request"),
+            }
+        }
+    }
+}
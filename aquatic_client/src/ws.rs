@@ -0,0 +1,154 @@
+//! WebTorrent tracker protocol
+//!
+//! A new WebSocket connection is opened for each request. Announce requests
+//! are sent without WebRTC offers, so no peers are returned.
+
+use std::sync::Arc;
+
+use aquatic_ws_protocol::{
+    AnnounceAction, AnnounceEvent as WsAnnounceEvent, AnnounceRequest as WsAnnounceRequest,
+    InMessage, InfoHash as WsInfoHash, OutMessage, PeerId as WsPeerId, ScrapeAction,
+    ScrapeRequest as WsScrapeRequest, ScrapeRequestInfoHashes,
+};
+use async_tungstenite::WebSocketStream;
+use futures::{SinkExt, StreamExt};
+use url::Url;
+
+use crate::common::*;
+use crate::stream::{connect, Stream};
+
+pub async fn announce(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, ClientError> {
+    let event = match request.event {
+        AnnounceEvent::Started => Some(WsAnnounceEvent::Started),
+        AnnounceEvent::Stopped => Some(WsAnnounceEvent::Stopped),
+        AnnounceEvent::Completed => Some(WsAnnounceEvent::Completed),
+        AnnounceEvent::None => None,
+    };
+
+    let request = InMessage::AnnounceRequest(WsAnnounceRequest {
+        action: AnnounceAction,
+        info_hash: WsInfoHash(request.info_hash),
+        peer_id: WsPeerId(request.peer_id),
+        bytes_left: Some(request.bytes_left as usize),
+        event,
+        offers: None,
+        numwant: request.numwant,
+        answer: None,
+        to_peer_id: None,
+        offer_id: None,
+    });
+
+    let mut ws = connect_ws(tls_config, url).await?;
+
+    ws.send(request.to_ws_message()).await?;
+
+    let result = loop {
+        match receive(&mut ws).await? {
+            OutMessage::AnnounceResponse(r) => {
+                break Ok(AnnounceResponse {
+                    announce_interval: r.announce_interval,
+                    seeders: r.complete,
+                    leechers: r.incomplete,
+                    peers: Vec::new(),
+                    warning_message: None,
+                });
+            }
+            OutMessage::ErrorResponse(r) => {
+                break Err(ClientError::Tracker(r.failure_reason.into_owned()));
+            }
+            // Offers and answers from other peers are not relevant
+            _ => (),
+        }
+    };
+
+    close(ws).await;
+
+    result
+}
+
+pub async fn scrape(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+    info_hashes: &[InfoHash],
+) -> Result<ScrapeResponse, ClientError> {
+    let request = InMessage::ScrapeRequest(WsScrapeRequest {
+        action: ScrapeAction,
+        info_hashes: Some(ScrapeRequestInfoHashes::Multiple(
+            info_hashes.iter().copied().map(WsInfoHash).collect(),
+        )),
+    });
+
+    let mut ws = connect_ws(tls_config, url).await?;
+
+    ws.send(request.to_ws_message()).await?;
+
+    let result = loop {
+        match receive(&mut ws).await? {
+            OutMessage::ScrapeResponse(r) => {
+                let files = r
+                    .files
+                    .into_iter()
+                    .map(|(info_hash, stats)| {
+                        let stats = ScrapeStatistics {
+                            seeders: stats.complete,
+                            leechers: stats.incomplete,
+                            completed: stats.downloaded,
+                        };
+
+                        (info_hash.0, stats)
+                    })
+                    .collect();
+
+                break Ok(ScrapeResponse { files });
+            }
+            OutMessage::ErrorResponse(r) => {
+                break Err(ClientError::Tracker(r.failure_reason.into_owned()));
+            }
+            _ => (),
+        }
+    };
+
+    close(ws).await;
+
+    result
+}
+
+async fn connect_ws(
+    tls_config: &Arc<rustls::ClientConfig>,
+    url: &Url,
+) -> Result<WebSocketStream<Stream>, ClientError> {
+    let stream = connect(tls_config, url, url.scheme() == "wss").await?;
+
+    let (ws, _) = async_tungstenite::client_async(url.as_str(), stream).await?;
+
+    Ok(ws)
+}
+
+/// Receive next tracker message, skipping control frames
+async fn receive(ws: &mut WebSocketStream<Stream>) -> Result<OutMessage, ClientError> {
+    loop {
+        let message = match ws.next().await {
+            Some(message) => message?,
+            None => {
+                return Err(ClientError::InvalidResponse(
+                    "connection closed by tracker".into(),
+                ))
+            }
+        };
+
+        if message.is_text() || message.is_binary() {
+            return OutMessage::from_ws_message(message)
+                .map_err(|err| ClientError::InvalidResponse(format!("{:#}", err)));
+        }
+    }
+}
+
+async fn close(mut ws: WebSocketStream<Stream>) {
+    if let Err(err) = ws.close(None).await {
+        ::log::debug!("couldn't close websocket connection: {:#}", err);
+    }
+}
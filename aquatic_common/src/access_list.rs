@@ -1,13 +1,19 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Context;
 use aquatic_toml_config::TomlConfig;
 use arc_swap::{ArcSwap, Cache};
 use hashbrown::HashSet;
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Maximum nesting of lists and dictionaries in torrent files
+const MAX_BENCODE_DEPTH: usize = 64;
 
 /// Access list mode. Available modes are allow, deny and off.
 #[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
@@ -31,10 +37,22 @@ impl AccessListMode {
 #[serde(default, deny_unknown_fields)]
 pub struct AccessListConfig {
     pub mode: AccessListMode,
-    /// Path to access list file consisting of newline-separated hex-encoded info hashes.
+    /// Path to access list file or directory.
+    ///
+    /// Files consist of newline-separated info hashes, either hex-encoded
+    /// (40 characters), base32-encoded (32 characters) or as magnet URIs.
+    ///
+    /// If path is a directory, the info hashes of all .torrent files in it
+    /// are used. Torrents with v2 metadata (including hybrid torrents) are
+    /// also identified by their v2 info hash truncated to 20 bytes.
+    /// Invalid .torrent files are skipped with a warning.
     ///
     /// If using chroot mode, path must be relative to new root.
     pub path: PathBuf,
+    /// Check path for changes this often (seconds) and update access list
+    /// when a file has been added, removed or modified. Set to zero to only
+    /// update access list on start and on SIGUSR1.
+    pub watch_interval: u64,
 }
 
 impl Default for AccessListConfig {
@@ -42,6 +60,7 @@ impl Default for AccessListConfig {
         Self {
             path: "./access-list.txt".into(),
             mode: AccessListMode::Off,
+            watch_interval: 0,
         }
     }
 }
@@ -51,12 +70,20 @@ pub struct AccessList(HashSet<[u8; 20]>);
 
 impl AccessList {
     pub fn insert_from_line(&mut self, line: &str) -> anyhow::Result<()> {
-        self.0.insert(parse_info_hash(line)?);
+        if let Some(query) = line.strip_prefix("magnet:?") {
+            self.0.extend(parse_magnet_info_hashes(query)?);
+        } else {
+            self.0.insert(parse_info_hash(line)?);
+        }
 
         Ok(())
     }
 
     pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        if path.is_dir() {
+            return Self::create_from_torrent_dir(path);
+        }
+
         let file = File::open(path)?;
         let reader = BufReader::new(file);
 
@@ -78,6 +105,35 @@ impl AccessList {
         Ok(new_list)
     }
 
+    fn create_from_torrent_dir(path: &Path) -> anyhow::Result<Self> {
+        let mut new_list = Self::default();
+
+        for entry in ::std::fs::read_dir(path)? {
+            let path = entry?.path();
+
+            if path.extension() != Some("torrent".as_ref()) {
+                continue;
+            }
+
+            let result = ::std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| parse_torrent_info_hashes(&bytes));
+
+            match result {
+                Ok(info_hashes) => new_list.0.extend(info_hashes),
+                Err(err) => {
+                    ::log::warn!(
+                        "Skipping invalid torrent file in access list: {}: {:#}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        Ok(new_list)
+    }
+
     pub fn allows(&self, mode: AccessListMode, info_hash: &[u8; 20]) -> bool {
         match mode {
             AccessListMode::Allow => self.0.contains(info_hash),
@@ -139,14 +195,250 @@ pub fn update_access_list(
     Ok(())
 }
 
+/// Start thread updating access list when files at config.path change, if
+/// access list is on and watch_interval is set
+pub fn spawn_access_list_watcher(
+    config: &AccessListConfig,
+    access_list: &Arc<AccessListArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() && config.watch_interval != 0 {
+        let config = config.clone();
+        let access_list = access_list.clone();
+
+        ::std::thread::Builder::new()
+            .name("access-list".into())
+            .spawn(move || run_access_list_watcher(config, access_list))?;
+    }
+
+    Ok(())
+}
+
+fn run_access_list_watcher(config: AccessListConfig, access_list: Arc<AccessListArcSwap>) {
+    let interval = Duration::from_secs(config.watch_interval);
+
+    let mut opt_last_files = read_file_metadata(&config.path).ok();
+
+    loop {
+        ::std::thread::sleep(interval);
+
+        match read_file_metadata(&config.path) {
+            Ok(files) if opt_last_files.as_ref() != Some(&files) => {
+                let _ = update_access_list(&config, &access_list);
+
+                opt_last_files = Some(files);
+            }
+            Ok(_) => (),
+            Err(err) => {
+                ::log::error!("Checking access list for changes failed: {:#}", err);
+            }
+        }
+    }
+}
+
+/// Modification times and sizes of file at path or of files in directory
+/// at path
+fn read_file_metadata(path: &Path) -> anyhow::Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let paths = if path.is_dir() {
+        ::std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        vec![path.to_owned()]
+    };
+
+    let mut files = paths
+        .into_iter()
+        .map(|path| {
+            let metadata = ::std::fs::metadata(&path)?;
+
+            Ok((path, metadata.modified()?, metadata.len()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    files.sort();
+
+    Ok(files)
+}
+
+/// Parse hex-encoded or base32-encoded info hash
 fn parse_info_hash(line: &str) -> anyhow::Result<[u8; 20]> {
     let mut bytes = [0u8; 20];
 
-    hex::decode_to_slice(line, &mut bytes)?;
+    if line.len() == 32 {
+        let mut buffer = 0u64;
+        let mut num_bits = 0;
+        let mut bytes_iter = bytes.iter_mut();
+
+        for c in line.bytes() {
+            let value = match c.to_ascii_uppercase() {
+                c @ b'A'..=b'Z' => c - b'A',
+                c @ b'2'..=b'7' => c - b'2' + 26,
+                _ => return Err(anyhow::anyhow!("invalid base32 character")),
+            };
+
+            buffer = (buffer << 5) | u64::from(value);
+            num_bits += 5;
+
+            if num_bits >= 8 {
+                num_bits -= 8;
+
+                if let Some(byte) = bytes_iter.next() {
+                    *byte = (buffer >> num_bits) as u8;
+                }
+            }
+        }
+    } else {
+        hex::decode_to_slice(line, &mut bytes)?;
+    }
 
     Ok(bytes)
 }
 
+/// Parse info hashes in exact topic parameters of magnet URI query. Hybrid
+/// torrents have both a v1 (btih) and a v2 (btmh) exact topic. v2 info
+/// hashes are truncated to 20 bytes.
+fn parse_magnet_info_hashes(query: &str) -> anyhow::Result<Vec<[u8; 20]>> {
+    let mut info_hashes = Vec::new();
+
+    for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        if key != "xt" && !key.starts_with("xt.") {
+            continue;
+        }
+
+        if let Some(info_hash) = value.strip_prefix("urn:btih:") {
+            info_hashes.push(parse_info_hash(info_hash)?);
+        } else if let Some(multihash) = value.strip_prefix("urn:btmh:") {
+            // Multihash prefix: 0x12 (SHA-256), 0x20 (32 bytes)
+            let sha256_hash = multihash
+                .strip_prefix("1220")
+                .ok_or_else(|| anyhow::anyhow!("unsupported multihash in magnet URI"))?;
+
+            let mut bytes = [0u8; 32];
+
+            hex::decode_to_slice(sha256_hash, &mut bytes)?;
+
+            info_hashes.push(truncate_v2_info_hash(&bytes));
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return Err(anyhow::anyhow!("no info hash in magnet URI"));
+    }
+
+    Ok(info_hashes)
+}
+
+/// Calculate info hashes of torrent file: v1 info hash unless torrent only
+/// has v2 metadata, and truncated v2 info hash if torrent has v2 metadata
+fn parse_torrent_info_hashes(bytes: &[u8]) -> anyhow::Result<Vec<[u8; 20]>> {
+    let info = bencode_dictionary_entries(bytes)?
+        .into_iter()
+        .find_map(|(key, value)| (key == b"info").then_some(value))
+        .ok_or_else(|| anyhow::anyhow!("no info dictionary"))?;
+
+    let info_entries = bencode_dictionary_entries(info)?;
+
+    let has_v1_metadata = info_entries.iter().any(|(key, _)| *key == b"pieces");
+    let has_v2_metadata = info_entries
+        .iter()
+        .any(|(key, value)| *key == b"meta version" && *value == b"i2e");
+
+    let mut info_hashes = Vec::new();
+
+    if has_v1_metadata || !has_v2_metadata {
+        info_hashes.push(Sha1::digest(info).into());
+    }
+    if has_v2_metadata {
+        info_hashes.push(truncate_v2_info_hash(&Sha256::digest(info)));
+    }
+
+    Ok(info_hashes)
+}
+
+fn truncate_v2_info_hash(hash: &[u8]) -> [u8; 20] {
+    let mut bytes = [0u8; 20];
+
+    bytes.copy_from_slice(&hash[..20]);
+
+    bytes
+}
+
+/// Split bencoded dictionary into raw keys and raw bencoded values
+fn bencode_dictionary_entries(bytes: &[u8]) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+    if bytes.first() != Some(&b'd') {
+        return Err(anyhow::anyhow!("expected bencoded dictionary"));
+    }
+
+    let mut entries = Vec::new();
+    let mut position = 1;
+
+    while bytes.get(position) != Some(&b'e') {
+        if bytes.get(position).map_or(true, |b| !b.is_ascii_digit()) {
+            return Err(anyhow::anyhow!(
+                "expected bencoded string as dictionary key"
+            ));
+        }
+
+        let key_end = bencode_value_end(bytes, position, 0)?;
+        let value_end = bencode_value_end(bytes, key_end, 0)?;
+
+        let key = &bytes[position..key_end];
+        let key = &key[key.iter().position(|b| *b == b':').unwrap_or(0) + 1..];
+
+        entries.push((key, &bytes[key_end..value_end]));
+
+        position = value_end;
+    }
+
+    Ok(entries)
+}
+
+/// Return position after end of bencoded value starting at start
+fn bencode_value_end(bytes: &[u8], start: usize, depth: usize) -> anyhow::Result<usize> {
+    let unexpected_end = || anyhow::anyhow!("unexpected end of bencoded data");
+
+    if depth > MAX_BENCODE_DEPTH {
+        return Err(anyhow::anyhow!("bencoded data nested too deeply"));
+    }
+
+    match bytes.get(start) {
+        Some(b'i') => bytes[start..]
+            .iter()
+            .position(|b| *b == b'e')
+            .map(|offset| start + offset + 1)
+            .ok_or_else(unexpected_end),
+        Some(b'l' | b'd') => {
+            let mut position = start + 1;
+
+            while bytes.get(position) != Some(&b'e') {
+                position = bencode_value_end(bytes, position, depth + 1)?;
+            }
+
+            Ok(position + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let colon_offset = bytes[start..]
+                .iter()
+                .position(|b| *b == b':')
+                .ok_or_else(unexpected_end)?;
+
+            let len: usize = ::std::str::from_utf8(&bytes[start..start + colon_offset])?.parse()?;
+
+            let end = (start + colon_offset + 1)
+                .checked_add(len)
+                .ok_or_else(|| anyhow::anyhow!("invalid bencoded string length"))?;
+
+            if end > bytes.len() {
+                return Err(unexpected_end());
+            }
+
+            Ok(end)
+        }
+        Some(_) => Err(anyhow::anyhow!("invalid bencoded data")),
+        None => Err(unexpected_end()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -159,6 +451,98 @@ mod tests {
         assert!(f("aaaabbbbccccddddeeeeaaaabbbbccccddddeeeef".into()).is_err());
         assert!(f("aaaabbbbccccddddeeeeaaaabbbbccccddddeee".into()).is_err());
         assert!(f("aaaabbbbccccddddeeeeaaaabbbbccccddddeeeö".into()).is_err());
+
+        assert_eq!(
+            f("AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap(),
+            f("0123456789abcdef0123456789abcdef01234567").unwrap()
+        );
+        assert_eq!(
+            f("vkvkvkvkvkvkvkvkvkvkvkvkvkvkvkvk").unwrap(),
+            f("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap()
+        );
+        assert!(f("AERUKZ4JVPG66AJDIVTYTK6N54ASGRL1").is_err());
+    }
+
+    #[test]
+    fn test_insert_magnet_uri() {
+        let mut access_list = AccessList::default();
+
+        access_list
+            .insert_from_line("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=a")
+            .unwrap();
+        access_list
+            .insert_from_line(&format!(
+                "magnet:?xt=urn:btih:VKVKVKVKVKVKVKVKVKVKVKVKVKVKVKVK&xt=urn:btmh:1220{}",
+                "b".repeat(64)
+            ))
+            .unwrap();
+
+        assert!(access_list
+            .insert_from_line("magnet:?dn=a&tr=udp://127.0.0.1:3000")
+            .is_err());
+
+        for info_hash in [
+            "0123456789abcdef0123456789abcdef01234567",
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+        ] {
+            assert!(access_list.0.contains(&parse_info_hash(info_hash).unwrap()));
+        }
+
+        assert_eq!(access_list.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_torrent_info_hashes() {
+        let v1 = b"d8:announce3:foo4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+        let hybrid = b"d8:announce3:foo4:infod6:lengthi1e12:meta versioni2e4:name1:a12:piece lengthi16384e6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+
+        assert_eq!(
+            parse_torrent_info_hashes(v1).unwrap(),
+            vec![parse_info_hash("3e4563f4994f40610251ef9c7e6c90533b688b5e").unwrap()]
+        );
+        assert_eq!(
+            parse_torrent_info_hashes(hybrid).unwrap(),
+            vec![
+                parse_info_hash("98f6e1c0caaeb10f24f9a052c02d22dc8cd4f22f").unwrap(),
+                parse_info_hash("b7d3a36760a2f5e3747224f23b78768285867fe6").unwrap(),
+            ]
+        );
+
+        assert!(parse_torrent_info_hashes(b"d8:announce3:fooe").is_err());
+        assert!(parse_torrent_info_hashes(&v1[..v1.len() - 2]).is_err());
+
+        // String length that would overflow end position
+        let overflow = format!("d4:info{}:xe", usize::MAX);
+
+        assert!(parse_torrent_info_hashes(overflow.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_create_from_torrent_dir() {
+        let v1 = b"d8:announce3:foo4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:xxxxxxxxxxxxxxxxxxxxee";
+
+        let dir = ::std::env::temp_dir().join(format!(
+            "aquatic_common_test_create_from_torrent_dir_{}",
+            ::std::process::id()
+        ));
+
+        ::std::fs::create_dir_all(&dir).unwrap();
+        ::std::fs::write(dir.join("good.torrent"), v1).unwrap();
+        ::std::fs::write(dir.join("bad.torrent"), b"d8:announce3:fooe").unwrap();
+        ::std::fs::write(dir.join("other.txt"), b"not a torrent").unwrap();
+
+        let result = AccessList::create_from_torrent_dir(&dir);
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+
+        let access_list = result.unwrap();
+
+        assert_eq!(access_list.len(), 1);
+        assert!(access_list.allows(
+            AccessListMode::Allow,
+            &parse_info_hash("3e4563f4994f40610251ef9c7e6c90533b688b5e").unwrap()
+        ));
     }
 
     #[test]
@@ -0,0 +1,178 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use aquatic_toml_config::TomlConfig;
+use serde::Deserialize;
+
+/// Update requests per second this often
+const REQUEST_RATE_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Adapt announce intervals sent to peers to swarm size and load
+///
+/// The configured announce interval is lengthened proportionally for
+/// torrents with more than large_swarm_peers peers and when socket workers
+/// receive more than max_requests_per_second requests per second. The result
+/// is then bounded by min_interval and max_interval.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveAnnounceIntervalConfig {
+    pub active: bool,
+    /// Never ask peers to announce more often than this (seconds)
+    pub min_interval: usize,
+    /// Never ask peers to announce less often than this (seconds). Must be
+    /// lower than cleaning.max_peer_age, since peers that don't announce
+    /// within it are removed from swarms.
+    pub max_interval: usize,
+    /// Lengthen intervals for torrents with more peers than this. Set to zero
+    /// to not take swarm size into account.
+    pub large_swarm_peers: usize,
+    /// Lengthen intervals when more requests than this are received per
+    /// second. Set to zero to not take request rate into account.
+    pub max_requests_per_second: usize,
+}
+
+impl Default for AdaptiveAnnounceIntervalConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            min_interval: 60,
+            max_interval: 60 * 15,
+            large_swarm_peers: 1000,
+            max_requests_per_second: 10_000,
+        }
+    }
+}
+
+impl AdaptiveAnnounceIntervalConfig {
+    /// Make sure that peers asked to announce as seldom as max_interval
+    /// aren't removed from swarms before their next announce
+    pub fn validate(&self, max_peer_age: u32) -> anyhow::Result<()> {
+        if self.active && self.max_interval >= max_peer_age as usize {
+            Err(anyhow::anyhow!(
+                "adaptive_announce_interval.max_interval ({}) must be lower than cleaning.max_peer_age ({})",
+                self.max_interval,
+                max_peer_age
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Calculate announce interval for torrent with given number of peers.
+    /// Returns base_interval if adaptive intervals are not active.
+    pub fn calculate(
+        &self,
+        base_interval: usize,
+        num_peers: usize,
+        request_rate: &RequestRate,
+    ) -> usize {
+        if !self.active {
+            return base_interval;
+        }
+
+        let mut interval = base_interval;
+
+        if self.large_swarm_peers != 0 && num_peers > self.large_swarm_peers {
+            interval = interval.saturating_mul(num_peers) / self.large_swarm_peers;
+        }
+
+        let requests_per_second = request_rate.requests_per_second();
+
+        if self.max_requests_per_second != 0 && requests_per_second > self.max_requests_per_second {
+            interval = interval.saturating_mul(requests_per_second) / self.max_requests_per_second;
+        }
+
+        interval.max(self.min_interval).min(self.max_interval)
+    }
+}
+
+/// Number of requests received by socket workers per second
+#[derive(Default)]
+pub struct RequestRate {
+    num_requests: AtomicUsize,
+    requests_per_second: AtomicUsize,
+}
+
+impl RequestRate {
+    pub fn add(&self, num_requests: usize) {
+        self.num_requests.fetch_add(num_requests, Ordering::Relaxed);
+    }
+
+    pub fn requests_per_second(&self) -> usize {
+        self.requests_per_second.load(Ordering::Relaxed)
+    }
+
+    /// Periodically calculate requests per second from requests added since
+    /// last calculation. Never returns.
+    pub fn run_updater(&self) {
+        let mut last_update = Instant::now();
+
+        loop {
+            ::std::thread::sleep(REQUEST_RATE_UPDATE_INTERVAL);
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(last_update).as_secs_f64();
+            let num_requests = self.num_requests.swap(0, Ordering::Relaxed);
+
+            self.requests_per_second
+                .store((num_requests as f64 / elapsed) as usize, Ordering::Relaxed);
+
+            last_update = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_interval() {
+        let config = AdaptiveAnnounceIntervalConfig {
+            active: true,
+            min_interval: 100,
+            max_interval: 1000,
+            large_swarm_peers: 10,
+            max_requests_per_second: 50,
+        };
+
+        let request_rate = RequestRate::default();
+
+        assert_eq!(config.calculate(200, 10, &request_rate), 200);
+        assert_eq!(config.calculate(200, 20, &request_rate), 400);
+        assert_eq!(config.calculate(200, 1000, &request_rate), 1000);
+        assert_eq!(config.calculate(50, 0, &request_rate), 100);
+
+        request_rate
+            .requests_per_second
+            .store(75, Ordering::Relaxed);
+
+        assert_eq!(config.calculate(200, 10, &request_rate), 300);
+        assert_eq!(config.calculate(200, 20, &request_rate), 600);
+
+        let config = AdaptiveAnnounceIntervalConfig {
+            active: false,
+            ..config
+        };
+
+        assert_eq!(config.calculate(200, 20, &request_rate), 200);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = AdaptiveAnnounceIntervalConfig {
+            active: true,
+            ..Default::default()
+        };
+
+        assert!(config.validate(1200).is_ok());
+        assert!(config.validate(900).is_err());
+
+        let config = AdaptiveAnnounceIntervalConfig {
+            active: false,
+            ..config
+        };
+
+        assert!(config.validate(900).is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Client filter mode. Available modes are allow, deny and off.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientFilterMode {
+    /// Only serve clients with peer_id matching a rule in file
+    Allow,
+    /// Do not serve clients with peer_id matching a rule in file
+    Deny,
+    /// Turn off client filter functionality
+    Off,
+}
+
+impl ClientFilterMode {
+    pub fn is_on(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientFilterConfig {
+    pub mode: ClientFilterMode,
+    /// Path to client filter file consisting of newline-separated rules.
+    /// Rules are either peer_id prefixes, such as -TR3000- or -qB, or
+    /// regular expressions prefixed with "regex:", such as
+    /// regex:^-XX\d{4}-. Lines starting with # are ignored.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+}
+
+impl Default for ClientFilterConfig {
+    fn default() -> Self {
+        Self {
+            path: "./client-filter.txt".into(),
+            mode: ClientFilterMode::Off,
+        }
+    }
+}
+
+enum Pattern {
+    Prefix(Vec<u8>),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn matches(&self, peer_id: &[u8; 20]) -> bool {
+        match self {
+            Self::Prefix(prefix) => peer_id.starts_with(prefix),
+            Self::Regex(regex) => regex.is_match(peer_id),
+        }
+    }
+}
+
+struct ClientFilterRule {
+    /// Line in client filter file
+    line: String,
+    pattern: Pattern,
+    /// Number of peer_ids checked that matched this rule
+    num_matches: AtomicUsize,
+}
+
+/// Reason for client filter not allowing a peer_id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientFilterRejection {
+    /// Deny mode: peer_id matched rule
+    Denied { rule: String },
+    /// Allow mode: peer_id didn't match any rule
+    NotAllowed,
+}
+
+impl Display for ClientFilterRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Denied { rule } => write!(f, "Client not allowed (banned: {})", rule),
+            Self::NotAllowed => write!(f, "Client not allowed (not on list of allowed clients)"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ClientFilter {
+    rules: Vec<ClientFilterRule>,
+    /// Number of peer_ids checked that matched no rule
+    num_unmatched: AtomicUsize,
+}
+
+impl ClientFilter {
+    pub fn insert_from_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let pattern = if let Some(regex) = line.strip_prefix("regex:") {
+            Pattern::Regex(Regex::new(regex)?)
+        } else if line.len() > 20 {
+            return Err(anyhow::anyhow!("prefix is longer than 20 bytes"));
+        } else {
+            Pattern::Prefix(line.as_bytes().to_vec())
+        };
+
+        self.rules.push(ClientFilterRule {
+            line: line.to_owned(),
+            pattern,
+            num_matches: AtomicUsize::new(0),
+        });
+
+        Ok(())
+    }
+
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let mut new_filter = Self::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            new_filter
+                .insert_from_line(line)
+                .with_context(|| format!("Invalid line in client filter: {}", line))?;
+        }
+
+        Ok(new_filter)
+    }
+
+    /// Returns index of first matching rule, if any
+    pub fn find_match(&self, peer_id: &[u8; 20]) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|rule| rule.pattern.matches(peer_id))
+    }
+
+    /// Check if peer_id is allowed and update match counters
+    pub fn check(
+        &self,
+        mode: ClientFilterMode,
+        peer_id: &[u8; 20],
+    ) -> Result<(), ClientFilterRejection> {
+        if !mode.is_on() {
+            return Ok(());
+        }
+
+        let opt_index = self.find_match(peer_id);
+
+        let counter = match opt_index {
+            Some(index) => &self.rules[index].num_matches,
+            None => &self.num_unmatched,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        match (mode, opt_index) {
+            (ClientFilterMode::Deny, Some(index)) => Err(ClientFilterRejection::Denied {
+                rule: self.rules[index].line.clone(),
+            }),
+            (ClientFilterMode::Allow, None) => Err(ClientFilterRejection::NotAllowed),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn allows(&self, mode: ClientFilterMode, peer_id: &[u8; 20]) -> bool {
+        match mode {
+            ClientFilterMode::Allow => self.find_match(peer_id).is_some(),
+            ClientFilterMode::Deny => self.find_match(peer_id).is_none(),
+            ClientFilterMode::Off => true,
+        }
+    }
+
+    /// Rules and number of peer_ids that matched them
+    pub fn match_counts(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.rules
+            .iter()
+            .map(|rule| (rule.line.as_str(), rule.num_matches.load(Ordering::Relaxed)))
+    }
+
+    /// Number of peer_ids that didn't match any rule
+    pub fn num_unmatched(&self) -> usize {
+        self.num_unmatched.load(Ordering::Relaxed)
+    }
+
+    /// Carry over match counts for rules present in previous filter
+    fn inherit_counts(&self, previous: &Self) {
+        for rule in self.rules.iter() {
+            if let Some(previous_rule) = previous.rules.iter().find(|r| r.line == rule.line) {
+                rule.num_matches.store(
+                    previous_rule.num_matches.load(Ordering::Relaxed),
+                    Ordering::Relaxed,
+                );
+            }
+        }
+
+        self.num_unmatched
+            .store(previous.num_unmatched(), Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+pub type ClientFilterArcSwap = ArcSwap<ClientFilter>;
+pub type ClientFilterCache = Cache<Arc<ClientFilterArcSwap>, Arc<ClientFilter>>;
+
+pub fn create_client_filter_cache(arc_swap: &Arc<ClientFilterArcSwap>) -> ClientFilterCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_client_filter(
+    config: &ClientFilterConfig,
+    client_filter: &Arc<ClientFilterArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match ClientFilter::create_from_path(&config.path) {
+            Ok(new_filter) => {
+                new_filter.inherit_counts(&client_filter.load());
+
+                client_filter.store(Arc::new(new_filter));
+
+                ::log::info!("Client filter updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating client filter failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_filter_allows() {
+        let mut client_filter = ClientFilter::default();
+
+        client_filter.insert_from_line("-TR3000-").unwrap();
+        client_filter.insert_from_line("-qB").unwrap();
+        client_filter.insert_from_line(r"regex:^-XX\d{4}-").unwrap();
+
+        assert!(client_filter
+            .insert_from_line("-aaaaaaaaaaaaaaaaaaaaaaaaa-")
+            .is_err());
+        assert!(client_filter.insert_from_line("regex:(").is_err());
+
+        let a = *b"-TR3000-aaaaaaaaaaaa";
+        let b = *b"-qB4250-aaaaaaaaaaaa";
+        let c = *b"-TR2940-aaaaaaaaaaaa";
+        let d = *b"-XX1234-aaaaaaaaaaaa";
+        let e = *b"-XX12a4-aaaaaaaaaaaa";
+
+        assert!(client_filter.allows(ClientFilterMode::Allow, &a));
+        assert!(client_filter.allows(ClientFilterMode::Allow, &b));
+        assert!(!client_filter.allows(ClientFilterMode::Allow, &c));
+        assert!(client_filter.allows(ClientFilterMode::Allow, &d));
+        assert!(!client_filter.allows(ClientFilterMode::Allow, &e));
+
+        assert!(!client_filter.allows(ClientFilterMode::Deny, &a));
+        assert!(!client_filter.allows(ClientFilterMode::Deny, &b));
+        assert!(client_filter.allows(ClientFilterMode::Deny, &c));
+        assert!(!client_filter.allows(ClientFilterMode::Deny, &d));
+        assert!(client_filter.allows(ClientFilterMode::Deny, &e));
+
+        assert!(client_filter.allows(ClientFilterMode::Off, &a));
+        assert!(client_filter.allows(ClientFilterMode::Off, &c));
+    }
+
+    #[test]
+    fn test_client_filter_check() {
+        let mut client_filter = ClientFilter::default();
+
+        client_filter.insert_from_line("-TR3000-").unwrap();
+        client_filter.insert_from_line(r"regex:^-XX\d{4}-").unwrap();
+
+        let a = *b"-TR3000-aaaaaaaaaaaa";
+        let b = *b"-XX1234-aaaaaaaaaaaa";
+        let c = *b"-qB4250-aaaaaaaaaaaa";
+
+        assert_eq!(
+            client_filter.check(ClientFilterMode::Deny, &b),
+            Err(ClientFilterRejection::Denied {
+                rule: r"regex:^-XX\d{4}-".into()
+            })
+        );
+        assert_eq!(client_filter.check(ClientFilterMode::Deny, &c), Ok(()));
+        assert_eq!(client_filter.check(ClientFilterMode::Allow, &a), Ok(()));
+        assert_eq!(
+            client_filter.check(ClientFilterMode::Allow, &c),
+            Err(ClientFilterRejection::NotAllowed)
+        );
+        assert_eq!(client_filter.check(ClientFilterMode::Off, &c), Ok(()));
+
+        let counts: Vec<(&str, usize)> = client_filter.match_counts().collect();
+
+        assert_eq!(counts, vec![("-TR3000-", 1), (r"regex:^-XX\d{4}-", 1)]);
+        assert_eq!(client_filter.num_unmatched(), 2);
+
+        let mut new_filter = ClientFilter::default();
+
+        new_filter.insert_from_line("-TR3000-").unwrap();
+        new_filter.insert_from_line("-qB").unwrap();
+        new_filter.inherit_counts(&client_filter);
+
+        let counts: Vec<(&str, usize)> = new_filter.match_counts().collect();
+
+        assert_eq!(counts, vec![("-TR3000-", 1), ("-qB", 0)]);
+    }
+}
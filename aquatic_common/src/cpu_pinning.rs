@@ -16,7 +16,6 @@ impl Default for CpuPinningDirection {
     }
 }
 
-#[cfg(feature = "glommio")]
 #[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum HyperThreadMapping {
@@ -25,7 +24,6 @@ pub enum HyperThreadMapping {
     Split,
 }
 
-#[cfg(feature = "glommio")]
 impl Default for HyperThreadMapping {
     fn default() -> Self {
         Self::System
@@ -35,7 +33,6 @@ impl Default for HyperThreadMapping {
 pub trait CpuPinningConfig {
     fn active(&self) -> bool;
     fn direction(&self) -> CpuPinningDirection;
-    #[cfg(feature = "glommio")]
     fn hyperthread(&self) -> HyperThreadMapping;
     fn core_offset(&self) -> usize;
 }
@@ -54,7 +51,8 @@ pub mod mod_name {
     pub struct struct_name {
         pub active: bool,
         pub direction: CpuPinningDirection,
-        #[cfg(feature = "glommio")]
+        /// Hyperthread mapping. Only supported by glommio-based workers:
+        /// startup fails if it is set to anything but "system" otherwise.
         pub hyperthread: HyperThreadMapping,
         pub core_offset: usize,
     }
@@ -64,7 +62,6 @@ pub mod mod_name {
             Self {
                 active: false,
                 direction: cpu_pinning_direction,
-                #[cfg(feature = "glommio")]
                 hyperthread: Default::default(),
                 core_offset: 0,
             }
@@ -77,7 +74,6 @@ pub mod mod_name {
         fn direction(&self) -> CpuPinningDirection {
             self.direction
         }
-        #[cfg(feature = "glommio")]
         fn hyperthread(&self) -> HyperThreadMapping {
             self.hyperthread
         }
@@ -267,6 +263,21 @@ pub mod glommio {
     }
 }
 
+/// Return an error if a hyperthread mapping other than the default is set
+///
+/// Call on startup when not pinning workers with glommio, since other
+/// mappings are only implemented there.
+pub fn require_system_hyperthread_mapping<C: CpuPinningConfig>(config: &C) -> anyhow::Result<()> {
+    if config.hyperthread() == HyperThreadMapping::System {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "CPU pinning: hyperthread mapping {:?} is only supported with glommio, set it to \"system\"",
+            config.hyperthread()
+        ))
+    }
+}
+
 /// Pin current thread to a suitable core
 ///
 /// Requires hwloc (`apt-get install libhwloc-dev`)
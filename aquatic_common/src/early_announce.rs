@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+/// Number of characters allowed in client codes: 0-9, A-Z and a-z
+const NUM_CODE_CHARS: usize = 62;
+const NUM_CODES: usize = NUM_CODE_CHARS * NUM_CODE_CHARS;
+
+/// How to respond to announce requests without event from peers that
+/// announced less than the minimum announce interval ago. Available values
+/// are cached and failure.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EarlyAnnounceAction {
+    /// Respond with current torrent statistics and the peers sent in
+    /// response to the last regular announce, but don't update peer or
+    /// select new peers (or relay offers) for it. Peer lists are stored
+    /// for every peer while this is active.
+    Cached,
+    /// Respond with failure/error message
+    Failure,
+}
+
+/// Number of early announces by client
+///
+/// Clients are identified by the two-character client code of Azureus-style
+/// peer_ids, e.g., TR for -TR3000-. Other peer_ids are counted together.
+pub struct EarlyAnnounceCounts(Box<[AtomicUsize]>);
+
+impl Default for EarlyAnnounceCounts {
+    fn default() -> Self {
+        Self(
+            ::std::iter::repeat_with(AtomicUsize::default)
+                .take(NUM_CODES + 1)
+                .collect(),
+        )
+    }
+}
+
+impl EarlyAnnounceCounts {
+    pub fn increment(&self, peer_id: &[u8; 20]) {
+        self.0[code_index(peer_id)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Non-zero counts by client code (e.g., -TR), with "other" used for
+    /// peer_ids without client code
+    pub fn counts(&self) -> impl Iterator<Item = (String, usize)> + '_ {
+        self.0.iter().enumerate().filter_map(|(index, count)| {
+            let count = count.load(Ordering::Relaxed);
+
+            if count == 0 {
+                return None;
+            }
+
+            let client = if index == NUM_CODES {
+                "other".to_string()
+            } else {
+                format!(
+                    "-{}{}",
+                    code_char(index / NUM_CODE_CHARS),
+                    code_char(index % NUM_CODE_CHARS)
+                )
+            };
+
+            Some((client, count))
+        })
+    }
+}
+
+fn code_index(peer_id: &[u8; 20]) -> usize {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return NUM_CODES;
+    }
+
+    match (char_index(peer_id[1]), char_index(peer_id[2])) {
+        (Some(a), Some(b)) => a * NUM_CODE_CHARS + b,
+        _ => NUM_CODES,
+    }
+}
+
+fn char_index(c: u8) -> Option<usize> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as usize),
+        b'A'..=b'Z' => Some((c - b'A') as usize + 10),
+        b'a'..=b'z' => Some((c - b'a') as usize + 36),
+        _ => None,
+    }
+}
+
+fn code_char(index: usize) -> char {
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz"[index] as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_early_announce_counts() {
+        let counts = EarlyAnnounceCounts::default();
+
+        counts.increment(b"-TR3000-aaaaaaaaaaaa");
+        counts.increment(b"-TR2940-bbbbbbbbbbbb");
+        counts.increment(b"-qB4250-aaaaaaaaaaaa");
+        counts.increment(b"M4-4-0--aaaaaaaaaaaa");
+        counts.increment(b"-%%1000-aaaaaaaaaaaa");
+
+        let mut counts = counts.counts().collect::<Vec<_>>();
+
+        counts.sort();
+
+        assert_eq!(
+            counts,
+            vec![
+                ("-TR".to_string(), 2),
+                ("-qB".to_string(), 1),
+                ("other".to_string(), 2),
+            ]
+        );
+    }
+}
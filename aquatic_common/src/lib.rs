@@ -7,11 +7,17 @@ use ahash::RandomState;
 use rand::Rng;
 
 pub mod access_list;
+pub mod adaptive_interval;
 pub mod cli;
+pub mod client_filter;
 pub mod cpu_pinning;
+pub mod early_announce;
+pub mod peer_identity;
 pub mod privileges;
 #[cfg(feature = "rustls")]
 pub mod rustls_config;
+pub mod shutdown;
+pub mod url_access;
 
 /// IndexMap using AHash hasher
 pub type IndexMap<K, V> = indexmap::IndexMap<K, V, RandomState>;
@@ -36,6 +42,10 @@ impl ValidUntil {
     pub fn valid(&self, now: SecondsSinceServerStart) -> bool {
         self.0 .0 > now.0
     }
+    /// Number of seconds left until no longer valid
+    pub fn seconds_left(&self, now: SecondsSinceServerStart) -> u32 {
+        self.0.seconds_since(now)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -59,6 +69,13 @@ impl ServerStartInstant {
 #[derive(Debug, Clone, Copy)]
 pub struct SecondsSinceServerStart(u32);
 
+impl SecondsSinceServerStart {
+    /// Number of seconds between `earlier` and self
+    pub fn seconds_since(&self, earlier: Self) -> u32 {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
 pub struct PanicSentinelWatcher(Arc<AtomicBool>);
 
 impl PanicSentinelWatcher {
@@ -162,6 +179,61 @@ where
     }
 }
 
+/// Extract response peers for which `filter` returns true
+///
+/// Works like [`extract_response_peers`], but filters peers before doing the
+/// selection, so that excluded peers don't take up places in the response.
+/// Since this iterates over the whole map, only use it when there are peers
+/// that actually need to be excluded.
+#[inline]
+pub fn extract_filtered_response_peers<K, V, R, P, F>(
+    rng: &mut impl Rng,
+    peer_map: &IndexMap<K, V>,
+    max_num_peers_to_take: usize,
+    sender_peer_map_key: K,
+    filter: P,
+    peer_conversion_function: F,
+) -> Vec<R>
+where
+    K: Eq + ::std::hash::Hash,
+    P: Fn(&V) -> bool,
+    F: Fn(&V) -> R,
+{
+    let indices: Vec<usize> = peer_map
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (k, v))| (*k != sender_peer_map_key && filter(v)).then_some(i))
+        .collect();
+
+    let num_indices = indices.len();
+
+    let (first_range, second_range) = if num_indices <= max_num_peers_to_take {
+        (0..num_indices, num_indices..num_indices)
+    } else {
+        let half_num_to_take = max_num_peers_to_take / 2;
+        let half_num_indices = num_indices / 2;
+
+        let offset_first_half =
+            rng.gen_range(0..(half_num_indices + (num_indices % 2)) - half_num_to_take);
+        let offset_second_half = rng.gen_range(half_num_indices..num_indices - half_num_to_take);
+
+        let end_first_half = offset_first_half + half_num_to_take;
+        let end_second_half = offset_second_half + half_num_to_take + (max_num_peers_to_take % 2);
+
+        (
+            offset_first_half..end_first_half,
+            offset_second_half..end_second_half,
+        )
+    };
+
+    indices[first_range]
+        .iter()
+        .chain(indices[second_range].iter())
+        .filter_map(|i| peer_map.get_index(*i))
+        .map(|(_, peer)| peer_conversion_function(peer))
+        .collect()
+}
+
 /// SocketAddr that is not an IPv6-mapped IPv4 address
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct CanonicalSocketAddr(SocketAddr);
@@ -208,3 +280,35 @@ impl CanonicalSocketAddr {
         self.0.is_ipv4()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_extract_filtered_response_peers() {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        let mut peer_map: IndexMap<usize, usize> = Default::default();
+
+        for i in 0..100 {
+            peer_map.insert(i, i);
+        }
+
+        for max_num_peers_to_take in [0, 1, 10, 49, 50, 51, 100] {
+            let peers = extract_filtered_response_peers(
+                &mut rng,
+                &peer_map,
+                max_num_peers_to_take,
+                0,
+                |peer| peer % 2 == 0,
+                |peer| *peer,
+            );
+
+            assert_eq!(peers.len(), max_num_peers_to_take.min(49));
+            assert!(peers.iter().all(|peer| peer % 2 == 0 && *peer != 0));
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+/// How to handle announce requests for a peer_id that is registered from a
+/// different IP address. Peers can prove their identity by sending the same
+/// key as in earlier requests. Available values are off, lenient and strict.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerIdentityCheck {
+    /// Allow any request to update peer
+    Off,
+    /// Allow request to update peer if key matches or if peer was registered
+    /// without key
+    Lenient,
+    /// Only allow request to update peer if key matches
+    Strict,
+}
+
+impl PeerIdentityCheck {
+    /// Check if request with request_key, sent from a different IP address
+    /// than the one the peer was registered from, may update peer
+    pub fn allows_ip_change<K: PartialEq + ?Sized>(
+        self,
+        peer_key: Option<&K>,
+        request_key: Option<&K>,
+    ) -> bool {
+        match self {
+            Self::Off => true,
+            Self::Lenient => peer_key.map_or(true, |key| Some(key) == request_key),
+            Self::Strict => peer_key.is_some() && peer_key == request_key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows_ip_change() {
+        let a = Some(&1u32);
+        let b = Some(&2u32);
+
+        for (peer_key, request_key, off, lenient, strict) in [
+            (a, a, true, true, true),
+            (a, b, true, false, false),
+            (a, None, true, false, false),
+            (None, a, true, true, false),
+            (None, None, true, true, false),
+        ] {
+            assert_eq!(
+                PeerIdentityCheck::Off.allows_ip_change(peer_key, request_key),
+                off
+            );
+            assert_eq!(
+                PeerIdentityCheck::Lenient.allows_ip_change(peer_key, request_key),
+                lenient
+            );
+            assert_eq!(
+                PeerIdentityCheck::Strict.allows_ip_change(peer_key, request_key),
+                strict
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use serde::Deserialize;
+
+use aquatic_toml_config::TomlConfig;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// On SIGTERM, stop accepting connections and let existing ones finish
+    /// (or close them cleanly) before exiting, instead of exiting immediately
+    pub graceful: bool,
+    /// Maximum time to wait for connections to finish during graceful
+    /// shutdown (seconds). Remaining connections are then dropped.
+    pub drain_timeout: u64,
+    /// Directory to save torrent state to during graceful shutdown and load
+    /// it from on startup. Files are removed after loading. Leave empty to
+    /// not persist state.
+    pub state_path: PathBuf,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            graceful: false,
+            drain_timeout: 10,
+            state_path: "".into(),
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn persist_state(&self) -> bool {
+        self.state_path != PathBuf::new()
+    }
+}
+
+/// Triggered by main thread to tell workers to shut down gracefully
+///
+/// Waiting doesn't depend on a specific async runtime.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    receiver: Shared<oneshot::Receiver<()>>,
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        let (sender, receiver) = oneshot::channel();
+
+        Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver: receiver.shared(),
+        }
+    }
+}
+
+impl ShutdownSignal {
+    pub fn trigger(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.receiver.clone().now_or_never().is_some()
+    }
+
+    /// Wait until signal is triggered
+    pub async fn wait(&self) {
+        let _ = self.receiver.clone().await;
+    }
+}
+
+/// Run function joining worker threads on separate thread, returning error
+/// if it doesn't finish in time or returns false
+pub fn join_workers_with_timeout<F>(join_workers: F, timeout: Duration) -> anyhow::Result<()>
+where
+    F: FnOnce() -> bool + Send + 'static,
+{
+    let (sender, receiver) = channel();
+
+    ::std::thread::spawn(move || {
+        let _ = sender.send(join_workers());
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(anyhow::anyhow!("worker failed during shutdown")),
+        Err(RecvTimeoutError::Timeout) => Err(anyhow::anyhow!("workers didn't shut down in time")),
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(anyhow::anyhow!("worker joining thread panicked"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_signal() {
+        let signal = ShutdownSignal::default();
+        let clone = signal.clone();
+
+        assert!(!clone.is_triggered());
+        assert!(clone.wait().now_or_never().is_none());
+
+        signal.trigger();
+        signal.trigger();
+
+        assert!(clone.is_triggered());
+        assert!(clone.wait().now_or_never().is_some());
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+
+/// URL access mode. Available modes are path, passkey and off.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlAccessMode {
+    /// Only serve announce requests with URL path present in file
+    Path,
+    /// Only serve announce requests with passkey present in file
+    Passkey,
+    /// Turn off URL access functionality
+    Off,
+}
+
+impl UrlAccessMode {
+    pub fn is_on(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UrlAccessConfig {
+    pub mode: UrlAccessMode,
+    /// Path to file consisting of newline-separated allowed URL paths (such
+    /// as /announce) in path mode or allowed passkeys in passkey mode. Lines
+    /// starting with # are ignored. Entries are compared to URL data without
+    /// percent-decoding.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+    /// Name of URL query parameter containing passkey in passkey mode, such
+    /// as passkey for /announce?passkey=abc. If empty, the first path segment
+    /// is used instead, such as abc for /abc/announce.
+    pub passkey_parameter: String,
+}
+
+impl Default for UrlAccessConfig {
+    fn default() -> Self {
+        Self {
+            mode: UrlAccessMode::Off,
+            path: "./url-access.txt".into(),
+            passkey_parameter: "".into(),
+        }
+    }
+}
+
+/// Reason for URL access list not allowing request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlAccessRejection {
+    PathNotAllowed,
+    PasskeyMissing,
+    PasskeyNotAllowed,
+}
+
+impl Display for UrlAccessRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PathNotAllowed => write!(f, "URL path not allowed"),
+            Self::PasskeyMissing => write!(f, "Passkey missing"),
+            Self::PasskeyNotAllowed => write!(f, "Passkey not allowed"),
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct UrlAccessList(HashSet<Vec<u8>>);
+
+impl UrlAccessList {
+    pub fn insert_from_line(&mut self, line: &str) {
+        self.0.insert(line.as_bytes().to_vec());
+    }
+
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let mut new_list = Self::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            new_list.insert_from_line(line);
+        }
+
+        Ok(new_list)
+    }
+
+    /// Check URL data (path and query of tracker URL) against list
+    pub fn check(
+        &self,
+        config: &UrlAccessConfig,
+        url_data: &[u8],
+    ) -> Result<(), UrlAccessRejection> {
+        match config.mode {
+            UrlAccessMode::Path => {
+                if self.0.contains(url_path(url_data)) {
+                    Ok(())
+                } else {
+                    Err(UrlAccessRejection::PathNotAllowed)
+                }
+            }
+            UrlAccessMode::Passkey => match passkey(url_data, &config.passkey_parameter) {
+                Some(passkey) if self.0.contains(passkey) => Ok(()),
+                Some(_) => Err(UrlAccessRejection::PasskeyNotAllowed),
+                None => Err(UrlAccessRejection::PasskeyMissing),
+            },
+            UrlAccessMode::Off => Ok(()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+pub type UrlAccessListArcSwap = ArcSwap<UrlAccessList>;
+pub type UrlAccessListCache = Cache<Arc<UrlAccessListArcSwap>, Arc<UrlAccessList>>;
+
+pub fn create_url_access_list_cache(arc_swap: &Arc<UrlAccessListArcSwap>) -> UrlAccessListCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_url_access_list(
+    config: &UrlAccessConfig,
+    url_access_list: &Arc<UrlAccessListArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match UrlAccessList::create_from_path(&config.path) {
+            Ok(new_list) => {
+                url_access_list.store(Arc::new(new_list));
+
+                ::log::info!("URL access list updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating URL access list failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn url_path(url_data: &[u8]) -> &[u8] {
+    url_data.split(|b| *b == b'?').next().unwrap_or(url_data)
+}
+
+fn passkey<'a>(url_data: &'a [u8], parameter: &str) -> Option<&'a [u8]> {
+    let opt_passkey = if parameter.is_empty() {
+        url_path(url_data)
+            .split(|b| *b == b'/')
+            .find(|segment| !segment.is_empty())
+    } else {
+        let query = url_data.splitn(2, |b| *b == b'?').nth(1)?;
+
+        query.split(|b| *b == b'&').find_map(|pair| {
+            let mut parts = pair.splitn(2, |b| *b == b'=');
+
+            if parts.next()? == parameter.as_bytes() {
+                parts.next()
+            } else {
+                None
+            }
+        })
+    };
+
+    opt_passkey.filter(|passkey| !passkey.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_access_list_check() {
+        let mut list = UrlAccessList::default();
+
+        list.insert_from_line("/announce");
+        list.insert_from_line("abc");
+
+        let mut config = UrlAccessConfig {
+            mode: UrlAccessMode::Path,
+            ..Default::default()
+        };
+
+        assert_eq!(list.check(&config, b"/announce"), Ok(()));
+        assert_eq!(list.check(&config, b"/announce?a=b"), Ok(()));
+        assert_eq!(
+            list.check(&config, b"/other"),
+            Err(UrlAccessRejection::PathNotAllowed)
+        );
+        assert_eq!(
+            list.check(&config, b""),
+            Err(UrlAccessRejection::PathNotAllowed)
+        );
+
+        config.mode = UrlAccessMode::Passkey;
+
+        assert_eq!(list.check(&config, b"/abc/announce"), Ok(()));
+        assert_eq!(
+            list.check(&config, b"/abd/announce"),
+            Err(UrlAccessRejection::PasskeyNotAllowed)
+        );
+        assert_eq!(
+            list.check(&config, b"/"),
+            Err(UrlAccessRejection::PasskeyMissing)
+        );
+
+        config.passkey_parameter = "passkey".into();
+
+        assert_eq!(list.check(&config, b"/announce?x=1&passkey=abc"), Ok(()));
+        assert_eq!(
+            list.check(&config, b"/announce?passkey=abd"),
+            Err(UrlAccessRejection::PasskeyNotAllowed)
+        );
+        assert_eq!(
+            list.check(&config, b"/announce?passkey="),
+            Err(UrlAccessRejection::PasskeyMissing)
+        );
+        assert_eq!(
+            list.check(&config, b"/abc/announce"),
+            Err(UrlAccessRejection::PasskeyMissing)
+        );
+
+        config.mode = UrlAccessMode::Off;
+
+        assert_eq!(list.check(&config, b""), Ok(()));
+    }
+}
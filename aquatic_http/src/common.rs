@@ -1,15 +1,30 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::client_filter::ClientFilterArcSwap;
+use aquatic_common::early_announce::EarlyAnnounceCounts;
+use aquatic_common::shutdown::ShutdownSignal;
 use aquatic_common::CanonicalSocketAddr;
 
 pub use aquatic_common::ValidUntil;
 
 use aquatic_http_protocol::{
-    request::{AnnounceRequest, ScrapeRequest},
-    response::{AnnounceResponse, ScrapeResponse},
+    common::InfoHash,
+    request::{AnnounceRequest, RequestParseError, ScrapeRequest},
+    response::{Response, ScrapeResponse},
 };
-use glommio::channels::shared_channel::SharedSender;
+
+use crate::config::Config;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        pub type ResponseSender<T> = tokio::sync::oneshot::Sender<T>;
+    } else if #[cfg(feature = "glommio")] {
+        pub type ResponseSender<T> = glommio::channels::shared_channel::SharedSender<T>;
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct ConsumerId(pub usize);
@@ -22,16 +37,46 @@ pub enum ChannelRequest {
     Announce {
         request: AnnounceRequest,
         peer_addr: CanonicalSocketAddr,
-        response_sender: SharedSender<AnnounceResponse>,
+        /// Receives failure response if peer announces too often
+        response_sender: ResponseSender<Response>,
     },
     Scrape {
         request: ScrapeRequest,
         peer_addr: CanonicalSocketAddr,
-        response_sender: SharedSender<ScrapeResponse>,
+        response_sender: ResponseSender<ScrapeResponse>,
     },
 }
 
+/// Index of swarm worker responsible for torrent
+pub fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+    (info_hash.0[0] as usize) % config.swarm_workers
+}
+
 #[derive(Default, Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
+    pub request_parse_errors: Arc<RequestParseErrorCounts>,
+    pub early_announces: Arc<EarlyAnnounceCounts>,
+    pub request_rate: Arc<RequestRate>,
+    pub shutdown: ShutdownSignal,
+}
+
+/// Number of invalid requests received for each kind of parse error
+#[derive(Default)]
+pub struct RequestParseErrorCounts([AtomicUsize; RequestParseError::INVALID.len()]);
+
+impl RequestParseErrorCounts {
+    pub fn increment(&self, err: RequestParseError) {
+        if let Some(index) = RequestParseError::INVALID.iter().position(|e| *e == err) {
+            self.0[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn counts(&self) -> impl Iterator<Item = (RequestParseError, usize)> + '_ {
+        RequestParseError::INVALID
+            .iter()
+            .copied()
+            .zip(self.0.iter().map(|count| count.load(Ordering::Relaxed)))
+    }
 }
@@ -1,9 +1,12 @@
 use std::{net::SocketAddr, path::PathBuf};
 
 use aquatic_common::{
-    access_list::AccessListConfig, cpu_pinning::asc::CpuPinningConfigAsc,
-    privileges::PrivilegeConfig,
+    access_list::AccessListConfig, adaptive_interval::AdaptiveAnnounceIntervalConfig,
+    client_filter::ClientFilterConfig, cpu_pinning::asc::CpuPinningConfigAsc,
+    early_announce::EarlyAnnounceAction, peer_identity::PeerIdentityCheck,
+    privileges::PrivilegeConfig, shutdown::ShutdownConfig,
 };
+use aquatic_http_protocol::response::RetryIn;
 use aquatic_toml_config::TomlConfig;
 use serde::Deserialize;
 
@@ -25,9 +28,14 @@ pub struct Config {
     pub log_level: LogLevel,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
+    /// Adapt protocol.peer_announce_interval to swarm size and request rate
+    pub adaptive_announce_interval: AdaptiveAnnounceIntervalConfig,
     pub cleaning: CleaningConfig,
+    pub statistics: StatisticsConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub client_filter: ClientFilterConfig,
+    pub shutdown: ShutdownConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
 }
 
@@ -39,9 +47,13 @@ impl Default for Config {
             log_level: LogLevel::default(),
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
+            adaptive_announce_interval: AdaptiveAnnounceIntervalConfig::default(),
             cleaning: CleaningConfig::default(),
+            statistics: StatisticsConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            client_filter: ClientFilterConfig::default(),
+            shutdown: ShutdownConfig::default(),
             cpu_pinning: Default::default(),
         }
     }
@@ -68,6 +80,11 @@ pub struct NetworkConfig {
     pub tls_private_key_path: PathBuf,
     /// Keep connections alive after sending a response
     pub keep_alive: bool,
+    /// Maximum size of an HTTP request, including headers (bytes)
+    ///
+    /// Request buffers start out small and grow as needed up to this size.
+    /// A scrape request for 100 torrents is roughly 7.5 KiB.
+    pub max_request_size: usize,
 }
 
 impl Default for NetworkConfig {
@@ -79,6 +96,7 @@ impl Default for NetworkConfig {
             only_ipv6: false,
             tcp_backlog: 1024,
             keep_alive: true,
+            max_request_size: 16384,
         }
     }
 }
@@ -92,6 +110,31 @@ pub struct ProtocolConfig {
     pub max_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Ask peers not to announce more often than this (seconds)
+    ///
+    /// Announces without event from peers that announced less than this
+    /// long ago are handled according to early_announce_action. Set to
+    /// zero to neither send nor enforce a minimum interval.
+    pub peer_announce_min_interval: usize,
+    /// How to respond to early announces: cached (current statistics and
+    /// the peers that were sent in response to the last regular announce)
+    /// or failure (failure response with retry time)
+    pub early_announce_action: EarlyAnnounceAction,
+    /// Check key of announce requests for peer_ids registered from another
+    /// IP address. Requests that fail the check get a failure response and
+    /// don't update the peer.
+    pub peer_identity_check: PeerIdentityCheck,
+    /// Send this tracker id in announce responses. Clients are expected to
+    /// include it in subsequent announce requests. Leave empty to not send
+    /// a tracker id.
+    pub tracker_id: String,
+    /// Tell peers which IP address the tracker sees them announce from
+    /// (BEP 24)
+    pub send_external_ip: bool,
+    /// Ask clients to wait this many minutes before retrying announces
+    /// that were rejected because of the access list or client filter
+    /// (BEP 31). Set to zero to not include a retry time.
+    pub failure_retry_in: usize,
 }
 
 impl Default for ProtocolConfig {
@@ -100,6 +143,22 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 100,
             max_peers: 50,
             peer_announce_interval: 120,
+            peer_announce_min_interval: 0,
+            early_announce_action: EarlyAnnounceAction::Cached,
+            peer_identity_check: PeerIdentityCheck::Lenient,
+            tracker_id: "".into(),
+            send_external_ip: false,
+            failure_retry_in: 0,
+        }
+    }
+}
+
+impl ProtocolConfig {
+    pub fn retry_in(&self) -> Option<RetryIn> {
+        if self.failure_retry_in == 0 {
+            None
+        } else {
+            Some(RetryIn::Minutes(self.failure_retry_in))
         }
     }
 }
@@ -128,6 +187,32 @@ impl Default for CleaningConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Print statistics this often (seconds)
+    pub interval: u64,
+    /// Print statistics (currently counts of invalid requests by kind, of
+    /// client filter matches by rule and of early announces by client) to
+    /// standard output
+    pub print_to_stdout: bool,
+}
+
+impl StatisticsConfig {
+    pub fn active(&self) -> bool {
+        (self.interval != 0) & self.print_to_stdout
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            print_to_stdout: false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Config;
@@ -1,22 +1,20 @@
 use aquatic_common::{
-    access_list::update_access_list,
-    cpu_pinning::{
-        glommio::{get_worker_placement, set_affinity_for_util_worker},
-        WorkerIndex,
-    },
+    access_list::{spawn_access_list_watcher, update_access_list},
+    client_filter::update_client_filter,
     privileges::PrivilegeDropper,
-    rustls_config::create_rustls_config,
-    PanicSentinelWatcher, ServerStartInstant,
+    rustls_config::{create_rustls_config, RustlsConfig},
+    shutdown::join_workers_with_timeout,
+    PanicSentinel, PanicSentinelWatcher, ServerStartInstant,
 };
 use common::State;
-use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
 use signal_hook::{
     consts::{SIGTERM, SIGUSR1},
     iterator::Signals,
 };
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::config::Config;
+use crate::workers::swarm::TorrentMaps;
 
 mod common;
 pub mod config;
@@ -27,16 +25,25 @@ pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const SHARED_CHANNEL_SIZE: usize = 1024;
 
+#[cfg(not(any(feature = "glommio", feature = "tokio")))]
+compile_error!("either the glommio or the tokio feature must be enabled");
+
 pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
+    config
+        .adaptive_announce_interval
+        .validate(config.cleaning.max_peer_age)?;
+
+    #[cfg(feature = "tokio")]
+    aquatic_common::cpu_pinning::require_system_hyperthread_mapping(&config.cpu_pinning)?;
+
     let state = State::default();
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_client_filter(&config.client_filter, &state.client_filter)?;
 
-    let num_peers = config.socket_workers + config.swarm_workers;
-
-    let request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_CHANNEL_SIZE);
+    spawn_access_list_watcher(&config.access_list, &state.access_list)?;
 
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
@@ -48,6 +55,87 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     let server_start_instant = ServerStartInstant::new();
 
+    if config.adaptive_announce_interval.active {
+        let request_rate = state.request_rate.clone();
+
+        ::std::thread::Builder::new()
+            .name("request-rate".into())
+            .spawn(move || request_rate.run_updater())?;
+    }
+
+    if config.statistics.active() {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
+
+        ::std::thread::Builder::new()
+            .name("statistics".into())
+            .spawn(move || workers::statistics::run_statistics_worker(sentinel, config, state))?;
+    }
+
+    let torrent_maps = workers::swarm::load_torrent_maps(&config, server_start_instant)?;
+
+    let join_workers = start_workers(
+        &config,
+        &state,
+        torrent_maps,
+        sentinel,
+        tls_config,
+        priv_dropper,
+        server_start_instant,
+    )?;
+
+    for signal in &mut signals {
+        match signal {
+            SIGUSR1 => {
+                let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_client_filter(&config.client_filter, &state.client_filter);
+            }
+            SIGTERM => {
+                if sentinel_watcher.panic_was_triggered() {
+                    return Err(anyhow::anyhow!("worker thread panicked"));
+                } else if config.shutdown.graceful {
+                    ::log::info!("shutting down gracefully");
+
+                    state.shutdown.trigger();
+
+                    // Allow some time beyond drain timeout for workers to
+                    // finish up after closing remaining connections
+                    let timeout = Duration::from_secs(config.shutdown.drain_timeout + 5);
+
+                    return join_workers_with_timeout(join_workers, timeout);
+                } else {
+                    return Ok(());
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+/// Start socket and swarm workers as glommio executors
+#[cfg(all(feature = "glommio", not(feature = "tokio")))]
+fn start_workers(
+    config: &Config,
+    state: &State,
+    torrent_maps: Vec<TorrentMaps>,
+    sentinel: PanicSentinel,
+    tls_config: Arc<RustlsConfig>,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<impl FnOnce() -> bool + Send + 'static> {
+    use aquatic_common::cpu_pinning::{
+        glommio::{get_worker_placement, set_affinity_for_util_worker},
+        WorkerIndex,
+    };
+    use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
+
+    let num_peers = config.socket_workers + config.swarm_workers;
+
+    let request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_CHANNEL_SIZE);
+
     let mut executors = Vec::new();
 
     for i in 0..(config.socket_workers) {
@@ -84,7 +172,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         executors.push(executor);
     }
 
-    for i in 0..(config.swarm_workers) {
+    for (i, torrents) in torrent_maps.into_iter().enumerate() {
         let sentinel = sentinel.clone();
         let config = config.clone();
         let state = state.clone();
@@ -104,6 +192,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     sentinel,
                     config,
                     state,
+                    i,
+                    torrents,
                     request_mesh_builder,
                     server_start_instant,
                 )
@@ -122,21 +212,111 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         )?;
     }
 
-    for signal in &mut signals {
-        match signal {
-            SIGUSR1 => {
-                let _ = update_access_list(&config.access_list, &state.access_list);
-            }
-            SIGTERM => {
-                if sentinel_watcher.panic_was_triggered() {
-                    return Err(anyhow::anyhow!("worker thread panicked"));
-                } else {
-                    return Ok(());
-                }
-            }
-            _ => unreachable!(),
-        }
+    Ok(move || executors.into_iter().all(|e| e.join().is_ok()))
+}
+
+/// Start socket and swarm workers as threads running single-threaded tokio
+/// runtimes
+#[cfg(feature = "tokio")]
+fn start_workers(
+    config: &Config,
+    state: &State,
+    torrent_maps: Vec<TorrentMaps>,
+    sentinel: PanicSentinel,
+    tls_config: Arc<RustlsConfig>,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<impl FnOnce() -> bool + Send + 'static> {
+    #[cfg(feature = "cpu-pinning")]
+    use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+
+    let mut request_senders = Vec::new();
+    let mut request_receivers = Vec::new();
+
+    for _ in 0..config.swarm_workers {
+        let (request_sender, request_receiver) = tokio::sync::mpsc::channel(SHARED_CHANNEL_SIZE);
+
+        request_senders.push(request_sender);
+        request_receivers.push(request_receiver);
     }
 
-    Ok(())
+    let mut handles = Vec::new();
+
+    for i in 0..(config.socket_workers) {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
+        let tls_config = tls_config.clone();
+        let request_senders = request_senders.clone();
+        let priv_dropper = priv_dropper.clone();
+
+        let handle = ::std::thread::Builder::new()
+            .name(format!("socket-{:02}", i + 1))
+            .spawn(move || {
+                #[cfg(feature = "cpu-pinning")]
+                pin_current_if_configured_to(
+                    &config.cpu_pinning,
+                    config.socket_workers,
+                    config.swarm_workers,
+                    WorkerIndex::SocketWorker(i),
+                );
+
+                workers::socket::run_socket_worker(
+                    sentinel,
+                    config,
+                    state,
+                    tls_config,
+                    request_senders,
+                    priv_dropper,
+                    server_start_instant,
+                )
+            })?;
+
+        handles.push(handle);
+    }
+
+    // Swarm worker channels should close when all socket workers have exited
+    drop(request_senders);
+
+    for (i, (request_receiver, torrents)) in
+        request_receivers.into_iter().zip(torrent_maps).enumerate()
+    {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
+
+        let handle = ::std::thread::Builder::new()
+            .name(format!("swarm-{:02}", i + 1))
+            .spawn(move || {
+                #[cfg(feature = "cpu-pinning")]
+                pin_current_if_configured_to(
+                    &config.cpu_pinning,
+                    config.socket_workers,
+                    config.swarm_workers,
+                    WorkerIndex::SwarmWorker(i),
+                );
+
+                workers::swarm::run_swarm_worker(
+                    sentinel,
+                    config,
+                    state,
+                    i,
+                    torrents,
+                    request_receiver,
+                    server_start_instant,
+                )
+            })?;
+
+        handles.push(handle);
+    }
+
+    #[cfg(feature = "cpu-pinning")]
+    pin_current_if_configured_to(
+        &config.cpu_pinning,
+        config.socket_workers,
+        config.swarm_workers,
+        WorkerIndex::Util,
+    );
+
+    Ok(move || handles.into_iter().all(|h| h.join().is_ok()))
 }
@@ -0,0 +1,505 @@
+//! Connection handling shared by the glommio and tokio socket workers
+//!
+//! Runtime-specific channel and timer functionality is selected with
+//! cfg_if below. Everything else works with any stream implementing
+//! futures::AsyncRead and futures::AsyncWrite.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::client_filter::{
+    create_client_filter_cache, ClientFilterArcSwap, ClientFilterCache,
+};
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
+use aquatic_http_protocol::common::InfoHash;
+use aquatic_http_protocol::request::{Request, RequestParseError, ScrapeRequest};
+use aquatic_http_protocol::response::{FailureResponse, Response, ScrapeResponse};
+use either::Either;
+use futures::stream::FuturesUnordered;
+use futures::{AsyncRead, AsyncWrite, FutureExt};
+use futures_lite::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use slab::Slab;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::{
+    create_response_buffer, parse_request, write_response_to_buffer, PendingScrapeResponse,
+    RequestBuffer, ShutdownStarted,
+};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        use tokio::sync::mpsc::Sender;
+        use tokio::sync::oneshot;
+
+        pub type RequestSenders = Vec<Sender<ChannelRequest>>;
+        pub type TaskHandle = tokio::task::JoinHandle<()>;
+
+        type ResponseReceiver<T> = oneshot::Receiver<T>;
+
+        fn create_response_channel<T>() -> (ResponseSender<T>, ResponseReceiver<T>) {
+            oneshot::channel()
+        }
+
+        async fn receive_response<T>(receiver: ResponseReceiver<T>) -> Option<T> {
+            receiver.await.ok()
+        }
+
+        async fn send_request(
+            senders: &RequestSenders,
+            index: usize,
+            request: ChannelRequest,
+        ) -> anyhow::Result<()> {
+            senders[index]
+                .send(request)
+                .await
+                .map_err(|_| anyhow::anyhow!("request receiver closed"))
+        }
+
+        fn cancel_task(handle: &TaskHandle) {
+            handle.abort();
+        }
+
+        pub async fn sleep(duration: Duration) {
+            tokio::time::sleep(duration).await
+        }
+    } else if #[cfg(feature = "glommio")] {
+        use glommio::channels::channel_mesh::Senders;
+        use glommio::channels::shared_channel::{self, SharedReceiver};
+
+        pub type RequestSenders = Senders<ChannelRequest>;
+        pub type TaskHandle = glommio::task::JoinHandle<()>;
+
+        type ResponseReceiver<T> = SharedReceiver<T>;
+
+        fn create_response_channel<T: Send + Sized>() -> (ResponseSender<T>, ResponseReceiver<T>) {
+            shared_channel::new_bounded(1)
+        }
+
+        async fn receive_response<T: Send + Sized>(receiver: ResponseReceiver<T>) -> Option<T> {
+            receiver.connect().await.recv().await
+        }
+
+        async fn send_request(
+            senders: &RequestSenders,
+            index: usize,
+            request: ChannelRequest,
+        ) -> anyhow::Result<()> {
+            senders
+                .send_to(index, request)
+                .await
+                .map_err(|_| anyhow::anyhow!("request receiver closed"))
+        }
+
+        fn cancel_task(handle: &TaskHandle) {
+            handle.cancel();
+        }
+
+        pub async fn sleep(duration: Duration) {
+            glommio::timer::sleep(duration).await
+        }
+    }
+}
+
+/// Socket worker state shared by all of its connections
+pub struct SocketWorkerContext {
+    pub config: Config,
+    pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
+    pub request_parse_errors: Arc<RequestParseErrorCounts>,
+    pub request_rate: Arc<RequestRate>,
+    pub request_senders: RequestSenders,
+    pub tls_config: Arc<RustlsConfig>,
+    pub server_start_instant: ServerStartInstant,
+    pub connection_slab: RefCell<Slab<ConnectionReference>>,
+}
+
+pub struct ConnectionReference {
+    task_handle: Option<TaskHandle>,
+    valid_until: ValidUntil,
+}
+
+/// Insert reference to accepted connection into connection slab
+pub fn insert_connection(ctx: &SocketWorkerContext) -> ConnectionId {
+    let key = ctx
+        .connection_slab
+        .borrow_mut()
+        .insert(ConnectionReference {
+            task_handle: None,
+            valid_until: ValidUntil::new(
+                ctx.server_start_instant,
+                ctx.config.cleaning.max_connection_idle,
+            ),
+        });
+
+    ConnectionId(key)
+}
+
+/// Store handle of task running connection, so that it can be cancelled
+pub fn set_connection_task_handle(
+    ctx: &SocketWorkerContext,
+    connection_id: ConnectionId,
+    task_handle: TaskHandle,
+) {
+    if let Some(reference) = ctx.connection_slab.borrow_mut().get_mut(connection_id.0) {
+        reference.task_handle = Some(task_handle);
+    }
+}
+
+/// Run connection until it is closed, then remove its reference
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    ctx: Rc<SocketWorkerContext>,
+    shutdown_started: ShutdownStarted,
+    connection_id: ConnectionId,
+    peer_addr: CanonicalSocketAddr,
+    stream: S,
+) {
+    if let Err(err) = Connection::run(
+        ctx.clone(),
+        shutdown_started,
+        connection_id,
+        peer_addr,
+        stream,
+    )
+    .await
+    {
+        ::log::debug!("Connection::run() error: {:?}", err);
+    }
+
+    ctx.connection_slab.borrow_mut().try_remove(connection_id.0);
+}
+
+/// Cancel connections that haven't seen valid requests in a while
+pub fn clean_connections(ctx: &SocketWorkerContext) {
+    let now = ctx.server_start_instant.seconds_elapsed();
+
+    let mut connection_slab = ctx.connection_slab.borrow_mut();
+
+    connection_slab.retain(|_, reference| {
+        if reference.valid_until.valid(now) {
+            true
+        } else {
+            if let Some(ref handle) = reference.task_handle {
+                cancel_task(handle);
+            }
+
+            false
+        }
+    });
+
+    connection_slab.shrink_to_fit();
+}
+
+/// Wait for connections to close, cancelling the ones remaining when drain
+/// timeout is reached
+pub async fn drain_connections(ctx: &SocketWorkerContext) {
+    let deadline = Instant::now() + Duration::from_secs(ctx.config.shutdown.drain_timeout);
+
+    while !ctx.connection_slab.borrow().is_empty() && Instant::now() < deadline {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut connection_slab = ctx.connection_slab.borrow_mut();
+
+    if !connection_slab.is_empty() {
+        ::log::warn!(
+            "closing {} connections that didn't finish within drain timeout",
+            connection_slab.len()
+        );
+
+        for (_, reference) in connection_slab.iter() {
+            if let Some(ref handle) = reference.task_handle {
+                cancel_task(handle);
+            }
+        }
+
+        connection_slab.clear();
+    }
+}
+
+struct Connection<S> {
+    ctx: Rc<SocketWorkerContext>,
+    access_list_cache: AccessListCache,
+    client_filter_cache: ClientFilterCache,
+    shutdown_started: ShutdownStarted,
+    stream: TlsStream<S>,
+    peer_addr: CanonicalSocketAddr,
+    connection_id: ConnectionId,
+    request_buffer: RequestBuffer,
+    response_buffer: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    async fn run(
+        ctx: Rc<SocketWorkerContext>,
+        shutdown_started: ShutdownStarted,
+        connection_id: ConnectionId,
+        peer_addr: CanonicalSocketAddr,
+        stream: S,
+    ) -> anyhow::Result<()> {
+        let tls_acceptor: TlsAcceptor = ctx.tls_config.clone().into();
+        let stream = tls_acceptor.accept(stream).await?;
+
+        let mut conn = Connection {
+            access_list_cache: create_access_list_cache(&ctx.access_list),
+            client_filter_cache: create_client_filter_cache(&ctx.client_filter),
+            shutdown_started,
+            stream,
+            peer_addr,
+            connection_id,
+            request_buffer: RequestBuffer::new(ctx.config.network.max_request_size),
+            response_buffer: create_response_buffer(),
+            ctx,
+        };
+
+        conn.run_request_response_loop().await?;
+
+        Ok(())
+    }
+
+    async fn run_request_response_loop(&mut self) -> anyhow::Result<()> {
+        loop {
+            let response = match self.read_request().await? {
+                Either::Left(response) => Response::Failure(response),
+                Either::Right(request) => self.handle_request(request).await?,
+            };
+
+            self.write_response(&response).await?;
+
+            // Close connection after finishing in-flight request during
+            // graceful shutdown
+            let shutting_down = self.shutdown_started.clone().now_or_never().is_some();
+
+            if matches!(response, Response::Failure(_))
+                || !self.ctx.config.network.keep_alive
+                || shutting_down
+            {
+                let _ = self.stream.close().await;
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn read_request(&mut self) -> anyhow::Result<Either<FailureResponse, Request>> {
+        loop {
+            // Buffer might already contain a complete pipelined request
+            if !self.request_buffer.is_empty() {
+                match parse_request(
+                    &self.ctx.config,
+                    &self.ctx.request_parse_errors,
+                    &self.ctx.request_rate,
+                    self.request_buffer.filled(),
+                ) {
+                    Ok((request, request_len)) => {
+                        self.request_buffer.consume(request_len);
+
+                        return Ok(Either::Right(request));
+                    }
+                    Err(RequestParseError::NeedMoreData) => {
+                        ::log::debug!(
+                            "need more request data. current data: {}",
+                            self.request_buffer.filled().escape_ascii()
+                        );
+                    }
+                    Err(err) => {
+                        ::log::debug!("Invalid request: {}", err);
+
+                        return Ok(Either::Left(FailureResponse::new(err.as_str())));
+                    }
+                }
+            }
+
+            let connection_idle = self.request_buffer.is_empty();
+
+            let buffer = self
+                .request_buffer
+                .unfilled()
+                .ok_or_else(|| anyhow::anyhow!("request exceeds max_request_size"))?;
+
+            let bytes_read = if connection_idle {
+                // Don't wait for new requests on idle connection during
+                // graceful shutdown
+                let shutdown_started = self.shutdown_started.clone();
+
+                futures_lite::future::or(self.stream.read(buffer), async {
+                    let _ = shutdown_started.await;
+
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "shutting down",
+                    ))
+                })
+                .await?
+            } else {
+                self.stream.read(buffer).await?
+            };
+
+            if bytes_read == 0 {
+                return Err(anyhow::anyhow!("peer closed connection"));
+            }
+
+            self.request_buffer.advance(bytes_read);
+        }
+    }
+
+    /// Take a request and:
+    /// - Update connection ValidUntil
+    /// - Return error response if request is not allowed
+    /// - If it is an announce request, send it to swarm workers an await a
+    ///   response
+    /// - If it is a scrape requests, split it up, pass on the parts to
+    ///   relevant swarm workers and await a response
+    async fn handle_request(&mut self, request: Request) -> anyhow::Result<Response> {
+        let config = &self.ctx.config;
+
+        if let Ok(mut slab) = self.ctx.connection_slab.try_borrow_mut() {
+            if let Some(reference) = slab.get_mut(self.connection_id.0) {
+                reference.valid_until = ValidUntil::new(
+                    self.ctx.server_start_instant,
+                    config.cleaning.max_connection_idle,
+                );
+            }
+        }
+
+        match request {
+            Request::Announce(request) => {
+                let info_hash = request.info_hash;
+
+                if !self
+                    .access_list_cache
+                    .load()
+                    .allows(config.access_list.mode, &info_hash.0)
+                {
+                    let response = Response::Failure(FailureResponse {
+                        failure_reason: "Info hash not allowed".into(),
+                        retry_in: config.protocol.retry_in(),
+                    });
+
+                    Ok(response)
+                } else if let Err(rejection) = self
+                    .client_filter_cache
+                    .load()
+                    .check(config.client_filter.mode, &request.peer_id.0)
+                {
+                    let response = Response::Failure(FailureResponse {
+                        failure_reason: rejection.to_string().into(),
+                        retry_in: config.protocol.retry_in(),
+                    });
+
+                    Ok(response)
+                } else {
+                    let (response_sender, response_receiver) = create_response_channel();
+
+                    let request = ChannelRequest::Announce {
+                        request,
+                        peer_addr: self.peer_addr,
+                        response_sender,
+                    };
+
+                    let consumer_index = calculate_request_consumer_index(config, info_hash);
+
+                    send_request(&self.ctx.request_senders, consumer_index, request).await?;
+
+                    receive_response(response_receiver)
+                        .await
+                        .ok_or_else(|| anyhow::anyhow!("response sender closed"))
+                }
+            }
+            Request::Scrape(ScrapeRequest { info_hashes }) => {
+                let mut info_hashes_by_worker: BTreeMap<usize, Vec<InfoHash>> = BTreeMap::new();
+
+                for info_hash in info_hashes.into_iter() {
+                    let info_hashes = info_hashes_by_worker
+                        .entry(calculate_request_consumer_index(config, info_hash))
+                        .or_default();
+
+                    info_hashes.push(info_hash);
+                }
+
+                let pending_worker_responses = info_hashes_by_worker.len();
+                let mut response_receivers = Vec::with_capacity(pending_worker_responses);
+
+                for (consumer_index, info_hashes) in info_hashes_by_worker {
+                    let (response_sender, response_receiver) = create_response_channel();
+
+                    response_receivers.push(response_receiver);
+
+                    let request = ChannelRequest::Scrape {
+                        request: ScrapeRequest { info_hashes },
+                        peer_addr: self.peer_addr,
+                        response_sender,
+                    };
+
+                    send_request(&self.ctx.request_senders, consumer_index, request).await?;
+                }
+
+                let pending_scrape_response = PendingScrapeResponse {
+                    pending_worker_responses,
+                    stats: Default::default(),
+                };
+
+                self.wait_for_scrape_responses(response_receivers, pending_scrape_response)
+                    .await
+            }
+        }
+    }
+
+    /// Wait for partial scrape responses to arrive,
+    /// return full response
+    async fn wait_for_scrape_responses(
+        &self,
+        response_receivers: Vec<ResponseReceiver<ScrapeResponse>>,
+        mut pending: PendingScrapeResponse,
+    ) -> anyhow::Result<Response> {
+        let mut responses = response_receivers
+            .into_iter()
+            .map(receive_response)
+            .collect::<FuturesUnordered<_>>();
+
+        loop {
+            let response = responses
+                .next()
+                .await
+                .ok_or_else(|| {
+                    anyhow::anyhow!("stream ended before all partial scrape responses received")
+                })?
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "wait_for_scrape_response: can't receive response, sender is closed"
+                    )
+                })?;
+
+            pending.stats.extend(response.files);
+            pending.pending_worker_responses -= 1;
+
+            if pending.pending_worker_responses == 0 {
+                let response = Response::Scrape(ScrapeResponse {
+                    files: pending.stats,
+                });
+
+                break Ok(response);
+            }
+        }
+    }
+
+    async fn write_response(&mut self, response: &Response) -> anyhow::Result<()> {
+        write_response_to_buffer(response, &mut self.response_buffer)?;
+
+        self.stream.write_all(&self.response_buffer).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::os::unix::prelude::{FromRawFd, IntoRawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
+use futures::channel::oneshot;
+use futures::FutureExt;
+use futures_lite::StreamExt;
+use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
+use glommio::net::TcpListener;
+use glommio::timer::TimerActionRepeat;
+use glommio::{enclose, prelude::*};
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::connection::{
+    clean_connections, drain_connections, handle_connection, insert_connection,
+    set_connection_task_handle, SocketWorkerContext,
+};
+use super::{create_tcp_listener, ShutdownStarted};
+
+pub async fn run_socket_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    tls_config: Arc<RustlsConfig>,
+    request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) {
+    let listener = create_tcp_listener(&config, priv_dropper)
+        .map(|socket| unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
+        .expect("create tcp listener");
+
+    let (request_senders, _) = request_mesh_builder.join(Role::Producer).await.unwrap();
+
+    let ctx = Rc::new(SocketWorkerContext {
+        config,
+        access_list: state.access_list,
+        client_filter: state.client_filter,
+        request_parse_errors: state.request_parse_errors,
+        request_rate: state.request_rate,
+        request_senders,
+        tls_config,
+        server_start_instant,
+        connection_slab: Default::default(),
+    });
+    let shutdown = state.shutdown;
+
+    TimerActionRepeat::repeat(enclose!((ctx) move || run_connection_cleaning(ctx.clone())));
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    let shutdown_started: ShutdownStarted = shutdown_receiver.shared();
+
+    let mut incoming = listener.incoming();
+
+    loop {
+        let opt_stream = futures_lite::future::or(incoming.next(), async {
+            shutdown.wait().await;
+
+            None
+        })
+        .await;
+
+        let stream = if let Some(stream) = opt_stream {
+            stream
+        } else {
+            break;
+        };
+
+        match stream {
+            Ok(stream) => {
+                let peer_addr = match stream.peer_addr() {
+                    Ok(addr) => CanonicalSocketAddr::new(addr),
+                    Err(err) => {
+                        ::log::debug!("Couldn't get peer addr: {:?}", err);
+
+                        continue;
+                    }
+                };
+
+                let connection_id = insert_connection(&ctx);
+
+                let task_handle = spawn_local(handle_connection(
+                    ctx.clone(),
+                    shutdown_started.clone(),
+                    connection_id,
+                    peer_addr,
+                    stream,
+                ))
+                .detach();
+
+                set_connection_task_handle(&ctx, connection_id, task_handle);
+            }
+            Err(err) => {
+                ::log::error!("accept connection: {:?}", err);
+            }
+        }
+    }
+
+    // Stop accepting connections and tell existing ones to finish up
+    drop(incoming);
+    drop(listener);
+
+    let _ = shutdown_sender.send(());
+
+    drain_connections(&ctx).await;
+}
+
+async fn run_connection_cleaning(ctx: Rc<SocketWorkerContext>) -> Option<Duration> {
+    clean_connections(&ctx);
+
+    Some(Duration::from_secs(
+        ctx.config.cleaning.connection_cleaning_interval,
+    ))
+}
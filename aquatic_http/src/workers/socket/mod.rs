@@ -0,0 +1,254 @@
+mod connection;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        mod tokio;
+
+        pub use self::tokio::run_socket_worker;
+    } else if #[cfg(feature = "glommio")] {
+        mod glommio;
+
+        pub use self::glommio::run_socket_worker;
+    }
+}
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_http_protocol::common::InfoHash;
+use aquatic_http_protocol::request::{Request, RequestParseError};
+use aquatic_http_protocol::response::{Response, ScrapeStatistics};
+use futures::channel::oneshot;
+use futures::future::Shared;
+use once_cell::sync::Lazy;
+
+use crate::common::RequestParseErrorCounts;
+use crate::config::Config;
+
+const REQUEST_BUFFER_SIZE: usize = 2048;
+const RESPONSE_BUFFER_SIZE: usize = 4096;
+
+const RESPONSE_HEADER_A: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: ";
+const RESPONSE_HEADER_B: &[u8] = b"        ";
+const RESPONSE_HEADER_C: &[u8] = b"\r\n\r\n";
+
+static RESPONSE_HEADER: Lazy<Vec<u8>> =
+    Lazy::new(|| [RESPONSE_HEADER_A, RESPONSE_HEADER_B, RESPONSE_HEADER_C].concat());
+
+/// Resolves when socket worker has stopped accepting connections because of
+/// graceful shutdown
+type ShutdownStarted = Shared<oneshot::Receiver<()>>;
+
+struct PendingScrapeResponse {
+    pending_worker_responses: usize,
+    stats: BTreeMap<InfoHash, ScrapeStatistics>,
+}
+
+/// Buffer for bytes read from a connection
+///
+/// Grows as needed up to a maximum size. Bytes following a parsed request,
+/// such as pipelined requests, are kept for parsing.
+struct RequestBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+    max_size: usize,
+}
+
+impl RequestBuffer {
+    fn new(max_size: usize) -> Self {
+        Self {
+            bytes: vec![0; REQUEST_BUFFER_SIZE.min(max_size)],
+            len: 0,
+            max_size,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn filled(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Space to read into, growing buffer if it is full. Returns None if
+    /// buffer has reached maximum size.
+    fn unfilled(&mut self) -> Option<&mut [u8]> {
+        if self.len == self.bytes.len() {
+            if self.bytes.len() >= self.max_size {
+                return None;
+            }
+
+            let new_size = (self.bytes.len() * 2).min(self.max_size);
+
+            self.bytes.resize(new_size, 0);
+        }
+
+        Some(&mut self.bytes[self.len..])
+    }
+
+    /// Mark bytes as read into space returned by `unfilled`
+    fn advance(&mut self, num_bytes: usize) {
+        self.len += num_bytes;
+    }
+
+    /// Remove bytes of parsed request from start of buffer
+    fn consume(&mut self, num_bytes: usize) {
+        self.bytes.copy_within(num_bytes..self.len, 0);
+        self.len -= num_bytes;
+
+        // Don't keep large buffers around for idle connections
+        if self.len == 0 && self.bytes.len() > REQUEST_BUFFER_SIZE {
+            self.bytes.truncate(REQUEST_BUFFER_SIZE);
+            self.bytes.shrink_to_fit();
+        }
+    }
+}
+
+/// Parse request from start of request bytes, rejecting scrapes for more
+/// than the configured maximum number of torrents. Returns request together
+/// with its length. Counts errors other than `RequestParseError::NeedMoreData`
+/// and, if adaptive announce intervals are active, valid requests.
+fn parse_request(
+    config: &Config,
+    request_parse_errors: &RequestParseErrorCounts,
+    request_rate: &RequestRate,
+    bytes: &[u8],
+) -> Result<(Request, usize), RequestParseError> {
+    let result = match Request::from_bytes_with_len(bytes) {
+        Ok((Request::Scrape(request), _))
+            if request.info_hashes.len() > config.protocol.max_scrape_torrents =>
+        {
+            Err(RequestParseError::TooManyInfoHashes)
+        }
+        result => result,
+    };
+
+    match result {
+        Err(RequestParseError::NeedMoreData) => (),
+        Err(err) => request_parse_errors.increment(err),
+        Ok(_) if config.adaptive_announce_interval.active => request_rate.add(1),
+        Ok(_) => (),
+    }
+
+    result
+}
+
+fn create_response_buffer() -> Vec<u8> {
+    let mut response_buffer = Vec::with_capacity(RESPONSE_BUFFER_SIZE);
+
+    response_buffer.extend_from_slice(&RESPONSE_HEADER);
+
+    response_buffer
+}
+
+/// Write response body into buffer created by `create_response_buffer` and
+/// update Content-Length header. Buffer grows if response doesn't fit.
+fn write_response_to_buffer(
+    response: &Response,
+    response_buffer: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    // Write body and final newline to response buffer
+
+    response_buffer.truncate(RESPONSE_HEADER.len());
+
+    let body_len = response.write(response_buffer)?;
+
+    response_buffer.extend_from_slice(b"\r\n");
+
+    let content_len = body_len + 2;
+
+    // Clear content-len header value
+
+    {
+        let start = RESPONSE_HEADER_A.len();
+        let end = start + RESPONSE_HEADER_B.len();
+
+        response_buffer[start..end].copy_from_slice(RESPONSE_HEADER_B);
+    }
+
+    // Set content-len header value
+
+    {
+        let mut buf = ::itoa::Buffer::new();
+        let content_len_bytes = buf.format(content_len).as_bytes();
+
+        let start = RESPONSE_HEADER_A.len();
+        let end = start + content_len_bytes.len();
+
+        response_buffer[start..end].copy_from_slice(content_len_bytes);
+    }
+
+    Ok(())
+}
+
+fn create_tcp_listener(
+    config: &Config,
+    priv_dropper: PrivilegeDropper,
+) -> anyhow::Result<socket2::Socket> {
+    let domain = if config.network.address.is_ipv4() {
+        socket2::Domain::IPV4
+    } else {
+        socket2::Domain::IPV6
+    };
+
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+
+    if config.network.only_ipv6 {
+        socket
+            .set_only_v6(true)
+            .with_context(|| "socket: set only ipv6")?;
+    }
+
+    socket
+        .set_reuse_port(true)
+        .with_context(|| "socket: set reuse port")?;
+
+    socket
+        .bind(&config.network.address.into())
+        .with_context(|| format!("socket: bind to {}", config.network.address))?;
+
+    socket
+        .listen(config.network.tcp_backlog)
+        .with_context(|| format!("socket: listen on {}", config.network.address))?;
+
+    priv_dropper.after_socket_creation()?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_buffer() {
+        let request = b"GET /scrape?info_hash=aaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n\r\n";
+
+        let mut buffer = RequestBuffer::new(request.len() * 2);
+
+        // Read first request split in two, followed by pipelined request
+        for chunk in [&request[..10], &request[10..], &request[..]] {
+            let unfilled = buffer.unfilled().unwrap();
+
+            unfilled[..chunk.len()].copy_from_slice(chunk);
+
+            buffer.advance(chunk.len());
+        }
+
+        assert_eq!(buffer.filled().len(), request.len() * 2);
+        assert!(buffer.unfilled().is_none());
+
+        for _ in 0..2 {
+            let (_, request_len) = Request::from_bytes_with_len(buffer.filled()).unwrap();
+
+            assert_eq!(request_len, request.len());
+
+            buffer.consume(request_len);
+        }
+
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
+use futures::channel::oneshot as shutdown_oneshot;
+use futures::FutureExt;
+use tokio::net::TcpListener;
+use tokio::task::{spawn_local, LocalSet};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::connection::{
+    clean_connections, drain_connections, handle_connection, insert_connection,
+    set_connection_task_handle, sleep, RequestSenders, SocketWorkerContext,
+};
+use super::{create_tcp_listener, ShutdownStarted};
+
+pub fn run_socket_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    tls_config: Arc<RustlsConfig>,
+    request_senders: RequestSenders,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+
+    LocalSet::new().block_on(
+        &runtime,
+        run_inner(
+            config,
+            state,
+            tls_config,
+            request_senders,
+            priv_dropper,
+            server_start_instant,
+        ),
+    );
+}
+
+async fn run_inner(
+    config: Config,
+    state: State,
+    tls_config: Arc<RustlsConfig>,
+    request_senders: RequestSenders,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) {
+    let listener = create_tcp_listener(&config, priv_dropper)
+        .and_then(|socket| {
+            socket.set_nonblocking(true)?;
+
+            Ok(TcpListener::from_std(socket.into())?)
+        })
+        .expect("create tcp listener");
+
+    let ctx = Rc::new(SocketWorkerContext {
+        config,
+        access_list: state.access_list,
+        client_filter: state.client_filter,
+        request_parse_errors: state.request_parse_errors,
+        request_rate: state.request_rate,
+        request_senders,
+        tls_config,
+        server_start_instant,
+        connection_slab: Default::default(),
+    });
+    let shutdown = state.shutdown;
+
+    spawn_local(periodically_clean_connections(ctx.clone()));
+
+    let (shutdown_sender, shutdown_receiver) = shutdown_oneshot::channel();
+    let shutdown_started: ShutdownStarted = shutdown_receiver.shared();
+
+    loop {
+        let opt_result = futures_lite::future::or(async { Some(listener.accept().await) }, async {
+            shutdown.wait().await;
+
+            None
+        })
+        .await;
+
+        let result = if let Some(result) = opt_result {
+            result
+        } else {
+            break;
+        };
+
+        match result {
+            Ok((stream, peer_addr)) => {
+                let connection_id = insert_connection(&ctx);
+
+                let task_handle = spawn_local(handle_connection(
+                    ctx.clone(),
+                    shutdown_started.clone(),
+                    connection_id,
+                    CanonicalSocketAddr::new(peer_addr),
+                    stream.compat(),
+                ));
+
+                set_connection_task_handle(&ctx, connection_id, task_handle);
+            }
+            Err(err) => {
+                ::log::error!("accept connection: {:?}", err);
+            }
+        }
+    }
+
+    // Stop accepting connections and tell existing ones to finish up
+    drop(listener);
+
+    let _ = shutdown_sender.send(());
+
+    drain_connections(&ctx).await;
+}
+
+async fn periodically_clean_connections(ctx: Rc<SocketWorkerContext>) {
+    loop {
+        sleep(Duration::from_secs(
+            ctx.config.cleaning.connection_cleaning_interval,
+        ))
+        .await;
+
+        clean_connections(&ctx);
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use aquatic_common::PanicSentinel;
+
+use crate::common::State;
+use crate::config::Config;
+
+pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: State) {
+    loop {
+        ::std::thread::sleep(Duration::from_secs(config.statistics.interval));
+
+        if config.statistics.print_to_stdout {
+            println!("Invalid requests:");
+
+            for (err, count) in state.request_parse_errors.counts() {
+                if count != 0 {
+                    println!("  {:<40} {:>10}", err.as_str(), count);
+                }
+            }
+
+            if config.client_filter.mode.is_on() {
+                let client_filter = state.client_filter.load();
+
+                println!("Client filter matches:");
+
+                for (rule, num_matches) in client_filter.match_counts() {
+                    println!("  {:<40} {:>10}", rule, num_matches);
+                }
+
+                println!(
+                    "  {:<40} {:>10}",
+                    "(no match)",
+                    client_filter.num_unmatched()
+                );
+            }
+
+            if config.protocol.peer_announce_min_interval != 0 {
+                println!("Early announces by client:");
+
+                for (client, count) in state.early_announces.counts() {
+                    println!("  {:<40} {:>10}", client, count);
+                }
+            }
+
+            println!();
+        }
+    }
+}
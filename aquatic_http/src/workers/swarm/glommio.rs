@@ -0,0 +1,120 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures_lite::{Stream, StreamExt};
+use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
+use glommio::timer::TimerActionRepeat;
+use glommio::{enclose, prelude::*};
+use rand::prelude::SmallRng;
+use rand::SeedableRng;
+
+use aquatic_common::{PanicSentinel, ServerStartInstant};
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::{handle_announce_request, handle_scrape_request, save_torrent_maps, TorrentMaps};
+
+pub async fn run_swarm_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    worker_index: usize,
+    torrents: TorrentMaps,
+    request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
+    server_start_instant: ServerStartInstant,
+) {
+    let (_, mut request_receivers) = request_mesh_builder.join(Role::Consumer).await.unwrap();
+
+    let torrents = Rc::new(RefCell::new(torrents));
+    let access_list = state.access_list.clone();
+
+    // Periodically clean torrents
+    TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
+        enclose!((config, torrents, access_list) move || async move {
+            torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+
+            Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+        })()
+    }));
+
+    let mut handles = Vec::new();
+
+    for (_, receiver) in request_receivers.streams() {
+        let handle = spawn_local(handle_request_stream(
+            config.clone(),
+            torrents.clone(),
+            state.clone(),
+            server_start_instant,
+            receiver,
+        ))
+        .detach();
+
+        handles.push(handle);
+    }
+
+    // Request streams end when all socket workers have exited
+    for handle in handles {
+        handle.await;
+    }
+
+    if state.shutdown.is_triggered() && config.shutdown.persist_state() {
+        if let Err(err) = save_torrent_maps(
+            &config,
+            worker_index,
+            &torrents.borrow(),
+            server_start_instant.seconds_elapsed(),
+        ) {
+            ::log::error!("couldn't save torrent state: {:#}", err);
+        }
+    }
+}
+
+async fn handle_request_stream<S>(
+    config: Config,
+    torrents: Rc<RefCell<TorrentMaps>>,
+    state: State,
+    server_start_instant: ServerStartInstant,
+    mut stream: S,
+) where
+    S: Stream<Item = ChannelRequest> + ::std::marker::Unpin,
+{
+    let mut rng = SmallRng::from_entropy();
+
+    while let Some(channel_request) = stream.next().await {
+        match channel_request {
+            ChannelRequest::Announce {
+                request,
+                peer_addr,
+                response_sender,
+            } => {
+                let response = handle_announce_request(
+                    &config,
+                    &mut rng,
+                    &mut torrents.borrow_mut(),
+                    &state,
+                    server_start_instant.seconds_elapsed(),
+                    peer_addr,
+                    request,
+                );
+
+                if let Err(err) = response_sender.connect().await.send(response).await {
+                    ::log::error!("swarm worker could not send announce response: {:#}", err);
+                }
+            }
+            ChannelRequest::Scrape {
+                request,
+                peer_addr,
+                response_sender,
+            } => {
+                let response =
+                    handle_scrape_request(&config, &mut torrents.borrow_mut(), peer_addr, request);
+
+                if let Err(err) = response_sender.connect().await.send(response).await {
+                    ::log::error!("swarm worker could not send scrape response: {:#}", err);
+                }
+            }
+        };
+    }
+}
@@ -0,0 +1,792 @@
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        mod tokio;
+
+        pub use self::tokio::run_swarm_worker;
+    } else if #[cfg(feature = "glommio")] {
+        mod glommio;
+
+        pub use self::glommio::run_swarm_worker;
+    }
+}
+
+mod persistence;
+
+pub use persistence::{load_torrent_maps, save_torrent_maps};
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use smartstring::{LazyCompact, SmartString};
+
+use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::early_announce::EarlyAnnounceAction;
+use aquatic_common::{extract_filtered_response_peers, extract_response_peers, IndexMap};
+use aquatic_common::{AmortizedIndexMap, CanonicalSocketAddr};
+use aquatic_common::{SecondsSinceServerStart, ServerStartInstant, ValidUntil};
+use aquatic_http_protocol::common::*;
+use aquatic_http_protocol::request::*;
+use aquatic_http_protocol::response::ResponsePeer;
+use aquatic_http_protocol::response::*;
+
+use crate::common::State;
+use crate::config::Config;
+
+pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash + Into<IpAddr> {}
+
+impl Ip for Ipv4Addr {}
+impl Ip for Ipv6Addr {}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum PeerStatus {
+    Seeding,
+    Leeching,
+    /// Peer has stopped downloading without having the complete torrent
+    /// (BEP 21)
+    PartialSeeding,
+    Stopped,
+}
+
+impl PeerStatus {
+    /// Determine peer status from announce event and number of bytes left.
+    ///
+    /// Likely, the last branch will be taken most of the time.
+    #[inline]
+    pub fn from_event_and_bytes_left(event: AnnounceEvent, opt_bytes_left: Option<usize>) -> Self {
+        if let AnnounceEvent::Stopped = event {
+            Self::Stopped
+        } else if let Some(0) = opt_bytes_left {
+            Self::Seeding
+        } else if let AnnounceEvent::Paused = event {
+            Self::PartialSeeding
+        } else {
+            Self::Leeching
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer<I: Ip> {
+    pub ip_address: I,
+    pub port: u16,
+    pub key: Option<SmartString<LazyCompact>>,
+    pub status: PeerStatus,
+    pub valid_until: ValidUntil,
+    /// None for peers loaded from saved state
+    pub last_announce: Option<SecondsSinceServerStart>,
+}
+
+impl<I: Ip> Peer<I> {
+    pub fn to_response_peer(&self) -> ResponsePeer<I> {
+        ResponsePeer {
+            ip_address: self.ip_address,
+            port: self.port,
+        }
+    }
+}
+
+pub type PeerMap<I> = IndexMap<PeerId, Peer<I>>;
+
+pub struct TorrentData<I: Ip> {
+    pub peers: PeerMap<I>,
+    pub num_seeders: usize,
+    /// Number of leechers, excluding partial seeds
+    pub num_leechers: usize,
+    pub num_partial_seeds: usize,
+    /// Response peers last sent to each peer. Only filled in when early
+    /// announces are answered with cached responses.
+    pub cached_response_peers: IndexMap<PeerId, Vec<ResponsePeer<I>>>,
+}
+
+impl<I: Ip> Default for TorrentData<I> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            peers: Default::default(),
+            num_seeders: 0,
+            num_leechers: 0,
+            num_partial_seeds: 0,
+            cached_response_peers: Default::default(),
+        }
+    }
+}
+
+pub type TorrentMap<I> = AmortizedIndexMap<InfoHash, TorrentData<I>>;
+
+#[derive(Default)]
+pub struct TorrentMaps {
+    pub ipv4: TorrentMap<Ipv4Addr>,
+    pub ipv6: TorrentMap<Ipv6Addr>,
+}
+
+impl TorrentMaps {
+    pub fn clean(
+        &mut self,
+        config: &Config,
+        access_list: &Arc<AccessListArcSwap>,
+        server_start_instant: ServerStartInstant,
+    ) {
+        let mut access_list_cache = create_access_list_cache(access_list);
+
+        let now = server_start_instant.seconds_elapsed();
+
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now);
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now);
+    }
+
+    fn clean_torrent_map<I: Ip>(
+        config: &Config,
+        access_list_cache: &mut AccessListCache,
+        torrent_map: &mut TorrentMap<I>,
+        now: SecondsSinceServerStart,
+    ) {
+        torrent_map.retain(|info_hash, torrent_data| {
+            if !access_list_cache
+                .load()
+                .allows(config.access_list.mode, &info_hash.0)
+            {
+                return false;
+            }
+
+            let num_seeders = &mut torrent_data.num_seeders;
+            let num_leechers = &mut torrent_data.num_leechers;
+            let num_partial_seeds = &mut torrent_data.num_partial_seeds;
+
+            torrent_data.peers.retain(|_, peer| {
+                let keep = peer.valid_until.valid(now);
+
+                if !keep {
+                    match peer.status {
+                        PeerStatus::Seeding => {
+                            *num_seeders -= 1;
+                        }
+                        PeerStatus::Leeching => {
+                            *num_leechers -= 1;
+                        }
+                        PeerStatus::PartialSeeding => {
+                            *num_partial_seeds -= 1;
+                        }
+                        _ => (),
+                    };
+                }
+
+                keep
+            });
+
+            if !torrent_data.cached_response_peers.is_empty() {
+                let peers = &torrent_data.peers;
+
+                torrent_data
+                    .cached_response_peers
+                    .retain(|peer_id, _| peers.contains_key(peer_id));
+            }
+
+            !torrent_data.peers.is_empty()
+        });
+
+        torrent_map.shrink_to_fit();
+    }
+}
+
+pub fn handle_announce_request(
+    config: &Config,
+    rng: &mut impl Rng,
+    torrent_maps: &mut TorrentMaps,
+    state: &State,
+    now: SecondsSinceServerStart,
+    peer_addr: CanonicalSocketAddr,
+    request: AnnounceRequest,
+) -> Response {
+    match peer_addr.get().ip() {
+        IpAddr::V4(peer_ip_address) => {
+            let torrent_data: &mut TorrentData<Ipv4Addr> =
+                torrent_maps.ipv4.entry(request.info_hash).or_default();
+
+            if let Some(response) =
+                check_peer_identity(config, torrent_data, peer_ip_address, &request)
+            {
+                return response;
+            }
+
+            let (seeders, leechers, response_peers) = match handle_early_announce(
+                config,
+                state,
+                torrent_data,
+                peer_ip_address,
+                &request,
+                now,
+            ) {
+                Some(Ok(cached)) => cached,
+                Some(Err(response)) => return response,
+                None => upsert_peer_and_get_response_peers(
+                    config,
+                    rng,
+                    peer_ip_address,
+                    torrent_data,
+                    request,
+                    now,
+                ),
+            };
+
+            let mut response =
+                create_announce_response(config, state, peer_ip_address, seeders, leechers);
+
+            response.peers = ResponsePeerListV4(response_peers);
+
+            Response::Announce(response)
+        }
+        IpAddr::V6(peer_ip_address) => {
+            let torrent_data: &mut TorrentData<Ipv6Addr> =
+                torrent_maps.ipv6.entry(request.info_hash).or_default();
+
+            if let Some(response) =
+                check_peer_identity(config, torrent_data, peer_ip_address, &request)
+            {
+                return response;
+            }
+
+            let (seeders, leechers, response_peers) = match handle_early_announce(
+                config,
+                state,
+                torrent_data,
+                peer_ip_address,
+                &request,
+                now,
+            ) {
+                Some(Ok(cached)) => cached,
+                Some(Err(response)) => return response,
+                None => upsert_peer_and_get_response_peers(
+                    config,
+                    rng,
+                    peer_ip_address,
+                    torrent_data,
+                    request,
+                    now,
+                ),
+            };
+
+            let mut response =
+                create_announce_response(config, state, peer_ip_address, seeders, leechers);
+
+            response.peers6 = ResponsePeerListV6(response_peers);
+
+            Response::Announce(response)
+        }
+    }
+}
+
+/// Create announce response without peers
+fn create_announce_response<I: Ip>(
+    config: &Config,
+    state: &State,
+    peer_ip_address: I,
+    seeders: usize,
+    leechers: usize,
+) -> AnnounceResponse {
+    AnnounceResponse {
+        complete: seeders,
+        incomplete: leechers,
+        announce_interval: config.adaptive_announce_interval.calculate(
+            config.protocol.peer_announce_interval,
+            seeders + leechers,
+            &state.request_rate,
+        ),
+        min_announce_interval: Some(config.protocol.peer_announce_min_interval)
+            .filter(|interval| *interval != 0),
+        peers: ResponsePeerListV4(vec![]),
+        peers6: ResponsePeerListV6(vec![]),
+        tracker_id: (!config.protocol.tracker_id.is_empty())
+            .then(|| config.protocol.tracker_id.clone()),
+        external_ip: config
+            .protocol
+            .send_external_ip
+            .then(|| peer_ip_address.into()),
+        warning_message: None,
+    }
+}
+
+/// Return failure response if request is for a peer_id registered from a
+/// different IP address and doesn't pass the peer identity check
+fn check_peer_identity<I: Ip>(
+    config: &Config,
+    torrent_data: &TorrentData<I>,
+    peer_ip_address: I,
+    request: &AnnounceRequest,
+) -> Option<Response> {
+    let peer = torrent_data.peers.get(&request.peer_id)?;
+
+    if peer.ip_address == peer_ip_address
+        || config
+            .protocol
+            .peer_identity_check
+            .allows_ip_change(peer.key.as_ref(), request.key.as_ref())
+    {
+        return None;
+    }
+
+    Some(Response::Failure(FailureResponse {
+        failure_reason: "Peer ID in use from other IP address".into(),
+        retry_in: config.protocol.retry_in(),
+    }))
+}
+
+/// Number of seeders, number of leechers and response peers
+type AnnounceOutcome<I> = (usize, usize, Vec<ResponsePeer<I>>);
+
+/// Handle request if it is an announce without event from a peer that
+/// announced less than the minimum announce interval ago. Such peers are
+/// not updated. Depending on early_announce_action, return current number
+/// of seeders and leechers along with the response peers that the peer was
+/// last sent, or a failure response.
+fn handle_early_announce<I: Ip>(
+    config: &Config,
+    state: &State,
+    torrent_data: &TorrentData<I>,
+    peer_ip_address: I,
+    request: &AnnounceRequest,
+    now: SecondsSinceServerStart,
+) -> Option<Result<AnnounceOutcome<I>, Response>> {
+    let min_interval = config.protocol.peer_announce_min_interval;
+
+    if min_interval == 0 || request.event != AnnounceEvent::Empty {
+        return None;
+    }
+
+    let peer = torrent_data
+        .peers
+        .get(&request.peer_id)
+        .filter(|peer| peer.ip_address == peer_ip_address)?;
+
+    let seconds_since_last_announce = now.seconds_since(peer.last_announce?) as usize;
+
+    if seconds_since_last_announce >= min_interval {
+        return None;
+    }
+
+    state.early_announces.increment(&request.peer_id.0);
+
+    let result = match config.protocol.early_announce_action {
+        EarlyAnnounceAction::Cached => Ok((
+            torrent_data.num_seeders,
+            torrent_data.num_leechers + torrent_data.num_partial_seeds,
+            torrent_data
+                .cached_response_peers
+                .get(&request.peer_id)
+                .cloned()
+                .unwrap_or_default(),
+        )),
+        EarlyAnnounceAction::Failure => {
+            let seconds_left = min_interval - seconds_since_last_announce;
+
+            Err(Response::Failure(FailureResponse {
+                failure_reason: "Announcing too often".into(),
+                retry_in: Some(RetryIn::Minutes((seconds_left + 59) / 60)),
+            }))
+        }
+    };
+
+    Some(result)
+}
+
+/// Insert/update peer. Return number of seeders, number of leechers
+/// (including partial seeds) and response peers
+pub fn upsert_peer_and_get_response_peers<I: Ip>(
+    config: &Config,
+    rng: &mut impl Rng,
+    peer_ip_address: I,
+    torrent_data: &mut TorrentData<I>,
+    request: AnnounceRequest,
+    now: SecondsSinceServerStart,
+) -> AnnounceOutcome<I> {
+    // Insert/update/remove peer who sent this request
+
+    let peer_status =
+        PeerStatus::from_event_and_bytes_left(request.event, Some(request.bytes_left));
+
+    let peer = Peer {
+        ip_address: peer_ip_address,
+        port: request.port,
+        key: request.key.clone(),
+        status: peer_status,
+        valid_until: ValidUntil::new_with_now(now, config.cleaning.max_peer_age),
+        last_announce: Some(now),
+    };
+
+    let opt_removed_peer = match peer_status {
+        PeerStatus::Leeching => {
+            torrent_data.num_leechers += 1;
+
+            torrent_data.peers.insert(request.peer_id, peer)
+        }
+        PeerStatus::Seeding => {
+            torrent_data.num_seeders += 1;
+
+            torrent_data.peers.insert(request.peer_id, peer)
+        }
+        PeerStatus::PartialSeeding => {
+            torrent_data.num_partial_seeds += 1;
+
+            torrent_data.peers.insert(request.peer_id, peer)
+        }
+        PeerStatus::Stopped => torrent_data.peers.remove(&request.peer_id),
+    };
+
+    match opt_removed_peer.map(|peer| peer.status) {
+        Some(PeerStatus::Leeching) => {
+            torrent_data.num_leechers -= 1;
+        }
+        Some(PeerStatus::Seeding) => {
+            torrent_data.num_seeders -= 1;
+        }
+        Some(PeerStatus::PartialSeeding) => {
+            torrent_data.num_partial_seeds -= 1;
+        }
+        _ => {}
+    }
+
+    let response_peers = if let PeerStatus::Stopped = peer_status {
+        Vec::new()
+    } else {
+        let max_num_peers_to_take = match request.numwant {
+            Some(0) | None => config.protocol.max_peers,
+            Some(numwant) => numwant.min(config.protocol.max_peers),
+        };
+
+        if peer_status == PeerStatus::Seeding && torrent_data.num_partial_seeds != 0 {
+            // Partial seeds won't download, so they are of no use to seeders
+            extract_filtered_response_peers(
+                rng,
+                &torrent_data.peers,
+                max_num_peers_to_take,
+                request.peer_id,
+                |peer| peer.status != PeerStatus::PartialSeeding,
+                Peer::to_response_peer,
+            )
+        } else {
+            extract_response_peers(
+                rng,
+                &torrent_data.peers,
+                max_num_peers_to_take,
+                request.peer_id,
+                Peer::to_response_peer,
+            )
+        }
+    };
+
+    // Store response peers for answering early announces
+    if config.protocol.peer_announce_min_interval != 0
+        && config.protocol.early_announce_action == EarlyAnnounceAction::Cached
+    {
+        if let PeerStatus::Stopped = peer_status {
+            torrent_data.cached_response_peers.remove(&request.peer_id);
+        } else {
+            torrent_data
+                .cached_response_peers
+                .insert(request.peer_id, response_peers.clone());
+        }
+    }
+
+    (
+        torrent_data.num_seeders,
+        torrent_data.num_leechers + torrent_data.num_partial_seeds,
+        response_peers,
+    )
+}
+
+pub fn handle_scrape_request(
+    config: &Config,
+    torrent_maps: &mut TorrentMaps,
+    peer_addr: CanonicalSocketAddr,
+    request: ScrapeRequest,
+) -> ScrapeResponse {
+    let num_to_take = request
+        .info_hashes
+        .len()
+        .min(config.protocol.max_scrape_torrents);
+
+    let mut response = ScrapeResponse {
+        files: BTreeMap::new(),
+    };
+
+    let peer_ip = peer_addr.get().ip();
+
+    // If request.info_hashes is empty, don't return scrape for all
+    // torrents, even though reference server does it. It is too expensive.
+    if peer_ip.is_ipv4() {
+        for info_hash in request.info_hashes.into_iter().take(num_to_take) {
+            if let Some(torrent_data) = torrent_maps.ipv4.get(&info_hash) {
+                let stats = ScrapeStatistics {
+                    complete: torrent_data.num_seeders,
+                    downloaded: 0, // No implementation planned
+                    incomplete: torrent_data.num_leechers + torrent_data.num_partial_seeds,
+                    downloaders: torrent_data.num_leechers,
+                };
+
+                response.files.insert(info_hash, stats);
+            }
+        }
+    } else {
+        for info_hash in request.info_hashes.into_iter().take(num_to_take) {
+            if let Some(torrent_data) = torrent_maps.ipv6.get(&info_hash) {
+                let stats = ScrapeStatistics {
+                    complete: torrent_data.num_seeders,
+                    downloaded: 0, // No implementation planned
+                    incomplete: torrent_data.num_leechers + torrent_data.num_partial_seeds,
+                    downloaders: torrent_data.num_leechers,
+                };
+
+                response.files.insert(info_hash, stats);
+            }
+        }
+    };
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use aquatic_common::peer_identity::PeerIdentityCheck;
+
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+
+    const INFO_HASH: InfoHash = InfoHash([0; 20]);
+
+    fn announce_request(
+        peer_index: u8,
+        bytes_left: usize,
+        event: AnnounceEvent,
+    ) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: INFO_HASH,
+            peer_id: PeerId([peer_index; 20]),
+            port: 1,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left,
+            event,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+        }
+    }
+
+    fn peer_addr(peer_index: u8) -> CanonicalSocketAddr {
+        CanonicalSocketAddr::new(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, peer_index)),
+            1,
+        ))
+    }
+
+    fn announce(
+        config: &Config,
+        torrent_maps: &mut TorrentMaps,
+        peer_addr: CanonicalSocketAddr,
+        request: AnnounceRequest,
+    ) -> Response {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        handle_announce_request(
+            config,
+            &mut rng,
+            torrent_maps,
+            &State::default(),
+            ServerStartInstant::new().seconds_elapsed(),
+            peer_addr,
+            request,
+        )
+    }
+
+    #[test]
+    fn test_partial_seeds() {
+        let mut config = Config::default();
+
+        config.protocol.max_peers = 5;
+
+        let mut torrent_maps = TorrentMaps::default();
+
+        // Partial seeds first, so that they would take up all places in
+        // response peer selection if filtered after it
+        for i in 1..=20 {
+            let request = announce_request(i, 1, AnnounceEvent::Paused);
+
+            announce(&config, &mut torrent_maps, peer_addr(i), request);
+        }
+        for i in 21..=25 {
+            let request = announce_request(i, 1, AnnounceEvent::Started);
+
+            announce(&config, &mut torrent_maps, peer_addr(i), request);
+        }
+
+        let request = announce_request(26, 0, AnnounceEvent::Started);
+
+        match announce(&config, &mut torrent_maps, peer_addr(26), request) {
+            Response::Announce(response) => {
+                assert_eq!(response.complete, 1);
+                assert_eq!(response.incomplete, 25);
+                assert_eq!(response.peers.0.len(), 5);
+                assert!(response.peers.0.iter().all(|peer| {
+                    (Ipv4Addr::new(10, 0, 0, 21)..=Ipv4Addr::new(10, 0, 0, 25))
+                        .contains(&peer.ip_address)
+                }));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        // Leechers get partial seeds too
+        let request = announce_request(27, 1, AnnounceEvent::Started);
+
+        match announce(&config, &mut torrent_maps, peer_addr(27), request) {
+            Response::Announce(response) => {
+                assert_eq!(response.incomplete, 26);
+                assert_eq!(response.peers.0.len(), 5);
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let scrape_request = ScrapeRequest {
+            info_hashes: vec![INFO_HASH],
+        };
+
+        let scrape_response =
+            handle_scrape_request(&config, &mut torrent_maps, peer_addr(1), scrape_request);
+
+        let stats = scrape_response.files.get(&INFO_HASH).unwrap();
+
+        assert_eq!(stats.complete, 1);
+        assert_eq!(stats.incomplete, 26);
+        assert_eq!(stats.downloaders, 6);
+
+        // Partial seed that completes download becomes seeder
+        let request = announce_request(1, 0, AnnounceEvent::Completed);
+
+        announce(&config, &mut torrent_maps, peer_addr(1), request);
+
+        let torrent_data = torrent_maps.ipv4.get(&INFO_HASH).unwrap();
+
+        assert_eq!(torrent_data.num_seeders, 2);
+        assert_eq!(torrent_data.num_leechers, 6);
+        assert_eq!(torrent_data.num_partial_seeds, 19);
+    }
+
+    #[test]
+    fn test_early_announce() {
+        let mut config = Config::default();
+
+        config.protocol.max_peers = 5;
+        config.protocol.peer_announce_min_interval = 60;
+
+        let mut torrent_maps = TorrentMaps::default();
+
+        for i in 1..=20 {
+            let request = announce_request(i, 1, AnnounceEvent::Started);
+
+            announce(&config, &mut torrent_maps, peer_addr(i), request);
+        }
+
+        let request = announce_request(21, 1, AnnounceEvent::Started);
+
+        let response_peers = match announce(&config, &mut torrent_maps, peer_addr(21), request) {
+            Response::Announce(response) => response.peers.0,
+            response => panic!("unexpected response: {:?}", response),
+        };
+
+        assert_eq!(response_peers.len(), 5);
+
+        // Early announce gets same peers as last regular announce
+        for _ in 0..3 {
+            let request = announce_request(21, 1, AnnounceEvent::Empty);
+
+            match announce(&config, &mut torrent_maps, peer_addr(21), request) {
+                Response::Announce(response) => {
+                    assert_eq!(response.peers.0, response_peers);
+                    assert_eq!(response.incomplete, 21);
+                }
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+
+        config.protocol.early_announce_action = EarlyAnnounceAction::Failure;
+
+        let request = announce_request(21, 1, AnnounceEvent::Empty);
+
+        match announce(&config, &mut torrent_maps, peer_addr(21), request) {
+            Response::Failure(response) => {
+                assert_eq!(response.retry_in, Some(RetryIn::Minutes(1)));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        // Stopping removes cached response peers
+        let request = announce_request(21, 1, AnnounceEvent::Stopped);
+
+        config.protocol.early_announce_action = EarlyAnnounceAction::Cached;
+
+        announce(&config, &mut torrent_maps, peer_addr(21), request);
+
+        let torrent_data = torrent_maps.ipv4.get(&INFO_HASH).unwrap();
+
+        assert!(!torrent_data
+            .cached_response_peers
+            .contains_key(&PeerId([21; 20])));
+        assert_eq!(torrent_data.cached_response_peers.len(), 20);
+    }
+
+    #[test]
+    fn test_peer_identity_check() {
+        let mut config = Config::default();
+
+        for (peer_key, request_key, off, lenient, strict) in [
+            (Some("a"), Some("a"), true, true, true),
+            (Some("a"), Some("b"), true, false, false),
+            (Some("a"), None, true, false, false),
+            (None, Some("a"), true, true, false),
+            (None, None, true, true, false),
+        ] {
+            for (check, allowed) in [
+                (PeerIdentityCheck::Off, off),
+                (PeerIdentityCheck::Lenient, lenient),
+                (PeerIdentityCheck::Strict, strict),
+            ] {
+                config.protocol.peer_identity_check = check;
+
+                let mut torrent_maps = TorrentMaps::default();
+
+                let mut request = announce_request(1, 1, AnnounceEvent::Started);
+                request.key = peer_key.map(Into::into);
+
+                announce(&config, &mut torrent_maps, peer_addr(1), request);
+
+                // Same peer_id from other IP address
+                let mut request = announce_request(1, 0, AnnounceEvent::Completed);
+                request.key = request_key.map(Into::into);
+
+                let response = announce(&config, &mut torrent_maps, peer_addr(2), request);
+
+                let peer = torrent_maps
+                    .ipv4
+                    .get(&INFO_HASH)
+                    .unwrap()
+                    .peers
+                    .get(&PeerId([1; 20]))
+                    .unwrap();
+
+                if allowed {
+                    assert!(matches!(response, Response::Announce(_)));
+                    assert_eq!(peer.ip_address, Ipv4Addr::new(10, 0, 0, 2));
+                    assert_eq!(peer.status, PeerStatus::Seeding);
+                } else {
+                    assert!(matches!(response, Response::Failure(_)));
+                    assert_eq!(peer.ip_address, Ipv4Addr::new(10, 0, 0, 1));
+                    assert_eq!(peer.status, PeerStatus::Leeching);
+                }
+            }
+        }
+    }
+}
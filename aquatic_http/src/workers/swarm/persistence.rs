@@ -0,0 +1,338 @@
+//! Saving torrent state during graceful shutdown and loading it on startup
+//!
+//! Each swarm worker saves its torrents to a separate file in
+//! shutdown.state_path. Peers are stored with the number of seconds they
+//! remain valid, which is reduced by the time the tracker was down when they
+//! are loaded.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_common::{SecondsSinceServerStart, ServerStartInstant, ValidUntil};
+use aquatic_http_protocol::common::{InfoHash, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::common::calculate_request_consumer_index;
+use crate::config::Config;
+
+use super::{Ip, Peer, PeerStatus, TorrentData, TorrentMap, TorrentMaps};
+
+const FILE_NAME_PREFIX: &str = "swarm-";
+const FILE_NAME_SUFFIX: &str = ".json";
+
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    /// Seconds since unix epoch
+    saved_at: u64,
+    torrents: Vec<SavedTorrent>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedTorrent {
+    info_hash: [u8; 20],
+    peers: Vec<SavedPeer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedPeer {
+    peer_id: [u8; 20],
+    ip_address: IpAddr,
+    port: u16,
+    key: Option<String>,
+    status: PeerStatus,
+    /// Seconds left until peer is removed
+    valid_for: u32,
+}
+
+/// Save torrents of swarm worker to file in shutdown.state_path
+pub fn save_torrent_maps(
+    config: &Config,
+    worker_index: usize,
+    torrent_maps: &TorrentMaps,
+    now: SecondsSinceServerStart,
+) -> anyhow::Result<()> {
+    let mut torrents = Vec::new();
+
+    add_saved_torrents(&mut torrents, &torrent_maps.ipv4, now);
+    add_saved_torrents(&mut torrents, &torrent_maps.ipv6, now);
+
+    let state = SavedState {
+        saved_at: unix_seconds()?,
+        torrents,
+    };
+
+    let path = config.shutdown.state_path.join(format!(
+        "{}{:02}{}",
+        FILE_NAME_PREFIX,
+        worker_index + 1,
+        FILE_NAME_SUFFIX
+    ));
+
+    let file = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    serde_json::to_writer(&mut writer, &state)
+        .with_context(|| format!("write {}", path.display()))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("write {}", path.display()))?;
+
+    ::log::info!(
+        "saved {} torrents to {}",
+        state.torrents.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Load torrents saved by swarm workers if shutdown.state_path is set and
+/// distribute them among swarm workers by info hash. Files are removed
+/// after loading.
+///
+/// Returns one TorrentMaps per swarm worker.
+pub fn load_torrent_maps(
+    config: &Config,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<Vec<TorrentMaps>> {
+    let mut torrent_maps = (0..config.swarm_workers)
+        .map(|_| TorrentMaps::default())
+        .collect::<Vec<_>>();
+
+    if !config.shutdown.persist_state() {
+        return Ok(torrent_maps);
+    }
+
+    let dir = &config.shutdown.state_path;
+
+    fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+
+    let now = server_start_instant.seconds_elapsed();
+    let unix_now = unix_seconds()?;
+
+    for entry in fs::read_dir(dir).with_context(|| format!("read {}", dir.display()))? {
+        let path = entry?.path();
+
+        if !is_state_file(&path) {
+            continue;
+        }
+
+        let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+        let state: SavedState = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parse {}", path.display()))?;
+
+        let seconds_down = unix_now.saturating_sub(state.saved_at);
+
+        for torrent in state.torrents {
+            let info_hash = InfoHash(torrent.info_hash);
+            let maps = &mut torrent_maps[calculate_request_consumer_index(config, info_hash)];
+
+            for peer in torrent.peers {
+                let valid_for = u64::from(peer.valid_for).saturating_sub(seconds_down);
+
+                if valid_for == 0 {
+                    continue;
+                }
+
+                // Fits, since it is at most the saved u32 value
+                let valid_until = ValidUntil::new_with_now(now, valid_for as u32);
+
+                match peer.ip_address {
+                    IpAddr::V4(ip_address) => insert_peer(
+                        maps.ipv4.entry(info_hash).or_default(),
+                        ip_address,
+                        peer,
+                        valid_until,
+                    ),
+                    IpAddr::V6(ip_address) => insert_peer(
+                        maps.ipv6.entry(info_hash).or_default(),
+                        ip_address,
+                        peer,
+                        valid_until,
+                    ),
+                }
+            }
+        }
+
+        fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+
+        ::log::info!("loaded torrent state from {}", path.display());
+    }
+
+    Ok(torrent_maps)
+}
+
+fn add_saved_torrents<I: Ip>(
+    torrents: &mut Vec<SavedTorrent>,
+    torrent_map: &TorrentMap<I>,
+    now: SecondsSinceServerStart,
+) {
+    for (info_hash, torrent_data) in torrent_map.iter() {
+        let peers = torrent_data
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.valid_until.valid(now))
+            .map(|(peer_id, peer)| SavedPeer {
+                peer_id: peer_id.0,
+                ip_address: peer.ip_address.into(),
+                port: peer.port,
+                key: peer.key.as_ref().map(|key| key.to_string()),
+                status: peer.status,
+                valid_for: peer.valid_until.seconds_left(now),
+            })
+            .collect::<Vec<_>>();
+
+        if !peers.is_empty() {
+            torrents.push(SavedTorrent {
+                info_hash: info_hash.0,
+                peers,
+            });
+        }
+    }
+}
+
+fn insert_peer<I: Ip>(
+    torrent_data: &mut TorrentData<I>,
+    ip_address: I,
+    saved_peer: SavedPeer,
+    valid_until: ValidUntil,
+) {
+    let peer_id = PeerId(saved_peer.peer_id);
+
+    if torrent_data.peers.contains_key(&peer_id) {
+        return;
+    }
+
+    match saved_peer.status {
+        PeerStatus::Seeding => torrent_data.num_seeders += 1,
+        PeerStatus::Leeching => torrent_data.num_leechers += 1,
+        PeerStatus::PartialSeeding => torrent_data.num_partial_seeds += 1,
+        PeerStatus::Stopped => return,
+    }
+
+    let peer = Peer {
+        ip_address,
+        port: saved_peer.port,
+        key: saved_peer.key.map(Into::into),
+        status: saved_peer.status,
+        valid_until,
+        last_announce: None,
+    };
+
+    torrent_data.peers.insert(peer_id, peer);
+}
+
+fn is_state_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| {
+            name.starts_with(FILE_NAME_PREFIX) && name.ends_with(FILE_NAME_SUFFIX)
+        })
+}
+
+fn unix_seconds() -> anyhow::Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time is before unix epoch")?
+        .as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_torrent_maps() {
+        let state_path = std::env::temp_dir().join(format!(
+            "aquatic_http_test_persistence_{}",
+            std::process::id()
+        ));
+
+        let mut config = Config {
+            swarm_workers: 2,
+            ..Default::default()
+        };
+
+        config.shutdown.state_path = state_path.clone();
+
+        let server_start_instant = ServerStartInstant::new();
+        let now = server_start_instant.seconds_elapsed();
+
+        let info_hash_a = InfoHash([0; 20]);
+        let info_hash_b = InfoHash([1; 20]);
+
+        let mut torrent_maps = TorrentMaps::default();
+
+        for (info_hash, peer_index, status) in [
+            (info_hash_a, 0, PeerStatus::Seeding),
+            (info_hash_a, 1, PeerStatus::Leeching),
+            (info_hash_b, 2, PeerStatus::PartialSeeding),
+        ] {
+            insert_peer(
+                torrent_maps.ipv4.entry(info_hash).or_default(),
+                Ipv4Addr::new(127, 0, 0, peer_index),
+                SavedPeer {
+                    peer_id: [peer_index; 20],
+                    ip_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, peer_index)),
+                    port: 1000,
+                    key: Some("key".into()),
+                    status,
+                    valid_for: 60,
+                },
+                ValidUntil::new_with_now(now, 60),
+            );
+        }
+
+        insert_peer(
+            torrent_maps.ipv6.entry(info_hash_b).or_default(),
+            Ipv6Addr::LOCALHOST,
+            SavedPeer {
+                peer_id: [3; 20],
+                ip_address: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                port: 1000,
+                key: None,
+                status: PeerStatus::Seeding,
+                valid_for: 60,
+            },
+            ValidUntil::new_with_now(now, 60),
+        );
+
+        fs::create_dir_all(&state_path).unwrap();
+
+        save_torrent_maps(&config, 0, &torrent_maps, now).unwrap();
+
+        let loaded = load_torrent_maps(&config, server_start_instant).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+
+        // Torrents are distributed by info hash
+        let torrent_a = loaded[0].ipv4.get(&info_hash_a).unwrap();
+
+        assert_eq!(torrent_a.num_seeders, 1);
+        assert_eq!(torrent_a.num_leechers, 1);
+        assert!(loaded[0].ipv4.get(&info_hash_b).is_none());
+
+        let torrent_b = loaded[1].ipv4.get(&info_hash_b).unwrap();
+        let peer = torrent_b.peers.get(&PeerId([2; 20])).unwrap();
+
+        assert_eq!(torrent_b.num_partial_seeds, 1);
+        assert_eq!(peer.key.as_deref(), Some("key"));
+        assert!(peer.last_announce.is_none());
+        assert!(peer.valid_until.valid(now));
+        assert_eq!(loaded[1].ipv6.get(&info_hash_b).unwrap().num_seeders, 1);
+
+        // Files are removed after loading
+        assert!(load_torrent_maps(&config, server_start_instant).unwrap()[0]
+            .ipv4
+            .is_empty());
+
+        fs::remove_dir_all(&state_path).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::prelude::SmallRng;
+use rand::SeedableRng;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::{spawn_local, LocalSet};
+use tokio::time::sleep;
+
+use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::{PanicSentinel, ServerStartInstant};
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::{handle_announce_request, handle_scrape_request, save_torrent_maps, TorrentMaps};
+
+pub fn run_swarm_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    worker_index: usize,
+    torrents: TorrentMaps,
+    request_receiver: Receiver<ChannelRequest>,
+    server_start_instant: ServerStartInstant,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+
+    LocalSet::new().block_on(
+        &runtime,
+        run_inner(
+            config,
+            state,
+            worker_index,
+            torrents,
+            request_receiver,
+            server_start_instant,
+        ),
+    );
+}
+
+async fn run_inner(
+    config: Config,
+    state: State,
+    worker_index: usize,
+    torrents: TorrentMaps,
+    mut request_receiver: Receiver<ChannelRequest>,
+    server_start_instant: ServerStartInstant,
+) {
+    let torrents = Rc::new(RefCell::new(torrents));
+
+    spawn_local(periodically_clean_torrents(
+        config.clone(),
+        torrents.clone(),
+        state.access_list.clone(),
+        server_start_instant,
+    ));
+
+    let mut rng = SmallRng::from_entropy();
+
+    // Channel is closed when all socket workers have exited
+    while let Some(channel_request) = request_receiver.recv().await {
+        match channel_request {
+            ChannelRequest::Announce {
+                request,
+                peer_addr,
+                response_sender,
+            } => {
+                let response = handle_announce_request(
+                    &config,
+                    &mut rng,
+                    &mut torrents.borrow_mut(),
+                    &state,
+                    server_start_instant.seconds_elapsed(),
+                    peer_addr,
+                    request,
+                );
+
+                if response_sender.send(response).is_err() {
+                    ::log::debug!("swarm worker could not send announce response: receiver closed");
+                }
+            }
+            ChannelRequest::Scrape {
+                request,
+                peer_addr,
+                response_sender,
+            } => {
+                let response =
+                    handle_scrape_request(&config, &mut torrents.borrow_mut(), peer_addr, request);
+
+                if response_sender.send(response).is_err() {
+                    ::log::debug!("swarm worker could not send scrape response: receiver closed");
+                }
+            }
+        };
+    }
+
+    if state.shutdown.is_triggered() && config.shutdown.persist_state() {
+        if let Err(err) = save_torrent_maps(
+            &config,
+            worker_index,
+            &torrents.borrow(),
+            server_start_instant.seconds_elapsed(),
+        ) {
+            ::log::error!("couldn't save torrent state: {:#}", err);
+        }
+    }
+}
+
+async fn periodically_clean_torrents(
+    config: Config,
+    torrents: Rc<RefCell<TorrentMaps>>,
+    access_list: Arc<AccessListArcSwap>,
+    server_start_instant: ServerStartInstant,
+) {
+    loop {
+        sleep(Duration::from_secs(
+            config.cleaning.torrent_cleaning_interval,
+        ))
+        .await;
+
+        torrents
+            .borrow_mut()
+            .clean(&config, &access_list, server_start_instant);
+    }
+}
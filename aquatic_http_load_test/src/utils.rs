@@ -45,6 +45,7 @@ fn create_announce_request(config: &Config, state: &LoadTestState, rng: &mut imp
         bytes_left,
         event,
         key: None,
+        tracker_id: None,
         numwant: None,
         port: rng.gen(),
         bytes_uploaded: 0,
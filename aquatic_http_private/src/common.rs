@@ -1,7 +1,16 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
 use tokio::sync::{mpsc, oneshot};
 
+use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::client_filter::ClientFilterArcSwap;
 use aquatic_common::CanonicalSocketAddr;
-use aquatic_http_protocol::{common::InfoHash, response::Response};
+use aquatic_http_protocol::{
+    common::{InfoHash, PeerId},
+    response::Response,
+};
 
 use crate::{config::Config, workers::socket::db::ValidatedAnnounceRequest};
 
@@ -50,3 +59,81 @@ impl ChannelRequestSender {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisteredTorrent {
+    /// Overrides configured peer announce interval, but not interval set by
+    /// stored procedure
+    pub announce_interval: Option<usize>,
+}
+
+/// Torrents registered on site, synced from database
+#[derive(Default, Clone)]
+pub struct TorrentRegistry(HashMap<InfoHash, RegisteredTorrent>);
+
+impl TorrentRegistry {
+    pub fn get(&self, info_hash: &InfoHash) -> Option<&RegisteredTorrent> {
+        self.0.get(info_hash)
+    }
+
+    pub fn insert(&mut self, info_hash: InfoHash, torrent: RegisteredTorrent) {
+        self.0.insert(info_hash, torrent);
+    }
+
+    pub fn remove(&mut self, info_hash: &InfoHash) {
+        self.0.remove(info_hash);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Kind of suspicious behaviour detected by comparing an announce request to
+/// the previous one by the same peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatKind {
+    /// Upload speed above configured maximum
+    UploadSpeed,
+    /// Upload reported while no other peers were leeching
+    UploadWithoutLeechers,
+    /// Number of bytes left increased
+    LeftIncreased,
+}
+
+impl CheatKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UploadSpeed => "upload_speed",
+            Self::UploadWithoutLeechers => "upload_without_leechers",
+            Self::LeftIncreased => "left_increased",
+        }
+    }
+}
+
+/// Change in transfer statistics between two announce requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferDelta {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left_before: u64,
+    pub left_after: u64,
+    pub seconds: u64,
+}
+
+#[derive(Debug)]
+pub struct CheatReport {
+    pub kind: CheatKind,
+    pub user_token: String,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub source_addr: CanonicalSocketAddr,
+    pub delta: TransferDelta,
+}
+
+#[derive(Default, Clone)]
+pub struct State {
+    pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
+    pub torrent_registry: Arc<ArcSwap<TorrentRegistry>>,
+}
@@ -1,8 +1,10 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::privileges::PrivilegeConfig;
+use aquatic_common::{
+    access_list::AccessListConfig, client_filter::ClientFilterConfig, privileges::PrivilegeConfig,
+};
 use aquatic_toml_config::TomlConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 
@@ -20,11 +22,22 @@ pub struct Config {
     pub worker_channel_size: usize,
     /// Number of database connections to establish in each socket worker
     pub db_connections_per_worker: u32,
+    /// Stored procedure to call for each announce request. Version 2
+    /// (aquatic_announce_v2) can additionally set per-user announce
+    /// interval, maximum number of peers and peer visibility.
+    pub announce_procedure_version: AnnounceProcedureVersion,
     pub log_level: LogLevel,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub cleaning: CleaningConfig,
+    pub statistics: StatisticsConfig,
+    pub torrent_registry: TorrentRegistryConfig,
+    pub cheat_detection: CheatDetectionConfig,
     pub privileges: PrivilegeConfig,
+    pub access_list: AccessListConfig,
+    pub client_filter: ClientFilterConfig,
+    #[cfg(feature = "cpu-pinning")]
+    pub cpu_pinning: aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc,
 }
 
 impl Default for Config {
@@ -34,11 +47,19 @@ impl Default for Config {
             swarm_workers: 1,
             worker_channel_size: 128,
             db_connections_per_worker: 4,
+            announce_procedure_version: AnnounceProcedureVersion::V1,
             log_level: LogLevel::default(),
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             cleaning: CleaningConfig::default(),
+            statistics: StatisticsConfig::default(),
+            torrent_registry: TorrentRegistryConfig::default(),
+            cheat_detection: CheatDetectionConfig::default(),
             privileges: PrivilegeConfig::default(),
+            access_list: AccessListConfig::default(),
+            client_filter: ClientFilterConfig::default(),
+            #[cfg(feature = "cpu-pinning")]
+            cpu_pinning: Default::default(),
         }
     }
 }
@@ -49,11 +70,23 @@ impl aquatic_common::cli::Config for Config {
     }
 }
 
+/// Stored procedure version. Available values are v1 and v2.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceProcedureVersion {
+    V1,
+    V2,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
     /// Bind to this address
     pub address: SocketAddr,
+    /// Only allow access over IPv6
+    pub only_ipv6: bool,
+    /// Maximum number of pending TCP connections
+    pub tcp_backlog: i32,
     /// Path to TLS certificate (DER-encoded X.509)
     pub tls_certificate_path: PathBuf,
     /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
@@ -67,6 +100,8 @@ impl Default for NetworkConfig {
             address: SocketAddr::from(([0, 0, 0, 0], 3000)),
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            only_ipv6: false,
+            tcp_backlog: 1024,
             keep_alive: true,
         }
     }
@@ -111,6 +146,103 @@ impl Default for CleaningConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Print statistics this often (seconds)
+    pub interval: u64,
+    /// Print statistics (currently counts of client filter matches by rule)
+    /// to standard output
+    pub print_to_stdout: bool,
+}
+
+impl StatisticsConfig {
+    pub fn active(&self) -> bool {
+        (self.interval != 0) & self.print_to_stdout
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            print_to_stdout: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TorrentRegistryConfig {
+    /// Only serve torrents present in database view aquatic_torrents_v1.
+    /// Requests for other torrents are rejected before the stored procedure
+    /// is called.
+    pub active: bool,
+    /// Fetch torrents updated since last fetch this often (seconds)
+    pub update_interval: u64,
+    /// Fetch all torrents this often (seconds). This is only necessary for
+    /// noticing torrents that are deleted from the view instead of being
+    /// marked as unregistered.
+    pub full_update_interval: u64,
+}
+
+impl Default for TorrentRegistryConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            update_interval: 10,
+            full_update_interval: 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheatDetectionConfig {
+    /// Compare transfer statistics reported by peers to their previous
+    /// announce and report suspicious behaviour
+    pub active: bool,
+    /// Report peers claiming to have uploaded faster than this (bytes per
+    /// second). Set to zero to disable.
+    pub max_upload_speed: u64,
+    /// Report peers claiming to have uploaded data to a torrent while no
+    /// other peers in its swarm were leeching at any point since their
+    /// previous announce
+    pub report_upload_without_leechers: bool,
+    /// Report peers claiming that the number of bytes left to download has
+    /// increased
+    pub report_left_increase: bool,
+    pub output: CheatReportOutput,
+    /// Path to file that reports are appended to when output is file
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub file_path: PathBuf,
+}
+
+impl Default for CheatDetectionConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            max_upload_speed: 100 * 1024 * 1024,
+            report_upload_without_leechers: true,
+            report_left_increase: true,
+            output: CheatReportOutput::Log,
+            file_path: "./cheat-reports.txt".into(),
+        }
+    }
+}
+
+/// Where to send cheat reports. Available values are log (log at warn
+/// level), file (append to file) and database (call stored procedure
+/// aquatic_report_cheat_v1).
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheatReportOutput {
+    Log,
+    File,
+    Database,
+}
+
 #[cfg(test)]
 mod tests {
     use super::Config;
@@ -4,13 +4,23 @@ mod workers;
 
 use std::{collections::VecDeque, sync::Arc};
 
+#[cfg(feature = "cpu-pinning")]
+use aquatic_common::cpu_pinning::{
+    pin_current_if_configured_to, require_system_hyperthread_mapping, WorkerIndex,
+};
 use aquatic_common::{
-    privileges::PrivilegeDropper, rustls_config::create_rustls_config, PanicSentinelWatcher,
-    ServerStartInstant,
+    access_list::{spawn_access_list_watcher, update_access_list},
+    client_filter::update_client_filter,
+    privileges::PrivilegeDropper,
+    rustls_config::create_rustls_config,
+    PanicSentinelWatcher, ServerStartInstant,
 };
-use common::ChannelRequestSender;
+use common::{ChannelRequestSender, State};
 use dotenv::dotenv;
-use signal_hook::{consts::SIGTERM, iterator::Signals};
+use signal_hook::{
+    consts::{SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
 use tokio::sync::mpsc::channel;
 
 use config::Config;
@@ -19,10 +29,20 @@ pub const APP_NAME: &str = "aquatic_http_private: private HTTP/TLS BitTorrent tr
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub fn run(config: Config) -> anyhow::Result<()> {
-    let mut signals = Signals::new([SIGTERM])?;
+    let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
+
+    #[cfg(feature = "cpu-pinning")]
+    require_system_hyperthread_mapping(&config.cpu_pinning)?;
 
     dotenv().ok();
 
+    let state = State::default();
+
+    update_access_list(&config.access_list, &state.access_list)?;
+    update_client_filter(&config.client_filter, &state.client_filter)?;
+
+    spawn_access_list_watcher(&config.access_list, &state.access_list)?;
+
     let tls_config = Arc::new(create_rustls_config(
         &config.network.tls_certificate_path,
         &config.network.tls_private_key_path,
@@ -38,6 +58,14 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         request_receivers.push_back(request_receiver);
     }
 
+    let (opt_cheat_report_sender, opt_cheat_report_receiver) = if config.cheat_detection.active {
+        let (sender, receiver) = channel(config.worker_channel_size);
+
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
@@ -45,19 +73,64 @@ pub fn run(config: Config) -> anyhow::Result<()> {
 
     let mut handles = Vec::new();
 
-    for _ in 0..config.socket_workers {
+    if config.torrent_registry.active {
+        let handle = workers::registry::start_registry_worker(
+            sentinel.clone(),
+            config.clone(),
+            state.torrent_registry.clone(),
+        )?;
+
+        handles.push(handle);
+    }
+
+    if config.statistics.active() {
         let sentinel = sentinel.clone();
         let config = config.clone();
+        let state = state.clone();
+
+        let handle = ::std::thread::Builder::new()
+            .name("statistics".into())
+            .spawn(move || workers::statistics::run_statistics_worker(sentinel, config, state))?;
+
+        handles.push(handle);
+    }
+
+    if let Some(report_receiver) = opt_cheat_report_receiver {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+
+        let handle = ::std::thread::Builder::new()
+            .name("cheat-reporter".into())
+            .spawn(move || {
+                workers::cheat_reporter::run_cheat_reporter(sentinel, config, report_receiver)
+            })?;
+
+        handles.push(handle);
+    }
+
+    for i in 0..config.socket_workers {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
         let tls_config = tls_config.clone();
         let request_sender = ChannelRequestSender::new(request_senders.clone());
         let priv_dropper = priv_dropper.clone();
 
         let handle = ::std::thread::Builder::new()
-            .name("socket".into())
+            .name(format!("socket-{:02}", i + 1))
             .spawn(move || {
+                #[cfg(feature = "cpu-pinning")]
+                pin_current_if_configured_to(
+                    &config.cpu_pinning,
+                    config.socket_workers,
+                    config.swarm_workers,
+                    WorkerIndex::SocketWorker(i),
+                );
+
                 workers::socket::run_socket_worker(
                     sentinel,
                     config,
+                    state,
                     tls_config,
                     request_sender,
                     priv_dropper,
@@ -67,18 +140,30 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         handles.push(handle);
     }
 
-    for _ in 0..config.swarm_workers {
+    for i in 0..config.swarm_workers {
         let sentinel = sentinel.clone();
         let config = config.clone();
+        let state = state.clone();
         let request_receiver = request_receivers.pop_front().unwrap();
+        let opt_cheat_report_sender = opt_cheat_report_sender.clone();
 
         let handle = ::std::thread::Builder::new()
-            .name("request".into())
+            .name(format!("swarm-{:02}", i + 1))
             .spawn(move || {
+                #[cfg(feature = "cpu-pinning")]
+                pin_current_if_configured_to(
+                    &config.cpu_pinning,
+                    config.socket_workers,
+                    config.swarm_workers,
+                    WorkerIndex::SwarmWorker(i),
+                );
+
                 workers::swarm::run_swarm_worker(
                     sentinel,
                     config,
+                    state,
                     request_receiver,
+                    opt_cheat_report_sender,
                     server_start_instant,
                 )
             })?;
@@ -86,8 +171,20 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         handles.push(handle);
     }
 
+    #[cfg(feature = "cpu-pinning")]
+    pin_current_if_configured_to(
+        &config.cpu_pinning,
+        config.socket_workers,
+        config.swarm_workers,
+        WorkerIndex::Util,
+    );
+
     for signal in &mut signals {
         match signal {
+            SIGUSR1 => {
+                let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_client_filter(&config.client_filter, &state.client_filter);
+            }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
                     return Err(anyhow::anyhow!("worker thread panicked"));
@@ -0,0 +1,133 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_common::PanicSentinel;
+use sqlx::mysql::MySqlPoolOptions;
+use sqlx::{MySql, Pool};
+use tokio::sync::mpsc::Receiver;
+
+use crate::common::CheatReport;
+use crate::config::{CheatReportOutput, Config};
+
+pub fn run_cheat_reporter(
+    _sentinel: PanicSentinel,
+    config: Config,
+    report_receiver: Receiver<CheatReport>,
+) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(run_inner(config, report_receiver))?;
+
+    Ok(())
+}
+
+async fn run_inner(
+    config: Config,
+    mut report_receiver: Receiver<CheatReport>,
+) -> anyhow::Result<()> {
+    let mut output = Output::create(&config).await?;
+
+    while let Some(report) = report_receiver.recv().await {
+        if let Err(err) = output.write(&report).await {
+            ::log::error!("couldn't write cheat report: {:#}", err);
+        }
+    }
+
+    Ok(())
+}
+
+enum Output {
+    Log,
+    File(BufWriter<File>),
+    Database(Pool<MySql>),
+}
+
+impl Output {
+    async fn create(config: &Config) -> anyhow::Result<Self> {
+        match config.cheat_detection.output {
+            CheatReportOutput::Log => Ok(Self::Log),
+            CheatReportOutput::File => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.cheat_detection.file_path)
+                    .with_context(|| {
+                        format!(
+                            "open cheat report file {}",
+                            config.cheat_detection.file_path.display()
+                        )
+                    })?;
+
+                Ok(Self::File(BufWriter::new(file)))
+            }
+            CheatReportOutput::Database => {
+                let db_url = ::std::env::var("DATABASE_URL")
+                    .with_context(|| "Retrieve env var DATABASE_URL")?;
+
+                let pool = MySqlPoolOptions::new()
+                    .max_connections(1)
+                    .connect(&db_url)
+                    .await?;
+
+                Ok(Self::Database(pool))
+            }
+        }
+    }
+
+    async fn write(&mut self, report: &CheatReport) -> anyhow::Result<()> {
+        match self {
+            Self::Log => {
+                ::log::warn!("cheat report: {}", format_report(report));
+            }
+            Self::File(writer) => {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+                writeln!(writer, "{} {}", timestamp, format_report(report))?;
+
+                writer.flush()?;
+            }
+            Self::Database(pool) => {
+                sqlx::query("CALL aquatic_report_cheat_v1(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);")
+                    .bind(report.kind.as_str())
+                    .bind(report.user_token.as_str())
+                    .bind(hex::encode(report.info_hash.0))
+                    .bind(&report.peer_id.0[..])
+                    .bind(match report.source_addr.get().ip() {
+                        IpAddr::V4(ip) => Vec::from(ip.octets()),
+                        IpAddr::V6(ip) => Vec::from(ip.octets()),
+                    })
+                    .bind(report.source_addr.get().port())
+                    .bind(report.delta.uploaded)
+                    .bind(report.delta.downloaded)
+                    .bind(report.delta.left_before)
+                    .bind(report.delta.left_after)
+                    .bind(report.delta.seconds)
+                    .execute(&*pool)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn format_report(report: &CheatReport) -> String {
+    format!(
+        "kind={} user_token={} info_hash={} peer_id={} source_addr={} uploaded={} downloaded={} left_before={} left_after={} seconds={}",
+        report.kind.as_str(),
+        report.user_token,
+        hex::encode(report.info_hash.0),
+        hex::encode(report.peer_id.0),
+        report.source_addr.get(),
+        report.delta.uploaded,
+        report.delta.downloaded,
+        report.delta.left_before,
+        report.delta.left_after,
+        report.delta.seconds,
+    )
+}
@@ -1,2 +1,5 @@
+pub mod cheat_reporter;
+pub mod registry;
 pub mod socket;
+pub mod statistics;
 pub mod swarm;
@@ -0,0 +1,284 @@
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_common::PanicSentinel;
+use aquatic_http_protocol::common::InfoHash;
+use arc_swap::ArcSwap;
+use sqlx::mysql::MySqlPoolOptions;
+use sqlx::{MySql, Pool};
+
+use crate::common::{RegisteredTorrent, TorrentRegistry};
+use crate::config::Config;
+
+const SELECT_TORRENTS: &str = "
+    SELECT
+        info_hash,
+        registered,
+        CAST(announce_interval AS UNSIGNED) as announce_interval,
+        CAST(UNIX_TIMESTAMP(updated_at) AS UNSIGNED) as updated_at
+    FROM aquatic_torrents_v1
+";
+
+#[derive(Debug, sqlx::FromRow)]
+struct TorrentRow {
+    info_hash: String,
+    registered: bool,
+    announce_interval: Option<u64>,
+    updated_at: u64,
+}
+
+impl TorrentRow {
+    fn parse(&self) -> anyhow::Result<(InfoHash, Option<RegisteredTorrent>)> {
+        let mut info_hash = [0u8; 20];
+
+        hex::decode_to_slice(&self.info_hash, &mut info_hash)
+            .with_context(|| format!("invalid info hash: {}", self.info_hash))?;
+
+        let opt_torrent = if self.registered {
+            Some(RegisteredTorrent {
+                announce_interval: self.announce_interval.map(|interval| interval as usize),
+            })
+        } else {
+            None
+        };
+
+        Ok((InfoHash(info_hash), opt_torrent))
+    }
+}
+
+/// Load torrent registry from database, then spawn thread that keeps it up
+/// to date
+pub fn start_registry_worker(
+    sentinel: PanicSentinel,
+    config: Config,
+    torrent_registry: Arc<ArcSwap<TorrentRegistry>>,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let mut updater = runtime.block_on(RegistryUpdater::connect(config, torrent_registry))?;
+
+    runtime
+        .block_on(updater.full_update())
+        .with_context(|| "initial torrent registry update")?;
+
+    let handle = ::std::thread::Builder::new()
+        .name("registry".into())
+        .spawn(move || {
+            let _sentinel = sentinel;
+
+            runtime.block_on(updater.run())
+        })?;
+
+    Ok(handle)
+}
+
+struct RegistryUpdater {
+    config: Config,
+    pool: Pool<MySql>,
+    torrent_registry: Arc<ArcSwap<TorrentRegistry>>,
+    /// Highest updated_at value seen (seconds since Unix epoch)
+    watermark: u64,
+}
+
+impl RegistryUpdater {
+    async fn connect(
+        config: Config,
+        torrent_registry: Arc<ArcSwap<TorrentRegistry>>,
+    ) -> anyhow::Result<Self> {
+        let db_url =
+            ::std::env::var("DATABASE_URL").with_context(|| "Retrieve env var DATABASE_URL")?;
+
+        let pool = MySqlPoolOptions::new()
+            .max_connections(1)
+            .connect(&db_url)
+            .await?;
+
+        Ok(Self {
+            config,
+            pool,
+            torrent_registry,
+            watermark: 0,
+        })
+    }
+
+    async fn run(mut self) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            self.config.torrent_registry.update_interval,
+        ));
+        let full_update_interval =
+            Duration::from_secs(self.config.torrent_registry.full_update_interval);
+
+        let mut last_full_update = Instant::now();
+
+        // First tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let result = if last_full_update.elapsed() >= full_update_interval {
+                last_full_update = Instant::now();
+
+                self.full_update().await
+            } else {
+                self.incremental_update().await
+            };
+
+            if let Err(err) = result {
+                ::log::error!("Updating torrent registry failed: {:#}", err);
+            }
+        }
+    }
+
+    async fn full_update(&mut self) -> anyhow::Result<()> {
+        let rows = sqlx::query_as::<_, TorrentRow>(SELECT_TORRENTS)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut registry = TorrentRegistry::default();
+        let mut watermark = 0;
+
+        for row in rows {
+            watermark = watermark.max(row.updated_at);
+
+            match row.parse() {
+                Ok((info_hash, Some(torrent))) => {
+                    registry.insert(info_hash, torrent);
+                }
+                Ok((_, None)) => (),
+                Err(err) => {
+                    ::log::warn!("Skipping torrent registry row: {:#}", err);
+                }
+            }
+        }
+
+        ::log::info!("Torrent registry updated: {} torrents", registry.len());
+
+        self.torrent_registry.store(Arc::new(registry));
+        self.watermark = watermark;
+
+        Ok(())
+    }
+
+    /// Fetch torrents updated at or after watermark
+    ///
+    /// Since timestamps have a resolution of one second, rows with
+    /// updated_at equal to the watermark are fetched again, so that updates
+    /// made later during that second are not missed. The registry is only
+    /// replaced if any rows actually differ from it.
+    async fn incremental_update(&mut self) -> anyhow::Result<()> {
+        let query = format!("{} WHERE updated_at >= FROM_UNIXTIME(?)", SELECT_TORRENTS);
+
+        let rows = sqlx::query_as::<_, TorrentRow>(&query)
+            .bind(self.watermark)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let current_registry = self.torrent_registry.load_full();
+
+        let mut changes = Vec::new();
+        let mut watermark = self.watermark;
+
+        for row in rows {
+            watermark = watermark.max(row.updated_at);
+
+            let (info_hash, opt_torrent) = match row.parse() {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    ::log::warn!("Skipping torrent registry row: {:#}", err);
+
+                    continue;
+                }
+            };
+
+            let changed = match (current_registry.get(&info_hash), opt_torrent) {
+                (Some(current), Some(new)) => *current != new,
+                (None, None) => false,
+                _ => true,
+            };
+
+            if changed {
+                changes.push((info_hash, opt_torrent));
+            }
+        }
+
+        self.watermark = watermark;
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut registry = TorrentRegistry::clone(&current_registry);
+
+        for (info_hash, opt_torrent) in changes.iter() {
+            match opt_torrent {
+                Some(torrent) => registry.insert(*info_hash, *torrent),
+                None => registry.remove(info_hash),
+            }
+        }
+
+        ::log::info!(
+            "Torrent registry updated: {} changes, {} torrents",
+            changes.len(),
+            registry.len()
+        );
+
+        self.torrent_registry.store(Arc::new(registry));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(info_hash: &str, registered: bool, announce_interval: Option<u64>) -> TorrentRow {
+        TorrentRow {
+            info_hash: info_hash.into(),
+            registered,
+            announce_interval,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_torrent_row() {
+        let hex = "0102030405060708090a0b0c0d0e0f1011121314";
+        let info_hash = InfoHash([
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+        ]);
+
+        assert_eq!(
+            row(hex, true, Some(900)).parse().unwrap(),
+            (
+                info_hash,
+                Some(RegisteredTorrent {
+                    announce_interval: Some(900)
+                })
+            )
+        );
+        assert_eq!(
+            row(&hex.to_uppercase(), true, None).parse().unwrap(),
+            (
+                info_hash,
+                Some(RegisteredTorrent {
+                    announce_interval: None
+                })
+            )
+        );
+        assert_eq!(
+            row(hex, false, Some(900)).parse().unwrap(),
+            (info_hash, None)
+        );
+
+        assert!(row(&hex[..38], true, None).parse().is_err());
+        assert!(row(&format!("{}15", hex), true, None).parse().is_err());
+        assert!(row(&hex.replace('a', "x"), true, None).parse().is_err());
+        assert!(row("", true, None).parse().is_err());
+    }
+}
@@ -4,12 +4,65 @@ use aquatic_common::CanonicalSocketAddr;
 use aquatic_http_protocol::{request::AnnounceRequest, response::FailureResponse};
 use sqlx::{Executor, MySql, Pool};
 
+use crate::common::RegisteredTorrent;
+use crate::config::AnnounceProcedureVersion;
+
 #[derive(Debug)]
-pub struct ValidatedAnnounceRequest(AnnounceRequest);
+pub struct ValidatedAnnounceRequest {
+    request: AnnounceRequest,
+    controls: AnnounceControls,
+    user_token: String,
+}
+
+impl ValidatedAnnounceRequest {
+    pub fn into_parts(self) -> (AnnounceRequest, AnnounceControls, String) {
+        (self.request, self.controls, self.user_token)
+    }
+
+    /// Use torrent settings where stored procedure didn't set any
+    pub fn apply_torrent_defaults(&mut self, torrent: &RegisteredTorrent) {
+        self.controls.announce_interval = self
+            .controls
+            .announce_interval
+            .or(torrent.announce_interval);
+    }
+}
+
+/// Per-user response settings returned by stored procedure v2
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AnnounceControls {
+    /// Overrides configured peer announce interval
+    pub announce_interval: Option<usize>,
+    /// Caps number of peers returned (in addition to configured max_peers)
+    pub max_peers: Option<usize>,
+    pub visibility: PeerVisibility,
+}
+
+/// Whether a peer is included in peer lists sent to other peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerVisibility {
+    /// Visible to all peers
+    Normal,
+    /// Only visible to peers that are seeding
+    SeedersOnly,
+    /// Not visible to any peers
+    Hidden,
+}
 
-impl Into<AnnounceRequest> for ValidatedAnnounceRequest {
-    fn into(self) -> AnnounceRequest {
-        self.0
+impl Default for PeerVisibility {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+impl PeerVisibility {
+    fn from_procedure_value(value: Option<&str>) -> anyhow::Result<Self> {
+        match value {
+            None | Some("normal") => Ok(Self::Normal),
+            Some("seeders") => Ok(Self::SeedersOnly),
+            Some("hidden") => Ok(Self::Hidden),
+            Some(other) => Err(anyhow::anyhow!("invalid peer visibility: {}", other)),
+        }
     }
 }
 
@@ -18,19 +71,72 @@ struct AnnounceProcedureResults {
     announce_allowed: bool,
     failure_reason: Option<String>,
     warning_message: Option<String>,
+    // The following are only set by stored procedure v2
+    announce_interval: Option<u64>,
+    max_peers: Option<u64>,
+    peer_visibility: Option<String>,
+    freeleech: Option<bool>,
+    upload_multiplier: Option<f64>,
+    download_multiplier: Option<f64>,
+}
+
+impl AnnounceProcedureResults {
+    fn controls(&self) -> anyhow::Result<AnnounceControls> {
+        Ok(AnnounceControls {
+            announce_interval: self.announce_interval.map(|interval| interval as usize),
+            max_peers: self.max_peers.map(|max_peers| max_peers as usize),
+            visibility: PeerVisibility::from_procedure_value(self.peer_visibility.as_deref())?,
+        })
+    }
 }
 
 pub async fn validate_announce_request(
     pool: &Pool<MySql>,
+    procedure_version: AnnounceProcedureVersion,
     source_addr: CanonicalSocketAddr,
     user_agent: Option<String>,
     user_token: String,
     request: AnnounceRequest,
 ) -> Result<(ValidatedAnnounceRequest, Option<String>), FailureResponse> {
-    match call_announce_procedure(pool, source_addr, user_agent, user_token, &request).await {
-        Ok(results) => {
+    let results = call_announce_procedure(
+        pool,
+        procedure_version,
+        source_addr,
+        user_agent,
+        &user_token,
+        &request,
+    )
+    .await
+    .and_then(|results| {
+        let controls = results.controls()?;
+
+        Ok((results, controls))
+    });
+
+    match results {
+        Ok((results, controls)) => {
             if results.announce_allowed {
-                Ok((ValidatedAnnounceRequest(request), results.warning_message))
+                if results.freeleech.is_some()
+                    || results.upload_multiplier.is_some()
+                    || results.download_multiplier.is_some()
+                {
+                    ::log::debug!(
+                        "announce from {} for info hash {}: freeleech: {:?}, upload multiplier: {:?}, download multiplier: {:?}",
+                        source_addr.get(),
+                        hex::encode(request.info_hash.0),
+                        results.freeleech,
+                        results.upload_multiplier,
+                        results.download_multiplier,
+                    );
+                }
+
+                let request = ValidatedAnnounceRequest {
+                    request,
+                    controls,
+                    user_token,
+                };
+
+                Ok((request, results.warning_message))
             } else {
                 Err(FailureResponse::new(
                     results
@@ -49,55 +155,93 @@ pub async fn validate_announce_request(
 
 async fn call_announce_procedure(
     pool: &Pool<MySql>,
+    procedure_version: AnnounceProcedureVersion,
     source_addr: CanonicalSocketAddr,
     user_agent: Option<String>,
-    user_token: String, // FIXME: length
+    user_token: &str, // FIXME: length
     request: &AnnounceRequest,
 ) -> anyhow::Result<AnnounceProcedureResults> {
     let mut t = pool.begin().await?;
 
+    // Variables only used by v2 are set for v1 too, so that the same
+    // results query can be used for both
     t.execute(
         "
         SET
             @p_announce_allowed = false,
             @p_failure_reason = NULL,
-            @p_warning_message = NULL;
+            @p_warning_message = NULL,
+            @p_announce_interval = NULL,
+            @p_max_peers = NULL,
+            @p_peer_visibility = NULL,
+            @p_freeleech = NULL,
+            @p_upload_multiplier = NULL,
+            @p_download_multiplier = NULL;
         ",
     )
     .await?;
 
-    let q = sqlx::query(
-        "
-        CALL aquatic_announce_v1(
-            ?,
-            ?,
-            ?,
-            ?,
-            ?,
-            ?,
-            ?,
-            ?,
-            ?,
-            ?,
-            @p_announce_allowed,
-            @p_failure_reason,
-            @p_warning_message
-        );
-        ",
-    )
-    .bind(match source_addr.get().ip() {
-        IpAddr::V4(ip) => Vec::from(ip.octets()),
-        IpAddr::V6(ip) => Vec::from(ip.octets()),
-    })
-    .bind(source_addr.get().port())
-    .bind(user_agent)
-    .bind(user_token)
-    .bind(hex::encode(request.info_hash.0))
-    .bind(&request.peer_id.0[..])
-    .bind(request.event.as_str())
-    .bind(request.bytes_uploaded as u64)
-    .bind(request.bytes_downloaded as u64)
-    .bind(request.bytes_left as u64);
+    let call = match procedure_version {
+        AnnounceProcedureVersion::V1 => {
+            "
+            CALL aquatic_announce_v1(
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                @p_announce_allowed,
+                @p_failure_reason,
+                @p_warning_message
+            );
+            "
+        }
+        AnnounceProcedureVersion::V2 => {
+            "
+            CALL aquatic_announce_v2(
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                ?,
+                @p_announce_allowed,
+                @p_failure_reason,
+                @p_warning_message,
+                @p_announce_interval,
+                @p_max_peers,
+                @p_peer_visibility,
+                @p_freeleech,
+                @p_upload_multiplier,
+                @p_download_multiplier
+            );
+            "
+        }
+    };
+
+    let q = sqlx::query(call)
+        .bind(match source_addr.get().ip() {
+            IpAddr::V4(ip) => Vec::from(ip.octets()),
+            IpAddr::V6(ip) => Vec::from(ip.octets()),
+        })
+        .bind(source_addr.get().port())
+        .bind(user_agent)
+        .bind(user_token)
+        .bind(hex::encode(request.info_hash.0))
+        .bind(&request.peer_id.0[..])
+        .bind(request.event.as_str())
+        .bind(request.bytes_uploaded as u64)
+        .bind(request.bytes_downloaded as u64)
+        .bind(request.bytes_left as u64);
 
     t.execute(q).await?;
 
@@ -106,8 +250,13 @@ async fn call_announce_procedure(
         SELECT
             @p_announce_allowed as announce_allowed,
             @p_failure_reason as failure_reason,
-            @p_warning_message as warning_message;
-        
+            @p_warning_message as warning_message,
+            CAST(@p_announce_interval AS UNSIGNED) as announce_interval,
+            CAST(@p_max_peers AS UNSIGNED) as max_peers,
+            @p_peer_visibility as peer_visibility,
+            @p_freeleech as freeleech,
+            @p_upload_multiplier as upload_multiplier,
+            @p_download_multiplier as download_multiplier;
         ",
     )
     .fetch_one(&mut t)
@@ -14,7 +14,10 @@ use hyper::server::conn::AddrIncoming;
 use sqlx::mysql::MySqlPoolOptions;
 
 use self::tls::{TlsAcceptor, TlsStream};
-use crate::{common::ChannelRequestSender, config::Config};
+use crate::{
+    common::{ChannelRequestSender, State},
+    config::Config,
+};
 
 impl<'a> Connected<&'a tls::TlsStream> for SocketAddr {
     fn connect_info(target: &'a TlsStream) -> Self {
@@ -25,23 +28,31 @@ impl<'a> Connected<&'a tls::TlsStream> for SocketAddr {
 pub fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
+    state: State,
     tls_config: Arc<RustlsConfig>,
     request_sender: ChannelRequestSender,
     priv_dropper: PrivilegeDropper,
 ) -> anyhow::Result<()> {
-    let tcp_listener = create_tcp_listener(config.network.address, priv_dropper)?;
+    let tcp_listener = create_tcp_listener(&config, priv_dropper)?;
 
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
-    runtime.block_on(run_app(config, tls_config, tcp_listener, request_sender))?;
+    runtime.block_on(run_app(
+        config,
+        state,
+        tls_config,
+        tcp_listener,
+        request_sender,
+    ))?;
 
     Ok(())
 }
 
 async fn run_app(
     config: Config,
+    state: State,
     tls_config: Arc<RustlsConfig>,
     tcp_listener: TcpListener,
     request_sender: ChannelRequestSender,
@@ -62,6 +73,7 @@ async fn run_app(
     let app = Router::new()
         .route("/announce/:user_token/", get(routes::announce))
         .layer(Extension(Arc::new(config.clone())))
+        .layer(Extension(state))
         .layer(Extension(pool))
         .layer(Extension(Arc::new(request_sender)));
 
@@ -74,9 +86,11 @@ async fn run_app(
 }
 
 fn create_tcp_listener(
-    addr: SocketAddr,
+    config: &Config,
     priv_dropper: PrivilegeDropper,
 ) -> anyhow::Result<TcpListener> {
+    let addr = config.network.address;
+
     let domain = if addr.is_ipv4() {
         socket2::Domain::IPV4
     } else {
@@ -85,6 +99,10 @@ fn create_tcp_listener(
 
     let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
 
+    if config.network.only_ipv6 {
+        socket.set_only_v6(true).with_context(|| "set_only_v6")?;
+    }
+
     socket
         .set_reuse_port(true)
         .with_context(|| "set_reuse_port")?;
@@ -95,7 +113,7 @@ fn create_tcp_listener(
         .bind(&addr.into())
         .with_context(|| format!("bind to {}", addr))?;
     socket
-        .listen(1024)
+        .listen(config.network.tcp_backlog)
         .with_context(|| format!("listen on {}", addr))?;
 
     priv_dropper.after_socket_creation()?;
@@ -1,4 +1,4 @@
-use aquatic_common::CanonicalSocketAddr;
+use aquatic_common::{access_list::AccessListQuery, CanonicalSocketAddr};
 use axum::{
     extract::{ConnectInfo, Path, RawQuery},
     headers::UserAgent,
@@ -13,7 +13,7 @@ use aquatic_http_protocol::{
 };
 
 use crate::{
-    common::{ChannelRequestSender, RequestWorkerIndex},
+    common::{ChannelRequestSender, RequestWorkerIndex, State},
     config::Config,
 };
 
@@ -21,6 +21,7 @@ use super::db;
 
 pub async fn announce(
     Extension(config): Extension<Arc<Config>>,
+    Extension(state): Extension<State>,
     Extension(pool): Extension<MySqlPool>,
     Extension(request_sender): Extension<Arc<ChannelRequestSender>>,
     ConnectInfo(source_addr): ConnectInfo<SocketAddr>,
@@ -31,16 +32,50 @@ pub async fn announce(
     let query = query.ok_or_else(|| FailureResponse::new("Empty query string"))?;
 
     let request = AnnounceRequest::from_query_string(&query)
-        .map_err(|_| FailureResponse::new("Malformed request"))?;
+        .map_err(|err| FailureResponse::new(err.as_str()))?;
+
+    if !state
+        .access_list
+        .allows(config.access_list.mode, &request.info_hash.0)
+    {
+        return Err(FailureResponse::new("Info hash not allowed"));
+    }
+
+    if let Err(rejection) = state
+        .client_filter
+        .load()
+        .check(config.client_filter.mode, &request.peer_id.0)
+    {
+        return Err(FailureResponse::new(rejection.to_string()));
+    }
+
+    let opt_registered_torrent = if config.torrent_registry.active {
+        match state.torrent_registry.load().get(&request.info_hash) {
+            Some(torrent) => Some(*torrent),
+            None => return Err(FailureResponse::new("Unregistered torrent")),
+        }
+    } else {
+        None
+    };
 
     let swarm_worker_index = RequestWorkerIndex::from_info_hash(&config, request.info_hash);
     let opt_user_agent = opt_user_agent.map(|header| header.as_str().to_owned());
 
     let source_addr = CanonicalSocketAddr::new(source_addr);
 
-    let (validated_request, opt_warning_message) =
-        db::validate_announce_request(&pool, source_addr, opt_user_agent, user_token, request)
-            .await?;
+    let (mut validated_request, opt_warning_message) = db::validate_announce_request(
+        &pool,
+        config.announce_procedure_version,
+        source_addr,
+        opt_user_agent,
+        user_token,
+        request,
+    )
+    .await?;
+
+    if let Some(torrent) = opt_registered_torrent {
+        validated_request.apply_torrent_defaults(&torrent);
+    }
 
     let response_receiver = request_sender
         .send_to(swarm_worker_index, validated_request, source_addr)
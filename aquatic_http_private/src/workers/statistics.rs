@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use aquatic_common::PanicSentinel;
+
+use crate::common::State;
+use crate::config::Config;
+
+pub fn run_statistics_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+) -> anyhow::Result<()> {
+    loop {
+        ::std::thread::sleep(Duration::from_secs(config.statistics.interval));
+
+        if config.client_filter.mode.is_on() {
+            let client_filter = state.client_filter.load();
+
+            println!("Client filter matches:");
+
+            for (rule, num_matches) in client_filter.match_counts() {
+                println!("  {:<20} {:>10}", rule, num_matches);
+            }
+
+            println!(
+                "  {:<20} {:>10}",
+                "(no match)",
+                client_filter.num_unmatched()
+            );
+
+            println!();
+        }
+    }
+}
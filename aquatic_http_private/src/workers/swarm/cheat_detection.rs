@@ -0,0 +1,206 @@
+use std::time::Instant;
+
+use aquatic_http_protocol::common::AnnounceEvent;
+use aquatic_http_protocol::request::AnnounceRequest;
+
+use crate::common::{CheatKind, TransferDelta};
+use crate::config::CheatDetectionConfig;
+
+use super::common::{Ip, Peer, PeerStatus};
+
+/// Latest times at which torrent had at least one and at least two leechers
+///
+/// Used to tell if a peer could have uploaded to someone since its previous
+/// announce, even if the leechers have since finished or left. Peers removed
+/// when cleaning are counted as present until they are removed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeecherPresence {
+    one_or_more: Option<Instant>,
+    two_or_more: Option<Instant>,
+}
+
+impl LeecherPresence {
+    /// Record number of leechers present until now. Call before changing
+    /// number of leechers in torrent.
+    pub fn update(&mut self, num_leechers: usize, now: Instant) {
+        if num_leechers > 0 {
+            self.one_or_more = Some(now);
+        }
+        if num_leechers > 1 {
+            self.two_or_more = Some(now);
+        }
+    }
+
+    /// Was a leecher other than the peer present at some point since `since`?
+    ///
+    /// Peer status can only change when it announces, so it is known to have
+    /// been leeching (and counted among leechers) or not for the whole period.
+    pub fn other_leecher_present_since(&self, peer_is_leeching: bool, since: Instant) -> bool {
+        let opt_latest = if peer_is_leeching {
+            self.two_or_more
+        } else {
+            self.one_or_more
+        };
+
+        opt_latest.map_or(false, |latest| latest >= since)
+    }
+}
+
+/// Compare announce request to previous announce by same peer. Return change
+/// in transfer statistics and kinds of suspicious behaviour detected.
+///
+/// Returns None if the request starts a new session, since clients reset
+/// their counters in that case.
+pub fn detect_cheating<I: Ip>(
+    config: &CheatDetectionConfig,
+    previous: &Peer<I>,
+    request: &AnnounceRequest,
+    now: Instant,
+    leecher_presence: &LeecherPresence,
+) -> Option<(TransferDelta, Vec<CheatKind>)> {
+    if let AnnounceEvent::Started = request.event {
+        return None;
+    }
+
+    let delta = TransferDelta {
+        uploaded: (request.bytes_uploaded as u64).saturating_sub(previous.uploaded),
+        downloaded: (request.bytes_downloaded as u64).saturating_sub(previous.downloaded),
+        left_before: previous.left,
+        left_after: request.bytes_left as u64,
+        seconds: now.duration_since(previous.announced_at).as_secs(),
+    };
+
+    let mut kinds = Vec::new();
+
+    if config.max_upload_speed != 0
+        && delta.uploaded / delta.seconds.max(1) > config.max_upload_speed
+    {
+        kinds.push(CheatKind::UploadSpeed);
+    }
+    if config.report_upload_without_leechers
+        && delta.uploaded > 0
+        && !leecher_presence.other_leecher_present_since(
+            previous.status == PeerStatus::Leeching,
+            previous.announced_at,
+        )
+    {
+        kinds.push(CheatKind::UploadWithoutLeechers);
+    }
+    if config.report_left_increase && delta.left_after > delta.left_before {
+        kinds.push(CheatKind::LeftIncreased);
+    }
+
+    Some((delta, kinds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use aquatic_common::{ServerStartInstant, ValidUntil};
+    use aquatic_http_protocol::common::{InfoHash, PeerId};
+
+    use crate::workers::socket::db::PeerVisibility;
+
+    use super::*;
+
+    fn create_request(event: AnnounceEvent, uploaded: usize, left: usize) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash([0; 20]),
+            peer_id: PeerId([0; 20]),
+            port: 1,
+            bytes_uploaded: uploaded,
+            bytes_downloaded: 0,
+            bytes_left: left,
+            event,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+        }
+    }
+
+    #[test]
+    fn test_detect_cheating() {
+        let config = CheatDetectionConfig {
+            max_upload_speed: 1000,
+            ..Default::default()
+        };
+
+        let announced_at = Instant::now();
+        let now = announced_at + Duration::from_secs(10);
+
+        let previous = Peer {
+            ip_address: Ipv4Addr::LOCALHOST,
+            port: 1,
+            status: PeerStatus::Leeching,
+            visibility: PeerVisibility::Normal,
+            valid_until: ValidUntil::new(ServerStartInstant::new(), 0),
+            uploaded: 1000,
+            downloaded: 0,
+            left: 100,
+            announced_at,
+        };
+
+        let mut present = LeecherPresence::default();
+
+        // Other leechers than the previously leeching peer
+        present.update(2, now);
+
+        let absent = LeecherPresence::default();
+
+        let f = |request: AnnounceRequest, leecher_presence: &LeecherPresence| {
+            detect_cheating(&config, &previous, &request, now, leecher_presence)
+                .map(|(_, kinds)| kinds)
+        };
+
+        assert_eq!(
+            f(create_request(AnnounceEvent::Empty, 1000, 100), &present),
+            Some(vec![])
+        );
+        assert_eq!(
+            f(create_request(AnnounceEvent::Empty, 11_000, 100), &present),
+            Some(vec![])
+        );
+        assert_eq!(
+            f(create_request(AnnounceEvent::Empty, 11_010, 100), &present),
+            Some(vec![CheatKind::UploadSpeed])
+        );
+        assert_eq!(
+            f(create_request(AnnounceEvent::Empty, 2000, 100), &absent),
+            Some(vec![CheatKind::UploadWithoutLeechers])
+        );
+        assert_eq!(
+            f(create_request(AnnounceEvent::Stopped, 1000, 101), &absent),
+            Some(vec![CheatKind::LeftIncreased])
+        );
+        assert_eq!(
+            f(create_request(AnnounceEvent::Started, 0, 500), &absent),
+            None
+        );
+    }
+
+    #[test]
+    fn test_leecher_presence() {
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(10);
+        let t2 = t0 + Duration::from_secs(20);
+
+        let mut presence = LeecherPresence::default();
+
+        assert!(!presence.other_leecher_present_since(false, t0));
+
+        // Leecher present until t1, then finished
+        presence.update(1, t1);
+        presence.update(0, t2);
+
+        assert!(presence.other_leecher_present_since(false, t0));
+        assert!(!presence.other_leecher_present_since(false, t2));
+        // The only leecher might have been the peer itself
+        assert!(!presence.other_leecher_present_since(true, t0));
+
+        presence.update(2, t2);
+
+        assert!(presence.other_leecher_present_since(true, t0));
+    }
+}
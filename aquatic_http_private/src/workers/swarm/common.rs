@@ -1,11 +1,19 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Instant;
 
+use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
 use aquatic_common::{
     AmortizedIndexMap, IndexMap, SecondsSinceServerStart, ServerStartInstant, ValidUntil,
 };
 use aquatic_http_protocol::common::{AnnounceEvent, InfoHash, PeerId};
 use aquatic_http_protocol::response::ResponsePeer;
 
+use crate::config::Config;
+use crate::workers::socket::db::PeerVisibility;
+
+use super::cheat_detection::LeecherPresence;
+
 pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash {}
 
 impl Ip for Ipv4Addr {}
@@ -39,10 +47,26 @@ pub struct Peer<I: Ip> {
     pub ip_address: I,
     pub port: u16,
     pub status: PeerStatus,
+    pub visibility: PeerVisibility,
     pub valid_until: ValidUntil,
+    /// Transfer statistics from latest announce, used for cheat detection
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub announced_at: Instant,
 }
 
 impl<I: Ip> Peer<I> {
+    /// Should peer be included in response to peer with given status?
+    #[inline]
+    pub fn is_visible_to(&self, status: PeerStatus) -> bool {
+        match self.visibility {
+            PeerVisibility::Normal => true,
+            PeerVisibility::SeedersOnly => status == PeerStatus::Seeding,
+            PeerVisibility::Hidden => false,
+        }
+    }
+
     pub fn to_response_peer(&self) -> ResponsePeer<I> {
         ResponsePeer {
             ip_address: self.ip_address,
@@ -63,6 +87,9 @@ pub struct TorrentData<I: Ip> {
     pub peers: PeerMap<I>,
     pub num_seeders: usize,
     pub num_leechers: usize,
+    /// Number of peers with visibility other than PeerVisibility::Normal
+    pub num_restricted_visibility: usize,
+    pub leecher_presence: LeecherPresence,
 }
 
 impl<I: Ip> Default for TorrentData<I> {
@@ -72,6 +99,8 @@ impl<I: Ip> Default for TorrentData<I> {
             peers: Default::default(),
             num_seeders: 0,
             num_leechers: 0,
+            num_restricted_visibility: 0,
+            leecher_presence: Default::default(),
         }
     }
 }
@@ -85,17 +114,43 @@ pub struct TorrentMaps {
 }
 
 impl TorrentMaps {
-    pub fn clean(&mut self, server_start_instant: ServerStartInstant) {
+    pub fn clean(
+        &mut self,
+        config: &Config,
+        access_list: &Arc<AccessListArcSwap>,
+        server_start_instant: ServerStartInstant,
+    ) {
+        let mut access_list_cache = create_access_list_cache(access_list);
+
         let now = server_start_instant.seconds_elapsed();
+        let instant = Instant::now();
 
-        Self::clean_torrent_map(&mut self.ipv4, now);
-        Self::clean_torrent_map(&mut self.ipv6, now);
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now, instant);
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now, instant);
     }
 
-    fn clean_torrent_map<I: Ip>(torrent_map: &mut TorrentMap<I>, now: SecondsSinceServerStart) {
-        torrent_map.retain(|_, torrent_data| {
+    fn clean_torrent_map<I: Ip>(
+        config: &Config,
+        access_list_cache: &mut AccessListCache,
+        torrent_map: &mut TorrentMap<I>,
+        now: SecondsSinceServerStart,
+        instant: Instant,
+    ) {
+        torrent_map.retain(|info_hash, torrent_data| {
+            if !access_list_cache
+                .load()
+                .allows(config.access_list.mode, &info_hash.0)
+            {
+                return false;
+            }
+
+            torrent_data
+                .leecher_presence
+                .update(torrent_data.num_leechers, instant);
+
             let num_seeders = &mut torrent_data.num_seeders;
             let num_leechers = &mut torrent_data.num_leechers;
+            let num_restricted_visibility = &mut torrent_data.num_restricted_visibility;
 
             torrent_data.peers.retain(|_, peer| {
                 if peer.valid_until.valid(now) {
@@ -111,6 +166,10 @@ impl TorrentMaps {
                         _ => (),
                     };
 
+                    if peer.visibility != PeerVisibility::Normal {
+                        *num_restricted_visibility -= 1;
+                    }
+
                     false
                 }
             });
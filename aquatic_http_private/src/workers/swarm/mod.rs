@@ -1,53 +1,72 @@
+mod cheat_detection;
 mod common;
 
 use std::cell::RefCell;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::rc::Rc;
+use std::time::Instant;
 
 use aquatic_http_protocol::request::AnnounceRequest;
 use rand::prelude::SmallRng;
 use rand::SeedableRng;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::LocalSet;
 use tokio::time;
 
 use aquatic_common::{
-    extract_response_peers, CanonicalSocketAddr, PanicSentinel, ServerStartInstant, ValidUntil,
+    extract_filtered_response_peers, extract_response_peers, CanonicalSocketAddr, PanicSentinel,
+    ServerStartInstant, ValidUntil,
 };
 use aquatic_http_protocol::response::{
     AnnounceResponse, Response, ResponsePeer, ResponsePeerListV4, ResponsePeerListV6,
 };
 
-use crate::common::ChannelAnnounceRequest;
+use crate::common::{ChannelAnnounceRequest, CheatKind, CheatReport, State, TransferDelta};
 use crate::config::Config;
+use crate::workers::socket::db::{AnnounceControls, PeerVisibility};
 
+use cheat_detection::detect_cheating;
 use common::*;
 
 pub fn run_swarm_worker(
     _sentinel: PanicSentinel,
     config: Config,
+    state: State,
     request_receiver: Receiver<ChannelAnnounceRequest>,
+    opt_cheat_report_sender: Option<Sender<CheatReport>>,
     server_start_instant: ServerStartInstant,
 ) -> anyhow::Result<()> {
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
-    runtime.block_on(run_inner(config, request_receiver, server_start_instant))?;
+    // Cleaning task is spawned with spawn_local, so it needs a LocalSet
+    let local_set = LocalSet::new();
+
+    runtime.block_on(local_set.run_until(run_inner(
+        config,
+        state,
+        request_receiver,
+        opt_cheat_report_sender,
+        server_start_instant,
+    )))?;
 
     Ok(())
 }
 
 async fn run_inner(
     config: Config,
+    state: State,
     mut request_receiver: Receiver<ChannelAnnounceRequest>,
+    opt_cheat_report_sender: Option<Sender<CheatReport>>,
     server_start_instant: ServerStartInstant,
 ) -> anyhow::Result<()> {
     let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
     let mut rng = SmallRng::from_entropy();
 
-    LocalSet::new().spawn_local(periodically_clean_torrents(
+    tokio::task::spawn_local(periodically_clean_torrents(
         config.clone(),
+        state,
         torrents.clone(),
         server_start_instant,
     ));
@@ -60,21 +79,63 @@ async fn run_inner(
 
         let valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
 
+        let (announce_request, controls, user_token) = request.request.into_parts();
+
+        let reporter = CheatReporter {
+            opt_sender: opt_cheat_report_sender.as_ref(),
+            user_token,
+            source_addr: request.source_addr,
+        };
+
         let response = handle_announce_request(
             &config,
             &mut rng,
             &mut torrents.borrow_mut(),
             valid_until,
             request.source_addr,
-            request.request.into(),
+            announce_request,
+            controls,
+            &reporter,
         );
 
         let _ = request.response_sender.send(Response::Announce(response));
     }
 }
 
+/// Sends cheat reports for a single announce request
+pub struct CheatReporter<'a> {
+    opt_sender: Option<&'a Sender<CheatReport>>,
+    user_token: String,
+    source_addr: CanonicalSocketAddr,
+}
+
+impl<'a> CheatReporter<'a> {
+    fn is_active(&self) -> bool {
+        self.opt_sender.is_some()
+    }
+
+    fn report(&self, request: &AnnounceRequest, kind: CheatKind, delta: TransferDelta) {
+        if let Some(sender) = self.opt_sender {
+            let report = CheatReport {
+                kind,
+                user_token: self.user_token.clone(),
+                info_hash: request.info_hash,
+                peer_id: request.peer_id,
+                source_addr: self.source_addr,
+                delta,
+            };
+
+            // Don't block swarm worker if reporter can't keep up
+            if let Err(err) = sender.try_send(report) {
+                ::log::warn!("couldn't send cheat report: {:#}", err);
+            }
+        }
+    }
+}
+
 async fn periodically_clean_torrents(
     config: Config,
+    state: State,
     torrents: Rc<RefCell<TorrentMaps>>,
     server_start_instant: ServerStartInstant,
 ) {
@@ -85,7 +146,9 @@ async fn periodically_clean_torrents(
     loop {
         interval.tick().await;
 
-        torrents.borrow_mut().clean(server_start_instant);
+        torrents
+            .borrow_mut()
+            .clean(&config, &state.access_list, server_start_instant);
     }
 }
 
@@ -96,7 +159,13 @@ fn handle_announce_request(
     valid_until: ValidUntil,
     source_addr: CanonicalSocketAddr,
     request: AnnounceRequest,
+    controls: AnnounceControls,
+    reporter: &CheatReporter,
 ) -> AnnounceResponse {
+    let announce_interval = controls
+        .announce_interval
+        .unwrap_or(config.protocol.peer_announce_interval);
+
     match source_addr.get().ip() {
         IpAddr::V4(source_ip) => {
             let torrent_data: &mut TorrentData<Ipv4Addr> =
@@ -108,15 +177,20 @@ fn handle_announce_request(
                 torrent_data,
                 source_ip,
                 request,
+                controls,
                 valid_until,
+                reporter,
             );
 
             let response = AnnounceResponse {
                 complete: seeders,
                 incomplete: leechers,
-                announce_interval: config.protocol.peer_announce_interval,
+                announce_interval,
+                min_announce_interval: None,
                 peers: ResponsePeerListV4(response_peers),
                 peers6: ResponsePeerListV6(vec![]),
+                tracker_id: None,
+                external_ip: None,
                 warning_message: None,
             };
 
@@ -132,15 +206,20 @@ fn handle_announce_request(
                 torrent_data,
                 source_ip,
                 request,
+                controls,
                 valid_until,
+                reporter,
             );
 
             let response = AnnounceResponse {
                 complete: seeders,
                 incomplete: leechers,
-                announce_interval: config.protocol.peer_announce_interval,
+                announce_interval,
+                min_announce_interval: None,
                 peers: ResponsePeerListV4(vec![]),
                 peers6: ResponsePeerListV6(response_peers),
+                tracker_id: None,
+                external_ip: None,
                 warning_message: None,
             };
 
@@ -156,18 +235,27 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
     torrent_data: &mut TorrentData<I>,
     source_ip: I,
     request: AnnounceRequest,
+    controls: AnnounceControls,
     valid_until: ValidUntil,
+    reporter: &CheatReporter,
 ) -> (usize, usize, Vec<ResponsePeer<I>>) {
     // Insert/update/remove peer who sent this request
 
     let peer_status =
         PeerStatus::from_event_and_bytes_left(request.event, Some(request.bytes_left));
 
+    let now = Instant::now();
+
     let peer = Peer {
         ip_address: source_ip,
         port: request.port,
         status: peer_status,
+        visibility: controls.visibility,
         valid_until,
+        uploaded: request.bytes_uploaded as u64,
+        downloaded: request.bytes_downloaded as u64,
+        left: request.bytes_left as u64,
+        announced_at: now,
     };
 
     let peer_map_key = PeerMapKey {
@@ -175,6 +263,26 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         ip_address: source_ip,
     };
 
+    torrent_data
+        .leecher_presence
+        .update(torrent_data.num_leechers, now);
+
+    if reporter.is_active() {
+        if let Some(previous) = torrent_data.peers.get(&peer_map_key) {
+            if let Some((delta, kinds)) = detect_cheating(
+                &config.cheat_detection,
+                previous,
+                &request,
+                now,
+                &torrent_data.leecher_presence,
+            ) {
+                for kind in kinds {
+                    reporter.report(&request, kind, delta);
+                }
+            }
+        }
+    }
+
     let opt_removed_peer = match peer_status {
         PeerStatus::Leeching => {
             torrent_data.num_leechers += 1;
@@ -199,18 +307,45 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         _ => {}
     }
 
+    // Keep track of peers with restricted visibility so that response peers
+    // only need to be filtered when there are any
+    if peer_status != PeerStatus::Stopped && controls.visibility != PeerVisibility::Normal {
+        torrent_data.num_restricted_visibility += 1;
+    }
+    if let Some(PeerVisibility::SeedersOnly | PeerVisibility::Hidden) =
+        opt_removed_peer.map(|peer| peer.visibility)
+    {
+        torrent_data.num_restricted_visibility -= 1;
+    }
+
+    let max_peers = controls
+        .max_peers
+        .map(|max_peers| max_peers.min(config.protocol.max_peers))
+        .unwrap_or(config.protocol.max_peers);
+
     let max_num_peers_to_take = match request.numwant {
-        Some(0) | None => config.protocol.max_peers,
-        Some(numwant) => numwant.min(config.protocol.max_peers),
+        Some(0) | None => max_peers,
+        Some(numwant) => numwant.min(max_peers),
     };
 
-    let response_peers: Vec<ResponsePeer<I>> = extract_response_peers(
-        rng,
-        &torrent_data.peers,
-        max_num_peers_to_take,
-        peer_map_key,
-        Peer::to_response_peer,
-    );
+    let response_peers: Vec<ResponsePeer<I>> = if torrent_data.num_restricted_visibility == 0 {
+        extract_response_peers(
+            rng,
+            &torrent_data.peers,
+            max_num_peers_to_take,
+            peer_map_key,
+            Peer::to_response_peer,
+        )
+    } else {
+        extract_filtered_response_peers(
+            rng,
+            &torrent_data.peers,
+            max_num_peers_to_take,
+            peer_map_key,
+            |peer| peer.is_visible_to(peer_status),
+            Peer::to_response_peer,
+        )
+    };
 
     (
         torrent_data.num_seeders,
@@ -218,3 +353,210 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         response_peers,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use aquatic_http_protocol::common::{AnnounceEvent, InfoHash, PeerId};
+
+    use super::*;
+
+    fn announce_request(peer_index: u8, bytes_left: usize) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash([0; 20]),
+            peer_id: PeerId([peer_index; 20]),
+            port: 1,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left,
+            event: AnnounceEvent::Started,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+        }
+    }
+
+    fn source_addr(peer_index: u8) -> CanonicalSocketAddr {
+        CanonicalSocketAddr::new(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, peer_index)),
+            1,
+        ))
+    }
+
+    fn announce(
+        config: &Config,
+        torrent_maps: &mut TorrentMaps,
+        peer_index: u8,
+        bytes_left: usize,
+        controls: AnnounceControls,
+    ) -> AnnounceResponse {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 60);
+
+        let reporter = CheatReporter {
+            opt_sender: None,
+            user_token: String::new(),
+            source_addr: source_addr(peer_index),
+        };
+
+        handle_announce_request(
+            config,
+            &mut rng,
+            torrent_maps,
+            valid_until,
+            source_addr(peer_index),
+            announce_request(peer_index, bytes_left),
+            controls,
+            &reporter,
+        )
+    }
+
+    #[test]
+    fn test_peer_visibility() {
+        let mut config = Config::default();
+
+        config.protocol.max_peers = 10;
+
+        let mut torrent_maps = TorrentMaps::default();
+
+        // Fill first half of peer map with hidden peers so that they would
+        // take up all places in responses if filtered after selection
+        for i in 1..=20 {
+            let controls = AnnounceControls {
+                visibility: PeerVisibility::Hidden,
+                ..Default::default()
+            };
+
+            announce(&config, &mut torrent_maps, i, 1, controls);
+        }
+        for i in 21..=25 {
+            let controls = AnnounceControls {
+                visibility: PeerVisibility::SeedersOnly,
+                ..Default::default()
+            };
+
+            announce(&config, &mut torrent_maps, i, 1, controls);
+        }
+        for i in 26..=40 {
+            announce(&config, &mut torrent_maps, i, 1, Default::default());
+        }
+
+        let ip = |peer_index: u8| Ipv4Addr::new(10, 0, 0, peer_index);
+
+        let leecher_response = announce(&config, &mut torrent_maps, 41, 1, Default::default());
+
+        assert_eq!(leecher_response.peers.0.len(), 10);
+        assert!(leecher_response
+            .peers
+            .0
+            .iter()
+            .all(|peer| (ip(26)..=ip(40)).contains(&peer.ip_address)));
+
+        let seeder_response = announce(&config, &mut torrent_maps, 42, 0, Default::default());
+
+        assert_eq!(seeder_response.peers.0.len(), 10);
+        assert!(seeder_response
+            .peers
+            .0
+            .iter()
+            .all(|peer| (ip(21)..=ip(41)).contains(&peer.ip_address)));
+
+        assert_eq!(seeder_response.complete, 1);
+        assert_eq!(seeder_response.incomplete, 41);
+    }
+
+    #[test]
+    fn test_announce_controls() {
+        let config = Config::default();
+        let mut torrent_maps = TorrentMaps::default();
+
+        for i in 1..=20 {
+            announce(&config, &mut torrent_maps, i, 1, Default::default());
+        }
+
+        let response = announce(&config, &mut torrent_maps, 21, 1, Default::default());
+
+        assert_eq!(
+            response.announce_interval,
+            config.protocol.peer_announce_interval
+        );
+        assert_eq!(response.peers.0.len(), 20);
+
+        let controls = AnnounceControls {
+            announce_interval: Some(1800),
+            max_peers: Some(5),
+            visibility: PeerVisibility::Normal,
+        };
+
+        let response = announce(&config, &mut torrent_maps, 22, 1, controls);
+
+        assert_eq!(response.announce_interval, 1800);
+        assert_eq!(response.peers.0.len(), 5);
+
+        // Configured max_peers is still respected
+        let controls = AnnounceControls {
+            max_peers: Some(config.protocol.max_peers + 10),
+            ..Default::default()
+        };
+
+        for i in 23..=100 {
+            announce(&config, &mut torrent_maps, i, 1, Default::default());
+        }
+
+        let response = announce(&config, &mut torrent_maps, 101, 1, controls);
+
+        assert_eq!(response.peers.0.len(), config.protocol.max_peers);
+    }
+
+    #[test]
+    fn test_upload_to_finished_leecher() {
+        let config = Config::default();
+        let mut torrent_maps = TorrentMaps::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 60);
+
+        let (report_sender, mut report_receiver) = tokio::sync::mpsc::channel(8);
+
+        let mut announce = |peer_index: u8, event, uploaded, bytes_left| {
+            let reporter = CheatReporter {
+                opt_sender: Some(&report_sender),
+                user_token: String::new(),
+                source_addr: source_addr(peer_index),
+            };
+
+            let request = AnnounceRequest {
+                event,
+                bytes_uploaded: uploaded,
+                ..announce_request(peer_index, bytes_left)
+            };
+
+            handle_announce_request(
+                &config,
+                &mut rng,
+                &mut torrent_maps,
+                valid_until,
+                source_addr(peer_index),
+                request,
+                Default::default(),
+                &reporter,
+            );
+        };
+
+        // Seeder uploads to leecher, which finishes before seeder announces
+        announce(1, AnnounceEvent::Started, 0, 0);
+        announce(2, AnnounceEvent::Started, 0, 1000);
+        announce(2, AnnounceEvent::Completed, 0, 0);
+        announce(1, AnnounceEvent::Empty, 1000, 0);
+
+        assert!(report_receiver.try_recv().is_err());
+
+        // No leechers at all since previous announce
+        announce(1, AnnounceEvent::Empty, 2000, 0);
+
+        let report = report_receiver.try_recv().unwrap();
+
+        assert_eq!(report.kind, CheatKind::UploadWithoutLeechers);
+        assert_eq!(report.delta.uploaded, 1000);
+    }
+}
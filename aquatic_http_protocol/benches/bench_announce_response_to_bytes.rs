@@ -17,10 +17,13 @@ pub fn bench(c: &mut Criterion) {
 
     let announce_response = AnnounceResponse {
         announce_interval: 120,
+        min_announce_interval: None,
         complete: 100,
         incomplete: 500,
         peers: ResponsePeerListV4(peers),
         peers6: ResponsePeerListV6(Vec::new()),
+        tracker_id: None,
+        external_ip: None,
         warning_message: None,
     };
 
@@ -29,6 +29,9 @@ pub enum AnnounceEvent {
     Started,
     Stopped,
     Completed,
+    /// Peer is a partial seed: it has stopped downloading without having
+    /// the complete torrent (BEP 21)
+    Paused,
     Empty,
 }
 
@@ -46,6 +49,7 @@ impl FromStr for AnnounceEvent {
             "started" => Ok(Self::Started),
             "stopped" => Ok(Self::Stopped),
             "completed" => Ok(Self::Completed),
+            "paused" => Ok(Self::Paused),
             "empty" => Ok(Self::Empty),
             value => Err(format!("Unknown value: {}", value)),
         }
@@ -58,6 +62,7 @@ impl AnnounceEvent {
             Self::Started => Some("started"),
             Self::Stopped => Some("stopped"),
             Self::Completed => Some("completed"),
+            Self::Paused => Some("paused"),
             Self::Empty => None,
         }
     }
@@ -92,11 +97,13 @@ impl quickcheck::Arbitrary for PeerId {
 #[cfg(test)]
 impl quickcheck::Arbitrary for AnnounceEvent {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-        match (bool::arbitrary(g), bool::arbitrary(g)) {
-            (false, false) => Self::Started,
-            (true, false) => Self::Started,
-            (false, true) => Self::Completed,
-            (true, true) => Self::Empty,
-        }
+        *g.choose(&[
+            Self::Started,
+            Self::Stopped,
+            Self::Completed,
+            Self::Paused,
+            Self::Empty,
+        ])
+        .unwrap()
     }
 }
@@ -1,6 +1,5 @@
 use std::io::Write;
 
-use anyhow::Context;
 use smartstring::{LazyCompact, SmartString};
 
 use super::common::*;
@@ -18,13 +17,24 @@ pub struct AnnounceRequest {
     /// Number of response peers wanted
     pub numwant: Option<usize>,
     pub key: Option<SmartString<LazyCompact>>,
+    /// Tracker id sent in previous announce response
+    pub tracker_id: Option<SmartString<LazyCompact>>,
 }
 
 impl AnnounceRequest {
     fn write<W: Write>(&self, output: &mut W, url_suffix: &[u8]) -> ::std::io::Result<()> {
         output.write_all(b"GET /announce")?;
         output.write_all(url_suffix)?;
-        output.write_all(b"?info_hash=")?;
+        output.write_all(b"?")?;
+        self.write_query_string(output)?;
+        output.write_all(b" HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+        Ok(())
+    }
+
+    /// Write URL query string (without leading question mark)
+    pub fn write_query_string<W: Write>(&self, output: &mut W) -> ::std::io::Result<()> {
+        output.write_all(b"info_hash=")?;
         urlencode_20_bytes(self.info_hash.0, output)?;
 
         output.write_all(b"&peer_id=")?;
@@ -46,6 +56,7 @@ impl AnnounceRequest {
             AnnounceEvent::Started => output.write_all(b"&event=started")?,
             AnnounceEvent::Stopped => output.write_all(b"&event=stopped")?,
             AnnounceEvent::Completed => output.write_all(b"&event=completed")?,
+            AnnounceEvent::Paused => output.write_all(b"&event=paused")?,
             AnnounceEvent::Empty => (),
         };
 
@@ -59,15 +70,18 @@ impl AnnounceRequest {
             output.write_all(::urlencoding::encode(key.as_str()).as_bytes())?;
         }
 
+        if let Some(ref tracker_id) = self.tracker_id {
+            output.write_all(b"&trackerid=")?;
+            output.write_all(::urlencoding::encode(tracker_id.as_str()).as_bytes())?;
+        }
+
         // Always ask for compact responses to ease load testing of non-aquatic trackers
         output.write_all(b"&compact=1")?;
 
-        output.write_all(b" HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
-
         Ok(())
     }
 
-    pub fn from_query_string(query_string: &str) -> anyhow::Result<Self> {
+    pub fn from_query_string(query_string: &str) -> Result<Self, RequestParseError> {
         // -- Parse key-value pairs
 
         let mut opt_info_hash = None;
@@ -79,90 +93,106 @@ impl AnnounceRequest {
         let mut event = AnnounceEvent::default();
         let mut opt_numwant = None;
         let mut opt_key = None;
+        let mut opt_tracker_id = None;
 
-        let query_string_bytes = query_string.as_bytes();
-
-        let mut ampersand_iter = ::memchr::memchr_iter(b'&', query_string_bytes);
-        let mut position = 0usize;
-
-        for equal_sign_index in ::memchr::memchr_iter(b'=', query_string_bytes) {
-            let segment_end = ampersand_iter.next().unwrap_or_else(|| query_string.len());
-
-            let key = query_string
-                .get(position..equal_sign_index)
-                .with_context(|| format!("no key at {}..{}", position, equal_sign_index))?;
-            let value = query_string
-                .get(equal_sign_index + 1..segment_end)
-                .with_context(|| {
-                    format!("no value at {}..{}", equal_sign_index + 1, segment_end)
-                })?;
+        for (key, value) in QueryPairs::new(query_string) {
+            let (key, value) = (key?, value?);
 
             match key {
                 "info_hash" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value = urldecode_20_bytes(value)
+                        .map_err(|_| RequestParseError::InvalidInfoHash)?;
 
                     opt_info_hash = Some(InfoHash(value));
                 }
                 "peer_id" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value =
+                        urldecode_20_bytes(value).map_err(|_| RequestParseError::InvalidPeerId)?;
 
                     opt_peer_id = Some(PeerId(value));
                 }
                 "port" => {
-                    opt_port = Some(value.parse::<u16>().with_context(|| "parse port")?);
+                    opt_port = Some(
+                        value
+                            .parse::<u16>()
+                            .map_err(|_| RequestParseError::InvalidPort)?,
+                    );
                 }
                 "left" => {
-                    opt_bytes_left = Some(value.parse::<usize>().with_context(|| "parse left")?);
+                    opt_bytes_left = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|_| RequestParseError::InvalidLeft)?,
+                    );
                 }
                 "uploaded" => {
-                    opt_bytes_uploaded =
-                        Some(value.parse::<usize>().with_context(|| "parse uploaded")?);
+                    opt_bytes_uploaded = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|_| RequestParseError::InvalidUploaded)?,
+                    );
                 }
                 "downloaded" => {
-                    opt_bytes_downloaded =
-                        Some(value.parse::<usize>().with_context(|| "parse downloaded")?);
+                    opt_bytes_downloaded = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|_| RequestParseError::InvalidDownloaded)?,
+                    );
                 }
                 "event" => {
                     event = value
                         .parse::<AnnounceEvent>()
-                        .map_err(|err| anyhow::anyhow!("invalid event: {}", err))?;
+                        .map_err(|_| RequestParseError::InvalidEvent)?;
                 }
                 "compact" => {
                     if value != "1" {
-                        return Err(anyhow::anyhow!("compact set, but not to 1"));
+                        return Err(RequestParseError::CompactNotSupported);
                     }
                 }
                 "numwant" => {
-                    opt_numwant = Some(value.parse::<usize>().with_context(|| "parse numwant")?);
+                    opt_numwant = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|_| RequestParseError::InvalidNumwant)?,
+                    );
                 }
                 "key" => {
                     if value.len() > 100 {
-                        return Err(anyhow::anyhow!("'key' is too long"));
+                        return Err(RequestParseError::InvalidKey);
+                    }
+                    opt_key = Some(
+                        ::urlencoding::decode(value)
+                            .map_err(|_| RequestParseError::InvalidKey)?
+                            .into(),
+                    );
+                }
+                "trackerid" => {
+                    if value.len() > 100 {
+                        return Err(RequestParseError::InvalidTrackerId);
                     }
-                    opt_key = Some(::urlencoding::decode(value)?.into());
+                    opt_tracker_id = Some(
+                        ::urlencoding::decode(value)
+                            .map_err(|_| RequestParseError::InvalidTrackerId)?
+                            .into(),
+                    );
                 }
                 k => {
                     ::log::debug!("ignored unrecognized key: {}", k)
                 }
             }
-
-            if segment_end == query_string.len() {
-                break;
-            } else {
-                position = segment_end + 1;
-            }
         }
 
         Ok(AnnounceRequest {
-            info_hash: opt_info_hash.with_context(|| "no info_hash")?,
-            peer_id: opt_peer_id.with_context(|| "no peer_id")?,
-            port: opt_port.with_context(|| "no port")?,
-            bytes_uploaded: opt_bytes_uploaded.with_context(|| "no uploaded")?,
-            bytes_downloaded: opt_bytes_downloaded.with_context(|| "no downloaded")?,
-            bytes_left: opt_bytes_left.with_context(|| "no left")?,
+            info_hash: opt_info_hash.ok_or(RequestParseError::MissingInfoHash)?,
+            peer_id: opt_peer_id.ok_or(RequestParseError::MissingPeerId)?,
+            port: opt_port.ok_or(RequestParseError::MissingPort)?,
+            bytes_uploaded: opt_bytes_uploaded.ok_or(RequestParseError::MissingUploaded)?,
+            bytes_downloaded: opt_bytes_downloaded.ok_or(RequestParseError::MissingDownloaded)?,
+            bytes_left: opt_bytes_left.ok_or(RequestParseError::MissingLeft)?,
             event,
             numwant: opt_numwant,
             key: opt_key,
+            tracker_id: opt_tracker_id,
         })
     }
 }
@@ -177,7 +207,14 @@ impl ScrapeRequest {
         output.write_all(b"GET /scrape")?;
         output.write_all(url_suffix)?;
         output.write_all(b"?")?;
+        self.write_query_string(output)?;
+        output.write_all(b" HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+        Ok(())
+    }
 
+    /// Write URL query string (without leading question mark)
+    pub fn write_query_string<W: Write>(&self, output: &mut W) -> ::std::io::Result<()> {
         let mut first = true;
 
         for info_hash in self.info_hashes.iter() {
@@ -191,36 +228,21 @@ impl ScrapeRequest {
             first = false;
         }
 
-        output.write_all(b" HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
-
         Ok(())
     }
 
-    pub fn from_query_string(query_string: &str) -> anyhow::Result<Self> {
+    pub fn from_query_string(query_string: &str) -> Result<Self, RequestParseError> {
         // -- Parse key-value pairs
 
         let mut info_hashes = Vec::new();
 
-        let query_string_bytes = query_string.as_bytes();
-
-        let mut ampersand_iter = ::memchr::memchr_iter(b'&', query_string_bytes);
-        let mut position = 0usize;
-
-        for equal_sign_index in ::memchr::memchr_iter(b'=', query_string_bytes) {
-            let segment_end = ampersand_iter.next().unwrap_or_else(|| query_string.len());
-
-            let key = query_string
-                .get(position..equal_sign_index)
-                .with_context(|| format!("no key at {}..{}", position, equal_sign_index))?;
-            let value = query_string
-                .get(equal_sign_index + 1..segment_end)
-                .with_context(|| {
-                    format!("no value at {}..{}", equal_sign_index + 1, segment_end)
-                })?;
+        for (key, value) in QueryPairs::new(query_string) {
+            let (key, value) = (key?, value?);
 
             match key {
                 "info_hash" => {
-                    let value = urldecode_20_bytes(value)?;
+                    let value = urldecode_20_bytes(value)
+                        .map_err(|_| RequestParseError::InvalidInfoHash)?;
 
                     info_hashes.push(InfoHash(value));
                 }
@@ -228,37 +250,174 @@ impl ScrapeRequest {
                     ::log::debug!("ignored unrecognized key: {}", k)
                 }
             }
-
-            if segment_end == query_string.len() {
-                break;
-            } else {
-                position = segment_end + 1;
-            }
         }
 
         if info_hashes.is_empty() {
-            return Err(anyhow::anyhow!("No info hashes sent"));
+            return Err(RequestParseError::FullScrapeNotSupported);
         }
 
         Ok(ScrapeRequest { info_hashes })
     }
 }
 
-#[derive(Debug)]
+/// Iterator over key-value pairs in query string. Keys and values are not
+/// percent-decoded.
+struct QueryPairs<'a> {
+    query_string: &'a str,
+    ampersand_iter: ::memchr::Memchr<'a>,
+    equal_sign_iter: ::memchr::Memchr<'a>,
+    position: usize,
+    done: bool,
+}
+
+impl<'a> QueryPairs<'a> {
+    fn new(query_string: &'a str) -> Self {
+        let query_string_bytes = query_string.as_bytes();
+
+        Self {
+            query_string,
+            ampersand_iter: ::memchr::memchr_iter(b'&', query_string_bytes),
+            equal_sign_iter: ::memchr::memchr_iter(b'=', query_string_bytes),
+            position: 0,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for QueryPairs<'a> {
+    type Item = (
+        Result<&'a str, RequestParseError>,
+        Result<&'a str, RequestParseError>,
+    );
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let equal_sign_index = self.equal_sign_iter.next()?;
+        let segment_end = self
+            .ampersand_iter
+            .next()
+            .unwrap_or(self.query_string.len());
+
+        let key = self
+            .query_string
+            .get(self.position..equal_sign_index)
+            .ok_or(RequestParseError::InvalidQueryString);
+        let value = self
+            .query_string
+            .get(equal_sign_index + 1..segment_end)
+            .ok_or(RequestParseError::InvalidQueryString);
+
+        if segment_end == self.query_string.len() {
+            self.done = true;
+        } else {
+            self.position = segment_end + 1;
+        }
+
+        Some((key, value))
+    }
+}
+
+/// Reason for request not being parsed
+///
+/// Apart from NeedMoreData, variants correspond to invalid requests. Their
+/// messages are stable and suitable for sending to clients as failure
+/// reasons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RequestParseError {
+    /// Request is incomplete
     NeedMoreData,
-    Invalid(anyhow::Error),
+    InvalidHttp,
+    InvalidPath,
+    InvalidQueryString,
+    MissingInfoHash,
+    InvalidInfoHash,
+    MissingPeerId,
+    InvalidPeerId,
+    MissingPort,
+    InvalidPort,
+    MissingUploaded,
+    InvalidUploaded,
+    MissingDownloaded,
+    InvalidDownloaded,
+    MissingLeft,
+    InvalidLeft,
+    InvalidEvent,
+    InvalidNumwant,
+    InvalidKey,
+    InvalidTrackerId,
+    CompactNotSupported,
+    FullScrapeNotSupported,
+    /// Not returned by parsing functions, since the limit is set by the
+    /// tracker
+    TooManyInfoHashes,
 }
 
-impl ::std::fmt::Display for RequestParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl RequestParseError {
+    /// All variants corresponding to invalid requests, e.g., for keeping
+    /// a count of each
+    pub const INVALID: [Self; 22] = [
+        Self::InvalidHttp,
+        Self::InvalidPath,
+        Self::InvalidQueryString,
+        Self::MissingInfoHash,
+        Self::InvalidInfoHash,
+        Self::MissingPeerId,
+        Self::InvalidPeerId,
+        Self::MissingPort,
+        Self::InvalidPort,
+        Self::MissingUploaded,
+        Self::InvalidUploaded,
+        Self::MissingDownloaded,
+        Self::InvalidDownloaded,
+        Self::MissingLeft,
+        Self::InvalidLeft,
+        Self::InvalidEvent,
+        Self::InvalidNumwant,
+        Self::InvalidKey,
+        Self::InvalidTrackerId,
+        Self::CompactNotSupported,
+        Self::FullScrapeNotSupported,
+        Self::TooManyInfoHashes,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
         match self {
-            Self::NeedMoreData => write!(f, "Incomplete request, more data needed"),
-            Self::Invalid(err) => write!(f, "Invalid request: {:#}", err),
+            Self::NeedMoreData => "Incomplete request",
+            Self::InvalidHttp => "Invalid HTTP request",
+            Self::InvalidPath => "Path must be /announce or /scrape",
+            Self::InvalidQueryString => "Invalid query string",
+            Self::MissingInfoHash => "Missing info_hash",
+            Self::InvalidInfoHash => "Invalid info_hash",
+            Self::MissingPeerId => "Missing peer_id",
+            Self::InvalidPeerId => "Invalid peer_id",
+            Self::MissingPort => "Missing port",
+            Self::InvalidPort => "Invalid port",
+            Self::MissingUploaded => "Missing uploaded",
+            Self::InvalidUploaded => "Invalid uploaded",
+            Self::MissingDownloaded => "Missing downloaded",
+            Self::InvalidDownloaded => "Invalid downloaded",
+            Self::MissingLeft => "Missing left",
+            Self::InvalidLeft => "Invalid left",
+            Self::InvalidEvent => "Invalid event",
+            Self::InvalidNumwant => "Invalid numwant",
+            Self::InvalidKey => "Invalid key",
+            Self::InvalidTrackerId => "Invalid trackerid",
+            Self::CompactNotSupported => "Only compact responses are supported",
+            Self::FullScrapeNotSupported => "Full scrapes are not supported",
+            Self::TooManyInfoHashes => "Too many info hashes",
         }
     }
 }
 
+impl ::std::fmt::Display for RequestParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl ::std::error::Error for RequestParseError {}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -270,20 +429,45 @@ pub enum Request {
 impl Request {
     /// Parse Request from HTTP request bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, RequestParseError> {
+        Self::from_bytes_with_len(bytes).map(|(request, _)| request)
+    }
+
+    /// Parse Request from start of HTTP request bytes, also returning the
+    /// number of bytes it occupies (including any body). Remaining bytes
+    /// might belong to pipelined requests.
+    pub fn from_bytes_with_len(bytes: &[u8]) -> Result<(Self, usize), RequestParseError> {
         let mut headers = [httparse::EMPTY_HEADER; 16];
         let mut http_request = httparse::Request::new(&mut headers);
 
-        match http_request.parse(bytes) {
-            Ok(httparse::Status::Complete(_)) => {
-                if let Some(path) = http_request.path {
-                    Self::from_http_get_path(path).map_err(RequestParseError::Invalid)
-                } else {
-                    Err(RequestParseError::Invalid(anyhow::anyhow!("no http path")))
-                }
+        let head_len = match http_request.parse(bytes) {
+            Ok(httparse::Status::Complete(head_len)) => head_len,
+            Ok(httparse::Status::Partial) => return Err(RequestParseError::NeedMoreData),
+            Err(_) => return Err(RequestParseError::InvalidHttp),
+        };
+
+        let mut body_len = 0;
+
+        for header in http_request.headers.iter() {
+            if header.name.eq_ignore_ascii_case("content-length") {
+                body_len = ::std::str::from_utf8(header.value)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .ok_or(RequestParseError::InvalidHttp)?;
             }
-            Ok(httparse::Status::Partial) => Err(RequestParseError::NeedMoreData),
-            Err(err) => Err(RequestParseError::Invalid(anyhow::Error::from(err))),
         }
+
+        let request_len = head_len
+            .checked_add(body_len)
+            .ok_or(RequestParseError::InvalidHttp)?;
+
+        // Body is ignored, but needs to be skipped over
+        if bytes.len() < request_len {
+            return Err(RequestParseError::NeedMoreData);
+        }
+
+        let path = http_request.path.ok_or(RequestParseError::InvalidHttp)?;
+
+        Ok((Self::from_http_get_path(path)?, request_len))
     }
 
     /// Parse Request from http GET path (`/announce?info_hash=...`)
@@ -299,24 +483,28 @@ impl Request {
     /// UTF-8 string, meaning that non-ascii bytes are invalid characters.
     /// Therefore, these bytes must be converted to their equivalent multi-byte
     /// UTF-8 encodings.
-    pub fn from_http_get_path(path: &str) -> anyhow::Result<Self> {
+    pub fn from_http_get_path(path: &str) -> Result<Self, RequestParseError> {
         ::log::debug!("request GET path: {}", path);
 
         let mut split_parts = path.splitn(2, '?');
 
-        let location = split_parts.next().with_context(|| "no location")?;
-        let query_string = split_parts.next().with_context(|| "no query string")?;
+        let location = split_parts.next().ok_or(RequestParseError::InvalidPath)?;
+        let opt_query_string = split_parts.next();
 
         if location == "/announce" {
+            let query_string = opt_query_string.ok_or(RequestParseError::MissingInfoHash)?;
+
             Ok(Request::Announce(AnnounceRequest::from_query_string(
                 query_string,
             )?))
         } else if location == "/scrape" {
+            let query_string = opt_query_string.ok_or(RequestParseError::FullScrapeNotSupported)?;
+
             Ok(Request::Scrape(ScrapeRequest::from_query_string(
                 query_string,
             )?))
         } else {
-            Err(anyhow::anyhow!("Path must be /announce or /scrape"))
+            Err(RequestParseError::InvalidPath)
         }
     }
 
@@ -334,7 +522,7 @@ mod tests {
 
     use super::*;
 
-    static ANNOUNCE_REQUEST_PATH: &str = "/announce?info_hash=%04%0bkV%3f%5cr%14%a6%b7%98%adC%c3%c9.%40%24%00%b9&peer_id=-ABC940-5ert69muw5t8&port=12345&uploaded=1&downloaded=2&left=3&numwant=0&key=4ab4b877&compact=1&supportcrypto=1&event=started";
+    static ANNOUNCE_REQUEST_PATH: &str = "/announce?info_hash=%04%0bkV%3f%5cr%14%a6%b7%98%adC%c3%c9.%40%24%00%b9&peer_id=-ABC940-5ert69muw5t8&port=12345&uploaded=1&downloaded=2&left=3&numwant=0&key=4ab4b877&trackerid=abc%20d&compact=1&supportcrypto=1&event=started";
     static SCRAPE_REQUEST_PATH: &str =
         "/scrape?info_hash=%04%0bkV%3f%5cr%14%a6%b7%98%adC%c3%c9.%40%24%00%b9";
     static REFERENCE_INFO_HASH: [u8; 20] = [
@@ -357,6 +545,7 @@ mod tests {
             event: AnnounceEvent::Started,
             numwant: Some(0),
             key: Some("4ab4b877".into()),
+            tracker_id: Some("abc d".into()),
         })
     }
 
@@ -390,9 +579,95 @@ mod tests {
         assert_eq!(parsed_request, reference_request);
     }
 
+    #[test]
+    fn test_pipelined_requests_from_bytes() {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(b"GET ");
+        bytes.extend_from_slice(&ANNOUNCE_REQUEST_PATH.as_bytes());
+        bytes.extend_from_slice(b" HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc");
+
+        let first_request_len = bytes.len();
+
+        bytes.extend_from_slice(b"GET ");
+        bytes.extend_from_slice(&SCRAPE_REQUEST_PATH.as_bytes());
+        bytes.extend_from_slice(b" HTTP/1.1\r\n\r\n");
+
+        assert_eq!(
+            Request::from_bytes_with_len(&bytes[..first_request_len - 1]),
+            Err(RequestParseError::NeedMoreData)
+        );
+
+        let (request, request_len) = Request::from_bytes_with_len(&bytes).unwrap();
+
+        assert_eq!(request, get_reference_announce_request());
+        assert_eq!(request_len, first_request_len);
+
+        let (request, request_len) =
+            Request::from_bytes_with_len(&bytes[first_request_len..]).unwrap();
+
+        assert!(matches!(request, Request::Scrape(_)));
+        assert_eq!(request_len, bytes.len() - first_request_len);
+    }
+
+    #[test]
+    fn test_request_parse_errors() {
+        let parse = |path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+
+            Request::from_bytes(request.as_bytes())
+        };
+
+        let announce_path =
+            |replace: &str, with: &str| ANNOUNCE_REQUEST_PATH.replacen(replace, with, 1);
+
+        assert_eq!(
+            Request::from_bytes(b"GET /announce"),
+            Err(RequestParseError::NeedMoreData)
+        );
+        assert_eq!(
+            Request::from_bytes(b"GET\0 /announce HTTP/1.1\r\n\r\n"),
+            Err(RequestParseError::InvalidHttp)
+        );
+        assert_eq!(parse("/other?a=b"), Err(RequestParseError::InvalidPath));
+        assert_eq!(
+            parse(&announce_path("info_hash=", "x=")),
+            Err(RequestParseError::MissingInfoHash)
+        );
+        assert_eq!(
+            parse(&announce_path("info_hash=%04", "info_hash=")),
+            Err(RequestParseError::InvalidInfoHash)
+        );
+        assert_eq!(
+            parse(&announce_path("port=12345", "port=123456")),
+            Err(RequestParseError::InvalidPort)
+        );
+        assert_eq!(
+            parse(&announce_path("left=3", "x=3")),
+            Err(RequestParseError::MissingLeft)
+        );
+        assert_eq!(
+            parse(&announce_path("compact=1", "compact=0")),
+            Err(RequestParseError::CompactNotSupported)
+        );
+        assert_eq!(
+            parse(&announce_path("event=started", "event=x")),
+            Err(RequestParseError::InvalidEvent)
+        );
+        assert_eq!(
+            parse("/scrape"),
+            Err(RequestParseError::FullScrapeNotSupported)
+        );
+        assert_eq!(
+            parse("/scrape?a=b"),
+            Err(RequestParseError::FullScrapeNotSupported)
+        );
+    }
+
     impl Arbitrary for AnnounceRequest {
         fn arbitrary(g: &mut Gen) -> Self {
             let key: Option<String> = Arbitrary::arbitrary(g);
+            let tracker_id: Option<String> = Arbitrary::arbitrary(g);
 
             AnnounceRequest {
                 info_hash: Arbitrary::arbitrary(g),
@@ -404,6 +679,7 @@ mod tests {
                 event: Arbitrary::arbitrary(g),
                 numwant: Arbitrary::arbitrary(g),
                 key: key.map(|key| key.into()),
+                tracker_id: tracker_id.map(|tracker_id| tracker_id.into()),
             }
         }
     }
@@ -431,9 +707,11 @@ mod tests {
         fn prop(request: Request) -> TestResult {
             match request {
                 Request::Announce(AnnounceRequest {
-                    key: Some(ref key), ..
+                    ref key,
+                    ref tracker_id,
+                    ..
                 }) => {
-                    if key.len() > 30 {
+                    if key.iter().chain(tracker_id).any(|s| s.len() > 30) {
                         return TestResult::discard();
                     }
                 }
@@ -442,7 +720,6 @@ mod tests {
                         return TestResult::discard();
                     }
                 }
-                _ => {}
             }
 
             let mut bytes = Vec::new();
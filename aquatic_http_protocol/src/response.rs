@@ -1,8 +1,8 @@
 use std::borrow::Cow;
 use std::io::Write;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::BTreeMap;
 
 use super::common::*;
@@ -37,20 +37,43 @@ pub struct ResponsePeerListV6(
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScrapeStatistics {
     pub complete: usize,
+    /// Number of leechers, including partial seeds
     pub incomplete: usize,
     pub downloaded: usize,
+    /// Number of leechers that are not partial seeds (BEP 21)
+    #[serde(default)]
+    pub downloaders: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnnounceResponse {
     #[serde(rename = "interval")]
     pub announce_interval: usize,
+    /// Peers should not announce more often than this (seconds)
+    #[serde(rename = "min interval", skip_serializing_if = "Option::is_none")]
+    pub min_announce_interval: Option<usize>,
     pub complete: usize,
     pub incomplete: usize,
     #[serde(default)]
     pub peers: ResponsePeerListV4,
     #[serde(default)]
     pub peers6: ResponsePeerListV6,
+    /// Should be sent back by peer in subsequent announce requests
+    #[serde(
+        rename = "tracker id",
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_string"
+    )]
+    pub tracker_id: Option<String>,
+    /// IP address of peer, as seen by tracker (BEP 24)
+    #[serde(
+        rename = "external ip",
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_ip",
+        deserialize_with = "deserialize_optional_ip"
+    )]
+    pub external_ip: Option<IpAddr>,
     // Serialize as string if Some, otherwise skip
     #[serde(
         rename = "warning message",
@@ -66,8 +89,21 @@ impl AnnounceResponse {
 
         bytes_written += output.write(b"d8:completei")?;
         bytes_written += output.write(itoa::Buffer::new().format(self.complete).as_bytes())?;
+        bytes_written += output.write(b"e")?;
+
+        match self.external_ip {
+            Some(IpAddr::V4(ip)) => {
+                bytes_written += output.write(b"11:external ip4:")?;
+                bytes_written += output.write(&ip.octets())?;
+            }
+            Some(IpAddr::V6(ip)) => {
+                bytes_written += output.write(b"11:external ip16:")?;
+                bytes_written += output.write(&ip.octets())?;
+            }
+            None => (),
+        }
 
-        bytes_written += output.write(b"e10:incompletei")?;
+        bytes_written += output.write(b"10:incompletei")?;
         bytes_written += output.write(itoa::Buffer::new().format(self.incomplete).as_bytes())?;
 
         bytes_written += output.write(b"e8:intervali")?;
@@ -76,8 +112,16 @@ impl AnnounceResponse {
                 .format(self.announce_interval)
                 .as_bytes(),
         )?;
+        bytes_written += output.write(b"e")?;
 
-        bytes_written += output.write(b"e5:peers")?;
+        if let Some(min_announce_interval) = self.min_announce_interval {
+            bytes_written += output.write(b"12:min intervali")?;
+            bytes_written +=
+                output.write(itoa::Buffer::new().format(min_announce_interval).as_bytes())?;
+            bytes_written += output.write(b"e")?;
+        }
+
+        bytes_written += output.write(b"5:peers")?;
         bytes_written += output.write(
             itoa::Buffer::new()
                 .format(self.peers.0.len() * 6)
@@ -101,6 +145,19 @@ impl AnnounceResponse {
             bytes_written += output.write(&peer.port.to_be_bytes())?;
         }
 
+        if let Some(ref tracker_id) = self.tracker_id {
+            let tracker_id_bytes = tracker_id.as_bytes();
+
+            bytes_written += output.write(b"10:tracker id")?;
+            bytes_written += output.write(
+                itoa::Buffer::new()
+                    .format(tracker_id_bytes.len())
+                    .as_bytes(),
+            )?;
+            bytes_written += output.write(b":")?;
+            bytes_written += output.write(tracker_id_bytes)?;
+        }
+
         if let Some(ref warning_message) = self.warning_message {
             let message_bytes = warning_message.as_bytes();
 
@@ -146,7 +203,13 @@ impl ScrapeResponse {
             bytes_written += output.write(b"d8:completei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.complete).as_bytes())?;
-            bytes_written += output.write(b"e10:downloadedi0e10:incompletei")?;
+            bytes_written += output.write(b"e10:downloadedi0e11:downloadersi")?;
+            bytes_written += output.write(
+                itoa::Buffer::new()
+                    .format(statistics.downloaders)
+                    .as_bytes(),
+            )?;
+            bytes_written += output.write(b"e10:incompletei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.incomplete).as_bytes())?;
             bytes_written += output.write(b"ee")?;
@@ -169,16 +232,75 @@ impl axum::response::IntoResponse for ScrapeResponse {
     }
 }
 
+/// When client may retry request after failure (BEP 31)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryIn {
+    Minutes(usize),
+    Never,
+}
+
+impl Serialize for RetryIn {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Minutes(minutes) => serializer.serialize_u64(*minutes as u64),
+            Self::Never => serializer.serialize_str("never"),
+        }
+    }
+}
+
+struct RetryInVisitor;
+
+impl<'de> Visitor<'de> for RetryInVisitor {
+    type Value = RetryIn;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("number of minutes or \"never\"")
+    }
+
+    fn visit_u64<E: ::serde::de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        usize::try_from(value)
+            .map(RetryIn::Minutes)
+            .map_err(|_| E::custom("too many minutes"))
+    }
+
+    fn visit_i64<E: ::serde::de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        usize::try_from(value)
+            .map(RetryIn::Minutes)
+            .map_err(|_| E::custom("negative number of minutes"))
+    }
+
+    fn visit_bytes<E: ::serde::de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+        if value == b"never" {
+            Ok(RetryIn::Never)
+        } else {
+            Err(E::custom("string other than \"never\""))
+        }
+    }
+
+    fn visit_str<E: ::serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        self.visit_bytes(value.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for RetryIn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(RetryInVisitor)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FailureResponse {
     #[serde(rename = "failure reason")]
     pub failure_reason: Cow<'static, str>,
+    #[serde(rename = "retry in", skip_serializing_if = "Option::is_none")]
+    pub retry_in: Option<RetryIn>,
 }
 
 impl FailureResponse {
     pub fn new<S: Into<Cow<'static, str>>>(reason: S) -> Self {
         Self {
             failure_reason: reason.into(),
+            retry_in: None,
         }
     }
 
@@ -191,6 +313,19 @@ impl FailureResponse {
         bytes_written += output.write(itoa::Buffer::new().format(reason_bytes.len()).as_bytes())?;
         bytes_written += output.write(b":")?;
         bytes_written += output.write(reason_bytes)?;
+
+        match self.retry_in {
+            Some(RetryIn::Minutes(minutes)) => {
+                bytes_written += output.write(b"8:retry ini")?;
+                bytes_written += output.write(itoa::Buffer::new().format(minutes).as_bytes())?;
+                bytes_written += output.write(b"e")?;
+            }
+            Some(RetryIn::Never) => {
+                bytes_written += output.write(b"8:retry in5:never")?;
+            }
+            None => (),
+        }
+
         bytes_written += output.write(b"e")?;
 
         Ok(bytes_written)
@@ -281,6 +416,7 @@ impl quickcheck::Arbitrary for ScrapeStatistics {
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
             downloaded: 0,
+            downloaders: usize::arbitrary(g),
         }
     }
 }
@@ -290,10 +426,13 @@ impl quickcheck::Arbitrary for AnnounceResponse {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
             announce_interval: usize::arbitrary(g),
+            min_announce_interval: Option::arbitrary(g),
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
             peers: ResponsePeerListV4::arbitrary(g),
             peers6: ResponsePeerListV6::arbitrary(g),
+            tracker_id: quickcheck::Arbitrary::arbitrary(g),
+            external_ip: Option::arbitrary(g),
             warning_message: quickcheck::Arbitrary::arbitrary(g),
         }
     }
@@ -313,6 +452,18 @@ impl quickcheck::Arbitrary for FailureResponse {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
             failure_reason: String::arbitrary(g).into(),
+            retry_in: Option::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for RetryIn {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        if bool::arbitrary(g) {
+            Self::Minutes(usize::arbitrary(g))
+        } else {
+            Self::Never
         }
     }
 }
@@ -341,6 +492,39 @@ mod tests {
         success
     }
 
+    #[test]
+    fn test_announce_response_optional_fields_from_bytes() {
+        for external_ip in [
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ] {
+            let response = AnnounceResponse {
+                announce_interval: 120,
+                min_announce_interval: Some(60),
+                complete: 1,
+                incomplete: 2,
+                peers: Default::default(),
+                peers6: Default::default(),
+                tracker_id: Some("abc".into()),
+                external_ip: Some(external_ip),
+                warning_message: None,
+            };
+
+            let mut bytes = Vec::new();
+
+            response.write(&mut bytes).unwrap();
+
+            match Response::from_bytes(&bytes).unwrap() {
+                Response::Announce(parsed) => {
+                    assert_eq!(parsed.min_announce_interval, Some(60));
+                    assert_eq!(parsed.tracker_id.as_deref(), Some("abc"));
+                    assert_eq!(parsed.external_ip, Some(external_ip));
+                }
+                _ => panic!("expected announce response"),
+            }
+        }
+    }
+
     #[quickcheck]
     fn test_scrape_response_to_bytes(response: ScrapeResponse) -> bool {
         let reference = bendy::serde::to_bytes(&Response::Scrape(response.clone())).unwrap();
@@ -376,4 +560,23 @@ mod tests {
 
         success
     }
+
+    #[test]
+    fn test_failure_response_retry_in_from_bytes() {
+        for retry_in in [RetryIn::Minutes(5), RetryIn::Never] {
+            let response = FailureResponse {
+                failure_reason: "test".into(),
+                retry_in: Some(retry_in),
+            };
+
+            let mut bytes = Vec::new();
+
+            response.write(&mut bytes).unwrap();
+
+            match Response::from_bytes(&bytes).unwrap() {
+                Response::Failure(parsed) => assert_eq!(parsed.retry_in, Some(retry_in)),
+                _ => panic!("expected failure response"),
+            }
+        }
+    }
 }
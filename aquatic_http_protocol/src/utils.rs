@@ -1,5 +1,5 @@
 use std::io::Write;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use anyhow::Context;
 use serde::{de::Visitor, Deserializer, Serializer};
@@ -244,6 +244,51 @@ where
     deserializer.deserialize_any(ResponsePeersIpv6Visitor)
 }
 
+/// Serialize IP address as 4 or 16 bytes (BEP 24)
+#[inline]
+pub fn serialize_optional_ip<S>(v: &Option<IpAddr>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match v {
+        Some(IpAddr::V4(ip)) => serializer.serialize_bytes(&ip.octets()),
+        Some(IpAddr::V6(ip)) => serializer.serialize_bytes(&ip.octets()),
+        None => Err(serde::ser::Error::custom("use skip_serializing_if")),
+    }
+}
+
+struct IpVisitor;
+
+impl<'de> Visitor<'de> for IpVisitor {
+    type Value = Option<IpAddr>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("byte-encoded ipv4 or ipv6 address")
+    }
+
+    #[inline]
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
+    where
+        E: ::serde::de::Error,
+    {
+        if let Ok(bytes) = <[u8; 4]>::try_from(value) {
+            Ok(Some(IpAddr::V4(Ipv4Addr::from(bytes))))
+        } else if let Ok(bytes) = <[u8; 16]>::try_from(value) {
+            Ok(Some(IpAddr::V6(Ipv6Addr::from(bytes))))
+        } else {
+            Err(::serde::de::Error::custom("not 4 or 16 bytes"))
+        }
+    }
+}
+
+#[inline]
+pub fn deserialize_optional_ip<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(IpVisitor)
+}
+
 #[cfg(test)]
 mod tests {
     use quickcheck_macros::*;
@@ -123,4 +123,20 @@ pub mod __private {
 
     impl_trait!(PathBuf);
     impl_trait!(SocketAddr);
+
+    impl<T: ::serde::Serialize> Private for Vec<T> {
+        fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+            let mut output = String::new();
+
+            if let Some(comment) = comment {
+                output.push_str(&comment);
+            }
+
+            let value = crate::toml::ser::to_string(self).unwrap();
+
+            output.push_str(&format!("{} = {}\n", field_name, value));
+
+            output
+        }
+    }
 }
@@ -0,0 +1,136 @@
+//! Compare throughput of sending and receiving datagrams one at a time with
+//! that of batched I/O (recvmmsg/sendmmsg on Linux) over loopback
+
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use aquatic_udp::workers::socket::batch::{RecvBatch, SendBatch};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mio::net::UdpSocket;
+
+const NUM_DATAGRAMS: usize = 64;
+/// Roughly the size of an IPv4 announce response with 15 peers
+const DATAGRAM_SIZE: usize = 110;
+
+fn bind_localhost() -> UdpSocket {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+
+    socket2::SockRef::from(&socket)
+        .set_recv_buffer_size(4 * 1024 * 1024)
+        .unwrap();
+
+    socket
+}
+
+fn fill_socket(sender: &UdpSocket, addr: SocketAddr) {
+    for _ in 0..NUM_DATAGRAMS {
+        sender.send_to(&[1; DATAGRAM_SIZE], addr).unwrap();
+    }
+}
+
+fn drain_socket(socket: &UdpSocket) {
+    let mut buffer = [0; DATAGRAM_SIZE];
+
+    while socket.recv_from(&mut buffer).is_ok() {}
+}
+
+pub fn bench_send(c: &mut Criterion) {
+    let sender = bind_localhost();
+    let receiver = bind_localhost();
+    let receiver_addr = receiver.local_addr().unwrap();
+
+    let mut group = c.benchmark_group("udp-send");
+
+    group.throughput(Throughput::Elements(NUM_DATAGRAMS as u64));
+
+    group.bench_function("send_to", |b| {
+        b.iter_batched(
+            || drain_socket(&receiver),
+            |_| {
+                for _ in 0..NUM_DATAGRAMS {
+                    sender.send_to(&[1; DATAGRAM_SIZE], receiver_addr).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let mut send_batch = SendBatch::new(NUM_DATAGRAMS);
+
+    group.bench_function("send-batch", |b| {
+        b.iter_batched(
+            || drain_socket(&receiver),
+            |_| {
+                for _ in 0..NUM_DATAGRAMS {
+                    send_batch
+                        .push(receiver_addr, (), |_, buffer| {
+                            buffer[..DATAGRAM_SIZE].fill(1);
+
+                            Ok(DATAGRAM_SIZE)
+                        })
+                        .unwrap();
+                }
+
+                send_batch.send(&sender, |_, result| {
+                    result.unwrap();
+                });
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+pub fn bench_recv(c: &mut Criterion) {
+    let sender = bind_localhost();
+    let receiver = bind_localhost();
+    let receiver_addr = receiver.local_addr().unwrap();
+
+    let mut group = c.benchmark_group("udp-recv");
+
+    group.throughput(Throughput::Elements(NUM_DATAGRAMS as u64));
+
+    group.bench_function("recv_from", |b| {
+        let mut buffer = [0; 8192];
+
+        b.iter_batched(
+            || fill_socket(&sender, receiver_addr),
+            |_| loop {
+                match receiver.recv_from(&mut buffer) {
+                    Ok(_) => (),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => panic!("recv_from: {:#}", err),
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let mut recv_batch = RecvBatch::new(NUM_DATAGRAMS);
+
+    group.bench_function("recv-batch", |b| {
+        b.iter_batched(
+            || fill_socket(&sender, receiver_addr),
+            |_| loop {
+                match recv_batch.recv(&receiver) {
+                    Ok(_) => (),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => panic!("recv: {:#}", err),
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .measurement_time(Duration::from_secs(10));
+    targets = bench_send, bench_recv
+}
+criterion_main!(benches);
@@ -7,6 +7,10 @@ use std::sync::Arc;
 use crossbeam_channel::{Sender, TrySendError};
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::client_filter::ClientFilterArcSwap;
+use aquatic_common::early_announce::EarlyAnnounceCounts;
+use aquatic_common::url_access::UrlAccessListArcSwap;
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::*;
 use hdrhistogram::Histogram;
@@ -38,6 +42,7 @@ pub enum ConnectedResponse {
     AnnounceIpv4(AnnounceResponse<Ipv4Addr>),
     AnnounceIpv6(AnnounceResponse<Ipv6Addr>),
     Scrape(PendingScrapeResponse),
+    Error(ErrorResponse),
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -175,6 +180,10 @@ impl Statistics {
 #[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
+    pub url_access_list: Arc<UrlAccessListArcSwap>,
+    pub early_announces: Arc<EarlyAnnounceCounts>,
+    pub request_rate: Arc<RequestRate>,
     pub statistics_ipv4: Arc<Statistics>,
     pub statistics_ipv6: Arc<Statistics>,
 }
@@ -183,6 +192,10 @@ impl State {
     pub fn new(num_swarm_workers: usize) -> Self {
         Self {
             access_list: Arc::new(AccessListArcSwap::default()),
+            client_filter: Arc::new(ClientFilterArcSwap::default()),
+            url_access_list: Arc::new(UrlAccessListArcSwap::default()),
+            early_announces: Default::default(),
+            request_rate: Default::default(),
             statistics_ipv4: Arc::new(Statistics::new(num_swarm_workers)),
             statistics_ipv6: Arc::new(Statistics::new(num_swarm_workers)),
         }
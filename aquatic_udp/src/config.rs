@@ -1,6 +1,10 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+use aquatic_common::{
+    access_list::AccessListConfig, adaptive_interval::AdaptiveAnnounceIntervalConfig,
+    client_filter::ClientFilterConfig, early_announce::EarlyAnnounceAction,
+    peer_identity::PeerIdentityCheck, privileges::PrivilegeConfig, url_access::UrlAccessConfig,
+};
 use serde::Deserialize;
 
 use aquatic_common::cli::LogLevel;
@@ -30,10 +34,17 @@ pub struct Config {
     pub request_channel_recv_timeout_ms: u64,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
+    /// Adapt protocol.peer_announce_interval to swarm size and request rate
+    pub adaptive_announce_interval: AdaptiveAnnounceIntervalConfig,
     pub statistics: StatisticsConfig,
     pub cleaning: CleaningConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub client_filter: ClientFilterConfig,
+    /// Access control based on path and query of tracker URL, which clients
+    /// send as BEP 41 URL data in announce requests. Scrape requests can't
+    /// carry URL data and are not affected.
+    pub url_access: UrlAccessConfig,
     #[cfg(feature = "cpu-pinning")]
     pub cpu_pinning: aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc,
 }
@@ -48,10 +59,13 @@ impl Default for Config {
             request_channel_recv_timeout_ms: 100,
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
+            adaptive_announce_interval: AdaptiveAnnounceIntervalConfig::default(),
             statistics: StatisticsConfig::default(),
             cleaning: CleaningConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            client_filter: ClientFilterConfig::default(),
+            url_access: UrlAccessConfig::default(),
             #[cfg(feature = "cpu-pinning")]
             cpu_pinning: Default::default(),
         }
@@ -86,6 +100,16 @@ pub struct NetworkConfig {
     pub socket_recv_buffer_size: usize,
     pub poll_event_capacity: usize,
     pub poll_timeout_ms: u64,
+    /// Maximum number of datagrams to receive or send per syscall
+    ///
+    /// On Linux, recvmmsg and sendmmsg are used to receive and send batches
+    /// of datagrams, which reduces syscall overhead at high packet rates.
+    /// Set to 1 to receive and send one datagram at a time. On other
+    /// operating systems, datagrams are always handled one at a time.
+    ///
+    /// When built with the io-uring feature, this is the number of receive
+    /// and send operations that each socket worker keeps in flight.
+    pub batch_size: usize,
     /// Store this many responses at most for retrying (once) on send failure
     ///
     /// Useful on operating systems that do not provide an udp send buffer,
@@ -111,6 +135,7 @@ impl Default for NetworkConfig {
             socket_recv_buffer_size: 4096 * 128,
             poll_event_capacity: 4096,
             poll_timeout_ms: 50,
+            batch_size: 64,
             resend_buffer_max_len: 0,
         }
     }
@@ -125,6 +150,23 @@ pub struct ProtocolConfig {
     pub max_response_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: i32,
+    /// Minimum time between announces from a peer (seconds)
+    ///
+    /// The UDP tracker protocol has no way of telling peers about this
+    /// interval. Announces without event from peers that announced less
+    /// than this long ago are handled according to early_announce_action.
+    /// Set to zero to not enforce a minimum interval.
+    pub peer_announce_min_interval: u32,
+    /// How to respond to early announces: cached (current statistics and
+    /// the peers that were sent in response to the last regular announce)
+    /// or failure (error response)
+    pub early_announce_action: EarlyAnnounceAction,
+    /// Check key of announce requests for peer_ids registered from another
+    /// IP address. Requests that fail the check get an error response and
+    /// don't update the peer. A key of zero is treated as no key, so with
+    /// lenient, peers that were registered with key zero can be updated by
+    /// any request.
+    pub peer_identity_check: PeerIdentityCheck,
 }
 
 impl Default for ProtocolConfig {
@@ -133,6 +175,9 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 70,
             max_response_peers: 50,
             peer_announce_interval: 60 * 15,
+            peer_announce_min_interval: 0,
+            early_announce_action: EarlyAnnounceAction::Cached,
+            peer_identity_check: PeerIdentityCheck::Lenient,
         }
     }
 }
@@ -10,10 +10,14 @@ use crossbeam_channel::{bounded, unbounded};
 use signal_hook::consts::{SIGTERM, SIGUSR1};
 use signal_hook::iterator::Signals;
 
-use aquatic_common::access_list::update_access_list;
+use aquatic_common::access_list::{spawn_access_list_watcher, update_access_list};
+use aquatic_common::client_filter::update_client_filter;
 #[cfg(feature = "cpu-pinning")]
-use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+use aquatic_common::cpu_pinning::{
+    pin_current_if_configured_to, require_system_hyperthread_mapping, WorkerIndex,
+};
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::url_access::update_url_access_list;
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
 
 use common::{
@@ -29,12 +33,23 @@ pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
+    config
+        .adaptive_announce_interval
+        .validate(config.cleaning.max_peer_age)?;
+
+    #[cfg(feature = "cpu-pinning")]
+    require_system_hyperthread_mapping(&config.cpu_pinning)?;
+
     let state = State::new(config.swarm_workers);
     let connection_validator = ConnectionValidator::new(&config)?;
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_client_filter(&config.client_filter, &state.client_filter)?;
+    update_url_access_list(&config.url_access, &state.url_access_list)?;
+
+    spawn_access_list_watcher(&config.access_list, &state.access_list)?;
 
     let mut request_senders = Vec::new();
     let mut request_receivers = BTreeMap::new();
@@ -136,6 +151,15 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             .with_context(|| "spawn socket worker")?;
     }
 
+    if config.adaptive_announce_interval.active {
+        let request_rate = state.request_rate.clone();
+
+        Builder::new()
+            .name("request-rate".into())
+            .spawn(move || request_rate.run_updater())
+            .with_context(|| "spawn request rate worker")?;
+    }
+
     if config.statistics.active() {
         let sentinel = sentinel.clone();
         let state = state.clone();
@@ -174,6 +198,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         match signal {
             SIGUSR1 => {
                 let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_client_filter(&config.client_filter, &state.client_filter);
+                let _ = update_url_access_list(&config.url_access, &state.url_access_list);
             }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
@@ -0,0 +1,380 @@
+//! Batched datagram I/O
+//!
+//! On Linux, recvmmsg and sendmmsg are used to receive and send up to a
+//! batch of datagrams per syscall. On other operating systems, datagrams are
+//! received and sent one at a time with recv_from and send_to.
+
+use std::io;
+use std::net::SocketAddr;
+
+use mio::net::UdpSocket;
+
+use crate::common::BUFFER_SIZE;
+
+/// Buffers for receiving a batch of datagrams
+pub struct RecvBatch {
+    buffers: Vec<[u8; BUFFER_SIZE]>,
+    lengths: Vec<usize>,
+    #[cfg(target_os = "linux")]
+    addresses: Vec<libc::sockaddr_storage>,
+    #[cfg(target_os = "linux")]
+    address_lengths: Vec<libc::socklen_t>,
+    #[cfg(target_os = "linux")]
+    iovecs: Vec<libc::iovec>,
+    #[cfg(target_os = "linux")]
+    headers: Vec<libc::mmsghdr>,
+    #[cfg(not(target_os = "linux"))]
+    addresses: Vec<Option<SocketAddr>>,
+}
+
+impl RecvBatch {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+
+        Self {
+            buffers: vec![[0; BUFFER_SIZE]; capacity],
+            lengths: vec![0; capacity],
+            #[cfg(target_os = "linux")]
+            addresses: vec![unsafe { ::std::mem::zeroed() }; capacity],
+            #[cfg(target_os = "linux")]
+            address_lengths: vec![0; capacity],
+            #[cfg(target_os = "linux")]
+            iovecs: vec![unsafe { ::std::mem::zeroed() }; capacity],
+            #[cfg(target_os = "linux")]
+            headers: vec![unsafe { ::std::mem::zeroed() }; capacity],
+            #[cfg(not(target_os = "linux"))]
+            addresses: vec![None; capacity],
+        }
+    }
+
+    /// Receive as many datagrams as are available, up to batch capacity.
+    ///
+    /// Returns number of datagrams received. Returns an error of kind
+    /// WouldBlock if no datagrams were available.
+    #[cfg(target_os = "linux")]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        let capacity = self.buffers.len();
+
+        // Refresh headers in place. Address lengths need to be reset since
+        // recvmmsg overwrites them.
+        for (((buffer, address), iovec), header) in self
+            .buffers
+            .iter_mut()
+            .zip(self.addresses.iter_mut())
+            .zip(self.iovecs.iter_mut())
+            .zip(self.headers.iter_mut())
+        {
+            iovec.iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = buffer.len();
+
+            header.msg_hdr.msg_name = address as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = ::std::mem::size_of::<libc::sockaddr_storage>() as _;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                self.headers.as_mut_ptr(),
+                capacity as _,
+                0,
+                ::std::ptr::null_mut(),
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let num_received = result as usize;
+
+        for (i, header) in self.headers.iter().take(num_received).enumerate() {
+            self.lengths[i] = header.msg_len as usize;
+            self.address_lengths[i] = header.msg_hdr.msg_namelen;
+        }
+
+        Ok(num_received)
+    }
+
+    /// Receive as many datagrams as are available, up to batch capacity.
+    ///
+    /// Returns number of datagrams received. Returns an error of kind
+    /// WouldBlock if no datagrams were available.
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let mut num_received = 0;
+
+        for ((buffer, length), address) in self
+            .buffers
+            .iter_mut()
+            .zip(self.lengths.iter_mut())
+            .zip(self.addresses.iter_mut())
+        {
+            match socket.recv_from(&mut buffer[..]) {
+                Ok((bytes_read, src)) => {
+                    *length = bytes_read;
+                    *address = Some(src);
+
+                    num_received += 1;
+                }
+                Err(err) if num_received == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+
+        Ok(num_received)
+    }
+
+    /// Get data and source address of datagram received by last call to
+    /// `recv`. Address is None if it could not be parsed.
+    pub fn get(&self, index: usize) -> (&[u8], Option<SocketAddr>) {
+        let data = &self.buffers[index][..self.lengths[index]];
+
+        #[cfg(target_os = "linux")]
+        let address =
+            unsafe { socket2::SockAddr::new(self.addresses[index], self.address_lengths[index]) }
+                .as_socket();
+
+        #[cfg(not(target_os = "linux"))]
+        let address = self.addresses[index];
+
+        (data, address)
+    }
+}
+
+/// Buffers for sending a batch of datagrams, each with associated metadata
+/// of type `T` that is passed back when the send result is known
+pub struct SendBatch<T> {
+    buffers: Vec<[u8; BUFFER_SIZE]>,
+    lengths: Vec<usize>,
+    addresses: Vec<socket2::SockAddr>,
+    metadata: Vec<T>,
+    #[cfg(target_os = "linux")]
+    iovecs: Vec<libc::iovec>,
+    #[cfg(target_os = "linux")]
+    headers: Vec<libc::mmsghdr>,
+}
+
+impl<T> SendBatch<T> {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+
+        Self {
+            buffers: vec![[0; BUFFER_SIZE]; capacity],
+            lengths: Vec::with_capacity(capacity),
+            addresses: Vec::with_capacity(capacity),
+            metadata: Vec::with_capacity(capacity),
+            #[cfg(target_os = "linux")]
+            iovecs: vec![unsafe { ::std::mem::zeroed() }; capacity],
+            #[cfg(target_os = "linux")]
+            headers: vec![unsafe { ::std::mem::zeroed() }; capacity],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.lengths.len() == self.buffers.len()
+    }
+
+    /// Add datagram to batch, using `write` to fill its buffer. `write`
+    /// returns number of bytes written. If it fails, the datagram is not
+    /// added and the metadata is dropped.
+    ///
+    /// Panics if batch is full.
+    pub fn push<F>(&mut self, addr: SocketAddr, metadata: T, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&T, &mut [u8]) -> io::Result<usize>,
+    {
+        assert!(!self.is_full(), "SendBatch::push called when batch is full");
+
+        let bytes_written = write(&metadata, &mut self.buffers[self.lengths.len()][..])?;
+
+        self.lengths.push(bytes_written);
+        self.addresses.push(addr.into());
+        self.metadata.push(metadata);
+
+        Ok(())
+    }
+
+    /// Send all datagrams in batch and clear it.
+    ///
+    /// `on_result` is called once for each datagram, in the order they were
+    /// added, with its metadata and the number of bytes sent or the error
+    /// that occured.
+    #[cfg(target_os = "linux")]
+    pub fn send<F>(&mut self, socket: &UdpSocket, mut on_result: F)
+    where
+        F: FnMut(T, io::Result<usize>),
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let num_datagrams = self.lengths.len();
+
+        // Refresh headers in place with addresses and lengths of this batch
+        for ((((buffer, length), address), iovec), header) in self
+            .buffers
+            .iter_mut()
+            .zip(self.lengths.iter())
+            .zip(self.addresses.iter())
+            .zip(self.iovecs.iter_mut())
+            .zip(self.headers.iter_mut())
+        {
+            iovec.iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = *length;
+
+            header.msg_hdr.msg_name = address.as_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = address.len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let headers = &mut self.headers[..num_datagrams];
+
+        let mut metadata = self.metadata.drain(..);
+        let mut offset = 0;
+
+        while offset < num_datagrams {
+            let result = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    headers[offset..].as_mut_ptr(),
+                    (num_datagrams - offset) as _,
+                    0,
+                )
+            };
+
+            if result < 0 {
+                // Error applies to first remaining datagram. Skip it and try
+                // sending the rest.
+                on_result(metadata.next().unwrap(), Err(io::Error::last_os_error()));
+
+                offset += 1;
+            } else {
+                let num_sent = result as usize;
+
+                for header in headers[offset..offset + num_sent].iter() {
+                    on_result(metadata.next().unwrap(), Ok(header.msg_len as usize));
+                }
+
+                offset += num_sent;
+            }
+        }
+
+        self.lengths.clear();
+        self.addresses.clear();
+    }
+
+    /// Send all datagrams in batch and clear it.
+    ///
+    /// `on_result` is called once for each datagram, in the order they were
+    /// added, with its metadata and the number of bytes sent or the error
+    /// that occured.
+    #[cfg(not(target_os = "linux"))]
+    pub fn send<F>(&mut self, socket: &UdpSocket, mut on_result: F)
+    where
+        F: FnMut(T, io::Result<usize>),
+    {
+        for (((buffer, length), address), metadata) in self
+            .buffers
+            .iter()
+            .zip(self.lengths.iter())
+            .zip(self.addresses.iter())
+            .zip(self.metadata.drain(..))
+        {
+            let address = address
+                .as_socket()
+                .expect("SendBatch: address is not an inet address");
+
+            on_result(metadata, socket.send_to(&buffer[..*length], address));
+        }
+
+        self.lengths.clear();
+        self.addresses.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn bind_localhost() -> UdpSocket {
+        UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap()
+    }
+
+    /// Send and receive two batches, checking that buffers and headers
+    /// are reused correctly
+    #[test]
+    fn test_send_and_recv_batch() {
+        let sender = bind_localhost();
+        let receiver = bind_localhost();
+
+        let sender_addr = sender.local_addr().unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        let mut send_batch = SendBatch::new(4);
+        let mut recv_batch = RecvBatch::new(8);
+
+        for round in 0..2u8 {
+            // Send shorter datagrams in second round
+            let datagram_len = |i: u8| (4 - round) as usize * (i as usize + 1);
+
+            for i in 0..4u8 {
+                send_batch
+                    .push(receiver_addr, i, |i, buffer| {
+                        let len = datagram_len(*i);
+
+                        buffer[..len].fill(*i);
+
+                        Ok(len)
+                    })
+                    .unwrap();
+            }
+
+            assert!(send_batch.is_full());
+
+            let mut results = Vec::new();
+
+            send_batch.send(&sender, |i, result| results.push((i, result.unwrap())));
+
+            let expected_results: Vec<(u8, usize)> =
+                (0..4u8).map(|i| (i, datagram_len(i))).collect();
+
+            assert!(send_batch.is_empty());
+            assert_eq!(results, expected_results);
+
+            let mut received = Vec::new();
+            let deadline = Instant::now() + Duration::from_secs(5);
+
+            while received.len() < 4 && Instant::now() < deadline {
+                match recv_batch.recv(&receiver) {
+                    Ok(num_received) => {
+                        for i in 0..num_received {
+                            let (data, addr) = recv_batch.get(i);
+
+                            assert_eq!(addr, Some(sender_addr));
+
+                            received.push(data.to_vec());
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        ::std::thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(err) => panic!("recv error: {:#}", err),
+                }
+            }
+
+            let expected: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; datagram_len(i)]).collect();
+
+            assert_eq!(received, expected);
+        }
+    }
+}
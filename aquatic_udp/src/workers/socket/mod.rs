@@ -1,12 +1,18 @@
+pub mod batch;
 mod storage;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 pub mod validator;
 
-use std::io::{Cursor, ErrorKind};
+use std::io::{self, Cursor, ErrorKind};
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
+use aquatic_common::client_filter::{create_client_filter_cache, ClientFilterCache};
+use aquatic_common::url_access::{create_url_access_list_cache, UrlAccessListCache};
 use aquatic_common::ServerStartInstant;
 use crossbeam_channel::Receiver;
 use mio::net::UdpSocket;
@@ -22,6 +28,7 @@ use aquatic_udp_protocol::*;
 use crate::common::*;
 use crate::config::Config;
 
+use batch::{RecvBatch, SendBatch};
 use storage::PendingScrapeResponseSlab;
 use validator::ConnectionValidator;
 
@@ -49,11 +56,12 @@ pub struct SocketWorker {
     request_sender: ConnectedRequestSender,
     response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
     access_list_cache: AccessListCache,
+    client_filter_cache: ClientFilterCache,
+    url_access_list_cache: UrlAccessListCache,
     validator: ConnectionValidator,
     server_start_instant: ServerStartInstant,
     pending_scrape_responses: PendingScrapeResponseSlab,
     socket: UdpSocket,
-    buffer: [u8; BUFFER_SIZE],
 }
 
 impl SocketWorker {
@@ -70,6 +78,8 @@ impl SocketWorker {
         let socket =
             UdpSocket::from_std(create_socket(&config, priv_dropper).expect("create socket"));
         let access_list_cache = create_access_list_cache(&shared_state.access_list);
+        let client_filter_cache = create_client_filter_cache(&shared_state.client_filter);
+        let url_access_list_cache = create_url_access_list_cache(&shared_state.url_access_list);
 
         let mut worker = Self {
             config,
@@ -79,11 +89,17 @@ impl SocketWorker {
             request_sender,
             response_receiver,
             access_list_cache,
+            client_filter_cache,
+            url_access_list_cache,
             pending_scrape_responses: Default::default(),
             socket,
-            buffer: [0; BUFFER_SIZE],
         };
 
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Err(err) = worker.run_inner_io_uring() {
+            ::log::warn!("Could not use io_uring, falling back to mio: {:#}", err);
+        }
+
         worker.run_inner();
     }
 
@@ -92,6 +108,9 @@ impl SocketWorker {
         let mut opt_resend_buffer =
             (self.config.network.resend_buffer_max_len > 0).then_some(Vec::new());
 
+        let mut recv_batch = RecvBatch::new(self.config.network.batch_size);
+        let mut send_batch = SendBatch::new(self.config.network.batch_size);
+
         let mut events = Events::with_capacity(self.config.network.poll_event_capacity);
         let mut poll = Poll::new().expect("create poll");
 
@@ -101,9 +120,6 @@ impl SocketWorker {
 
         let poll_timeout = Duration::from_millis(self.config.network.poll_timeout_ms);
 
-        let pending_scrape_cleaning_duration =
-            Duration::from_secs(self.config.cleaning.pending_scrape_cleaning_interval);
-
         let mut pending_scrape_valid_until = ValidUntil::new(
             self.server_start_instant,
             self.config.cleaning.max_pending_scrape_age,
@@ -118,7 +134,11 @@ impl SocketWorker {
 
             for event in events.iter() {
                 if event.is_readable() {
-                    self.read_and_handle_requests(&mut local_responses, pending_scrape_valid_until);
+                    self.read_and_handle_requests(
+                        &mut recv_batch,
+                        &mut local_responses,
+                        pending_scrape_valid_until,
+                    );
                 }
             }
 
@@ -128,13 +148,21 @@ impl SocketWorker {
                     Self::send_response(
                         &self.config,
                         &self.shared_state,
-                        &mut self.socket,
-                        &mut self.buffer,
+                        &self.socket,
+                        &mut send_batch,
                         &mut None,
                         response,
                         addr,
                     );
                 }
+
+                Self::send_batch(
+                    &self.config,
+                    &self.shared_state,
+                    &self.socket,
+                    &mut send_batch,
+                    &mut None,
+                );
             }
 
             // Send any connect and error responses generated by this socket worker
@@ -142,8 +170,8 @@ impl SocketWorker {
                 Self::send_response(
                     &self.config,
                     &self.shared_state,
-                    &mut self.socket,
-                    &mut self.buffer,
+                    &self.socket,
+                    &mut send_batch,
                     &mut opt_resend_buffer,
                     response,
                     addr,
@@ -152,21 +180,14 @@ impl SocketWorker {
 
             // Check channel for any responses generated by swarm workers
             for (response, addr) in self.response_receiver.try_iter() {
-                let opt_response = match response {
-                    ConnectedResponse::Scrape(r) => self
-                        .pending_scrape_responses
-                        .add_and_get_finished(r)
-                        .map(Response::Scrape),
-                    ConnectedResponse::AnnounceIpv4(r) => Some(Response::AnnounceIpv4(r)),
-                    ConnectedResponse::AnnounceIpv6(r) => Some(Response::AnnounceIpv6(r)),
-                };
-
-                if let Some(response) = opt_response {
+                if let Some(response) =
+                    prepare_swarm_response(&mut self.pending_scrape_responses, response)
+                {
                     Self::send_response(
                         &self.config,
                         &self.shared_state,
-                        &mut self.socket,
-                        &mut self.buffer,
+                        &self.socket,
+                        &mut send_batch,
                         &mut opt_resend_buffer,
                         response,
                         addr,
@@ -174,22 +195,20 @@ impl SocketWorker {
                 }
             }
 
+            Self::send_batch(
+                &self.config,
+                &self.shared_state,
+                &self.socket,
+                &mut send_batch,
+                &mut opt_resend_buffer,
+            );
+
             // Run periodic ValidUntil updates and state cleaning
             if iter_counter % 256 == 0 {
-                let seconds_since_start = self.server_start_instant.seconds_elapsed();
-
-                pending_scrape_valid_until = ValidUntil::new_with_now(
-                    seconds_since_start,
-                    self.config.cleaning.max_pending_scrape_age,
+                self.update_pending_scrape_state(
+                    &mut pending_scrape_valid_until,
+                    &mut last_pending_scrape_cleaning,
                 );
-
-                let now = Instant::now();
-
-                if now > last_pending_scrape_cleaning + pending_scrape_cleaning_duration {
-                    self.pending_scrape_responses.clean(seconds_since_start);
-
-                    last_pending_scrape_cleaning = now;
-                }
             }
 
             iter_counter = iter_counter.wrapping_add(1);
@@ -198,73 +217,29 @@ impl SocketWorker {
 
     fn read_and_handle_requests(
         &mut self,
+        recv_batch: &mut RecvBatch,
         local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
         pending_scrape_valid_until: ValidUntil,
     ) {
-        let mut requests_received_ipv4: usize = 0;
-        let mut requests_received_ipv6: usize = 0;
-        let mut bytes_received_ipv4: usize = 0;
-        let mut bytes_received_ipv6 = 0;
+        let mut statistics = ReceivedStatistics::default();
 
         loop {
-            match self.socket.recv_from(&mut self.buffer[..]) {
-                Ok((bytes_read, src)) => {
-                    if src.port() == 0 {
-                        ::log::info!("Ignored request from {} because source port is zero", src);
+            match recv_batch.recv(&self.socket) {
+                Ok(num_received) => {
+                    for i in 0..num_received {
+                        let (data, opt_src) = recv_batch.get(i);
 
-                        continue;
-                    }
-
-                    let src = CanonicalSocketAddr::new(src);
-
-                    let request_parsable = match Request::from_bytes(
-                        &self.buffer[..bytes_read],
-                        self.config.protocol.max_scrape_torrents,
-                    ) {
-                        Ok(request) => {
-                            self.handle_request(
+                        if let Some(src) = opt_src {
+                            self.handle_datagram(
                                 local_responses,
                                 pending_scrape_valid_until,
-                                request,
+                                &mut statistics,
+                                data,
                                 src,
                             );
-
-                            true
-                        }
-                        Err(err) => {
-                            ::log::debug!("Request::from_bytes error: {:?}", err);
-
-                            if let RequestParseError::Sendable {
-                                connection_id,
-                                transaction_id,
-                                err,
-                            } = err
-                            {
-                                if self.validator.connection_id_valid(src, connection_id) {
-                                    let response = ErrorResponse {
-                                        transaction_id,
-                                        message: err.right_or("Parse error").into(),
-                                    };
-
-                                    local_responses.push((response.into(), src));
-                                }
-                            }
-
-                            false
-                        }
-                    };
-
-                    // Update statistics for converted address
-                    if src.is_ipv4() {
-                        if request_parsable {
-                            requests_received_ipv4 += 1;
-                        }
-                        bytes_received_ipv4 += bytes_read + EXTRA_PACKET_SIZE_IPV4;
-                    } else {
-                        if request_parsable {
-                            requests_received_ipv6 += 1;
+                        } else {
+                            ::log::warn!("recv_from error: couldn't parse source address");
                         }
-                        bytes_received_ipv6 += bytes_read + EXTRA_PACKET_SIZE_IPV6;
                     }
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
@@ -277,25 +252,64 @@ impl SocketWorker {
         }
 
         if self.config.statistics.active() {
+            statistics.add_to_shared_state(&self.shared_state);
+        }
+        if self.config.adaptive_announce_interval.active {
             self.shared_state
-                .statistics_ipv4
-                .requests_received
-                .fetch_add(requests_received_ipv4, Ordering::Relaxed);
-            self.shared_state
-                .statistics_ipv6
-                .requests_received
-                .fetch_add(requests_received_ipv6, Ordering::Relaxed);
-            self.shared_state
-                .statistics_ipv4
-                .bytes_received
-                .fetch_add(bytes_received_ipv4, Ordering::Relaxed);
-            self.shared_state
-                .statistics_ipv6
-                .bytes_received
-                .fetch_add(bytes_received_ipv6, Ordering::Relaxed);
+                .request_rate
+                .add(statistics.requests_ipv4 + statistics.requests_ipv6);
         }
     }
 
+    fn handle_datagram(
+        &mut self,
+        local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+        pending_scrape_valid_until: ValidUntil,
+        statistics: &mut ReceivedStatistics,
+        data: &[u8],
+        src: SocketAddr,
+    ) {
+        if src.port() == 0 {
+            ::log::info!("Ignored request from {} because source port is zero", src);
+
+            return;
+        }
+
+        let src = CanonicalSocketAddr::new(src);
+
+        let request_parsable =
+            match Request::from_bytes(data, self.config.protocol.max_scrape_torrents) {
+                Ok(request) => {
+                    self.handle_request(local_responses, pending_scrape_valid_until, request, src);
+
+                    true
+                }
+                Err(err) => {
+                    ::log::debug!("Request::from_bytes error: {:?}", err);
+
+                    if let RequestParseError::Sendable {
+                        connection_id,
+                        transaction_id,
+                        err,
+                    } = err
+                    {
+                        if self.validator.connection_id_valid(src, connection_id) {
+                            let response = ErrorResponse {
+                                transaction_id,
+                                message: err.right_or("Parse error").into(),
+                            };
+
+                            local_responses.push((response.into(), src));
+                        }
+                    }
+
+                    false
+                }
+            };
+
+        statistics.update(src, data.len(), request_parsable);
+    }
+
     fn handle_request(
         &mut self,
         local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
@@ -321,11 +335,40 @@ impl SocketWorker {
                     .validator
                     .connection_id_valid(src, request.connection_id)
                 {
-                    if self
+                    if let Err(rejection) = self
+                        .url_access_list_cache
+                        .load()
+                        .check(&self.config.url_access, &request.url_data)
+                    {
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: rejection.to_string().into(),
+                        });
+
+                        local_responses.push((response, src))
+                    } else if !self
                         .access_list_cache
                         .load()
                         .allows(access_list_mode, &request.info_hash.0)
                     {
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: "Info hash not allowed".into(),
+                        });
+
+                        local_responses.push((response, src))
+                    } else if let Err(rejection) = self
+                        .client_filter_cache
+                        .load()
+                        .check(self.config.client_filter.mode, &request.peer_id.0)
+                    {
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: rejection.to_string().into(),
+                        });
+
+                        local_responses.push((response, src))
+                    } else {
                         let worker_index =
                             SwarmWorkerIndex::from_info_hash(&self.config, request.info_hash);
 
@@ -334,13 +377,6 @@ impl SocketWorker {
                             ConnectedRequest::Announce(request),
                             src,
                         );
-                    } else {
-                        let response = Response::Error(ErrorResponse {
-                            transaction_id: request.transaction_id,
-                            message: "Info hash not allowed".into(),
-                        });
-
-                        local_responses.push((response, src))
                     }
                 }
             }
@@ -367,88 +403,225 @@ impl SocketWorker {
         }
     }
 
+    fn update_pending_scrape_state(
+        &mut self,
+        pending_scrape_valid_until: &mut ValidUntil,
+        last_pending_scrape_cleaning: &mut Instant,
+    ) {
+        let seconds_since_start = self.server_start_instant.seconds_elapsed();
+
+        *pending_scrape_valid_until = ValidUntil::new_with_now(
+            seconds_since_start,
+            self.config.cleaning.max_pending_scrape_age,
+        );
+
+        let now = Instant::now();
+        let pending_scrape_cleaning_duration =
+            Duration::from_secs(self.config.cleaning.pending_scrape_cleaning_interval);
+
+        if now > *last_pending_scrape_cleaning + pending_scrape_cleaning_duration {
+            self.pending_scrape_responses.clean(seconds_since_start);
+
+            *last_pending_scrape_cleaning = now;
+        }
+    }
+
+    /// Add response to send batch, sending the batch if it is full
     fn send_response(
         config: &Config,
         shared_state: &State,
-        socket: &mut UdpSocket,
-        buffer: &mut [u8],
+        socket: &UdpSocket,
+        send_batch: &mut SendBatch<(Response, CanonicalSocketAddr)>,
         opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
         response: Response,
         canonical_addr: CanonicalSocketAddr,
     ) {
-        let mut cursor = Cursor::new(buffer);
+        let addr = response_socket_addr(config, canonical_addr);
+
+        let result = send_batch.push(addr, (response, canonical_addr), |(response, _), buffer| {
+            let mut cursor = Cursor::new(buffer);
+
+            response.write(&mut cursor)?;
+
+            Ok(cursor.position() as usize)
+        });
 
-        if let Err(err) = response.write(&mut cursor) {
+        if let Err(err) = result {
             ::log::error!("Converting response to bytes failed: {:#}", err);
 
             return;
         }
 
-        let bytes_written = cursor.position() as usize;
+        if send_batch.is_full() {
+            Self::send_batch(config, shared_state, socket, send_batch, opt_resend_buffer);
+        }
+    }
 
-        let addr = if config.network.address.is_ipv4() {
-            canonical_addr
-                .get_ipv4()
-                .expect("found peer ipv6 address while running bound to ipv4 address")
-        } else {
-            canonical_addr.get_ipv6_mapped()
-        };
+    fn send_batch(
+        config: &Config,
+        shared_state: &State,
+        socket: &UdpSocket,
+        send_batch: &mut SendBatch<(Response, CanonicalSocketAddr)>,
+        opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+    ) {
+        if send_batch.is_empty() {
+            return;
+        }
 
-        match socket.send_to(&cursor.get_ref()[..bytes_written], addr) {
+        send_batch.send(socket, |(response, canonical_addr), result| match result {
             Ok(amt) if config.statistics.active() => {
-                let stats = if canonical_addr.is_ipv4() {
-                    let stats = &shared_state.statistics_ipv4;
+                update_sent_statistics(shared_state, &response, canonical_addr, amt);
+            }
+            Ok(_) => (),
+            Err(err) => {
+                handle_send_error(config, opt_resend_buffer, response, canonical_addr, err);
+            }
+        });
+    }
+}
 
-                    stats
-                        .bytes_sent
-                        .fetch_add(amt + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+/// Counts of received requests and bytes, to be added to shared statistics
+/// after a batch of datagrams has been handled
+#[derive(Default)]
+struct ReceivedStatistics {
+    requests_ipv4: usize,
+    requests_ipv6: usize,
+    bytes_ipv4: usize,
+    bytes_ipv6: usize,
+}
 
-                    stats
-                } else {
-                    let stats = &shared_state.statistics_ipv6;
+impl ReceivedStatistics {
+    fn update(&mut self, src: CanonicalSocketAddr, bytes_read: usize, request_parsable: bool) {
+        // Update statistics for converted address
+        if src.is_ipv4() {
+            if request_parsable {
+                self.requests_ipv4 += 1;
+            }
+            self.bytes_ipv4 += bytes_read + EXTRA_PACKET_SIZE_IPV4;
+        } else {
+            if request_parsable {
+                self.requests_ipv6 += 1;
+            }
+            self.bytes_ipv6 += bytes_read + EXTRA_PACKET_SIZE_IPV6;
+        }
+    }
 
-                    stats
-                        .bytes_sent
-                        .fetch_add(amt + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+    fn add_to_shared_state(&self, shared_state: &State) {
+        shared_state
+            .statistics_ipv4
+            .requests_received
+            .fetch_add(self.requests_ipv4, Ordering::Relaxed);
+        shared_state
+            .statistics_ipv6
+            .requests_received
+            .fetch_add(self.requests_ipv6, Ordering::Relaxed);
+        shared_state
+            .statistics_ipv4
+            .bytes_received
+            .fetch_add(self.bytes_ipv4, Ordering::Relaxed);
+        shared_state
+            .statistics_ipv6
+            .bytes_received
+            .fetch_add(self.bytes_ipv6, Ordering::Relaxed);
+    }
+}
 
-                    stats
-                };
+/// Convert response from swarm worker to response that can be sent to peer.
+/// Returns None for scrape responses still waiting for other swarm workers.
+fn prepare_swarm_response(
+    pending_scrape_responses: &mut PendingScrapeResponseSlab,
+    response: ConnectedResponse,
+) -> Option<Response> {
+    match response {
+        ConnectedResponse::Scrape(r) => pending_scrape_responses
+            .add_and_get_finished(r)
+            .map(Response::Scrape),
+        ConnectedResponse::AnnounceIpv4(r) => Some(Response::AnnounceIpv4(r)),
+        ConnectedResponse::AnnounceIpv6(r) => Some(Response::AnnounceIpv6(r)),
+        ConnectedResponse::Error(r) => Some(Response::Error(r)),
+    }
+}
 
-                match response {
-                    Response::Connect(_) => {
-                        stats.responses_sent_connect.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
-                        stats
-                            .responses_sent_announce
-                            .fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::Scrape(_) => {
-                        stats.responses_sent_scrape.fetch_add(1, Ordering::Relaxed);
-                    }
-                    Response::Error(_) => {
-                        stats.responses_sent_error.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-            }
-            Ok(_) => (),
-            Err(err) => match opt_resend_buffer.as_mut() {
-                Some(resend_buffer)
-                    if (err.raw_os_error() == Some(libc::ENOBUFS))
-                        || (err.kind() == ErrorKind::WouldBlock) =>
-                {
-                    if resend_buffer.len() < config.network.resend_buffer_max_len {
-                        ::log::info!("Adding response to resend queue, since sending it to {} failed with: {:#}", addr, err);
+fn response_socket_addr(config: &Config, canonical_addr: CanonicalSocketAddr) -> SocketAddr {
+    if config.network.address.is_ipv4() {
+        canonical_addr
+            .get_ipv4()
+            .expect("found peer ipv6 address while running bound to ipv4 address")
+    } else {
+        canonical_addr.get_ipv6_mapped()
+    }
+}
 
-                        resend_buffer.push((response, canonical_addr));
-                    } else {
-                        ::log::warn!("Response resend buffer full, dropping response");
-                    }
-                }
-                _ => {
-                    ::log::warn!("Sending response to {} failed: {:#}", addr, err);
-                }
-            },
+fn update_sent_statistics(
+    shared_state: &State,
+    response: &Response,
+    canonical_addr: CanonicalSocketAddr,
+    amt: usize,
+) {
+    let stats = if canonical_addr.is_ipv4() {
+        let stats = &shared_state.statistics_ipv4;
+
+        stats
+            .bytes_sent
+            .fetch_add(amt + EXTRA_PACKET_SIZE_IPV4, Ordering::Relaxed);
+
+        stats
+    } else {
+        let stats = &shared_state.statistics_ipv6;
+
+        stats
+            .bytes_sent
+            .fetch_add(amt + EXTRA_PACKET_SIZE_IPV6, Ordering::Relaxed);
+
+        stats
+    };
+
+    match response {
+        Response::Connect(_) => {
+            stats.responses_sent_connect.fetch_add(1, Ordering::Relaxed);
+        }
+        Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
+            stats
+                .responses_sent_announce
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        Response::Scrape(_) => {
+            stats.responses_sent_scrape.fetch_add(1, Ordering::Relaxed);
+        }
+        Response::Error(_) => {
+            stats.responses_sent_error.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn handle_send_error(
+    config: &Config,
+    opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+    response: Response,
+    canonical_addr: CanonicalSocketAddr,
+    err: io::Error,
+) {
+    let addr = response_socket_addr(config, canonical_addr);
+
+    match opt_resend_buffer.as_mut() {
+        Some(resend_buffer)
+            if (err.raw_os_error() == Some(libc::ENOBUFS))
+                || (err.kind() == ErrorKind::WouldBlock) =>
+        {
+            if resend_buffer.len() < config.network.resend_buffer_max_len {
+                ::log::info!(
+                    "Adding response to resend queue, since sending it to {} failed with: {:#}",
+                    addr,
+                    err
+                );
+
+                resend_buffer.push((response, canonical_addr));
+            } else {
+                ::log::warn!("Response resend buffer full, dropping response");
+            }
+        }
+        _ => {
+            ::log::warn!("Sending response to {} failed: {:#}", addr, err);
         }
     }
 }
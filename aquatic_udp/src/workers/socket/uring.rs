@@ -0,0 +1,375 @@
+//! Socket worker event loop based on io_uring
+//!
+//! A fixed number of recvmsg and sendmsg operations are kept in flight, each
+//! with its own buffer. A repeating timeout operation makes sure that
+//! responses from swarm workers are sent even when no requests arrive.
+
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_common::{CanonicalSocketAddr, ValidUntil};
+use aquatic_udp_protocol::Response;
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::common::BUFFER_SIZE;
+use crate::config::Config;
+
+use super::{
+    handle_send_error, prepare_swarm_response, response_socket_addr, update_sent_statistics,
+    ReceivedStatistics, SocketWorker,
+};
+
+const USER_DATA_TIMEOUT: u64 = u64::MAX;
+/// Set in user data of send operations to tell them apart from receive
+/// operations. Remaining bits contain slot index.
+const USER_DATA_SEND_FLAG: u64 = 1 << 32;
+
+impl SocketWorker {
+    /// Run event loop using io_uring
+    ///
+    /// Only returns if io_uring setup fails, in which case the caller can fall
+    /// back to `run_inner`.
+    pub(super) fn run_inner_io_uring(&mut self) -> anyhow::Result<()> {
+        let batch_size = self.config.network.batch_size.max(1);
+        let entries = (batch_size * 2 + 1).next_power_of_two();
+
+        let mut recv_slots = Slot::create_slots(batch_size);
+        let mut send_slots = SendSlots::new(batch_size);
+
+        // Declared after slots, so that it is dropped before them
+        let mut ring = IoUring::new(entries as u32).with_context(|| "create io_uring instance")?;
+
+        // Without fast poll, operations on sockets that aren't ready would
+        // block kernel worker threads
+        if !ring.params().is_feature_fast_poll() {
+            return Err(anyhow::anyhow!(
+                "kernel doesn't support IORING_FEAT_FAST_POLL"
+            ));
+        }
+
+        // io_uring waits for socket readiness by itself, but returns
+        // EAGAIN errors to userspace when socket is in nonblocking mode
+        socket2::SockRef::from(&self.socket)
+            .set_nonblocking(false)
+            .with_context(|| "socket: unset nonblocking")?;
+
+        ::log::info!("using io_uring for socket I/O");
+
+        let fd = types::Fd(self.socket.as_raw_fd());
+        let timeout =
+            types::Timespec::from(Duration::from_millis(self.config.network.poll_timeout_ms));
+        let mut timeout_in_flight = false;
+
+        let mut local_responses = Vec::new();
+        let mut opt_resend_buffer =
+            (self.config.network.resend_buffer_max_len > 0).then_some(Vec::new());
+        let mut completions = Vec::with_capacity(entries * 2);
+
+        let mut pending_scrape_valid_until = ValidUntil::new(
+            self.server_start_instant,
+            self.config.cleaning.max_pending_scrape_age,
+        );
+        let mut last_pending_scrape_cleaning = Instant::now();
+
+        let mut iter_counter = 0usize;
+
+        for (index, slot) in recv_slots.iter_mut().enumerate() {
+            push_entry(&mut ring, &slot.recv_entry(fd, index as u64));
+        }
+
+        loop {
+            if !timeout_in_flight {
+                let entry = opcode::Timeout::new(&timeout)
+                    .build()
+                    .user_data(USER_DATA_TIMEOUT);
+
+                push_entry(&mut ring, &entry);
+
+                timeout_in_flight = true;
+            }
+
+            match ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                // Completion queue is full. Entries will be submitted on
+                // next iteration, after completions have been processed.
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => (),
+                Err(err) => panic!("io_uring submit_and_wait failed: {:#}", err),
+            }
+
+            completions.extend(
+                ring.completion()
+                    .map(|entry| (entry.user_data(), entry.result())),
+            );
+
+            let mut statistics = ReceivedStatistics::default();
+
+            for (user_data, result) in completions.drain(..) {
+                if user_data == USER_DATA_TIMEOUT {
+                    timeout_in_flight = false;
+                } else if user_data & USER_DATA_SEND_FLAG != 0 {
+                    let index = (user_data ^ USER_DATA_SEND_FLAG) as usize;
+                    let (response, canonical_addr, resend_on_failure) = send_slots.complete(index);
+
+                    if result >= 0 {
+                        if self.config.statistics.active() {
+                            update_sent_statistics(
+                                &self.shared_state,
+                                &response,
+                                canonical_addr,
+                                result as usize,
+                            );
+                        }
+                    } else {
+                        let opt_resend_buffer = if resend_on_failure {
+                            &mut opt_resend_buffer
+                        } else {
+                            &mut None
+                        };
+
+                        handle_send_error(
+                            &self.config,
+                            opt_resend_buffer,
+                            response,
+                            canonical_addr,
+                            io::Error::from_raw_os_error(-result),
+                        );
+                    }
+                } else {
+                    let index = user_data as usize;
+                    let slot = &mut recv_slots[index];
+
+                    if result >= 0 {
+                        let (data, opt_src) = slot.received(result as usize);
+
+                        if let Some(src) = opt_src {
+                            self.handle_datagram(
+                                &mut local_responses,
+                                pending_scrape_valid_until,
+                                &mut statistics,
+                                data,
+                                src,
+                            );
+                        } else {
+                            ::log::warn!("recvmsg error: couldn't parse source address");
+                        }
+                    } else {
+                        ::log::warn!("recvmsg error: {:#}", io::Error::from_raw_os_error(-result));
+                    }
+
+                    push_entry(&mut ring, &slot.recv_entry(fd, user_data));
+                }
+            }
+
+            if self.config.statistics.active() {
+                statistics.add_to_shared_state(&self.shared_state);
+            }
+            if self.config.adaptive_announce_interval.active {
+                self.shared_state
+                    .request_rate
+                    .add(statistics.requests_ipv4 + statistics.requests_ipv6);
+            }
+
+            // Responses that don't fit in free send slots are kept for next
+            // iteration. Failed responses from resend buffer are not retried.
+            if let Some(resend_buffer) = opt_resend_buffer.as_mut() {
+                let num_to_send = send_slots.num_free().min(resend_buffer.len());
+
+                for (response, addr) in resend_buffer.drain(..num_to_send) {
+                    send_slots.submit(&mut ring, fd, &self.config, response, addr, false);
+                }
+            }
+
+            let num_to_send = send_slots.num_free().min(local_responses.len());
+
+            for (response, addr) in local_responses.drain(..num_to_send) {
+                send_slots.submit(&mut ring, fd, &self.config, response, addr, true);
+            }
+
+            while send_slots.num_free() > 0 {
+                match self.response_receiver.try_recv() {
+                    Ok((response, addr)) => {
+                        if let Some(response) =
+                            prepare_swarm_response(&mut self.pending_scrape_responses, response)
+                        {
+                            send_slots.submit(&mut ring, fd, &self.config, response, addr, true);
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            // Run periodic ValidUntil updates and state cleaning
+            if iter_counter % 256 == 0 {
+                self.update_pending_scrape_state(
+                    &mut pending_scrape_valid_until,
+                    &mut last_pending_scrape_cleaning,
+                );
+            }
+
+            iter_counter = iter_counter.wrapping_add(1);
+        }
+    }
+}
+
+/// Push entry to submission queue, submitting queued entries first if it
+/// is full
+fn push_entry(ring: &mut IoUring, entry: &squeue::Entry) {
+    // Safety: entries only point to slot buffers and timeout, which are not
+    // dropped or moved while the ring exists
+    if unsafe { ring.submission().push(entry) }.is_err() {
+        ring.submit().expect("io_uring submit");
+
+        unsafe { ring.submission().push(entry) }.expect("push to io_uring submission queue");
+    }
+}
+
+/// Buffer and message header for a single recvmsg or sendmsg operation
+///
+/// The kernel accesses the buffer and address through pointers in the header
+/// while an operation is in flight, so slots are heap allocated by
+/// `create_slots` and never moved after that.
+struct Slot {
+    buffer: [u8; BUFFER_SIZE],
+    address: libc::sockaddr_storage,
+    iovec: libc::iovec,
+    header: libc::msghdr,
+}
+
+impl Slot {
+    fn create_slots(num: usize) -> Box<[Self]> {
+        let mut slots: Box<[Self]> = (0..num)
+            .map(|_| Self {
+                buffer: [0; BUFFER_SIZE],
+                address: unsafe { ::std::mem::zeroed() },
+                iovec: unsafe { ::std::mem::zeroed() },
+                header: unsafe { ::std::mem::zeroed() },
+            })
+            .collect();
+
+        for slot in slots.iter_mut() {
+            slot.iovec.iov_base = slot.buffer.as_mut_ptr() as *mut libc::c_void;
+            slot.iovec.iov_len = BUFFER_SIZE;
+
+            slot.header.msg_name = &mut slot.address as *mut libc::sockaddr_storage as *mut _;
+            slot.header.msg_namelen = ::std::mem::size_of::<libc::sockaddr_storage>() as _;
+            slot.header.msg_iov = &mut slot.iovec;
+            slot.header.msg_iovlen = 1;
+        }
+
+        slots
+    }
+
+    fn recv_entry(&mut self, fd: types::Fd, user_data: u64) -> squeue::Entry {
+        self.iovec.iov_len = BUFFER_SIZE;
+        self.header.msg_namelen = ::std::mem::size_of::<libc::sockaddr_storage>() as _;
+        self.header.msg_flags = 0;
+
+        opcode::RecvMsg::new(fd, &mut self.header)
+            .build()
+            .user_data(user_data)
+    }
+
+    fn received(&self, bytes_read: usize) -> (&[u8], Option<SocketAddr>) {
+        let address =
+            unsafe { socket2::SockAddr::new(self.address, self.header.msg_namelen) }.as_socket();
+
+        (&self.buffer[..bytes_read], address)
+    }
+
+    fn send_entry(
+        &mut self,
+        fd: types::Fd,
+        addr: SocketAddr,
+        bytes_written: usize,
+    ) -> squeue::Entry {
+        let addr = socket2::SockAddr::from(addr);
+
+        unsafe {
+            ::std::ptr::copy_nonoverlapping(
+                addr.as_ptr() as *const u8,
+                &mut self.address as *mut libc::sockaddr_storage as *mut u8,
+                addr.len() as usize,
+            );
+        }
+
+        self.iovec.iov_len = bytes_written;
+        self.header.msg_namelen = addr.len();
+        self.header.msg_flags = 0;
+
+        opcode::SendMsg::new(fd, &self.header).build()
+    }
+}
+
+/// Send slots and the responses currently being sent from them
+struct SendSlots {
+    slots: Box<[Slot]>,
+    /// Response, address and whether to add response to resend buffer if
+    /// sending fails
+    responses: Vec<Option<(Response, CanonicalSocketAddr, bool)>>,
+    free: Vec<usize>,
+}
+
+impl SendSlots {
+    fn new(num: usize) -> Self {
+        Self {
+            slots: Slot::create_slots(num),
+            responses: (0..num).map(|_| None).collect(),
+            free: (0..num).rev().collect(),
+        }
+    }
+
+    fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Write response to a free slot and submit send operation
+    ///
+    /// Panics if there are no free slots.
+    fn submit(
+        &mut self,
+        ring: &mut IoUring,
+        fd: types::Fd,
+        config: &Config,
+        response: Response,
+        canonical_addr: CanonicalSocketAddr,
+        resend_on_failure: bool,
+    ) {
+        let index = self.free.pop().expect("no free send slots");
+        let slot = &mut self.slots[index];
+
+        let mut cursor = Cursor::new(&mut slot.buffer[..]);
+
+        if let Err(err) = response.write(&mut cursor) {
+            ::log::error!("Converting response to bytes failed: {:#}", err);
+
+            self.free.push(index);
+
+            return;
+        }
+
+        let bytes_written = cursor.position() as usize;
+        let addr = response_socket_addr(config, canonical_addr);
+
+        let entry = slot
+            .send_entry(fd, addr, bytes_written)
+            .user_data(USER_DATA_SEND_FLAG | index as u64);
+
+        push_entry(ring, &entry);
+
+        self.responses[index] = Some((response, canonical_addr, resend_on_failure));
+    }
+
+    /// Mark slot as free after send operation completed, returning the
+    /// response that was sent from it
+    fn complete(&mut self, index: usize) -> (Response, CanonicalSocketAddr, bool) {
+        self.free.push(index);
+
+        self.responses[index]
+            .take()
+            .expect("completed send slot has no response")
+    }
+}
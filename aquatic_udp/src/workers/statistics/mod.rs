@@ -33,10 +33,19 @@ struct TemplateData {
     extended_active: bool,
     ipv4: CollectedStatistics,
     ipv6: CollectedStatistics,
+    client_filter_active: bool,
+    client_filter_rules: Vec<ClientFilterRuleStatistics>,
+    client_filter_num_unmatched: usize,
     last_updated: String,
     peer_update_interval: String,
 }
 
+#[derive(Debug, Serialize)]
+struct ClientFilterRuleStatistics {
+    rule: String,
+    num_matches: usize,
+}
+
 pub fn run_statistics_worker(
     _sentinel: PanicSentinel,
     config: Config,
@@ -73,6 +82,8 @@ pub fn run_statistics_worker(
         let statistics_ipv4 = ipv4_collector.collect_from_shared();
         let statistics_ipv6 = ipv6_collector.collect_from_shared();
 
+        let client_filter = shared_state.client_filter.load();
+
         if config.statistics.print_to_stdout {
             println!("General:");
             println!(
@@ -80,6 +91,28 @@ pub fn run_statistics_worker(
                 shared_state.access_list.load().len()
             );
 
+            if config.client_filter.mode.is_on() {
+                println!("Client filter matches:");
+
+                for (rule, num_matches) in client_filter.match_counts() {
+                    println!("  {:<20} {:>10}", rule, num_matches);
+                }
+
+                println!(
+                    "  {:<20} {:>10}",
+                    "(no match)",
+                    client_filter.num_unmatched()
+                );
+            }
+
+            if config.protocol.peer_announce_min_interval != 0 {
+                println!("Early announces by client:");
+
+                for (client, count) in shared_state.early_announces.counts() {
+                    println!("  {:<20} {:>10}", client, count);
+                }
+            }
+
             if config.network.ipv4_active() {
                 println!("IPv4:");
                 print_to_stdout(&config, &statistics_ipv4);
@@ -100,6 +133,15 @@ pub fn run_statistics_worker(
                 extended_active: config.statistics.extended,
                 ipv4: statistics_ipv4,
                 ipv6: statistics_ipv6,
+                client_filter_active: config.client_filter.mode.is_on(),
+                client_filter_rules: client_filter
+                    .match_counts()
+                    .map(|(rule, num_matches)| ClientFilterRuleStatistics {
+                        rule: rule.to_owned(),
+                        num_matches,
+                    })
+                    .collect(),
+                client_filter_num_unmatched: client_filter.num_unmatched(),
                 last_updated: OffsetDateTime::now_utc()
                     .format(&Rfc2822)
                     .unwrap_or("(formatting error)".into()),
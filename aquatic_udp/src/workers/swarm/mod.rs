@@ -5,7 +5,8 @@ use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::time::Instant;
 
-use aquatic_common::ServerStartInstant;
+use aquatic_common::early_announce::EarlyAnnounceAction;
+use aquatic_common::{SecondsSinceServerStart, ServerStartInstant};
 use crossbeam_channel::Receiver;
 use crossbeam_channel::Sender;
 use rand::{rngs::SmallRng, SeedableRng};
@@ -17,7 +18,7 @@ use aquatic_udp_protocol::*;
 use crate::common::*;
 use crate::config::Config;
 
-use storage::{TorrentMap, TorrentMaps};
+use storage::{TorrentData, TorrentMap, TorrentMaps};
 
 pub fn run_swarm_worker(
     _sentinel: PanicSentinel,
@@ -33,7 +34,7 @@ pub fn run_swarm_worker(
     let mut rng = SmallRng::from_entropy();
 
     let timeout = Duration::from_millis(config.request_channel_recv_timeout_ms);
-    let mut peer_valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
+    let mut seconds_elapsed = server_start_instant.seconds_elapsed();
 
     let cleaning_interval = Duration::from_secs(config.cleaning.torrent_cleaning_interval);
     let statistics_update_interval = Duration::from_secs(config.statistics.interval);
@@ -47,28 +48,32 @@ pub fn run_swarm_worker(
         if let Ok((sender_index, request, src)) = request_receiver.recv_timeout(timeout) {
             let response = match (request, src.get().ip()) {
                 (ConnectedRequest::Announce(request), IpAddr::V4(ip)) => {
-                    let response = handle_announce_request(
+                    match handle_announce_request(
                         &config,
                         &mut rng,
                         &mut torrents.ipv4,
+                        &state,
                         request,
                         ip,
-                        peer_valid_until,
-                    );
-
-                    ConnectedResponse::AnnounceIpv4(response)
+                        seconds_elapsed,
+                    ) {
+                        Ok(response) => ConnectedResponse::AnnounceIpv4(response),
+                        Err(response) => ConnectedResponse::Error(response),
+                    }
                 }
                 (ConnectedRequest::Announce(request), IpAddr::V6(ip)) => {
-                    let response = handle_announce_request(
+                    match handle_announce_request(
                         &config,
                         &mut rng,
                         &mut torrents.ipv6,
+                        &state,
                         request,
                         ip,
-                        peer_valid_until,
-                    );
-
-                    ConnectedResponse::AnnounceIpv6(response)
+                        seconds_elapsed,
+                    ) {
+                        Ok(response) => ConnectedResponse::AnnounceIpv6(response),
+                        Err(response) => ConnectedResponse::Error(response),
+                    }
                 }
                 (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => {
                     ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv4, request))
@@ -85,7 +90,7 @@ pub fn run_swarm_worker(
         if iter_counter % 128 == 0 {
             let now = Instant::now();
 
-            peer_valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
+            seconds_elapsed = server_start_instant.seconds_elapsed();
 
             if now > last_cleaning + cleaning_interval {
                 let (ipv4, ipv6) = torrents.clean_and_get_statistics(
@@ -128,14 +133,18 @@ pub fn run_swarm_worker(
     }
 }
 
+/// Handle announce request. Returns error response if request fails peer
+/// identity check, or if it is an early announce and early_announce_action
+/// is set to failure.
 fn handle_announce_request<I: Ip>(
     config: &Config,
     rng: &mut SmallRng,
     torrents: &mut TorrentMap<I>,
+    state: &State,
     request: AnnounceRequest,
     peer_ip: I,
-    peer_valid_until: ValidUntil,
-) -> AnnounceResponse<I> {
+    now: SecondsSinceServerStart,
+) -> Result<AnnounceResponse<I>, ErrorResponse> {
     let max_num_peers_to_take: usize = if request.peers_wanted.0 <= 0 {
         config.protocol.max_response_peers
     } else {
@@ -147,14 +156,56 @@ fn handle_announce_request<I: Ip>(
 
     let torrent_data = torrents.0.entry(request.info_hash).or_default();
 
+    if !torrent_data.may_update_peer(
+        config.protocol.peer_identity_check,
+        request.peer_id,
+        peer_ip,
+        request.key,
+    ) {
+        return Err(ErrorResponse {
+            transaction_id: request.transaction_id,
+            message: "Peer ID in use from other IP address".into(),
+        });
+    }
+
+    let min_interval = config.protocol.peer_announce_min_interval;
+
+    // Don't update peer or select new peers for it if it announced without
+    // event less than the minimum interval ago
+    if min_interval != 0 && request.event == AnnounceEvent::None {
+        if let Some(last_announce) = torrent_data.last_announce(request.peer_id, peer_ip) {
+            if now.seconds_since(last_announce) < min_interval {
+                state.early_announces.increment(&request.peer_id.0);
+
+                return match config.protocol.early_announce_action {
+                    EarlyAnnounceAction::Cached => Ok(AnnounceResponse {
+                        transaction_id: request.transaction_id,
+                        announce_interval: calculate_announce_interval(config, state, torrent_data),
+                        leechers: NumberOfPeers(
+                            torrent_data.num_leechers().try_into().unwrap_or(i32::MAX),
+                        ),
+                        seeders: NumberOfPeers(
+                            torrent_data.num_seeders().try_into().unwrap_or(i32::MAX),
+                        ),
+                        peers: torrent_data.cached_response_peers(request.peer_id),
+                    }),
+                    EarlyAnnounceAction::Failure => Err(ErrorResponse {
+                        transaction_id: request.transaction_id,
+                        message: "Announcing too often".into(),
+                    }),
+                };
+            }
+        }
+    }
+
     let peer_status = PeerStatus::from_event_and_bytes_left(request.event, request.bytes_left);
 
     torrent_data.update_peer(
-        request.peer_id,
+        &request,
         peer_ip,
-        request.port,
         peer_status,
-        peer_valid_until,
+        ValidUntil::new_with_now(now, config.cleaning.max_peer_age),
+        now,
     );
 
     let response_peers = if let PeerStatus::Stopped = peer_status {
@@ -163,13 +214,42 @@ fn handle_announce_request<I: Ip>(
         torrent_data.extract_response_peers(rng, request.peer_id, max_num_peers_to_take)
     };
 
-    AnnounceResponse {
+    // Store response peers for answering early announces
+    if min_interval != 0 && config.protocol.early_announce_action == EarlyAnnounceAction::Cached {
+        if let PeerStatus::Stopped = peer_status {
+            torrent_data.remove_cached_response_peers(request.peer_id);
+        } else {
+            torrent_data.cache_response_peers(request.peer_id, &response_peers);
+        }
+    }
+
+    Ok(AnnounceResponse {
         transaction_id: request.transaction_id,
-        announce_interval: AnnounceInterval(config.protocol.peer_announce_interval),
+        announce_interval: calculate_announce_interval(config, state, torrent_data),
         leechers: NumberOfPeers(torrent_data.num_leechers().try_into().unwrap_or(i32::MAX)),
         seeders: NumberOfPeers(torrent_data.num_seeders().try_into().unwrap_or(i32::MAX)),
         peers: response_peers,
-    }
+    })
+}
+
+/// Adapt configured announce interval to swarm size and request rate if
+/// adaptive announce intervals are active
+fn calculate_announce_interval<I: Ip>(
+    config: &Config,
+    state: &State,
+    torrent_data: &TorrentData<I>,
+) -> AnnounceInterval {
+    let interval = config.adaptive_announce_interval.calculate(
+        config
+            .protocol
+            .peer_announce_interval
+            .try_into()
+            .unwrap_or(0),
+        torrent_data.num_seeders() + torrent_data.num_leechers(),
+        &state.request_rate,
+    );
+
+    AnnounceInterval(interval.try_into().unwrap_or(i32::MAX))
 }
 
 fn handle_scrape_request<I: Ip>(
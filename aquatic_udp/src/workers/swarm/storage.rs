@@ -2,6 +2,7 @@ use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::sync::Arc;
 
+use aquatic_common::peer_identity::PeerIdentityCheck;
 use aquatic_common::IndexMap;
 use aquatic_common::SecondsSinceServerStart;
 use aquatic_common::ServerStartInstant;
@@ -23,8 +24,10 @@ use super::create_torrent_scrape_statistics;
 struct Peer<I: Ip> {
     ip_address: I,
     port: Port,
+    key: PeerKey,
     is_seeder: bool,
     valid_until: ValidUntil,
+    last_announce: SecondsSinceServerStart,
 }
 
 impl<I: Ip> Peer<I> {
@@ -41,41 +44,48 @@ type PeerMap<I> = IndexMap<PeerId, Peer<I>>;
 pub struct TorrentData<I: Ip> {
     peers: PeerMap<I>,
     num_seeders: usize,
+    /// Response peers last sent to each peer. Only filled in when early
+    /// announces are answered with cached responses.
+    cached_response_peers: IndexMap<PeerId, Vec<ResponsePeer<I>>>,
 }
 
 impl<I: Ip> TorrentData<I> {
     pub fn update_peer(
         &mut self,
-        peer_id: PeerId,
+        request: &AnnounceRequest,
         ip_address: I,
-        port: Port,
         status: PeerStatus,
         valid_until: ValidUntil,
+        now: SecondsSinceServerStart,
     ) {
         let opt_removed_peer = match status {
             PeerStatus::Leeching => {
                 let peer = Peer {
                     ip_address,
-                    port,
+                    port: request.port,
+                    key: request.key,
                     is_seeder: false,
                     valid_until,
+                    last_announce: now,
                 };
 
-                self.peers.insert(peer_id, peer)
+                self.peers.insert(request.peer_id, peer)
             }
             PeerStatus::Seeding => {
                 let peer = Peer {
                     ip_address,
-                    port,
+                    port: request.port,
+                    key: request.key,
                     is_seeder: true,
                     valid_until,
+                    last_announce: now,
                 };
 
                 self.num_seeders += 1;
 
-                self.peers.insert(peer_id, peer)
+                self.peers.insert(request.peer_id, peer)
             }
-            PeerStatus::Stopped => self.peers.remove(&peer_id),
+            PeerStatus::Stopped => self.peers.remove(&request.peer_id),
         };
 
         if let Some(Peer {
@@ -86,6 +96,56 @@ impl<I: Ip> TorrentData<I> {
         }
     }
 
+    /// Check if request from given IP address with given key may update
+    /// peer with given peer_id
+    ///
+    /// Since UDP announce requests always include a key, a key of zero is
+    /// treated as no key.
+    pub fn may_update_peer(
+        &self,
+        check: PeerIdentityCheck,
+        peer_id: PeerId,
+        ip_address: I,
+        key: PeerKey,
+    ) -> bool {
+        fn opt_key(key: &PeerKey) -> Option<&PeerKey> {
+            (key.0 != 0).then_some(key)
+        }
+
+        match self.peers.get(&peer_id) {
+            Some(peer) if peer.ip_address != ip_address => {
+                check.allows_ip_change(opt_key(&peer.key), opt_key(&key))
+            }
+            _ => true,
+        }
+    }
+
+    /// Time of last announce from peer with given peer_id and IP address
+    pub fn last_announce(&self, peer_id: PeerId, ip_address: I) -> Option<SecondsSinceServerStart> {
+        self.peers
+            .get(&peer_id)
+            .filter(|peer| peer.ip_address == ip_address)
+            .map(|peer| peer.last_announce)
+    }
+
+    /// Store response peers sent to peer, for answering its early announces
+    pub fn cache_response_peers(&mut self, peer_id: PeerId, response_peers: &[ResponsePeer<I>]) {
+        self.cached_response_peers
+            .insert(peer_id, response_peers.to_vec());
+    }
+
+    /// Response peers last sent to peer with given peer_id
+    pub fn cached_response_peers(&self, peer_id: PeerId) -> Vec<ResponsePeer<I>> {
+        self.cached_response_peers
+            .get(&peer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn remove_cached_response_peers(&mut self, peer_id: PeerId) {
+        self.cached_response_peers.remove(&peer_id);
+    }
+
     pub fn extract_response_peers(
         &self,
         rng: &mut SmallRng,
@@ -128,6 +188,13 @@ impl<I: Ip> TorrentData<I> {
             keep
         });
 
+        if !self.cached_response_peers.is_empty() {
+            let peers = &self.peers;
+
+            self.cached_response_peers
+                .retain(|peer_id, _| peers.contains_key(peer_id));
+        }
+
         if !self.peers.is_empty() {
             self.peers.shrink_to_fit();
         }
@@ -139,6 +206,7 @@ impl<I: Ip> Default for TorrentData<I> {
         Self {
             peers: Default::default(),
             num_seeders: 0,
+            cached_response_peers: Default::default(),
         }
     }
 }
@@ -271,8 +339,10 @@ mod tests {
         Peer {
             ip_address: Ipv4Addr::from(i.to_be_bytes()),
             port: Port(1),
+            key: PeerKey(i),
             is_seeder: false,
             valid_until: ValidUntil::new(ServerStartInstant::new(), 0),
+            last_announce: ServerStartInstant::new().seconds_elapsed(),
         }
     }
 
@@ -340,4 +410,51 @@ mod tests {
 
         quickcheck(prop as fn((u16, u16)) -> TestResult);
     }
+
+    #[test]
+    fn test_may_update_peer() {
+        let peer_id = gen_peer_id(1);
+        let ip_a = Ipv4Addr::new(10, 0, 0, 1);
+        let ip_b = Ipv4Addr::new(10, 0, 0, 2);
+
+        for (peer_key, request_key, off, lenient, strict) in [
+            (1, 1, true, true, true),
+            (1, 2, true, false, false),
+            (1, 0, true, false, false),
+            (0, 1, true, true, false),
+            (0, 0, true, true, false),
+        ] {
+            let mut torrent_data = TorrentData::<Ipv4Addr>::default();
+
+            torrent_data.peers.insert(
+                peer_id,
+                Peer {
+                    ip_address: ip_a,
+                    key: PeerKey(peer_key),
+                    ..gen_peer(1)
+                },
+            );
+
+            for (check, expected) in [
+                (PeerIdentityCheck::Off, off),
+                (PeerIdentityCheck::Lenient, lenient),
+                (PeerIdentityCheck::Strict, strict),
+            ] {
+                assert_eq!(
+                    torrent_data.may_update_peer(check, peer_id, ip_b, PeerKey(request_key)),
+                    expected
+                );
+
+                // Requests from the IP address the peer was registered from
+                // and requests for unknown peers are always allowed
+                assert!(torrent_data.may_update_peer(check, peer_id, ip_a, PeerKey(request_key)));
+                assert!(torrent_data.may_update_peer(
+                    check,
+                    gen_peer_id(2),
+                    ip_b,
+                    PeerKey(request_key)
+                ));
+            }
+        }
+    }
 }
@@ -103,6 +103,7 @@ pub fn create_requests(
             key: PeerKey(rng.gen()),
             peers_wanted: NumberOfPeers(rng.gen()),
             port: Port(rng.gen()),
+            url_data: Vec::new(),
         };
 
         requests.push((
@@ -5,7 +5,9 @@ use std::thread::{self, Builder};
 use std::time::{Duration, Instant};
 
 #[cfg(feature = "cpu-pinning")]
-use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+use aquatic_common::cpu_pinning::{
+    pin_current_if_configured_to, require_system_hyperthread_mapping, WorkerIndex,
+};
 use rand_distr::Gamma;
 
 mod common;
@@ -45,6 +47,9 @@ fn run(config: Config) -> ::anyhow::Result<()> {
         panic!("Error: at least one weight must be larger than zero.");
     }
 
+    #[cfg(feature = "cpu-pinning")]
+    require_system_hyperthread_mapping(&config.cpu_pinning)?;
+
     println!("Starting client with config: {:#?}", config);
 
     let mut info_hashes = Vec::with_capacity(config.requests.number_of_torrents);
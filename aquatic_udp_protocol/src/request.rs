@@ -9,6 +9,11 @@ use super::common::*;
 
 const PROTOCOL_IDENTIFIER: i64 = 4_497_486_125_440;
 
+/// BEP 41 option types
+const OPTION_END_OF_OPTIONS: u8 = 0x0;
+const OPTION_NOP: u8 = 0x1;
+const OPTION_URL_DATA: u8 = 0x2;
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum AnnounceEvent {
     Started,
@@ -58,6 +63,9 @@ pub struct AnnounceRequest {
     pub key: PeerKey,
     pub peers_wanted: NumberOfPeers,
     pub port: Port,
+    /// Path and query of tracker URL, reassembled from BEP 41 URLData
+    /// options. Empty if client didn't send any.
+    pub url_data: Vec<u8>,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -159,6 +167,12 @@ impl Request {
                 bytes.write_u32::<NetworkEndian>(r.key.0)?;
                 bytes.write_i32::<NetworkEndian>(r.peers_wanted.0)?;
                 bytes.write_u16::<NetworkEndian>(r.port.0)?;
+
+                for chunk in r.url_data.chunks(u8::MAX as usize) {
+                    bytes.write_u8(OPTION_URL_DATA)?;
+                    bytes.write_u8(chunk.len() as u8)?;
+                    bytes.write_all(chunk)?;
+                }
             }
 
             Request::Scrape(r) => {
@@ -249,6 +263,9 @@ impl Request {
                     Some(Ipv4Addr::from(ip))
                 };
 
+                let position = cursor.position() as usize;
+                let url_data = parse_url_data(&cursor.into_inner()[position..]);
+
                 Ok((AnnounceRequest {
                     connection_id: ConnectionId(connection_id),
                     transaction_id: TransactionId(transaction_id),
@@ -262,6 +279,7 @@ impl Request {
                     key: PeerKey(key),
                     peers_wanted: NumberOfPeers(peers_wanted),
                     port: Port(port),
+                    url_data,
                 })
                 .into())
             }
@@ -302,6 +320,40 @@ impl Request {
     }
 }
 
+/// Parse BEP 41 options following announce request fields and return
+/// concatenated contents of URLData options
+///
+/// Unknown options are skipped. Parsing stops at EndOfOptions, at end of
+/// input and at options that are truncated.
+fn parse_url_data(mut bytes: &[u8]) -> Vec<u8> {
+    let mut url_data = Vec::new();
+
+    loop {
+        match bytes {
+            [] | [OPTION_END_OF_OPTIONS, ..] => break,
+            [OPTION_NOP, rest @ ..] => {
+                bytes = rest;
+            }
+            [option_type, len, rest @ ..] => {
+                let len = *len as usize;
+
+                if rest.len() < len {
+                    break;
+                }
+
+                if *option_type == OPTION_URL_DATA {
+                    url_data.extend_from_slice(&rest[..len]);
+                }
+
+                bytes = &rest[len..];
+            }
+            [_] => break,
+        }
+    }
+
+    url_data
+}
+
 #[cfg(test)]
 mod tests {
     use quickcheck::TestResult;
@@ -343,6 +395,7 @@ mod tests {
                 key: PeerKey(u32::arbitrary(g)),
                 peers_wanted: NumberOfPeers(i32::arbitrary(g)),
                 port: Port(u16::arbitrary(g)),
+                url_data: Vec::arbitrary(g),
             }
         }
     }
@@ -394,4 +447,30 @@ mod tests {
 
         TestResult::from_bool(same_after_conversion(request.into()))
     }
+
+    #[test]
+    fn test_parse_url_data() {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&[OPTION_URL_DATA, 5]);
+        bytes.extend_from_slice(b"/anno");
+        bytes.push(OPTION_NOP);
+        // Unknown option
+        bytes.extend_from_slice(&[0x7, 2, 1, 1]);
+        bytes.extend_from_slice(&[OPTION_URL_DATA, 4]);
+        bytes.extend_from_slice(b"unce");
+        bytes.push(OPTION_END_OF_OPTIONS);
+        bytes.extend_from_slice(&[OPTION_URL_DATA, 2]);
+        bytes.extend_from_slice(b"?a");
+
+        assert_eq!(parse_url_data(&bytes), b"/announce".to_vec());
+
+        // Truncated option
+        assert_eq!(
+            parse_url_data(&[OPTION_URL_DATA, 1, b'/', OPTION_URL_DATA, 2, b'a']),
+            b"/".to_vec()
+        );
+
+        assert!(parse_url_data(&[]).is_empty());
+    }
 }
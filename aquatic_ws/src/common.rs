@@ -1,10 +1,27 @@
-use std::{net::IpAddr, sync::Arc};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::client_filter::ClientFilterArcSwap;
+use aquatic_common::early_announce::EarlyAnnounceCounts;
+use aquatic_common::shutdown::ShutdownSignal;
+use aquatic_common::CanonicalSocketAddr;
 
 pub use aquatic_common::ValidUntil;
 use aquatic_ws_protocol::{InfoHash, PeerId};
 
+use crate::config::Config;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        pub type ResponseSender<T> = tokio::sync::oneshot::Sender<T>;
+    } else if #[cfg(feature = "glommio")] {
+        pub type ResponseSender<T> = glommio::channels::shared_channel::SharedSender<T>;
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum IpVersion {
     V4,
@@ -26,6 +43,62 @@ impl IpVersion {
 #[derive(Default, Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
+    pub early_announces: Arc<EarlyAnnounceCounts>,
+    pub request_rate: Arc<RequestRate>,
+    pub shutdown: ShutdownSignal,
+    pub compression_statistics: Arc<CompressionStatistics>,
+    /// Number of socket and swarm workers that have finished setting up and
+    /// not yet exited
+    pub num_running_workers: Arc<AtomicUsize>,
+}
+
+/// Sizes of outgoing WebSocket messages compressed with permessage-deflate
+/// since last taken
+#[derive(Default)]
+pub struct CompressionStatistics {
+    messages: AtomicUsize,
+    uncompressed_bytes: AtomicUsize,
+    compressed_bytes: AtomicUsize,
+}
+
+impl CompressionStatistics {
+    pub fn add(&self, uncompressed_bytes: usize, compressed_bytes: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.uncompressed_bytes
+            .fetch_add(uncompressed_bytes, Ordering::Relaxed);
+        self.compressed_bytes
+            .fetch_add(compressed_bytes, Ordering::Relaxed);
+    }
+
+    /// Returns number of messages, uncompressed bytes and compressed bytes
+    /// and resets them to zero
+    pub fn take(&self) -> (usize, usize, usize) {
+        (
+            self.messages.swap(0, Ordering::Relaxed),
+            self.uncompressed_bytes.swap(0, Ordering::Relaxed),
+            self.compressed_bytes.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counts worker as running in State::num_running_workers until dropped,
+/// including when worker thread unwinds because of a panic
+#[must_use]
+pub struct RunningWorkerGuard(Arc<AtomicUsize>);
+
+impl RunningWorkerGuard {
+    pub fn new(num_running_workers: Arc<AtomicUsize>) -> Self {
+        num_running_workers.fetch_add(1, Ordering::Release);
+
+        Self(num_running_workers)
+    }
+}
+
+impl Drop for RunningWorkerGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -74,3 +147,26 @@ pub enum SwarmControlMessage {
         ip_version: IpVersion,
     },
 }
+
+/// Plain HTTP tracker request, only sent when http_tracker.active is set
+#[derive(Debug)]
+pub enum HttpChannelRequest {
+    Announce {
+        request: aquatic_http_protocol::request::AnnounceRequest,
+        peer_addr: CanonicalSocketAddr,
+        response_sender: ResponseSender<aquatic_http_protocol::response::Response>,
+    },
+    Scrape {
+        request: aquatic_http_protocol::request::ScrapeRequest,
+        peer_addr: CanonicalSocketAddr,
+        response_sender: ResponseSender<aquatic_http_protocol::response::ScrapeResponse>,
+    },
+}
+
+/// Index of swarm worker responsible for HTTP tracker torrent
+pub fn calculate_http_request_consumer_index(
+    config: &Config,
+    info_hash: aquatic_http_protocol::common::InfoHash,
+) -> usize {
+    (info_hash.0[0] as usize) % config.swarm_workers
+}
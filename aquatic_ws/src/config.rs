@@ -1,17 +1,22 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
 use aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc;
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
-use serde::Deserialize;
+use aquatic_common::{
+    access_list::AccessListConfig, adaptive_interval::AdaptiveAnnounceIntervalConfig,
+    client_filter::ClientFilterConfig, early_announce::EarlyAnnounceAction,
+    peer_identity::PeerIdentityCheck, privileges::PrivilegeConfig, shutdown::ShutdownConfig,
+};
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 use aquatic_toml_config::TomlConfig;
 
 /// aquatic_ws configuration
 ///
-/// Running behind a reverse proxy is supported, but IPv4 peer requests have
-/// to be proxied to IPv4 requests, and IPv6 requests to IPv6 requests.
+/// Running behind a reverse proxy is supported. Unless network.client_ip_source
+/// is set, IPv4 peer requests have to be proxied to IPv4 requests, and IPv6
+/// requests to IPv6 requests.
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
@@ -24,10 +29,17 @@ pub struct Config {
     pub swarm_workers: usize,
     pub log_level: LogLevel,
     pub network: NetworkConfig,
+    pub websocket_compression: WebSocketCompressionConfig,
     pub protocol: ProtocolConfig,
+    /// Adapt protocol.peer_announce_interval to swarm size and request rate
+    pub adaptive_announce_interval: AdaptiveAnnounceIntervalConfig,
+    pub http_tracker: HttpTrackerConfig,
     pub cleaning: CleaningConfig,
+    pub statistics: StatisticsConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub client_filter: ClientFilterConfig,
+    pub shutdown: ShutdownConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
 }
 
@@ -38,10 +50,16 @@ impl Default for Config {
             swarm_workers: 1,
             log_level: LogLevel::default(),
             network: NetworkConfig::default(),
+            websocket_compression: WebSocketCompressionConfig::default(),
             protocol: ProtocolConfig::default(),
+            adaptive_announce_interval: AdaptiveAnnounceIntervalConfig::default(),
+            http_tracker: HttpTrackerConfig::default(),
             cleaning: CleaningConfig::default(),
+            statistics: StatisticsConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            client_filter: ClientFilterConfig::default(),
+            shutdown: ShutdownConfig::default(),
             cpu_pinning: Default::default(),
         }
     }
@@ -62,6 +80,12 @@ pub struct NetworkConfig {
     pub only_ipv6: bool,
     /// Maximum number of pending TCP connections
     pub tcp_backlog: i32,
+    /// Where to get client IP addresses from when running behind reverse
+    /// proxies
+    pub client_ip_source: ClientIpSource,
+    /// Addresses of reverse proxies trusted to provide client IP addresses.
+    /// Connections from other addresses always use the socket address.
+    pub trusted_proxies: Vec<IpAddr>,
 
     /// Enable TLS
     pub enable_tls: bool,
@@ -72,10 +96,22 @@ pub struct NetworkConfig {
 
     pub websocket_max_message_size: usize,
     pub websocket_max_frame_size: usize,
+    /// Send ping frames to peers this often (seconds). Set to zero to disable.
+    pub websocket_ping_interval: u64,
+    /// Close connection if this many consecutive pings haven't been answered
+    /// with a pong when it is time to send another one. Receiving a pong also
+    /// postpones closing of connection due to max_connection_idle.
+    pub websocket_max_missed_pongs: usize,
 
-    /// Return a HTTP 200 Ok response when receiving GET /health. Can not be
-    /// combined with enable_tls.
+    /// Return a HTTP 200 Ok response when receiving GET /health. Also answer
+    /// GET /ready with 200 Ok once all socket and swarm workers are running
+    /// and with 503 Service Unavailable otherwise. Works both with and without
+    /// TLS.
     pub enable_http_health_checks: bool,
+    /// Maximum size of HTTP request heads (bytes), including those of
+    /// WebSocket upgrade requests. Only used when HTTP health checks or the
+    /// HTTP tracker are enabled.
+    pub max_http_request_head_size: usize,
 }
 
 impl Default for NetworkConfig {
@@ -84,6 +120,8 @@ impl Default for NetworkConfig {
             address: SocketAddr::from(([0, 0, 0, 0], 3000)),
             only_ipv6: false,
             tcp_backlog: 1024,
+            client_ip_source: ClientIpSource::SocketAddress,
+            trusted_proxies: Vec::new(),
 
             enable_tls: false,
             tls_certificate_path: "".into(),
@@ -91,12 +129,58 @@ impl Default for NetworkConfig {
 
             websocket_max_message_size: 64 * 1024,
             websocket_max_frame_size: 16 * 1024,
+            websocket_ping_interval: 60,
+            websocket_max_missed_pongs: 2,
 
             enable_http_health_checks: false,
+            max_http_request_head_size: 8 * 1024,
         }
     }
 }
 
+/// permessage-deflate WebSocket extension (RFC 7692)
+///
+/// Context takeover is disabled in both directions, so each message is
+/// compressed separately. Compression ratio is included in printed
+/// statistics.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketCompressionConfig {
+    /// Negotiate compression with clients offering it
+    pub active: bool,
+    /// Maximum LZ77 window size as a base-2 logarithm (8-15). Clients
+    /// allowing it are asked to use at most this window size. Outgoing
+    /// messages larger than the window size accepted by the client are sent
+    /// uncompressed.
+    pub window_bits: u8,
+    /// Only compress outgoing messages at least this large (bytes)
+    pub min_message_size: usize,
+}
+
+impl Default for WebSocketCompressionConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            window_bits: 15,
+            min_message_size: 256,
+        }
+    }
+}
+
+/// Source of client IP addresses. Available values are socket_address,
+/// x_forwarded_for, forwarded (RFC 7239 header) and proxy_protocol (version 1
+/// or 2). Header values are taken from the WebSocket upgrade request. The
+/// client IP address determines which swarm (IPv4 or IPv6) peers are added
+/// to.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpSource {
+    SocketAddress,
+    XForwardedFor,
+    Forwarded,
+    ProxyProtocol,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ProtocolConfig {
@@ -106,6 +190,36 @@ pub struct ProtocolConfig {
     pub max_offers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Maximum number of torrents that a single connection can announce to
+    pub max_torrents_per_connection: usize,
+    /// Maximum number of messages to accept from a single connection per
+    /// second. Further messages are answered with an error response.
+    pub max_messages_per_second: usize,
+    /// Maximum number of offers to relay from a single connection per second.
+    /// Further offers are dropped.
+    pub max_offers_per_second: usize,
+    /// Only relay offers and answers that are objects consisting of type
+    /// ("offer" or "answer") and sdp fields, with sdp no longer than
+    /// max_sdp_len. Other offers and answers are dropped.
+    pub validate_sdp: bool,
+    /// Maximum length of sdp field in offers and answers (bytes)
+    pub max_sdp_len: usize,
+    /// Before relaying offers and answers, remove ICE candidates of type
+    /// host and those with private, loopback, link-local or mDNS addresses,
+    /// and mask private related addresses in the remaining ones
+    pub strip_private_ice_candidates: bool,
+    /// Minimum time between announces from a peer (seconds)
+    ///
+    /// Announces without event or answer from peers that announced less
+    /// than this long ago are handled according to early_announce_action.
+    /// This also applies to plain HTTP tracker announces. Set to zero to not
+    /// enforce a minimum interval.
+    pub peer_announce_min_interval: usize,
+    /// How to respond to early announces: cached (current statistics,
+    /// without relaying offers, and for plain HTTP tracker announces the
+    /// peers that were sent in response to the last regular announce) or
+    /// failure (error response)
+    pub early_announce_action: EarlyAnnounceAction,
 }
 
 impl Default for ProtocolConfig {
@@ -114,6 +228,46 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 255,
             max_offers: 10,
             peer_announce_interval: 120,
+            max_torrents_per_connection: 100,
+            max_messages_per_second: 50,
+            max_offers_per_second: 100,
+            validate_sdp: false,
+            max_sdp_len: 8 * 1024,
+            strip_private_ice_candidates: false,
+            peer_announce_min_interval: 0,
+            early_announce_action: EarlyAnnounceAction::Cached,
+        }
+    }
+}
+
+/// Plain HTTP BitTorrent tracker served on the same port as the WebTorrent
+/// tracker
+///
+/// When active, connections (with or without TLS) that don't ask for a
+/// WebSocket upgrade can send one HTTP GET request for /announce or /scrape,
+/// which is answered before the connection is closed. HTTP peers are kept in
+/// swarms separate from the WebTorrent ones, since the two kinds of peers
+/// can't connect to each other. Announce interval and maximum number of
+/// torrents in scrape requests are taken from the protocol section.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpTrackerConfig {
+    pub active: bool,
+    /// Maximum number of peers to return in announce responses
+    pub max_peers: usize,
+    /// Check key of announce requests for peer_ids registered from another
+    /// IP address. Requests that fail the check get a failure response and
+    /// don't update the peer. (WebTorrent peers can only be updated over
+    /// the connection they were registered from.)
+    pub peer_identity_check: PeerIdentityCheck,
+}
+
+impl Default for HttpTrackerConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            max_peers: 50,
+            peer_identity_check: PeerIdentityCheck::Lenient,
         }
     }
 }
@@ -142,6 +296,32 @@ impl Default for CleaningConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Print statistics this often (seconds)
+    pub interval: u64,
+    /// Print statistics (currently counts of client filter matches by rule,
+    /// of early announces by client and WebSocket compression ratio) to
+    /// standard output
+    pub print_to_stdout: bool,
+}
+
+impl StatisticsConfig {
+    pub fn active(&self) -> bool {
+        (self.interval != 0) & self.print_to_stdout
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            print_to_stdout: false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Config;
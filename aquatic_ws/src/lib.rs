@@ -3,47 +3,49 @@ pub mod config;
 pub mod workers;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
-use aquatic_common::cpu_pinning::glommio::{get_worker_placement, set_affinity_for_util_worker};
-use aquatic_common::cpu_pinning::WorkerIndex;
-use aquatic_common::rustls_config::create_rustls_config;
-use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
-use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
+use aquatic_common::rustls_config::{create_rustls_config, RustlsConfig};
+use aquatic_common::{PanicSentinel, PanicSentinelWatcher, ServerStartInstant};
 use signal_hook::{
     consts::{SIGTERM, SIGUSR1},
     iterator::Signals,
 };
 
-use aquatic_common::access_list::update_access_list;
+use aquatic_common::access_list::{spawn_access_list_watcher, update_access_list};
+use aquatic_common::client_filter::update_client_filter;
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::shutdown::join_workers_with_timeout;
 
 use common::*;
 use config::Config;
+use workers::swarm::HttpTorrentMaps;
 
 pub const APP_NAME: &str = "aquatic_ws: WebTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const SHARED_IN_CHANNEL_SIZE: usize = 1024;
 
-pub fn run(config: Config) -> ::anyhow::Result<()> {
-    if config.network.enable_tls && config.network.enable_http_health_checks {
-        return Err(anyhow::anyhow!(
-            "configuration: network.enable_tls and network.enable_http_health_check can't both be set to true"
-        ));
-    }
+#[cfg(not(any(feature = "glommio", feature = "tokio")))]
+compile_error!("either the glommio or the tokio feature must be enabled");
 
+pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
+    config
+        .adaptive_announce_interval
+        .validate(config.cleaning.max_peer_age)?;
+
+    #[cfg(feature = "tokio")]
+    aquatic_common::cpu_pinning::require_system_hyperthread_mapping(&config.cpu_pinning)?;
+
     let state = State::default();
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_client_filter(&config.client_filter, &state.client_filter)?;
 
-    let num_peers = config.socket_workers + config.swarm_workers;
-
-    let request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE);
-    let response_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE * 16);
-    let control_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE);
+    spawn_access_list_watcher(&config.access_list, &state.access_list)?;
 
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
@@ -62,6 +64,90 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     let server_start_instant = ServerStartInstant::new();
 
+    if config.adaptive_announce_interval.active {
+        let request_rate = state.request_rate.clone();
+
+        ::std::thread::Builder::new()
+            .name("request-rate".into())
+            .spawn(move || request_rate.run_updater())?;
+    }
+
+    if config.statistics.active() {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
+
+        ::std::thread::Builder::new()
+            .name("statistics".into())
+            .spawn(move || workers::statistics::run_statistics_worker(sentinel, config, state))?;
+    }
+
+    let http_torrent_maps = workers::swarm::load_http_torrent_maps(&config, server_start_instant)?;
+
+    let join_workers = start_workers(
+        &config,
+        &state,
+        http_torrent_maps,
+        sentinel,
+        opt_tls_config,
+        priv_dropper,
+        server_start_instant,
+    )?;
+
+    for signal in &mut signals {
+        match signal {
+            SIGUSR1 => {
+                let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_client_filter(&config.client_filter, &state.client_filter);
+            }
+            SIGTERM => {
+                if sentinel_watcher.panic_was_triggered() {
+                    return Err(anyhow::anyhow!("worker thread panicked"));
+                } else if config.shutdown.graceful {
+                    ::log::info!("shutting down gracefully");
+
+                    state.shutdown.trigger();
+
+                    // Allow some time beyond drain timeout for workers to
+                    // finish up after closing remaining connections
+                    let timeout = Duration::from_secs(config.shutdown.drain_timeout + 5);
+
+                    return join_workers_with_timeout(join_workers, timeout);
+                } else {
+                    return Ok(());
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+/// Start socket and swarm workers as glommio executors
+#[cfg(all(feature = "glommio", not(feature = "tokio")))]
+fn start_workers(
+    config: &Config,
+    state: &State,
+    http_torrent_maps: Vec<HttpTorrentMaps>,
+    sentinel: PanicSentinel,
+    opt_tls_config: Option<Arc<RustlsConfig>>,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<impl FnOnce() -> bool + Send + 'static> {
+    use aquatic_common::cpu_pinning::glommio::{
+        get_worker_placement, set_affinity_for_util_worker,
+    };
+    use aquatic_common::cpu_pinning::WorkerIndex;
+    use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
+
+    let num_peers = config.socket_workers + config.swarm_workers;
+
+    let request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE);
+    let response_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE * 16);
+    let control_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE);
+    let http_request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_IN_CHANNEL_SIZE);
+
     let mut executors = Vec::new();
 
     for i in 0..(config.socket_workers) {
@@ -72,6 +158,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let control_mesh_builder = control_mesh_builder.clone();
         let request_mesh_builder = request_mesh_builder.clone();
         let response_mesh_builder = response_mesh_builder.clone();
+        let http_request_mesh_builder = http_request_mesh_builder.clone();
         let priv_dropper = priv_dropper.clone();
 
         let placement = get_worker_placement(
@@ -92,6 +179,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     control_mesh_builder,
                     request_mesh_builder,
                     response_mesh_builder,
+                    http_request_mesh_builder,
                     priv_dropper,
                     server_start_instant,
                 )
@@ -104,13 +192,14 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     ::log::info!("spawned socket workers");
 
-    for i in 0..(config.swarm_workers) {
+    for (i, http_torrents) in http_torrent_maps.into_iter().enumerate() {
         let sentinel = sentinel.clone();
         let config = config.clone();
         let state = state.clone();
         let control_mesh_builder = control_mesh_builder.clone();
         let request_mesh_builder = request_mesh_builder.clone();
         let response_mesh_builder = response_mesh_builder.clone();
+        let http_request_mesh_builder = http_request_mesh_builder.clone();
 
         let placement = get_worker_placement(
             &config.cpu_pinning,
@@ -126,9 +215,12 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     sentinel,
                     config,
                     state,
+                    i,
+                    http_torrents,
                     control_mesh_builder,
                     request_mesh_builder,
                     response_mesh_builder,
+                    http_request_mesh_builder,
                     server_start_instant,
                 )
                 .await
@@ -148,21 +240,164 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         )?;
     }
 
-    for signal in &mut signals {
-        match signal {
-            SIGUSR1 => {
-                let _ = update_access_list(&config.access_list, &state.access_list);
-            }
-            SIGTERM => {
-                if sentinel_watcher.panic_was_triggered() {
-                    return Err(anyhow::anyhow!("worker thread panicked"));
-                } else {
-                    return Ok(());
-                }
-            }
-            _ => unreachable!(),
-        }
+    Ok(move || executors.into_iter().all(|e| e.join().is_ok()))
+}
+
+/// Start socket and swarm workers as threads running single-threaded tokio
+/// runtimes
+#[cfg(feature = "tokio")]
+fn start_workers(
+    config: &Config,
+    state: &State,
+    http_torrent_maps: Vec<HttpTorrentMaps>,
+    sentinel: PanicSentinel,
+    opt_tls_config: Option<Arc<RustlsConfig>>,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<impl FnOnce() -> bool + Send + 'static> {
+    #[cfg(feature = "cpu-pinning")]
+    use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+    use tokio::sync::mpsc::channel;
+
+    let mut control_message_senders = Vec::new();
+    let mut control_message_receivers = Vec::new();
+    let mut in_message_senders = Vec::new();
+    let mut in_message_receivers = Vec::new();
+    let mut http_request_senders = Vec::new();
+    let mut http_request_receivers = Vec::new();
+
+    for _ in 0..config.swarm_workers {
+        let (sender, receiver) = channel(SHARED_IN_CHANNEL_SIZE);
+
+        control_message_senders.push(sender);
+        control_message_receivers.push(receiver);
+
+        let (sender, receiver) = channel(SHARED_IN_CHANNEL_SIZE);
+
+        in_message_senders.push(sender);
+        in_message_receivers.push(receiver);
+
+        let (sender, receiver) = channel(SHARED_IN_CHANNEL_SIZE);
+
+        http_request_senders.push(sender);
+        http_request_receivers.push(receiver);
     }
 
-    Ok(())
+    let mut out_message_senders = Vec::new();
+    let mut out_message_receivers = Vec::new();
+
+    for _ in 0..config.socket_workers {
+        let (sender, receiver) = channel(SHARED_IN_CHANNEL_SIZE * 16);
+
+        out_message_senders.push(sender);
+        out_message_receivers.push(receiver);
+    }
+
+    let mut handles = Vec::new();
+
+    for (i, out_message_receiver) in out_message_receivers.into_iter().enumerate() {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
+        let opt_tls_config = opt_tls_config.clone();
+        let control_message_senders = control_message_senders.clone();
+        let in_message_senders = in_message_senders.clone();
+        let http_request_senders = http_request_senders.clone();
+        let priv_dropper = priv_dropper.clone();
+
+        let out_message_consumer_id = ConsumerId(
+            i.try_into()
+                .with_context(|| "socket_workers can not exceed 256")?,
+        );
+
+        let handle = ::std::thread::Builder::new()
+            .name(format!("socket-{:02}", i + 1))
+            .spawn(move || {
+                #[cfg(feature = "cpu-pinning")]
+                pin_current_if_configured_to(
+                    &config.cpu_pinning,
+                    config.socket_workers,
+                    config.swarm_workers,
+                    WorkerIndex::SocketWorker(i),
+                );
+
+                workers::socket::run_socket_worker(
+                    sentinel,
+                    config,
+                    state,
+                    opt_tls_config,
+                    control_message_senders,
+                    in_message_senders,
+                    out_message_receiver,
+                    http_request_senders,
+                    out_message_consumer_id,
+                    priv_dropper,
+                    server_start_instant,
+                )
+            })?;
+
+        handles.push(handle);
+    }
+
+    ::log::info!("spawned socket workers");
+
+    // Swarm worker channels should close when all socket workers have exited
+    drop(control_message_senders);
+    drop(in_message_senders);
+    drop(http_request_senders);
+
+    let swarm_receivers = control_message_receivers
+        .into_iter()
+        .zip(in_message_receivers)
+        .zip(http_request_receivers);
+
+    for (
+        i,
+        (((control_message_receiver, in_message_receiver), http_request_receiver), http_torrents),
+    ) in swarm_receivers.zip(http_torrent_maps).enumerate()
+    {
+        let sentinel = sentinel.clone();
+        let config = config.clone();
+        let state = state.clone();
+        let out_message_senders = out_message_senders.clone();
+
+        let handle = ::std::thread::Builder::new()
+            .name(format!("swarm-{:02}", i + 1))
+            .spawn(move || {
+                #[cfg(feature = "cpu-pinning")]
+                pin_current_if_configured_to(
+                    &config.cpu_pinning,
+                    config.socket_workers,
+                    config.swarm_workers,
+                    WorkerIndex::SwarmWorker(i),
+                );
+
+                workers::swarm::run_swarm_worker(
+                    sentinel,
+                    config,
+                    state,
+                    i,
+                    http_torrents,
+                    control_message_receiver,
+                    in_message_receiver,
+                    out_message_senders,
+                    http_request_receiver,
+                    server_start_instant,
+                )
+            })?;
+
+        handles.push(handle);
+    }
+
+    ::log::info!("spawned swarm workers");
+
+    #[cfg(feature = "cpu-pinning")]
+    pin_current_if_configured_to(
+        &config.cpu_pinning,
+        config.socket_workers,
+        config.swarm_workers,
+        WorkerIndex::Util,
+    );
+
+    Ok(move || handles.into_iter().all(|h| h.join().is_ok()))
 }
@@ -1,2 +1,3 @@
 pub mod socket;
+pub mod statistics;
 pub mod swarm;
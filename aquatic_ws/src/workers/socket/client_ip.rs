@@ -0,0 +1,293 @@
+//! Determining client IP addresses for connections from trusted reverse
+//! proxies
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use futures::{AsyncRead, AsyncReadExt};
+
+const PROXY_PROTOCOL_V1_PREFIX: &[u8] = b"PROXY ";
+const PROXY_PROTOCOL_V1_MAX_LEN: usize = 107;
+const PROXY_PROTOCOL_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_PROTOCOL_V2_FIXED_LEN: usize = 16;
+/// Limit on size of PROXY protocol v2 headers, which may contain arbitrary
+/// extension data
+const PROXY_PROTOCOL_MAX_LEN: usize = 4096;
+const READ_CHUNK_SIZE: usize = 256;
+
+/// Get client IP from X-Forwarded-For header value
+///
+/// Returns the rightmost address not belonging to a trusted proxy, or the
+/// leftmost address if all of them do. Returns None if the value can't be
+/// parsed.
+pub fn client_ip_from_x_forwarded_for(value: &str, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    rightmost_untrusted_ip(value.split(',').map(parse_ip), trusted_proxies)
+}
+
+/// Get client IP from `for` parameters of Forwarded header value (RFC 7239)
+///
+/// Addresses are chosen in the same way as for X-Forwarded-For. Obfuscated
+/// identifiers and "unknown" can't be used and cause None to be returned
+/// if they are encountered before an untrusted address.
+pub fn client_ip_from_forwarded(value: &str, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let ips = value.split(',').map(|element| {
+        element.split(';').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+
+            if key.trim().eq_ignore_ascii_case("for") {
+                parse_ip(value.trim().trim_matches('"'))
+            } else {
+                None
+            }
+        })
+    });
+
+    rightmost_untrusted_ip(ips, trusted_proxies)
+}
+
+fn rightmost_untrusted_ip(
+    ips: impl DoubleEndedIterator<Item = Option<IpAddr>>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut opt_leftmost = None;
+
+    for opt_ip in ips.rev() {
+        let ip = opt_ip?;
+
+        if !trusted_proxies.contains(&ip) {
+            return Some(ip);
+        }
+
+        opt_leftmost = Some(ip);
+    }
+
+    opt_leftmost
+}
+
+/// Parse IP address, possibly followed by a port, with IPv6 addresses
+/// optionally in brackets
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+        .and_then(|value| value.parse::<Ipv6Addr>().ok())
+        .map(IpAddr::V6)
+}
+
+/// Parsed PROXY protocol header
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProxyProtocolHeader {
+    /// Length of header in bytes
+    pub len: usize,
+    /// Client address. None for health checks from the proxy itself and for
+    /// unsupported address families.
+    pub source: Option<SocketAddr>,
+}
+
+/// Read PROXY protocol header from stream. Returns client address (if any)
+/// and bytes read after the header.
+pub async fn read_proxy_protocol_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> anyhow::Result<(Option<SocketAddr>, Vec<u8>)> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let position = bytes.len();
+
+        if position >= PROXY_PROTOCOL_MAX_LEN {
+            return Err(anyhow::anyhow!("PROXY protocol header too large"));
+        }
+
+        bytes.resize((position + READ_CHUNK_SIZE).min(PROXY_PROTOCOL_MAX_LEN), 0);
+
+        let bytes_read = stream.read(&mut bytes[position..]).await?;
+
+        bytes.truncate(position + bytes_read);
+
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("peer closed connection"));
+        }
+
+        if let Some(header) = parse_proxy_protocol_header(&bytes)? {
+            return Ok((header.source, bytes.split_off(header.len)));
+        }
+    }
+}
+
+/// Parse PROXY protocol header (version 1 or 2). Returns None if more data is
+/// needed.
+pub fn parse_proxy_protocol_header(bytes: &[u8]) -> anyhow::Result<Option<ProxyProtocolHeader>> {
+    if bytes.starts_with(PROXY_PROTOCOL_V2_SIGNATURE) {
+        parse_proxy_protocol_v2_header(bytes)
+    } else if bytes.starts_with(PROXY_PROTOCOL_V1_PREFIX) {
+        parse_proxy_protocol_v1_header(bytes)
+    } else if PROXY_PROTOCOL_V2_SIGNATURE.starts_with(bytes)
+        || PROXY_PROTOCOL_V1_PREFIX.starts_with(bytes)
+    {
+        Ok(None)
+    } else {
+        Err(anyhow::anyhow!("missing PROXY protocol header"))
+    }
+}
+
+fn parse_proxy_protocol_v1_header(bytes: &[u8]) -> anyhow::Result<Option<ProxyProtocolHeader>> {
+    let bytes = &bytes[..bytes.len().min(PROXY_PROTOCOL_V1_MAX_LEN)];
+
+    let line_len = match bytes.windows(2).position(|window| window == b"\r\n") {
+        Some(position) => position,
+        None if bytes.len() == PROXY_PROTOCOL_V1_MAX_LEN => {
+            return Err(anyhow::anyhow!("PROXY protocol v1 header too long"));
+        }
+        None => return Ok(None),
+    };
+
+    let line = ::std::str::from_utf8(&bytes[..line_len])?;
+    let mut parts = line.split(' ').skip(1);
+
+    let source = match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let source_ip: IpAddr = parts
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("no source address"))?
+                .parse()?;
+            let _destination_ip = parts.next();
+            let source_port: u16 = parts
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("no source port"))?
+                .parse()?;
+
+            Some(SocketAddr::new(source_ip, source_port))
+        }
+        Some("UNKNOWN") => None,
+        _ => return Err(anyhow::anyhow!("invalid PROXY protocol v1 header")),
+    };
+
+    Ok(Some(ProxyProtocolHeader {
+        len: line_len + 2,
+        source,
+    }))
+}
+
+fn parse_proxy_protocol_v2_header(bytes: &[u8]) -> anyhow::Result<Option<ProxyProtocolHeader>> {
+    if bytes.len() < PROXY_PROTOCOL_V2_FIXED_LEN {
+        return Ok(None);
+    }
+
+    let version_and_command = bytes[12];
+    let family_and_protocol = bytes[13];
+    let address_len = u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+    let len = PROXY_PROTOCOL_V2_FIXED_LEN + address_len;
+
+    if version_and_command >> 4 != 2 {
+        return Err(anyhow::anyhow!("unsupported PROXY protocol version"));
+    }
+    if bytes.len() < len {
+        return Ok(None);
+    }
+
+    let addresses = &bytes[PROXY_PROTOCOL_V2_FIXED_LEN..len];
+
+    let source = match (version_and_command & 0x0f, family_and_protocol >> 4) {
+        // LOCAL command: connection was established by proxy itself
+        (0, _) => None,
+        // PROXY command, AF_INET
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        // PROXY command, AF_INET6
+        (1, 2) if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        // PROXY command, AF_UNSPEC or AF_UNIX
+        (1, _) => None,
+        _ => return Err(anyhow::anyhow!("invalid PROXY protocol v2 command")),
+    };
+
+    Ok(Some(ProxyProtocolHeader { len, source }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip_from_headers() {
+        let trusted_proxies: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+        let trusted_proxies = &trusted_proxies[..];
+
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(
+            client_ip_from_x_forwarded_for("1.1.1.1, 2.2.2.2, 10.0.0.1", trusted_proxies),
+            ip("2.2.2.2")
+        );
+        assert_eq!(
+            client_ip_from_x_forwarded_for("[2001:db8::1]:443,10.0.0.1", trusted_proxies),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            client_ip_from_x_forwarded_for("10.0.0.1", trusted_proxies),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            client_ip_from_x_forwarded_for("1.1.1.1, garbage", trusted_proxies),
+            None
+        );
+        assert_eq!(
+            client_ip_from_forwarded(
+                r#"for=1.1.1.1, For="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.1"#,
+                trusted_proxies
+            ),
+            ip("2001:db8:cafe::17")
+        );
+        assert_eq!(
+            client_ip_from_forwarded("for=1.1.1.1, for=unknown", trusted_proxies),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_proxy_protocol_header() {
+        let v1 = b"PROXY TCP4 1.2.3.4 5.6.7.8 1234 443\r\nGET";
+
+        assert_eq!(
+            parse_proxy_protocol_header(v1).unwrap(),
+            Some(ProxyProtocolHeader {
+                len: v1.len() - 3,
+                source: Some("1.2.3.4:1234".parse().unwrap()),
+            })
+        );
+        assert_eq!(parse_proxy_protocol_header(&v1[..20]).unwrap(), None);
+        assert_eq!(parse_proxy_protocol_header(b"PRO").unwrap(), None);
+        assert!(parse_proxy_protocol_header(b"GET / HTTP/1.1\r\n").is_err());
+
+        let mut v2 = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0x04, 0xd2, 0x01, 0xbb]);
+        v2.extend_from_slice(b"\x16\x03\x01");
+
+        assert_eq!(
+            parse_proxy_protocol_header(&v2).unwrap(),
+            Some(ProxyProtocolHeader {
+                len: 28,
+                source: Some("1.2.3.4:1234".parse().unwrap()),
+            })
+        );
+        assert_eq!(parse_proxy_protocol_header(&v2[..20]).unwrap(), None);
+    }
+}
@@ -0,0 +1,1104 @@
+//! Connection handling shared by the glommio and tokio socket workers
+//!
+//! Runtime-specific channel and timer functionality is selected with
+//! cfg_if below. Everything else works with any stream implementing
+//! futures::AsyncRead and futures::AsyncWrite.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::client_filter::{
+    create_client_filter_cache, ClientFilterArcSwap, ClientFilterCache,
+};
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
+use aquatic_ws_protocol::*;
+use async_tungstenite::WebSocketStream;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{AsyncRead, AsyncWrite, StreamExt};
+use futures_lite::future::race;
+use futures_rustls::TlsAcceptor;
+use hashbrown::hash_map::Entry;
+use hashbrown::HashMap;
+use slab::Slab;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+
+use crate::common::*;
+use crate::config::{ClientIpSource, Config};
+
+use super::client_ip::{
+    client_ip_from_forwarded, client_ip_from_x_forwarded_for, read_proxy_protocol_header,
+};
+use super::deflate::{accept_websocket, DeflateCodec};
+use super::http::{
+    handle_http_tracker_request, read_request_head, HttpRequestSenders, PrefixedStream,
+};
+use super::{
+    calculate_in_message_consumer_index, is_trusted_proxy, send_health_check_response,
+    AnnouncedTorrent, PendingScrapeResponse, RateLimiter, ShutdownStarted, WriterEvent,
+    LOCAL_CHANNEL_SIZE,
+};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        use tokio::sync::mpsc::{Receiver, Sender};
+
+        pub type ControlMessageSenders = Vec<Sender<SwarmControlMessage>>;
+        pub type InMessageSenders = Vec<Sender<(InMessageMeta, InMessage)>>;
+        pub type TaskHandle = tokio::task::JoinHandle<()>;
+
+        type OutMessageSender = Sender<(OutMessageMeta, OutMessage)>;
+        type OutMessageReceiver = Receiver<(OutMessageMeta, OutMessage)>;
+
+        fn create_out_message_channel() -> (OutMessageSender, OutMessageReceiver) {
+            tokio::sync::mpsc::channel(LOCAL_CHANNEL_SIZE)
+        }
+
+        fn out_message_channel_len(sender: &OutMessageSender) -> usize {
+            sender.max_capacity() - sender.capacity()
+        }
+
+        fn out_message_channel_is_full(sender: &OutMessageSender) -> bool {
+            sender.capacity() == 0
+        }
+
+        /// Send message if there is room in channel, otherwise drop it
+        fn try_send_out_message(sender: &OutMessageSender, message: (OutMessageMeta, OutMessage)) {
+            let _ = sender.try_send(message);
+        }
+
+        async fn send_out_message(
+            sender: &OutMessageSender,
+            message: (OutMessageMeta, OutMessage),
+        ) -> anyhow::Result<()> {
+            sender
+                .send(message)
+                .await
+                .map_err(|_| anyhow::anyhow!("out message receiver closed"))
+        }
+
+        async fn receive_out_message(
+            receiver: &mut OutMessageReceiver,
+        ) -> Option<(OutMessageMeta, OutMessage)> {
+            receiver.recv().await
+        }
+
+        async fn send_to<T>(senders: &[Sender<T>], index: usize, message: T) -> anyhow::Result<()> {
+            senders[index]
+                .send(message)
+                .await
+                .map_err(|_| anyhow::anyhow!("swarm worker receiver closed"))
+        }
+
+        fn cancel_task(handle: &TaskHandle) {
+            handle.abort();
+        }
+
+        pub async fn sleep(duration: Duration) {
+            tokio::time::sleep(duration).await
+        }
+
+        /// Tokio preempts tasks on its own
+        async fn yield_if_needed() {}
+    } else if #[cfg(feature = "glommio")] {
+        use glommio::channels::channel_mesh::Senders;
+        use glommio::channels::local_channel::{new_bounded, LocalReceiver, LocalSender};
+        use glommio::GlommioError;
+
+        pub type ControlMessageSenders = Senders<SwarmControlMessage>;
+        pub type InMessageSenders = Senders<(InMessageMeta, InMessage)>;
+        pub type TaskHandle = glommio::task::JoinHandle<()>;
+
+        type OutMessageSender = Rc<LocalSender<(OutMessageMeta, OutMessage)>>;
+        type OutMessageReceiver = LocalReceiver<(OutMessageMeta, OutMessage)>;
+
+        fn create_out_message_channel() -> (OutMessageSender, OutMessageReceiver) {
+            let (sender, receiver) = new_bounded(LOCAL_CHANNEL_SIZE);
+
+            (Rc::new(sender), receiver)
+        }
+
+        fn out_message_channel_len(sender: &OutMessageSender) -> usize {
+            sender.len()
+        }
+
+        fn out_message_channel_is_full(sender: &OutMessageSender) -> bool {
+            sender.is_full()
+        }
+
+        /// Send message if there is room in channel, otherwise drop it
+        fn try_send_out_message(sender: &OutMessageSender, message: (OutMessageMeta, OutMessage)) {
+            match sender.try_send(message) {
+                Ok(()) => {}
+                Err(GlommioError::Closed(_)) => {}
+                Err(GlommioError::WouldBlock(_)) => {}
+                Err(err) => {
+                    ::log::debug!(
+                        "Couldn't send out_message from shared channel to local receiver: {:?}",
+                        err
+                    );
+                }
+            }
+        }
+
+        async fn send_out_message(
+            sender: &OutMessageSender,
+            message: (OutMessageMeta, OutMessage),
+        ) -> anyhow::Result<()> {
+            sender
+                .send(message)
+                .await
+                .map_err(|_| anyhow::anyhow!("out message receiver closed"))
+        }
+
+        async fn receive_out_message(
+            receiver: &mut OutMessageReceiver,
+        ) -> Option<(OutMessageMeta, OutMessage)> {
+            receiver.recv().await
+        }
+
+        async fn send_to<T: Send + Sized>(
+            senders: &Senders<T>,
+            index: usize,
+            message: T,
+        ) -> anyhow::Result<()> {
+            senders
+                .send_to(index, message)
+                .await
+                .map_err(|_| anyhow::anyhow!("swarm worker receiver closed"))
+        }
+
+        fn cancel_task(handle: &TaskHandle) {
+            handle.cancel();
+        }
+
+        pub async fn sleep(duration: Duration) {
+            glommio::timer::sleep(duration).await
+        }
+
+        async fn yield_if_needed() {
+            glommio::yield_if_needed().await
+        }
+    }
+}
+
+/// Run future, returning None if it doesn't finish within duration
+async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    race(async { Some(future.await) }, async {
+        sleep(duration).await;
+
+        None
+    })
+    .await
+}
+
+/// Socket worker state shared by all of its connections
+pub struct SocketWorkerContext {
+    pub config: Config,
+    pub access_list: Arc<AccessListArcSwap>,
+    pub client_filter: Arc<ClientFilterArcSwap>,
+    pub request_rate: Arc<RequestRate>,
+    pub num_running_workers: Arc<AtomicUsize>,
+    pub control_message_senders: ControlMessageSenders,
+    pub in_message_senders: InMessageSenders,
+    pub http_request_senders: HttpRequestSenders,
+    pub opt_tls_config: Option<Arc<RustlsConfig>>,
+    /// Set if permessage-deflate is enabled
+    pub opt_deflate_codec: Option<Rc<DeflateCodec>>,
+    pub out_message_consumer_id: ConsumerId,
+    pub server_start_instant: ServerStartInstant,
+    pub connection_slab: RefCell<Slab<ConnectionReference>>,
+}
+
+pub struct ConnectionReference {
+    task_handle: Option<TaskHandle>,
+    /// Sender part of channel used to pass on outgoing messages from request
+    /// worker
+    out_message_sender: OutMessageSender,
+    /// Updated after sending message to peer
+    valid_until: ValidUntil,
+    announced_info_hashes: HashMap<InfoHash, AnnouncedTorrent>,
+    ip_version: IpVersion,
+}
+
+/// Connection that has been inserted into the connection slab but not yet
+/// started
+pub struct NewConnection {
+    connection_id: ConnectionId,
+    peer_addr: CanonicalSocketAddr,
+    ip_version: IpVersion,
+    out_message_sender: OutMessageSender,
+    out_message_receiver: OutMessageReceiver,
+}
+
+impl NewConnection {
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// Set client address and update IP version of connection reference to
+    /// match it, so that the peer is removed from the correct swarm when
+    /// connection is closed
+    fn set_peer_addr(&mut self, ctx: &SocketWorkerContext, client_addr: CanonicalSocketAddr) {
+        let ip_version = IpVersion::canonical_from_ip(client_addr.get().ip());
+
+        if let Some(reference) = ctx
+            .connection_slab
+            .borrow_mut()
+            .get_mut(self.connection_id.0)
+        {
+            reference.ip_version = ip_version;
+        }
+
+        self.peer_addr = client_addr;
+        self.ip_version = ip_version;
+    }
+}
+
+/// Insert reference to accepted connection into connection slab
+pub fn insert_connection(
+    ctx: &SocketWorkerContext,
+    peer_addr: CanonicalSocketAddr,
+) -> NewConnection {
+    let ip_version = IpVersion::canonical_from_ip(peer_addr.get().ip());
+
+    let (out_message_sender, out_message_receiver) = create_out_message_channel();
+
+    let key = ctx
+        .connection_slab
+        .borrow_mut()
+        .insert(ConnectionReference {
+            task_handle: None,
+            out_message_sender: out_message_sender.clone(),
+            valid_until: ValidUntil::new(
+                ctx.server_start_instant,
+                ctx.config.cleaning.max_connection_idle,
+            ),
+            announced_info_hashes: Default::default(),
+            ip_version,
+        });
+
+    ::log::trace!("accepting stream, assigning id {}", key);
+
+    NewConnection {
+        connection_id: ConnectionId(key),
+        peer_addr,
+        ip_version,
+        out_message_sender,
+        out_message_receiver,
+    }
+}
+
+/// Store handle of task running connection, so that it can be cancelled
+pub fn set_connection_task_handle(
+    ctx: &SocketWorkerContext,
+    connection_id: ConnectionId,
+    task_handle: TaskHandle,
+) {
+    if let Some(reference) = ctx.connection_slab.borrow_mut().get_mut(connection_id.0) {
+        reference.task_handle = Some(task_handle);
+    }
+}
+
+/// Run connection until it is closed, then remove its reference and tell
+/// swarm workers to remove the peers announced over it
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    ctx: Rc<SocketWorkerContext>,
+    shutdown_started: ShutdownStarted,
+    connection: NewConnection,
+    stream: S,
+) {
+    let connection_id = connection.connection_id;
+
+    if let Err(err) = run_connection(ctx.clone(), shutdown_started, connection, stream).await {
+        ::log::debug!("connection error: {:#}", err);
+    }
+
+    // Clean up after closed connection
+
+    // Remove reference in separate statement to avoid
+    // multiple RefCell borrows
+    let opt_reference = ctx.connection_slab.borrow_mut().try_remove(connection_id.0);
+
+    // Tell swarm workers to remove peer
+    if let Some(reference) = opt_reference {
+        for (info_hash, announced_torrent) in reference.announced_info_hashes {
+            let message = SwarmControlMessage::ConnectionClosed {
+                info_hash,
+                peer_id: announced_torrent.peer_id,
+                ip_version: reference.ip_version,
+            };
+
+            let consumer_index = calculate_in_message_consumer_index(&ctx.config, info_hash);
+
+            if let Err(err) = send_to(&ctx.control_message_senders, consumer_index, message).await {
+                ::log::debug!("couldn't send control message: {:#}", err);
+            }
+        }
+    }
+}
+
+/// Pass on message from swarm worker to connection it is meant for
+pub fn forward_out_message(
+    ctx: &SocketWorkerContext,
+    meta: OutMessageMeta,
+    out_message: OutMessage,
+) {
+    if let Some(reference) = ctx.connection_slab.borrow().get(meta.connection_id.0) {
+        ::log::trace!(
+            "local channel {} len: {}",
+            meta.connection_id.0,
+            out_message_channel_len(&reference.out_message_sender)
+        );
+
+        try_send_out_message(&reference.out_message_sender, (meta, out_message));
+    }
+}
+
+/// Cancel idle connections and forget torrents whose peers have been
+/// removed from swarms (or will be soon) because they haven't announced in
+/// a while
+pub fn clean_connections(ctx: &SocketWorkerContext) {
+    let now = ctx.server_start_instant.seconds_elapsed();
+
+    let mut connection_slab = ctx.connection_slab.borrow_mut();
+
+    connection_slab.retain(|_, reference| {
+        if reference.valid_until.valid(now) {
+            reference
+                .announced_info_hashes
+                .retain(|_, announced_torrent| announced_torrent.valid_until.valid(now));
+
+            true
+        } else {
+            if let Some(ref handle) = reference.task_handle {
+                cancel_task(handle);
+            }
+
+            false
+        }
+    });
+
+    connection_slab.shrink_to_fit();
+}
+
+/// Wait for connections to close, cancelling the ones remaining when drain
+/// timeout is reached
+pub async fn drain_connections(ctx: &SocketWorkerContext) {
+    let deadline = Instant::now() + Duration::from_secs(ctx.config.shutdown.drain_timeout);
+
+    while !ctx.connection_slab.borrow().is_empty() && Instant::now() < deadline {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut connection_slab = ctx.connection_slab.borrow_mut();
+
+    if !connection_slab.is_empty() {
+        ::log::warn!(
+            "closing {} connections that didn't finish within drain timeout",
+            connection_slab.len()
+        );
+
+        for (_, reference) in connection_slab.iter() {
+            if let Some(ref handle) = reference.task_handle {
+                cancel_task(handle);
+            }
+        }
+
+        connection_slab.clear();
+    }
+}
+
+async fn run_connection<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    ctx: Rc<SocketWorkerContext>,
+    shutdown_started: ShutdownStarted,
+    mut connection: NewConnection,
+    mut stream: S,
+) -> anyhow::Result<()> {
+    let mut bytes_after_proxy_protocol_header = Vec::new();
+
+    if ctx.config.network.client_ip_source == ClientIpSource::ProxyProtocol
+        && is_trusted_proxy(&ctx.config, connection.peer_addr)
+    {
+        let (opt_client_addr, bytes_after_header) = read_proxy_protocol_header(&mut stream).await?;
+
+        if let Some(client_addr) = opt_client_addr {
+            connection.set_peer_addr(&ctx, CanonicalSocketAddr::new(client_addr));
+        }
+
+        bytes_after_proxy_protocol_header = bytes_after_header;
+    }
+
+    let stream = PrefixedStream::new(bytes_after_proxy_protocol_header, stream);
+
+    if let Some(tls_config) = ctx.opt_tls_config.clone() {
+        let tls_acceptor: TlsAcceptor = tls_config.into();
+
+        let stream = tls_acceptor.accept(stream).await?;
+
+        run_stream_agnostic_connection(ctx, shutdown_started, connection, stream).await
+    } else {
+        run_stream_agnostic_connection(ctx, shutdown_started, connection, stream).await
+    }
+}
+
+async fn run_stream_agnostic_connection<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    ctx: Rc<SocketWorkerContext>,
+    shutdown_started: ShutdownStarted,
+    mut connection: NewConnection,
+    mut stream: S,
+) -> anyhow::Result<()> {
+    let config = &ctx.config;
+
+    let client_ip_in_headers = matches!(
+        config.network.client_ip_source,
+        ClientIpSource::XForwardedFor | ClientIpSource::Forwarded
+    ) && is_trusted_proxy(config, connection.peer_addr);
+
+    if !(config.http_tracker.active
+        || config.network.enable_http_health_checks
+        || client_ip_in_headers)
+    {
+        return run_websocket_connection(ctx, shutdown_started, connection, stream).await;
+    }
+
+    let request_head =
+        read_request_head(&mut stream, config.network.max_http_request_head_size).await?;
+
+    if client_ip_in_headers {
+        let trusted_proxies = &config.network.trusted_proxies;
+
+        let opt_client_ip = match config.network.client_ip_source {
+            ClientIpSource::XForwardedFor => request_head
+                .x_forwarded_for
+                .as_deref()
+                .and_then(|value| client_ip_from_x_forwarded_for(value, trusted_proxies)),
+            ClientIpSource::Forwarded => request_head
+                .forwarded
+                .as_deref()
+                .and_then(|value| client_ip_from_forwarded(value, trusted_proxies)),
+            _ => None,
+        };
+
+        if let Some(client_ip) = opt_client_ip {
+            // Port is not known
+            connection.set_peer_addr(
+                &ctx,
+                CanonicalSocketAddr::new(SocketAddr::new(client_ip, 0)),
+            );
+        } else {
+            ::log::debug!(
+                "could not get client ip from headers of request from trusted proxy {}",
+                connection.peer_addr.get().ip()
+            );
+        }
+    }
+
+    if config.network.enable_http_health_checks && !request_head.websocket_upgrade {
+        match request_head.path.as_str() {
+            "/health" => {
+                return send_health_check_response(stream, true, "Ok").await;
+            }
+            "/ready" => {
+                let ready = ctx.num_running_workers.load(Ordering::Acquire)
+                    == config.socket_workers + config.swarm_workers;
+
+                return send_health_check_response(
+                    stream,
+                    ready,
+                    if ready { "Ready" } else { "Not ready" },
+                )
+                .await;
+            }
+            _ => (),
+        }
+    }
+
+    if request_head.websocket_upgrade || !config.http_tracker.active {
+        let stream = PrefixedStream::new(request_head.bytes, stream);
+
+        run_websocket_connection(ctx, shutdown_started, connection, stream).await
+    } else {
+        if config.adaptive_announce_interval.active {
+            ctx.request_rate.add(1);
+        }
+
+        handle_http_tracker_request(
+            config,
+            &mut create_access_list_cache(&ctx.access_list),
+            &mut create_client_filter_cache(&ctx.client_filter),
+            &ctx.http_request_senders,
+            connection.peer_addr,
+            &request_head.bytes,
+            stream,
+        )
+        .await
+    }
+}
+
+async fn run_websocket_connection<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    ctx: Rc<SocketWorkerContext>,
+    shutdown_started: ShutdownStarted,
+    connection: NewConnection,
+    stream: S,
+) -> anyhow::Result<()> {
+    let stream = accept_websocket(&ctx.config, ctx.opt_deflate_codec.clone(), stream).await?;
+
+    let (ws_out, ws_in) = futures::StreamExt::split(stream);
+
+    let pending_scrape_slab = Rc::new(RefCell::new(Slab::new()));
+    let unanswered_pings = Rc::new(Cell::new(0));
+
+    let mut reader = ConnectionReader {
+        message_rate_limiter: RateLimiter::new(ctx.config.protocol.max_messages_per_second),
+        offer_rate_limiter: RateLimiter::new(ctx.config.protocol.max_offers_per_second),
+        access_list_cache: create_access_list_cache(&ctx.access_list),
+        client_filter_cache: create_client_filter_cache(&ctx.client_filter),
+        ctx: ctx.clone(),
+        out_message_sender: connection.out_message_sender,
+        pending_scrape_slab: pending_scrape_slab.clone(),
+        ws_in,
+        ip_version: connection.ip_version,
+        connection_id: connection.connection_id,
+        unanswered_pings: unanswered_pings.clone(),
+    };
+
+    let mut writer = ConnectionWriter {
+        ctx,
+        out_message_receiver: connection.out_message_receiver,
+        shutdown_started,
+        ws_out,
+        pending_scrape_slab,
+        connection_id: connection.connection_id,
+        unanswered_pings,
+    };
+
+    // Reader and writer run in the connection task, so that both are stopped
+    // when either finishes or the task is cancelled
+    race(reader.run_in_message_loop(), writer.run_out_message_loop()).await
+}
+
+struct ConnectionReader<S> {
+    ctx: Rc<SocketWorkerContext>,
+    access_list_cache: AccessListCache,
+    client_filter_cache: ClientFilterCache,
+    out_message_sender: OutMessageSender,
+    pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
+    ws_in: SplitStream<WebSocketStream<S>>,
+    ip_version: IpVersion,
+    connection_id: ConnectionId,
+    message_rate_limiter: RateLimiter,
+    offer_rate_limiter: RateLimiter,
+    /// Shared with ConnectionWriter, which sends the pings
+    unanswered_pings: Rc<Cell<usize>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ConnectionReader<S> {
+    async fn run_in_message_loop(&mut self) -> anyhow::Result<()> {
+        loop {
+            while out_message_channel_is_full(&self.out_message_sender) {
+                sleep(Duration::from_millis(100)).await;
+
+                yield_if_needed().await;
+            }
+
+            let message = self.ws_in.next().await.unwrap()?;
+
+            if let tungstenite::Message::Pong(_) = message {
+                self.handle_pong()?;
+
+                continue;
+            }
+
+            if self.message_rate_limiter.take(1) == 0 {
+                self.send_error_response("Too many messages".into(), None, None)
+                    .await?;
+
+                yield_if_needed().await;
+
+                continue;
+            }
+
+            match InMessage::from_ws_message(message) {
+                Ok(in_message) => {
+                    self.handle_in_message(in_message).await?;
+                }
+                Err(err) => {
+                    ::log::debug!("Couldn't parse in_message: {:?}", err);
+
+                    self.send_error_response("Invalid request".into(), None, None)
+                        .await?;
+                }
+            }
+
+            yield_if_needed().await;
+        }
+    }
+
+    fn handle_pong(&mut self) -> anyhow::Result<()> {
+        self.unanswered_pings.set(0);
+
+        self.ctx
+            .connection_slab
+            .borrow_mut()
+            .get_mut(self.connection_id.0)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "connection reference {} not found in slab",
+                    self.connection_id.0
+                )
+            })?
+            .valid_until = ValidUntil::new(
+            self.ctx.server_start_instant,
+            self.ctx.config.cleaning.max_connection_idle,
+        );
+
+        Ok(())
+    }
+
+    async fn handle_in_message(&mut self, in_message: InMessage) -> anyhow::Result<()> {
+        if self.ctx.config.adaptive_announce_interval.active {
+            self.ctx.request_rate.add(1);
+        }
+
+        match in_message {
+            InMessage::AnnounceRequest(mut announce_request) => {
+                let info_hash = announce_request.info_hash;
+
+                if self
+                    .access_list_cache
+                    .load()
+                    .allows(self.ctx.config.access_list.mode, &info_hash.0)
+                {
+                    if let Err(rejection) = self.client_filter_cache.load().check(
+                        self.ctx.config.client_filter.mode,
+                        &announce_request.peer_id.0,
+                    ) {
+                        self.send_error_response(
+                            rejection.to_string().into(),
+                            Some(ErrorResponseAction::Announce),
+                            Some(info_hash),
+                        )
+                        .await?;
+
+                        return Ok(());
+                    }
+
+                    {
+                        let mut connection_slab = self.ctx.connection_slab.borrow_mut();
+
+                        let connection_reference = connection_slab
+                            .get_mut(self.connection_id.0)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "connection reference {} not found in slab",
+                                    self.connection_id.0
+                                )
+                            })?;
+
+                        let num_announced_torrents =
+                            connection_reference.announced_info_hashes.len();
+                        let stopped =
+                            matches!(announce_request.event, Some(AnnounceEvent::Stopped));
+                        let valid_until = ValidUntil::new(
+                            self.ctx.server_start_instant,
+                            self.ctx.config.cleaning.max_peer_age,
+                        );
+
+                        // Store peer id / check if stored peer id matches
+                        match connection_reference
+                            .announced_info_hashes
+                            .entry(announce_request.info_hash)
+                        {
+                            Entry::Occupied(mut entry) => {
+                                if entry.get().peer_id != announce_request.peer_id {
+                                    // Drop Rc borrow before awaiting
+                                    drop(connection_slab);
+
+                                    self.send_error_response(
+                                        "Only one peer id can be used per torrent".into(),
+                                        Some(ErrorResponseAction::Announce),
+                                        Some(info_hash),
+                                    )
+                                    .await?;
+
+                                    return Err(anyhow::anyhow!(
+                                        "Peer used more than one PeerId for a single torrent"
+                                    ));
+                                }
+
+                                if stopped {
+                                    entry.remove();
+                                } else {
+                                    entry.get_mut().valid_until = valid_until;
+                                }
+                            }
+                            Entry::Vacant(entry) => {
+                                if num_announced_torrents
+                                    >= self.ctx.config.protocol.max_torrents_per_connection
+                                {
+                                    // Drop Rc borrow before awaiting
+                                    drop(connection_slab);
+
+                                    self.send_error_response(
+                                        "Too many torrents announced on this connection".into(),
+                                        Some(ErrorResponseAction::Announce),
+                                        Some(info_hash),
+                                    )
+                                    .await?;
+
+                                    return Ok(());
+                                }
+
+                                if !stopped {
+                                    entry.insert(AnnouncedTorrent {
+                                        peer_id: announce_request.peer_id,
+                                        valid_until,
+                                    });
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(offers) = announce_request.offers.as_mut() {
+                        // Offers beyond max_offers are ignored by swarm
+                        // workers, so don't count them towards rate limit
+                        offers.truncate(self.ctx.config.protocol.max_offers);
+
+                        let num_allowed = self.offer_rate_limiter.take(offers.len());
+
+                        if num_allowed < offers.len() {
+                            offers.truncate(num_allowed);
+
+                            self.send_error_response(
+                                "Too many offers, some were dropped".into(),
+                                Some(ErrorResponseAction::Announce),
+                                Some(info_hash),
+                            )
+                            .await?;
+                        }
+                    }
+
+                    let in_message = InMessage::AnnounceRequest(announce_request);
+
+                    let consumer_index =
+                        calculate_in_message_consumer_index(&self.ctx.config, info_hash);
+
+                    send_to(
+                        &self.ctx.in_message_senders,
+                        consumer_index,
+                        (self.make_connection_meta(None), in_message),
+                    )
+                    .await?;
+                } else {
+                    self.send_error_response(
+                        "Info hash not allowed".into(),
+                        Some(ErrorResponseAction::Announce),
+                        Some(info_hash),
+                    )
+                    .await?;
+                }
+            }
+            InMessage::ScrapeRequest(ScrapeRequest { info_hashes, .. }) => {
+                let info_hashes = if let Some(info_hashes) = info_hashes {
+                    info_hashes
+                } else {
+                    // If request.info_hashes is empty, don't return scrape for all
+                    // torrents, even though reference server does it. It is too expensive.
+                    self.send_error_response(
+                        "Full scrapes are not allowed".into(),
+                        Some(ErrorResponseAction::Scrape),
+                        None,
+                    )
+                    .await?;
+
+                    return Ok(());
+                };
+
+                let mut info_hashes_by_worker: BTreeMap<usize, Vec<InfoHash>> = BTreeMap::new();
+
+                for info_hash in info_hashes.as_vec() {
+                    let info_hashes = info_hashes_by_worker
+                        .entry(calculate_in_message_consumer_index(
+                            &self.ctx.config,
+                            info_hash,
+                        ))
+                        .or_default();
+
+                    info_hashes.push(info_hash);
+                }
+
+                let pending_worker_out_messages = info_hashes_by_worker.len();
+
+                let pending_scrape_response = PendingScrapeResponse {
+                    pending_worker_out_messages,
+                    stats: Default::default(),
+                };
+
+                let pending_scrape_id: u8 = self
+                    .pending_scrape_slab
+                    .borrow_mut()
+                    .insert(pending_scrape_response)
+                    .try_into()
+                    .with_context(|| "Reached 256 pending scrape responses")?;
+
+                let meta = self.make_connection_meta(Some(PendingScrapeId(pending_scrape_id)));
+
+                for (consumer_index, info_hashes) in info_hashes_by_worker {
+                    let in_message = InMessage::ScrapeRequest(ScrapeRequest {
+                        action: ScrapeAction,
+                        info_hashes: Some(ScrapeRequestInfoHashes::Multiple(info_hashes)),
+                    });
+
+                    send_to(
+                        &self.ctx.in_message_senders,
+                        consumer_index,
+                        (meta, in_message),
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_error_response(
+        &self,
+        failure_reason: Cow<'static, str>,
+        action: Option<ErrorResponseAction>,
+        info_hash: Option<InfoHash>,
+    ) -> anyhow::Result<()> {
+        let out_message = OutMessage::ErrorResponse(ErrorResponse {
+            action,
+            failure_reason,
+            info_hash,
+        });
+
+        send_out_message(
+            &self.out_message_sender,
+            (self.make_connection_meta(None).into(), out_message),
+        )
+        .await
+        .with_context(|| "ConnectionReader::send_error_response failed")
+    }
+
+    fn make_connection_meta(&self, pending_scrape_id: Option<PendingScrapeId>) -> InMessageMeta {
+        InMessageMeta {
+            connection_id: self.connection_id,
+            out_message_consumer_id: self.ctx.out_message_consumer_id,
+            ip_version: self.ip_version,
+            pending_scrape_id,
+        }
+    }
+}
+
+struct ConnectionWriter<S> {
+    ctx: Rc<SocketWorkerContext>,
+    out_message_receiver: OutMessageReceiver,
+    shutdown_started: ShutdownStarted,
+    ws_out: SplitSink<WebSocketStream<S>, tungstenite::Message>,
+    pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
+    connection_id: ConnectionId,
+    /// Shared with ConnectionReader, which receives the pongs
+    unanswered_pings: Rc<Cell<usize>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ConnectionWriter<S> {
+    async fn run_out_message_loop(&mut self) -> anyhow::Result<()> {
+        let ping_interval = Duration::from_secs(self.ctx.config.network.websocket_ping_interval);
+        let mut next_ping = Instant::now() + ping_interval;
+
+        loop {
+            let until_next_ping = next_ping.saturating_duration_since(Instant::now());
+            let shutdown_started = self.shutdown_started.clone();
+
+            let event = race(
+                async {
+                    WriterEvent::OutMessage(
+                        receive_out_message(&mut self.out_message_receiver).await,
+                    )
+                },
+                race(
+                    async {
+                        if ping_interval.is_zero() {
+                            futures::future::pending::<()>().await;
+                        }
+
+                        sleep(until_next_ping).await;
+
+                        WriterEvent::SendPing
+                    },
+                    async {
+                        let _ = shutdown_started.await;
+
+                        WriterEvent::Shutdown
+                    },
+                ),
+            )
+            .await;
+
+            let (meta, out_message) = match event {
+                WriterEvent::OutMessage(opt_message) => opt_message.ok_or_else(|| {
+                    anyhow::anyhow!("ConnectionWriter couldn't receive message, sender is closed")
+                })?,
+                WriterEvent::SendPing => {
+                    next_ping = Instant::now() + ping_interval;
+
+                    self.send_ping().await?;
+
+                    continue;
+                }
+                WriterEvent::Shutdown => {
+                    self.send_close().await?;
+
+                    return Ok(());
+                }
+            };
+
+            match out_message {
+                OutMessage::ScrapeResponse(out_message) => {
+                    let pending_scrape_id = meta
+                        .pending_scrape_id
+                        .expect("meta.pending_scrape_id not set");
+
+                    let finished = if let Some(pending) = Slab::get_mut(
+                        &mut RefCell::borrow_mut(&self.pending_scrape_slab),
+                        pending_scrape_id.0 as usize,
+                    ) {
+                        pending.stats.extend(out_message.files);
+                        pending.pending_worker_out_messages -= 1;
+
+                        pending.pending_worker_out_messages == 0
+                    } else {
+                        return Err(anyhow::anyhow!("pending scrape not found in slab"));
+                    };
+
+                    if finished {
+                        let out_message = {
+                            let mut slab = RefCell::borrow_mut(&self.pending_scrape_slab);
+
+                            let pending = slab.remove(pending_scrape_id.0 as usize);
+
+                            slab.shrink_to_fit();
+
+                            OutMessage::ScrapeResponse(ScrapeResponse {
+                                action: ScrapeAction,
+                                files: pending.stats,
+                            })
+                        };
+
+                        self.send_out_message(&out_message).await?;
+                    }
+                }
+                out_message => {
+                    self.send_out_message(&out_message).await?;
+                }
+            };
+        }
+    }
+
+    async fn send_ping(&mut self) -> anyhow::Result<()> {
+        let unanswered_pings = self.unanswered_pings.get();
+
+        if unanswered_pings >= self.ctx.config.network.websocket_max_missed_pongs {
+            return Err(anyhow::anyhow!(
+                "peer didn't answer {} pings, closing connection",
+                unanswered_pings
+            ));
+        }
+
+        let result = timeout(
+            Duration::from_secs(10),
+            futures::SinkExt::send(&mut self.ws_out, tungstenite::Message::Ping(Vec::new())),
+        )
+        .await;
+
+        match result {
+            Some(Ok(())) => {
+                self.unanswered_pings.set(unanswered_pings + 1);
+
+                Ok(())
+            }
+            Some(Err(err)) => Err(err.into()),
+            None => {
+                ::log::debug!("send_ping: sending to peer took to long");
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Send close frame telling peer that server is going away
+    async fn send_close(&mut self) -> anyhow::Result<()> {
+        let close_frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: "Server shutting down".into(),
+        };
+
+        let result = timeout(
+            Duration::from_secs(10),
+            futures::SinkExt::send(
+                &mut self.ws_out,
+                tungstenite::Message::Close(Some(close_frame)),
+            ),
+        )
+        .await;
+
+        match result {
+            Some(Ok(())) => Ok(()),
+            Some(Err(err)) => Err(err.into()),
+            None => {
+                ::log::debug!("send_close: sending to peer took to long");
+
+                Ok(())
+            }
+        }
+    }
+
+    async fn send_out_message(&mut self, out_message: &OutMessage) -> anyhow::Result<()> {
+        let result = timeout(
+            Duration::from_secs(10),
+            futures::SinkExt::send(&mut self.ws_out, out_message.to_ws_message()),
+        )
+        .await;
+
+        match result {
+            Some(Ok(())) => {
+                self.ctx
+                    .connection_slab
+                    .borrow_mut()
+                    .get_mut(self.connection_id.0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "connection reference {} not found in slab",
+                            self.connection_id.0
+                        )
+                    })?
+                    .valid_until = ValidUntil::new(
+                    self.ctx.server_start_instant,
+                    self.ctx.config.cleaning.max_connection_idle,
+                );
+
+                Ok(())
+            }
+            Some(Err(err)) => Err(err.into()),
+            None => {
+                ::log::debug!("send_out_message: sending to peer took to long");
+
+                Ok(())
+            }
+        }
+    }
+}
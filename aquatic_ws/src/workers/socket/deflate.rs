@@ -0,0 +1,1075 @@
+//! permessage-deflate WebSocket extension (RFC 7692)
+//!
+//! tungstenite doesn't support extensions, so compression is done by
+//! DeflateStream, which is placed between tungstenite and the connection
+//! stream. It decompresses messages received from the peer before tungstenite
+//! reads them and compresses messages written by tungstenite before sending
+//! them on.
+//!
+//! Context takeover is always disabled in both directions. The compressor and
+//! decompressor therefore don't keep state between messages and are shared by
+//! all connections of a socket worker. Since no back-references can reach
+//! further than the start of the message, messages no larger than the
+//! negotiated server window are valid regardless of the window size used by
+//! the compressor. Larger messages are sent uncompressed.
+
+use std::cell::RefCell;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_tungstenite::WebSocketStream;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::{ready, AsyncRead, AsyncWrite};
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::header::SEC_WEBSOCKET_EXTENSIONS;
+use tungstenite::http::HeaderValue;
+use tungstenite::protocol::WebSocketConfig;
+
+use crate::common::CompressionStatistics;
+use crate::config::Config;
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+/// Removed from end of compressed messages before sending and appended to
+/// received messages before decompressing
+const MESSAGE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const READ_CHUNK_SIZE: usize = 4096;
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASK: u8 = 0x80;
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+
+/// Parameters negotiated with peer
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DeflateParams {
+    /// Base-2 logarithm of LZ77 window size peer accepts in messages
+    server_max_window_bits: u8,
+}
+
+impl DeflateParams {
+    fn max_compressed_message_size(&self) -> usize {
+        1 << self.server_max_window_bits
+    }
+}
+
+/// Do WebSocket handshake, negotiating permessage-deflate if codec is set and
+/// the client offers it
+pub async fn accept_websocket<S: AsyncRead + AsyncWrite + Unpin>(
+    config: &Config,
+    opt_codec: Option<Rc<DeflateCodec>>,
+    stream: S,
+) -> Result<WebSocketStream<DeflateStream<S>>, tungstenite::Error> {
+    let ws_config = WebSocketConfig {
+        max_frame_size: Some(config.network.websocket_max_frame_size),
+        max_message_size: Some(config.network.websocket_max_message_size),
+        max_send_queue: Some(2),
+        ..Default::default()
+    };
+
+    let mut opt_params = None;
+
+    // Error type is defined by tungstenite
+    #[allow(clippy::result_large_err)]
+    let negotiate_compression = |request: &Request, mut response: Response| {
+        if opt_codec.is_some() {
+            let header_values = request
+                .headers()
+                .get_all(SEC_WEBSOCKET_EXTENSIONS)
+                .iter()
+                .filter_map(|value| value.to_str().ok());
+
+            if let Some((params, header_value)) = negotiate(config, header_values) {
+                // Value only contains visible ASCII characters
+                response.headers_mut().insert(
+                    SEC_WEBSOCKET_EXTENSIONS,
+                    HeaderValue::from_str(&header_value).unwrap(),
+                );
+
+                opt_params = Some(params);
+            }
+        }
+
+        Ok(response)
+    };
+
+    let mut stream = async_tungstenite::accept_hdr_async_with_config(
+        DeflateStream::new(stream),
+        negotiate_compression,
+        Some(ws_config),
+    )
+    .await?;
+
+    if let (Some(codec), Some(params)) = (opt_codec, opt_params) {
+        stream.get_mut().enable_compression(codec, params);
+    }
+
+    Ok(stream)
+}
+
+/// Accept first valid permessage-deflate offer in Sec-WebSocket-Extensions
+/// header values, if any. Returns negotiated parameters and the value of the
+/// response header.
+fn negotiate<'a>(
+    config: &Config,
+    header_values: impl Iterator<Item = &'a str>,
+) -> Option<(DeflateParams, String)> {
+    header_values
+        .flat_map(|value| value.split(','))
+        .find_map(|offer| negotiate_offer(config, offer))
+}
+
+fn negotiate_offer(config: &Config, offer: &str) -> Option<(DeflateParams, String)> {
+    let mut parts = offer.split(';').map(str::trim);
+
+    if !parts.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+        return None;
+    }
+
+    let mut server_no_context_takeover = false;
+    let mut client_no_context_takeover = false;
+    let mut server_max_window_bits = None;
+    let mut client_max_window_bits = None;
+
+    // Decline offers with unknown or repeated parameters
+    for param in parts {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+
+        match (name.to_ascii_lowercase().as_str(), value) {
+            ("server_no_context_takeover", None) if !server_no_context_takeover => {
+                server_no_context_takeover = true;
+            }
+            ("client_no_context_takeover", None) if !client_no_context_takeover => {
+                client_no_context_takeover = true;
+            }
+            ("server_max_window_bits", Some(value)) if server_max_window_bits.is_none() => {
+                server_max_window_bits = Some(parse_window_bits(value)?);
+            }
+            ("client_max_window_bits", None) if client_max_window_bits.is_none() => {
+                client_max_window_bits = Some(15);
+            }
+            ("client_max_window_bits", Some(value)) if client_max_window_bits.is_none() => {
+                client_max_window_bits = Some(parse_window_bits(value)?);
+            }
+            _ => return None,
+        }
+    }
+
+    let window_bits = config.websocket_compression.window_bits.clamp(8, 15);
+
+    let params = DeflateParams {
+        server_max_window_bits: server_max_window_bits.unwrap_or(15).min(window_bits),
+    };
+
+    let mut response = format!(
+        "{}; server_no_context_takeover; client_no_context_takeover; server_max_window_bits={}",
+        EXTENSION_NAME, params.server_max_window_bits
+    );
+
+    // Only allowed in response if included in offer
+    if let Some(bits) = client_max_window_bits {
+        response.push_str(&format!(
+            "; client_max_window_bits={}",
+            bits.min(window_bits)
+        ));
+    }
+
+    Some((params, response))
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    if !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+/// Compressor and decompressor shared by connections of a socket worker
+pub struct DeflateCodec {
+    compressor: RefCell<Compress>,
+    decompressor: RefCell<Decompress>,
+    min_message_size: usize,
+    max_frame_size: usize,
+    max_message_size: usize,
+    statistics: Arc<CompressionStatistics>,
+}
+
+impl DeflateCodec {
+    pub fn new(config: &Config, statistics: Arc<CompressionStatistics>) -> Self {
+        Self {
+            compressor: RefCell::new(Compress::new(Compression::default(), false)),
+            decompressor: RefCell::new(Decompress::new(false)),
+            min_message_size: config.websocket_compression.min_message_size,
+            max_frame_size: config.network.websocket_max_frame_size,
+            max_message_size: config.network.websocket_max_message_size,
+            statistics,
+        }
+    }
+
+    /// Compress message, leaving out trailer
+    fn compress(&self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let mut compressor = self.compressor.borrow_mut();
+
+        compressor.reset();
+
+        loop {
+            output.reserve((input.len() / 2).max(64));
+
+            let consumed = compressor.total_in() as usize;
+
+            compressor
+                .compress_vec(&input[consumed..], output, FlushCompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+            // Flush is complete when output buffer wasn't filled
+            if (compressor.total_in() as usize == input.len()) & (output.len() < output.capacity())
+            {
+                break;
+            }
+        }
+
+        if output.ends_with(&MESSAGE_TRAILER) {
+            output.truncate(output.len() - MESSAGE_TRAILER.len());
+        }
+
+        Ok(())
+    }
+
+    /// Decompress message with trailer appended
+    fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let mut decompressor = self.decompressor.borrow_mut();
+
+        decompressor.reset(false);
+
+        loop {
+            output.reserve(READ_CHUNK_SIZE.max(input.len()));
+
+            let consumed = decompressor.total_in();
+            let produced = decompressor.total_out();
+
+            let status = decompressor
+                .decompress_vec(&input[consumed as usize..], output, FlushDecompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+            if output.len() > self.max_message_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decompressed message too large",
+                ));
+            }
+
+            let finished = (decompressor.total_in() as usize == input.len())
+                & (output.len() < output.capacity());
+
+            if finished | (status == Status::StreamEnd) {
+                return Ok(());
+            }
+
+            if (decompressor.total_in() == consumed) & (decompressor.total_out() == produced) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid compressed message",
+                ));
+            }
+        }
+    }
+}
+
+/// Stream transcoding WebSocket frames once compression has been enabled
+pub struct DeflateStream<S> {
+    inner: S,
+    opt_state: Option<Box<DeflateState>>,
+}
+
+impl<S> DeflateStream<S> {
+    /// Create stream passing on data unchanged until compression is enabled
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            opt_state: None,
+        }
+    }
+
+    /// Start transcoding frames. Call after the WebSocket handshake is done.
+    fn enable_compression(&mut self, codec: Rc<DeflateCodec>, params: DeflateParams) {
+        self.opt_state = Some(Box::new(DeflateState::new(codec, params)));
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let Self { inner, opt_state } = self.get_mut();
+
+        let state = if let Some(state) = opt_state {
+            state
+        } else {
+            return Pin::new(inner).poll_read(cx, buf);
+        };
+
+        loop {
+            let decoded = &state.read_out[state.read_out_pos..];
+
+            if !decoded.is_empty() {
+                let len = decoded.len().min(buf.len());
+
+                buf[..len].copy_from_slice(&decoded[..len]);
+
+                state.read_out_pos += len;
+
+                if state.read_out_pos == state.read_out.len() {
+                    state.read_out.clear();
+                    state.read_out_pos = 0;
+                }
+
+                return Poll::Ready(Ok(len));
+            }
+
+            let start = state.read_in.len();
+
+            state.read_in.resize(start + READ_CHUNK_SIZE, 0);
+
+            let result = Pin::new(&mut *inner).poll_read(cx, &mut state.read_in[start..]);
+
+            match result {
+                Poll::Ready(Ok(len)) => {
+                    state.read_in.truncate(start + len);
+
+                    if len == 0 {
+                        return Poll::Ready(Ok(0));
+                    }
+
+                    state.decode_frames()?;
+                }
+                other => {
+                    state.read_in.truncate(start);
+
+                    return other;
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Self { inner, opt_state } = self.get_mut();
+
+        let state = if let Some(state) = opt_state {
+            state
+        } else {
+            return Pin::new(inner).poll_write(cx, buf);
+        };
+
+        // Only accept more data once previously encoded data has been sent
+        ready!(state.poll_write_out(inner, cx))?;
+
+        state.write_in.extend_from_slice(buf);
+        state.encode_frames()?;
+
+        if let Poll::Ready(Err(err)) = state.poll_write_out(inner, cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Self { inner, opt_state } = self.get_mut();
+
+        if let Some(state) = opt_state {
+            ready!(state.poll_write_out(inner, cx))?;
+        }
+
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Self { inner, opt_state } = self.get_mut();
+
+        if let Some(state) = opt_state {
+            ready!(state.poll_write_out(inner, cx))?;
+        }
+
+        Pin::new(inner).poll_close(cx)
+    }
+}
+
+struct DeflateState {
+    codec: Rc<DeflateCodec>,
+    params: DeflateParams,
+    /// Data received from peer that hasn't been decoded yet
+    read_in: Vec<u8>,
+    /// Decoded data not yet read by tungstenite
+    read_out: Vec<u8>,
+    read_out_pos: usize,
+    /// Opcode and payload of compressed message being received in fragments
+    opt_compressed_message: Option<(u8, Vec<u8>)>,
+    /// Data written by tungstenite that hasn't been encoded yet
+    write_in: Vec<u8>,
+    /// Encoded data not yet sent to peer
+    write_out: Vec<u8>,
+    write_out_pos: usize,
+    /// Fragmented messages are sent uncompressed
+    sending_fragmented_message: bool,
+}
+
+impl DeflateState {
+    fn new(codec: Rc<DeflateCodec>, params: DeflateParams) -> Self {
+        Self {
+            codec,
+            params,
+            read_in: Vec::new(),
+            read_out: Vec::new(),
+            read_out_pos: 0,
+            opt_compressed_message: None,
+            write_in: Vec::new(),
+            write_out: Vec::new(),
+            write_out_pos: 0,
+            sending_fragmented_message: false,
+        }
+    }
+
+    /// Pass on complete frames received from peer, replacing compressed
+    /// messages with uncompressed ones
+    fn decode_frames(&mut self) -> io::Result<()> {
+        let mut pos = 0;
+
+        while let Some(header) = FrameHeader::parse(&self.read_in[pos..])? {
+            if header.payload_len > self.codec.max_frame_size {
+                return Err(invalid_data("frame too large"));
+            }
+
+            let frame_len = header.header_len + header.payload_len;
+
+            if self.read_in.len() - pos < frame_len {
+                break;
+            }
+
+            let frame = &self.read_in[pos..pos + frame_len];
+
+            pos += frame_len;
+
+            let compressed = match (header.opcode, &mut self.opt_compressed_message) {
+                (OPCODE_TEXT | OPCODE_BINARY, Some(_)) => {
+                    return Err(invalid_data("new message started before previous finished"));
+                }
+                (OPCODE_TEXT | OPCODE_BINARY, opt_message @ None) if header.rsv1 => {
+                    opt_message.insert((header.opcode, Vec::new()))
+                }
+                (OPCODE_CONTINUATION, Some(_)) if header.rsv1 => {
+                    return Err(invalid_data("RSV1 set on continuation frame"));
+                }
+                (OPCODE_CONTINUATION, Some(message)) => message,
+                _ => {
+                    // Uncompressed messages and control frames are passed on
+                    // unchanged
+                    self.read_out.extend_from_slice(frame);
+
+                    continue;
+                }
+            };
+
+            let payload = &frame[header.header_len..];
+
+            if compressed.1.len() + payload.len() > self.codec.max_message_size {
+                return Err(invalid_data("compressed message too large"));
+            }
+
+            let start = compressed.1.len();
+
+            compressed.1.extend_from_slice(payload);
+
+            if let Some(mask) = header.opt_mask {
+                apply_mask(&mut compressed.1[start..], mask, 0);
+            }
+
+            if header.fin {
+                let (opcode, mut payload) = self.opt_compressed_message.take().unwrap();
+
+                payload.extend_from_slice(&MESSAGE_TRAILER);
+
+                let mut message = Vec::new();
+
+                self.codec.decompress(&payload, &mut message)?;
+
+                self.write_decoded_message(opcode, &message);
+            }
+        }
+
+        self.read_in.drain(..pos);
+
+        Ok(())
+    }
+
+    /// Write message as masked frames with RSV1 cleared, split up so that
+    /// tungstenite accepts their sizes
+    fn write_decoded_message(&mut self, opcode: u8, message: &[u8]) {
+        let mut chunks = message.chunks(self.codec.max_frame_size.max(1)).peekable();
+        let mut opcode = opcode;
+
+        // Empty messages are sent as one empty frame
+        if chunks.peek().is_none() {
+            write_frame_header(&mut self.read_out, true, false, opcode, Some([0; 4]), 0);
+        }
+
+        while let Some(chunk) = chunks.next() {
+            let fin = chunks.peek().is_none();
+
+            // Masking with zeroes leaves payload unchanged
+            write_frame_header(
+                &mut self.read_out,
+                fin,
+                false,
+                opcode,
+                Some([0; 4]),
+                chunk.len(),
+            );
+
+            self.read_out.extend_from_slice(chunk);
+
+            opcode = OPCODE_CONTINUATION;
+        }
+    }
+
+    /// Compress complete unfragmented data frames written by tungstenite
+    /// if they are within configured size bounds
+    fn encode_frames(&mut self) -> io::Result<()> {
+        let mut pos = 0;
+
+        while let Some(header) = FrameHeader::parse(&self.write_in[pos..])? {
+            let frame_len = header.header_len + header.payload_len;
+
+            if self.write_in.len() - pos < frame_len {
+                break;
+            }
+
+            let frame = &self.write_in[pos..pos + frame_len];
+            let payload = &frame[header.header_len..];
+
+            pos += frame_len;
+
+            let is_data = matches!(header.opcode, OPCODE_TEXT | OPCODE_BINARY);
+
+            if is_data & !header.fin {
+                self.sending_fragmented_message = true;
+            } else if (header.opcode == OPCODE_CONTINUATION) & header.fin {
+                self.sending_fragmented_message = false;
+            }
+
+            let compress = is_data
+                & header.fin
+                & !header.rsv1
+                & header.opt_mask.is_none()
+                & !self.sending_fragmented_message
+                & (payload.len() >= self.codec.min_message_size)
+                & (payload.len() <= self.params.max_compressed_message_size());
+
+            if compress {
+                let mut compressed = Vec::new();
+
+                self.codec.compress(payload, &mut compressed)?;
+
+                if compressed.len() < payload.len() {
+                    self.codec.statistics.add(payload.len(), compressed.len());
+
+                    write_frame_header(
+                        &mut self.write_out,
+                        true,
+                        true,
+                        header.opcode,
+                        None,
+                        compressed.len(),
+                    );
+
+                    self.write_out.extend_from_slice(&compressed);
+
+                    continue;
+                }
+            }
+
+            self.write_out.extend_from_slice(frame);
+        }
+
+        self.write_in.drain(..pos);
+
+        Ok(())
+    }
+
+    fn poll_write_out<S: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut S,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.write_out_pos < self.write_out.len() {
+            let len = ready!(
+                Pin::new(&mut *inner).poll_write(cx, &self.write_out[self.write_out_pos..])
+            )?;
+
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.write_out_pos += len;
+        }
+
+        self.write_out.clear();
+        self.write_out_pos = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    opt_mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl FrameHeader {
+    /// Returns None if more bytes are needed
+    fn parse(bytes: &[u8]) -> io::Result<Option<Self>> {
+        let (first, second) = match bytes {
+            [first, second, ..] => (*first, *second),
+            _ => return Ok(None),
+        };
+
+        let (mut header_len, payload_len) = match second & 0x7f {
+            126 => match bytes.get(2..4) {
+                Some(len) => (4, u16::from_be_bytes(len.try_into().unwrap()) as usize),
+                None => return Ok(None),
+            },
+            127 => match bytes.get(2..10) {
+                Some(len) => (
+                    10,
+                    usize::try_from(u64::from_be_bytes(len.try_into().unwrap()))
+                        .map_err(|_| invalid_data("frame too large"))?,
+                ),
+                None => return Ok(None),
+            },
+            len => (2, len as usize),
+        };
+
+        let opt_mask = if second & MASK != 0 {
+            match bytes.get(header_len..header_len + 4) {
+                Some(mask) => {
+                    header_len += 4;
+
+                    Some(mask.try_into().unwrap())
+                }
+                None => return Ok(None),
+            }
+        } else {
+            None
+        };
+
+        Ok(Some(Self {
+            fin: first & FIN != 0,
+            rsv1: first & RSV1 != 0,
+            opcode: first & 0x0f,
+            opt_mask,
+            header_len,
+            payload_len,
+        }))
+    }
+}
+
+fn write_frame_header(
+    out: &mut Vec<u8>,
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    opt_mask: Option<[u8; 4]>,
+    payload_len: usize,
+) {
+    let mut first = opcode;
+
+    if fin {
+        first |= FIN;
+    }
+    if rsv1 {
+        first |= RSV1;
+    }
+
+    let mask_bit = if opt_mask.is_some() { MASK } else { 0 };
+
+    out.push(first);
+
+    if payload_len < 126 {
+        out.push(mask_bit | payload_len as u8);
+    } else if let Ok(len) = u16::try_from(payload_len) {
+        out.push(mask_bit | 126);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(mask_bit | 127);
+        out.extend_from_slice(&(payload_len as u64).to_be_bytes());
+    }
+
+    if let Some(mask) = opt_mask {
+        out.extend_from_slice(&mask);
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[(offset + i) % 4];
+    }
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    use futures::io::AllowStdIo;
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+
+    fn create_codec(config: &Config) -> Rc<DeflateCodec> {
+        Rc::new(DeflateCodec::new(config, Default::default()))
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut config = Config::default();
+
+        config.websocket_compression.window_bits = 12;
+
+        let negotiate_single = |value: &str| negotiate(&config, ::std::iter::once(value));
+
+        assert_eq!(negotiate_single("x-webkit-deflate-frame"), None);
+        assert_eq!(negotiate_single("permessage-deflate; unknown_param"), None);
+        assert_eq!(
+            negotiate_single("permessage-deflate; server_max_window_bits=16"),
+            None
+        );
+        assert_eq!(
+            negotiate_single(
+                "permessage-deflate; client_no_context_takeover; client_no_context_takeover"
+            ),
+            None
+        );
+
+        assert_eq!(
+            negotiate_single("permessage-deflate; client_max_window_bits"),
+            Some((
+                DeflateParams {
+                    server_max_window_bits: 12
+                },
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=12; client_max_window_bits=12".into()
+            ))
+        );
+
+        // First acceptable offer is chosen
+        assert_eq!(
+            negotiate_single(
+                "permessage-deflate; server_max_window_bits=20, PerMessage-Deflate; server_max_window_bits=\"10\""
+            ),
+            Some((
+                DeflateParams {
+                    server_max_window_bits: 10
+                },
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=10".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decode_and_encode_frames() {
+        let mut config = Config::default();
+
+        config.network.websocket_max_frame_size = 64;
+        config.websocket_compression.min_message_size = 32;
+
+        let codec = create_codec(&config);
+        let mut state = DeflateState::new(
+            codec.clone(),
+            DeflateParams {
+                server_max_window_bits: 15,
+            },
+        );
+
+        let message = b"{\"offer\":{\"type\":\"offer\",\"sdp\":\"v=0\\r\\n\"}}".repeat(8);
+
+        // Compressed message from peer, split into two masked frames
+        let mut compressed = Vec::new();
+
+        codec.compress(&message, &mut compressed).unwrap();
+
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let mask = [1, 2, 3, 4];
+
+        for (fin, rsv1, opcode, payload) in [
+            (false, true, OPCODE_TEXT, first),
+            (true, false, OPCODE_CONTINUATION, second),
+        ] {
+            let mut payload = payload.to_vec();
+
+            apply_mask(&mut payload, mask, 0);
+            write_frame_header(
+                &mut state.read_in,
+                fin,
+                rsv1,
+                opcode,
+                Some(mask),
+                payload.len(),
+            );
+            state.read_in.extend_from_slice(&payload);
+        }
+
+        // Ping is passed on unchanged
+        write_frame_header(&mut state.read_in, true, false, 0x9, Some(mask), 0);
+
+        // Incomplete frame is kept until more data arrives
+        state.read_in.push(FIN | OPCODE_TEXT);
+
+        state.decode_frames().unwrap();
+
+        assert_eq!(state.read_in, [FIN | OPCODE_TEXT]);
+
+        let mut decoded = Vec::new();
+        let mut pos = 0;
+
+        while let Some(header) = FrameHeader::parse(&state.read_out[pos..]).unwrap() {
+            assert!(!header.rsv1);
+            assert!(header.payload_len <= 64);
+
+            if header.opcode == 0x9 {
+                assert!(header.fin);
+            } else {
+                decoded.extend_from_slice(
+                    &state.read_out[pos + header.header_len..][..header.payload_len],
+                );
+            }
+
+            pos += header.header_len + header.payload_len;
+        }
+
+        assert_eq!(pos, state.read_out.len());
+        assert_eq!(decoded, message);
+
+        // Messages written by tungstenite are compressed if large enough
+        for payload in [&b"short"[..], &message[..]] {
+            write_frame_header(
+                &mut state.write_in,
+                true,
+                false,
+                OPCODE_TEXT,
+                None,
+                payload.len(),
+            );
+            state.write_in.extend_from_slice(payload);
+        }
+
+        state.encode_frames().unwrap();
+
+        assert!(state.write_in.is_empty());
+
+        let short = FrameHeader::parse(&state.write_out).unwrap().unwrap();
+
+        assert!(!short.rsv1);
+
+        let long_start = short.header_len + short.payload_len;
+        let long = FrameHeader::parse(&state.write_out[long_start..])
+            .unwrap()
+            .unwrap();
+
+        assert!(long.rsv1);
+        assert!(long.payload_len < message.len());
+
+        let mut payload = state.write_out[long_start + long.header_len..].to_vec();
+        let mut decompressed = Vec::new();
+
+        payload.extend_from_slice(&MESSAGE_TRAILER);
+        codec.decompress(&payload, &mut decompressed).unwrap();
+
+        assert_eq!(decompressed, message);
+        assert_eq!(
+            codec.statistics.take(),
+            (1, message.len(), long.payload_len)
+        );
+    }
+
+    #[test]
+    fn test_accept_websocket() {
+        let mut config = Config::default();
+
+        config.websocket_compression.active = true;
+        config.websocket_compression.min_message_size = 64;
+
+        let statistics = Arc::new(CompressionStatistics::default());
+        let codec = Rc::new(DeflateCodec::new(&config, statistics.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = ::std::thread::spawn(move || run_test_client(addr));
+
+        let (stream, _) = listener.accept().unwrap();
+
+        // Echo text messages until client closes connection
+        futures::executor::block_on(async {
+            let mut stream = accept_websocket(&config, Some(codec), AllowStdIo::new(stream))
+                .await
+                .unwrap();
+
+            while let Some(message) = stream.next().await {
+                match message.unwrap() {
+                    message @ tungstenite::Message::Text(_) => stream.send(message).await.unwrap(),
+                    tungstenite::Message::Close(_) => break,
+                    message => panic!("unexpected message: {:?}", message),
+                }
+            }
+        });
+
+        client.join().unwrap();
+
+        assert_eq!(statistics.take().0, 1);
+    }
+
+    /// WebSocket client with handwritten handshake and frames, since
+    /// tungstenite doesn't support permessage-deflate. Messages are
+    /// compressed and decompressed with flate2 directly, not with
+    /// DeflateCodec.
+    fn run_test_client(addr: SocketAddr) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n",
+            )
+            .unwrap();
+
+        // Read response head byte by byte to not consume any frames
+        let mut response_head = Vec::new();
+
+        while !response_head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+
+            stream.read_exact(&mut byte).unwrap();
+            response_head.push(byte[0]);
+        }
+
+        let response_head = String::from_utf8(response_head)
+            .unwrap()
+            .to_ascii_lowercase();
+
+        assert!(response_head.starts_with("http/1.1 101"));
+        assert!(response_head.contains(
+            "sec-websocket-extensions: permessage-deflate; server_no_context_takeover; \
+            client_no_context_takeover; server_max_window_bits=15; client_max_window_bits=15"
+        ));
+
+        let large_message = b"{\"offer\":{\"type\":\"offer\",\"sdp\":\"v=0\\r\\n\"}}".repeat(16);
+        let small_message = b"{\"action\":\"scrape\"}";
+
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), Compression::default());
+
+        encoder.write_all(&large_message).unwrap();
+        encoder.flush().unwrap();
+
+        let compressed = encoder.get_ref();
+
+        assert!(compressed.ends_with(&MESSAGE_TRAILER));
+
+        write_client_frame(
+            &mut stream,
+            true,
+            OPCODE_TEXT,
+            &compressed[..compressed.len() - MESSAGE_TRAILER.len()],
+        );
+        write_client_frame(&mut stream, false, OPCODE_TEXT, small_message);
+
+        // Large message is compressed when echoed, small one isn't
+        let (rsv1, mut payload) = read_server_frame(&mut stream);
+
+        assert!(rsv1);
+
+        payload.extend_from_slice(&MESSAGE_TRAILER);
+
+        let mut decompressed = Vec::with_capacity(large_message.len() * 2);
+
+        Decompress::new(false)
+            .decompress_vec(&payload, &mut decompressed, FlushDecompress::Sync)
+            .unwrap();
+
+        assert_eq!(decompressed, large_message);
+        assert_eq!(
+            read_server_frame(&mut stream),
+            (false, small_message.to_vec())
+        );
+
+        write_client_frame(&mut stream, false, 0x8, &[]);
+    }
+
+    fn write_client_frame(stream: &mut TcpStream, rsv1: bool, opcode: u8, payload: &[u8]) {
+        let mask = [1, 2, 3, 4];
+        let mut frame = Vec::new();
+
+        write_frame_header(&mut frame, true, rsv1, opcode, Some(mask), payload.len());
+
+        let payload_start = frame.len();
+
+        frame.extend_from_slice(payload);
+        apply_mask(&mut frame[payload_start..], mask, 0);
+
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// Returns RSV1 bit and payload
+    fn read_server_frame(stream: &mut TcpStream) -> (bool, Vec<u8>) {
+        let mut header = [0; 2];
+
+        stream.read_exact(&mut header).unwrap();
+
+        assert_eq!(header[0] & (FIN | 0x0f), FIN | OPCODE_TEXT);
+        assert_eq!(header[1] & MASK, 0);
+
+        let payload_len = match header[1] {
+            126 => {
+                let mut len = [0; 2];
+
+                stream.read_exact(&mut len).unwrap();
+
+                u16::from_be_bytes(len) as usize
+            }
+            127 => panic!("unexpectedly large frame"),
+            len => len as usize,
+        };
+
+        let mut payload = vec![0; payload_len];
+
+        stream.read_exact(&mut payload).unwrap();
+
+        (header[0] & RSV1 != 0, payload)
+    }
+}
@@ -0,0 +1,194 @@
+use std::os::unix::prelude::{FromRawFd, IntoRawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
+use aquatic_ws_protocol::*;
+use futures::channel::oneshot;
+use futures::{FutureExt, StreamExt};
+use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
+use glommio::channels::shared_channel::ConnectedReceiver;
+use glommio::net::TcpListener;
+use glommio::timer::TimerActionRepeat;
+use glommio::{enclose, prelude::*};
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::connection::{
+    clean_connections, drain_connections, forward_out_message, handle_connection,
+    insert_connection, set_connection_task_handle, SocketWorkerContext,
+};
+use super::deflate::DeflateCodec;
+use super::{create_tcp_listener, ShutdownStarted};
+
+pub async fn run_socket_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<RustlsConfig>>,
+    control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
+    in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
+    out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
+    http_request_mesh_builder: MeshBuilder<HttpChannelRequest, Partial>,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) {
+    let listener = create_tcp_listener(&config, priv_dropper)
+        .map(|socket| unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
+        .expect("create tcp listener");
+
+    ::log::info!("created tcp listener");
+
+    let (control_message_senders, _) = control_message_mesh_builder
+        .join(Role::Producer)
+        .await
+        .unwrap();
+
+    let (in_message_senders, _) = in_message_mesh_builder.join(Role::Producer).await.unwrap();
+
+    let (http_request_senders, _) = http_request_mesh_builder
+        .join(Role::Producer)
+        .await
+        .unwrap();
+
+    let tq_prioritized = executor().create_task_queue(
+        Shares::Static(100),
+        Latency::Matters(Duration::from_millis(1)),
+        "prioritized",
+    );
+    let tq_regular =
+        executor().create_task_queue(Shares::Static(1), Latency::NotImportant, "regular");
+
+    let (_, mut out_message_receivers) =
+        out_message_mesh_builder.join(Role::Consumer).await.unwrap();
+    let out_message_consumer_id = ConsumerId(
+        out_message_receivers
+            .consumer_id()
+            .unwrap()
+            .try_into()
+            .unwrap(),
+    );
+
+    ::log::info!("joined channels");
+
+    let opt_deflate_codec = config.websocket_compression.active.then(|| {
+        Rc::new(DeflateCodec::new(
+            &config,
+            state.compression_statistics.clone(),
+        ))
+    });
+
+    let ctx = Rc::new(SocketWorkerContext {
+        config,
+        access_list: state.access_list,
+        client_filter: state.client_filter,
+        request_rate: state.request_rate,
+        num_running_workers: state.num_running_workers,
+        control_message_senders,
+        in_message_senders,
+        http_request_senders,
+        opt_tls_config,
+        opt_deflate_codec,
+        out_message_consumer_id,
+        server_start_instant,
+        connection_slab: Default::default(),
+    });
+    let shutdown = state.shutdown;
+
+    // Periodically clean connections
+    TimerActionRepeat::repeat_into(
+        enclose!((ctx) move || run_connection_cleaning(ctx.clone())),
+        tq_prioritized,
+    )
+    .unwrap();
+
+    for (_, out_message_receiver) in out_message_receivers.streams() {
+        spawn_local_into(
+            receive_out_messages(ctx.clone(), out_message_receiver),
+            tq_regular,
+        )
+        .unwrap()
+        .detach();
+    }
+
+    let running_worker_guard = RunningWorkerGuard::new(ctx.num_running_workers.clone());
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    let shutdown_started: ShutdownStarted = shutdown_receiver.shared();
+
+    let mut incoming = listener.incoming();
+
+    loop {
+        let opt_stream = futures_lite::future::or(incoming.next(), async {
+            shutdown.wait().await;
+
+            None
+        })
+        .await;
+
+        let stream = if let Some(stream) = opt_stream {
+            stream
+        } else {
+            break;
+        };
+
+        match stream {
+            Ok(stream) => {
+                let peer_addr = match stream.peer_addr() {
+                    Ok(addr) => CanonicalSocketAddr::new(addr),
+                    Err(err) => {
+                        ::log::info!("could not extract peer address: {:#}", err);
+
+                        continue;
+                    }
+                };
+
+                let connection = insert_connection(&ctx, peer_addr);
+                let connection_id = connection.connection_id();
+
+                let task_handle = spawn_local_into(
+                    handle_connection(ctx.clone(), shutdown_started.clone(), connection, stream),
+                    tq_regular,
+                )
+                .unwrap()
+                .detach();
+
+                set_connection_task_handle(&ctx, connection_id, task_handle);
+            }
+            Err(err) => {
+                ::log::error!("accept connection: {:#}", err);
+            }
+        }
+    }
+
+    drop(running_worker_guard);
+
+    // Stop accepting connections and tell existing ones to close
+    drop(incoming);
+    drop(listener);
+
+    let _ = shutdown_sender.send(());
+
+    drain_connections(&ctx).await;
+}
+
+async fn run_connection_cleaning(ctx: Rc<SocketWorkerContext>) -> Option<Duration> {
+    clean_connections(&ctx);
+
+    Some(Duration::from_secs(
+        ctx.config.cleaning.connection_cleaning_interval,
+    ))
+}
+
+async fn receive_out_messages(
+    ctx: Rc<SocketWorkerContext>,
+    mut out_message_receiver: ConnectedReceiver<(OutMessageMeta, OutMessage)>,
+) {
+    while let Some((meta, out_message)) = out_message_receiver.next().await {
+        forward_out_message(&ctx, meta, out_message);
+    }
+}
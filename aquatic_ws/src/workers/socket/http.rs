@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use aquatic_common::access_list::AccessListCache;
+use aquatic_common::client_filter::ClientFilterCache;
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_http_protocol::common::InfoHash;
+use aquatic_http_protocol::request::{Request, RequestParseError, ScrapeRequest};
+use aquatic_http_protocol::response::{FailureResponse, Response, ScrapeResponse};
+use futures::stream::FuturesUnordered;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+
+use crate::common::*;
+use crate::config::Config;
+
+const READ_CHUNK_SIZE: usize = 1024;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        pub type HttpRequestSenders = Vec<tokio::sync::mpsc::Sender<HttpChannelRequest>>;
+
+        type ResponseReceiver<T> = tokio::sync::oneshot::Receiver<T>;
+
+        fn create_response_channel<T>() -> (ResponseSender<T>, ResponseReceiver<T>) {
+            tokio::sync::oneshot::channel()
+        }
+
+        async fn send_request(
+            http_request_senders: &HttpRequestSenders,
+            consumer_index: usize,
+            request: HttpChannelRequest,
+        ) -> anyhow::Result<()> {
+            http_request_senders[consumer_index]
+                .send(request)
+                .await
+                .map_err(|_| anyhow::anyhow!("http request receiver closed"))
+        }
+
+        async fn receive_response<T>(response_receiver: ResponseReceiver<T>) -> Option<T> {
+            response_receiver.await.ok()
+        }
+    } else if #[cfg(feature = "glommio")] {
+        pub type HttpRequestSenders = glommio::channels::channel_mesh::Senders<HttpChannelRequest>;
+
+        type ResponseReceiver<T> = glommio::channels::shared_channel::SharedReceiver<T>;
+
+        fn create_response_channel<T: Send + Sized>() -> (ResponseSender<T>, ResponseReceiver<T>) {
+            glommio::channels::shared_channel::new_bounded(1)
+        }
+
+        async fn send_request(
+            http_request_senders: &HttpRequestSenders,
+            consumer_index: usize,
+            request: HttpChannelRequest,
+        ) -> anyhow::Result<()> {
+            // Only fails when receiver is closed
+            http_request_senders
+                .send_to(consumer_index, request)
+                .await
+                .unwrap();
+
+            Ok(())
+        }
+
+        async fn receive_response<T: Send + Sized>(
+            response_receiver: ResponseReceiver<T>,
+        ) -> Option<T> {
+            response_receiver.connect().await.recv().await
+        }
+    }
+}
+
+/// Stream that first returns bytes that have already been read from the
+/// inner stream, e.g., while inspecting the HTTP request head
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    prefix_position: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            prefix_position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+
+        if this.prefix_position < this.prefix.len() {
+            let remaining = &this.prefix[this.prefix_position..];
+            let len = remaining.len().min(buf.len());
+
+            buf[..len].copy_from_slice(&remaining[..len]);
+
+            this.prefix_position += len;
+
+            if this.prefix_position == this.prefix.len() {
+                this.prefix = Vec::new();
+                this.prefix_position = 0;
+            }
+
+            Poll::Ready(Ok(len))
+        } else {
+            Pin::new(&mut this.inner).poll_read(cx, buf)
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Parsed HTTP request head
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RequestHead {
+    /// All bytes read from stream, possibly including some following the head
+    pub bytes: Vec<u8>,
+    /// Path without query string
+    pub path: String,
+    pub websocket_upgrade: bool,
+    /// Values of all X-Forwarded-For headers, joined by commas
+    pub x_forwarded_for: Option<String>,
+    /// Values of all Forwarded headers, joined by commas
+    pub forwarded: Option<String>,
+}
+
+/// Read from stream until a full HTTP request head has been received
+pub async fn read_request_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_size: usize,
+) -> anyhow::Result<RequestHead> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let position = bytes.len();
+
+        if position >= max_size {
+            return Err(anyhow::anyhow!("request head too large"));
+        }
+
+        bytes.resize((position + READ_CHUNK_SIZE).min(max_size), 0);
+
+        let bytes_read = stream.read(&mut bytes[position..]).await?;
+
+        bytes.truncate(position + bytes_read);
+
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("peer closed connection"));
+        }
+
+        if let Some(request_head) = parse_request_head(&bytes)? {
+            return Ok(RequestHead {
+                bytes,
+                ..request_head
+            });
+        }
+    }
+}
+
+/// Returns None if request head is incomplete. Doesn't set bytes field.
+fn parse_request_head(bytes: &[u8]) -> anyhow::Result<Option<RequestHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+
+    match request.parse(bytes)? {
+        httparse::Status::Complete(_) => {
+            let path = request
+                .path
+                .and_then(|path| path.split('?').next())
+                .unwrap_or_default()
+                .to_owned();
+            let mut request_head = RequestHead {
+                path,
+                ..Default::default()
+            };
+
+            for header in request.headers.iter() {
+                let opt_joined_values = if header.name.eq_ignore_ascii_case("upgrade") {
+                    request_head.websocket_upgrade |=
+                        header.value.eq_ignore_ascii_case(b"websocket");
+
+                    None
+                } else if header.name.eq_ignore_ascii_case("x-forwarded-for") {
+                    Some(&mut request_head.x_forwarded_for)
+                } else if header.name.eq_ignore_ascii_case("forwarded") {
+                    Some(&mut request_head.forwarded)
+                } else {
+                    None
+                };
+
+                if let Some(joined_values) = opt_joined_values {
+                    let value = ::std::str::from_utf8(header.value)?;
+
+                    match joined_values {
+                        Some(joined_values) => {
+                            joined_values.push(',');
+                            joined_values.push_str(value);
+                        }
+                        None => {
+                            *joined_values = Some(value.to_owned());
+                        }
+                    }
+                }
+            }
+
+            Ok(Some(request_head))
+        }
+        httparse::Status::Partial => Ok(None),
+    }
+}
+
+/// Answer plain HTTP tracker request, then close connection
+pub async fn handle_http_tracker_request<S: AsyncWrite + Unpin>(
+    config: &Config,
+    access_list_cache: &mut AccessListCache,
+    client_filter_cache: &mut ClientFilterCache,
+    http_request_senders: &HttpRequestSenders,
+    peer_addr: CanonicalSocketAddr,
+    request_head: &[u8],
+    mut stream: S,
+) -> anyhow::Result<()> {
+    let response = match Request::from_bytes(request_head) {
+        Ok(Request::Announce(request)) => {
+            let info_hash = request.info_hash;
+
+            if !access_list_cache
+                .load()
+                .allows(config.access_list.mode, &info_hash.0)
+            {
+                Response::Failure(FailureResponse::new("Info hash not allowed"))
+            } else if let Err(rejection) = client_filter_cache
+                .load()
+                .check(config.client_filter.mode, &request.peer_id.0)
+            {
+                Response::Failure(FailureResponse::new(rejection.to_string()))
+            } else {
+                let (response_sender, response_receiver) = create_response_channel();
+
+                let request = HttpChannelRequest::Announce {
+                    request,
+                    peer_addr,
+                    response_sender,
+                };
+
+                send_request(
+                    http_request_senders,
+                    calculate_http_request_consumer_index(config, info_hash),
+                    request,
+                )
+                .await?;
+
+                receive_response(response_receiver)
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("http announce response sender closed"))?
+            }
+        }
+        Ok(Request::Scrape(ScrapeRequest { info_hashes })) => {
+            let mut info_hashes_by_worker: BTreeMap<usize, Vec<InfoHash>> = BTreeMap::new();
+
+            for info_hash in info_hashes
+                .into_iter()
+                .take(config.protocol.max_scrape_torrents)
+            {
+                info_hashes_by_worker
+                    .entry(calculate_http_request_consumer_index(config, info_hash))
+                    .or_default()
+                    .push(info_hash);
+            }
+
+            let mut response_receivers = Vec::with_capacity(info_hashes_by_worker.len());
+
+            for (consumer_index, info_hashes) in info_hashes_by_worker {
+                let (response_sender, response_receiver) = create_response_channel();
+
+                response_receivers.push(response_receiver);
+
+                let request = HttpChannelRequest::Scrape {
+                    request: ScrapeRequest { info_hashes },
+                    peer_addr,
+                    response_sender,
+                };
+
+                send_request(http_request_senders, consumer_index, request).await?;
+            }
+
+            Response::Scrape(wait_for_scrape_responses(response_receivers).await?)
+        }
+        Err(RequestParseError::NeedMoreData) => {
+            // Shouldn't happen, since full request head has been read
+            return Err(anyhow::anyhow!("incomplete http request"));
+        }
+        Err(err) => {
+            ::log::debug!("invalid http request: {}", err);
+
+            Response::Failure(FailureResponse::new(err.as_str()))
+        }
+    };
+
+    let mut body = Vec::new();
+
+    response.write(&mut body)?;
+
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+    stream.close().await?;
+
+    Ok(())
+}
+
+/// Merge partial scrape responses from swarm workers
+async fn wait_for_scrape_responses(
+    response_receivers: Vec<ResponseReceiver<ScrapeResponse>>,
+) -> anyhow::Result<ScrapeResponse> {
+    let mut responses = response_receivers
+        .into_iter()
+        .map(receive_response)
+        .collect::<FuturesUnordered<_>>();
+
+    let mut files = BTreeMap::new();
+
+    while let Some(opt_response) = responses.next().await {
+        let response =
+            opt_response.ok_or_else(|| anyhow::anyhow!("http scrape response sender closed"))?;
+
+        files.extend(response.files);
+    }
+
+    Ok(ScrapeResponse { files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_head() {
+        assert_eq!(
+            parse_request_head(b"GET /announce?info_hash=").unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_request_head(b"GET /announce?a=b HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap(),
+            Some(RequestHead {
+                path: "/announce".into(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse_request_head(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: WebSocket\r\nX-Forwarded-For: 1.1.1.1\r\nx-forwarded-for: 2.2.2.2\r\n\r\n"
+            )
+            .unwrap(),
+            Some(RequestHead {
+                path: "/".into(),
+                websocket_upgrade: true,
+                x_forwarded_for: Some("1.1.1.1,2.2.2.2".into()),
+                ..Default::default()
+            })
+        );
+        assert!(parse_request_head(b"\x16\x03\x01\x02\x00").is_err());
+    }
+
+    #[test]
+    fn test_prefixed_stream() {
+        let mut stream = PrefixedStream::new(b"GET ".to_vec(), &b"/ HTTP/1.1"[..]);
+        let mut buf = [0u8; 3];
+        let mut output = Vec::new();
+
+        futures_lite::future::block_on(async {
+            loop {
+                match stream.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => output.extend_from_slice(&buf[..n]),
+                }
+            }
+        });
+
+        assert_eq!(output, b"GET / HTTP/1.1");
+    }
+}
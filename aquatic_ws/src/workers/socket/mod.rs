@@ -0,0 +1,200 @@
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        mod tokio;
+
+        pub use self::tokio::run_socket_worker;
+    } else if #[cfg(feature = "glommio")] {
+        mod glommio;
+
+        pub use self::glommio::run_socket_worker;
+    }
+}
+
+mod client_ip;
+mod connection;
+mod deflate;
+mod http;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_ws_protocol::*;
+use futures::channel::oneshot;
+use futures::future::Shared;
+use futures::AsyncWriteExt;
+use hashbrown::HashMap;
+
+use crate::common::*;
+use crate::config::Config;
+
+const LOCAL_CHANNEL_SIZE: usize = 16;
+
+/// Resolves when socket worker has stopped accepting connections because of
+/// graceful shutdown
+type ShutdownStarted = Shared<oneshot::Receiver<()>>;
+
+struct PendingScrapeResponse {
+    pending_worker_out_messages: usize,
+    stats: HashMap<InfoHash, ScrapeStatistics>,
+}
+
+struct AnnouncedTorrent {
+    peer_id: PeerId,
+    /// Same as ValidUntil of peer in swarm worker, so that the entry is
+    /// removed around the time that the swarm worker removes the peer
+    valid_until: ValidUntil,
+}
+
+/// Limits number of events per one second window
+struct RateLimiter {
+    limit: usize,
+    window_start: Instant,
+    used: usize,
+}
+
+impl RateLimiter {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            used: 0,
+        }
+    }
+
+    /// Take up to `amount` units from current window, returning number taken
+    fn take(&mut self, amount: usize) -> usize {
+        let now = Instant::now();
+
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.used = 0;
+        }
+
+        let taken = amount.min(self.limit - self.used);
+
+        self.used += taken;
+
+        taken
+    }
+}
+
+fn is_trusted_proxy(config: &Config, peer_addr: CanonicalSocketAddr) -> bool {
+    config.network.trusted_proxies.iter().any(|proxy_ip| {
+        CanonicalSocketAddr::new(SocketAddr::new(*proxy_ip, 0))
+            .get()
+            .ip()
+            == peer_addr.get().ip()
+    })
+}
+
+async fn send_health_check_response<S: futures::AsyncWrite + Unpin>(
+    mut stream: S,
+    ok: bool,
+    body: &str,
+) -> anyhow::Result<()> {
+    let status = if ok {
+        "200 Ok"
+    } else {
+        "503 Service Unavailable"
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|err| anyhow::anyhow!("error sending health check response: {:#}", err))?;
+    stream
+        .flush()
+        .await
+        .map_err(|err| anyhow::anyhow!("error flushing health check response: {:#}", err))?;
+    stream.close().await?;
+
+    Ok(())
+}
+
+enum WriterEvent {
+    OutMessage(Option<(OutMessageMeta, OutMessage)>),
+    SendPing,
+    Shutdown,
+}
+
+fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+    (info_hash.0[0] as usize) % config.swarm_workers
+}
+
+fn create_tcp_listener(
+    config: &Config,
+    priv_dropper: PrivilegeDropper,
+) -> anyhow::Result<socket2::Socket> {
+    let domain = if config.network.address.is_ipv4() {
+        socket2::Domain::IPV4
+    } else {
+        socket2::Domain::IPV6
+    };
+
+    ::log::info!("creating socket..");
+
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))
+        .with_context(|| "create socket")?;
+
+    if config.network.only_ipv6 {
+        ::log::info!("setting socket to ipv6 only..");
+
+        socket
+            .set_only_v6(true)
+            .with_context(|| "socket: set only ipv6")?;
+    }
+
+    ::log::info!("setting SO_REUSEPORT on socket..");
+
+    socket
+        .set_reuse_port(true)
+        .with_context(|| "socket: set reuse port")?;
+
+    ::log::info!("binding socket..");
+
+    socket
+        .bind(&config.network.address.into())
+        .with_context(|| format!("socket: bind to {}", config.network.address))?;
+
+    ::log::info!("listening on socket..");
+
+    socket
+        .listen(config.network.tcp_backlog)
+        .with_context(|| format!("socket: listen {}", config.network.address))?;
+
+    ::log::info!("running PrivilegeDropper::after_socket_creation..");
+
+    priv_dropper.after_socket_creation()?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(10);
+
+        assert_eq!(limiter.take(4), 4);
+        assert_eq!(limiter.take(4), 4);
+        assert_eq!(limiter.take(4), 2);
+        assert_eq!(limiter.take(1), 0);
+
+        // Start new window
+        limiter.window_start = Instant::now() - Duration::from_secs(1);
+
+        assert_eq!(limiter.take(12), 10);
+        assert_eq!(limiter.take(0), 0);
+    }
+}
@@ -0,0 +1,178 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
+use aquatic_ws_protocol::*;
+use futures::channel::oneshot as shutdown_oneshot;
+use futures::FutureExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::{spawn_local, LocalSet};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::connection::{
+    clean_connections, drain_connections, forward_out_message, handle_connection,
+    insert_connection, set_connection_task_handle, sleep, SocketWorkerContext,
+};
+use super::deflate::DeflateCodec;
+use super::http::HttpRequestSenders;
+use super::{create_tcp_listener, ShutdownStarted};
+
+pub fn run_socket_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<RustlsConfig>>,
+    control_message_senders: Vec<Sender<SwarmControlMessage>>,
+    in_message_senders: Vec<Sender<(InMessageMeta, InMessage)>>,
+    out_message_receiver: Receiver<(OutMessageMeta, OutMessage)>,
+    http_request_senders: HttpRequestSenders,
+    out_message_consumer_id: ConsumerId,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+
+    LocalSet::new().block_on(
+        &runtime,
+        run_inner(
+            config,
+            state,
+            opt_tls_config,
+            control_message_senders,
+            in_message_senders,
+            out_message_receiver,
+            http_request_senders,
+            out_message_consumer_id,
+            priv_dropper,
+            server_start_instant,
+        ),
+    );
+}
+
+async fn run_inner(
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<RustlsConfig>>,
+    control_message_senders: Vec<Sender<SwarmControlMessage>>,
+    in_message_senders: Vec<Sender<(InMessageMeta, InMessage)>>,
+    out_message_receiver: Receiver<(OutMessageMeta, OutMessage)>,
+    http_request_senders: HttpRequestSenders,
+    out_message_consumer_id: ConsumerId,
+    priv_dropper: PrivilegeDropper,
+    server_start_instant: ServerStartInstant,
+) {
+    let listener = create_tcp_listener(&config, priv_dropper)
+        .and_then(|socket| {
+            socket.set_nonblocking(true)?;
+
+            Ok(TcpListener::from_std(socket.into())?)
+        })
+        .expect("create tcp listener");
+
+    ::log::info!("created tcp listener");
+
+    let opt_deflate_codec = config.websocket_compression.active.then(|| {
+        Rc::new(DeflateCodec::new(
+            &config,
+            state.compression_statistics.clone(),
+        ))
+    });
+
+    let ctx = Rc::new(SocketWorkerContext {
+        config,
+        access_list: state.access_list,
+        client_filter: state.client_filter,
+        request_rate: state.request_rate,
+        num_running_workers: state.num_running_workers,
+        control_message_senders,
+        in_message_senders,
+        http_request_senders,
+        opt_tls_config,
+        opt_deflate_codec,
+        out_message_consumer_id,
+        server_start_instant,
+        connection_slab: Default::default(),
+    });
+    let shutdown = state.shutdown;
+
+    spawn_local(periodically_clean_connections(ctx.clone()));
+    spawn_local(receive_out_messages(ctx.clone(), out_message_receiver));
+
+    let running_worker_guard = RunningWorkerGuard::new(ctx.num_running_workers.clone());
+
+    let (shutdown_sender, shutdown_receiver) = shutdown_oneshot::channel();
+    let shutdown_started: ShutdownStarted = shutdown_receiver.shared();
+
+    loop {
+        let opt_result = futures_lite::future::or(async { Some(listener.accept().await) }, async {
+            shutdown.wait().await;
+
+            None
+        })
+        .await;
+
+        let result = if let Some(result) = opt_result {
+            result
+        } else {
+            break;
+        };
+
+        match result {
+            Ok((stream, peer_addr)) => {
+                let connection = insert_connection(&ctx, CanonicalSocketAddr::new(peer_addr));
+                let connection_id = connection.connection_id();
+
+                let task_handle = spawn_local(handle_connection(
+                    ctx.clone(),
+                    shutdown_started.clone(),
+                    connection,
+                    stream.compat(),
+                ));
+
+                set_connection_task_handle(&ctx, connection_id, task_handle);
+            }
+            Err(err) => {
+                ::log::error!("accept connection: {:#}", err);
+            }
+        }
+    }
+
+    drop(running_worker_guard);
+
+    // Stop accepting connections and tell existing ones to close
+    drop(listener);
+
+    let _ = shutdown_sender.send(());
+
+    drain_connections(&ctx).await;
+}
+
+async fn periodically_clean_connections(ctx: Rc<SocketWorkerContext>) {
+    loop {
+        sleep(Duration::from_secs(
+            ctx.config.cleaning.connection_cleaning_interval,
+        ))
+        .await;
+
+        clean_connections(&ctx);
+    }
+}
+
+async fn receive_out_messages(
+    ctx: Rc<SocketWorkerContext>,
+    mut out_message_receiver: Receiver<(OutMessageMeta, OutMessage)>,
+) {
+    while let Some((meta, out_message)) = out_message_receiver.recv().await {
+        forward_out_message(&ctx, meta, out_message);
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use aquatic_common::PanicSentinel;
+
+use crate::common::State;
+use crate::config::Config;
+
+pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: State) {
+    loop {
+        ::std::thread::sleep(Duration::from_secs(config.statistics.interval));
+
+        if config.statistics.print_to_stdout {
+            if config.client_filter.mode.is_on() {
+                let client_filter = state.client_filter.load();
+
+                println!("Client filter matches:");
+
+                for (rule, num_matches) in client_filter.match_counts() {
+                    println!("  {:<20} {:>10}", rule, num_matches);
+                }
+
+                println!(
+                    "  {:<20} {:>10}",
+                    "(no match)",
+                    client_filter.num_unmatched()
+                );
+            }
+
+            if config.protocol.peer_announce_min_interval != 0 {
+                println!("Early announces by client:");
+
+                for (client, count) in state.early_announces.counts() {
+                    println!("  {:<20} {:>10}", client, count);
+                }
+            }
+
+            if config.websocket_compression.active {
+                let (messages, uncompressed_bytes, compressed_bytes) =
+                    state.compression_statistics.take();
+
+                let ratio = if uncompressed_bytes == 0 {
+                    1.0
+                } else {
+                    compressed_bytes as f64 / uncompressed_bytes as f64
+                };
+
+                println!("WebSocket compression:");
+                println!("  {:<20} {:>10}", "messages", messages);
+                println!("  {:<20} {:>10}", "uncompressed bytes", uncompressed_bytes);
+                println!("  {:<20} {:>10}", "compressed bytes", compressed_bytes);
+                println!("  {:<20} {:>10.2}", "ratio", ratio);
+            }
+
+            println!();
+        }
+    }
+}
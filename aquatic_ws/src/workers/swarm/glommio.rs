@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
+use glommio::enclose;
+use glommio::prelude::*;
+use glommio::timer::TimerActionRepeat;
+use rand::{rngs::SmallRng, SeedableRng};
+
+use aquatic_common::{PanicSentinel, SecondsSinceServerStart, ServerStartInstant};
+use aquatic_ws_protocol::*;
+
+use crate::common::*;
+use crate::config::Config;
+use crate::SHARED_IN_CHANNEL_SIZE;
+
+use super::http::{handle_http_request_stream, save_http_torrent_maps, HttpTorrentMaps};
+use super::{
+    handle_announce_request, handle_control_message_stream, handle_early_announce,
+    handle_scrape_request, TorrentMaps,
+};
+
+pub async fn run_swarm_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    worker_index: usize,
+    http_torrents: HttpTorrentMaps,
+    control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
+    in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
+    out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
+    http_request_mesh_builder: MeshBuilder<HttpChannelRequest, Partial>,
+    server_start_instant: ServerStartInstant,
+) {
+    let (_, mut control_message_receivers) = control_message_mesh_builder
+        .join(Role::Consumer)
+        .await
+        .unwrap();
+
+    let (_, mut in_message_receivers) = in_message_mesh_builder.join(Role::Consumer).await.unwrap();
+    let (out_message_senders, _) = out_message_mesh_builder.join(Role::Producer).await.unwrap();
+    let (_, mut http_request_receivers) = http_request_mesh_builder
+        .join(Role::Consumer)
+        .await
+        .unwrap();
+
+    let out_message_senders = Rc::new(out_message_senders);
+
+    let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
+    let http_torrents = Rc::new(RefCell::new(http_torrents));
+    let access_list = state.access_list.clone();
+
+    // Periodically clean torrents
+    TimerActionRepeat::repeat(
+        enclose!((config, torrents, http_torrents, access_list) move || {
+            enclose!((config, torrents, http_torrents, access_list) move || async move {
+                torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+                http_torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+
+                Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+            })()
+        }),
+    );
+
+    let mut handles = Vec::new();
+
+    for (_, receiver) in control_message_receivers.streams() {
+        let handle =
+            spawn_local(handle_control_message_stream(torrents.clone(), receiver)).detach();
+
+        handles.push(handle);
+    }
+
+    for (_, receiver) in in_message_receivers.streams() {
+        let handle = spawn_local(handle_request_stream(
+            config.clone(),
+            torrents.clone(),
+            state.clone(),
+            server_start_instant,
+            out_message_senders.clone(),
+            receiver,
+        ))
+        .detach();
+
+        handles.push(handle);
+    }
+
+    for (_, receiver) in http_request_receivers.streams() {
+        let now = create_now(server_start_instant);
+
+        let handle = spawn_local(handle_http_request_stream(
+            config.clone(),
+            http_torrents.clone(),
+            state.clone(),
+            now,
+            receiver,
+        ))
+        .detach();
+
+        handles.push(handle);
+    }
+
+    let running_worker_guard = RunningWorkerGuard::new(state.num_running_workers.clone());
+
+    // Streams end when all socket workers have exited
+    for handle in handles {
+        handle.await;
+    }
+
+    if state.shutdown.is_triggered() && config.shutdown.persist_state() {
+        if let Err(err) = save_http_torrent_maps(
+            &config,
+            worker_index,
+            &http_torrents.borrow(),
+            server_start_instant.seconds_elapsed(),
+        ) {
+            ::log::error!("couldn't save http torrent state: {:#}", err);
+        }
+    }
+
+    drop(running_worker_guard);
+}
+
+async fn handle_request_stream<S>(
+    config: Config,
+    torrents: Rc<RefCell<TorrentMaps>>,
+    state: State,
+    server_start_instant: ServerStartInstant,
+    out_message_senders: Rc<Senders<(OutMessageMeta, OutMessage)>>,
+    stream: S,
+) where
+    S: futures_lite::Stream<Item = (InMessageMeta, InMessage)> + ::std::marker::Unpin,
+{
+    let rng = Rc::new(RefCell::new(SmallRng::from_entropy()));
+
+    let now = create_now(server_start_instant);
+
+    let config = &config;
+    let torrents = &torrents;
+    let state = &state;
+    let now = &now;
+    let rng = &rng;
+    let out_message_senders = &out_message_senders;
+
+    stream
+        .for_each_concurrent(
+            SHARED_IN_CHANNEL_SIZE,
+            move |(meta, in_message)| async move {
+                let mut out_messages = Vec::new();
+
+                match in_message {
+                    InMessage::AnnounceRequest(request) => {
+                        if let Some(out_message) = handle_early_announce(
+                            config,
+                            state,
+                            &torrents.borrow(),
+                            now.borrow().to_owned(),
+                            meta,
+                            &request,
+                        ) {
+                            out_messages.push((meta.into(), out_message));
+                        } else {
+                            handle_announce_request(
+                                &config,
+                                &mut rng.borrow_mut(),
+                                &mut torrents.borrow_mut(),
+                                &state.request_rate,
+                                &mut out_messages,
+                                now.borrow().to_owned(),
+                                meta,
+                                request,
+                            )
+                        }
+                    }
+                    InMessage::ScrapeRequest(request) => handle_scrape_request(
+                        &config,
+                        &mut torrents.borrow_mut(),
+                        &mut out_messages,
+                        meta,
+                        request,
+                    ),
+                };
+
+                for (meta, out_message) in out_messages.drain(..) {
+                    // Fails when socket worker has exited, e.g., during
+                    // graceful shutdown
+                    if let Err(err) = out_message_senders
+                        .send_to(meta.out_message_consumer_id.0 as usize, (meta, out_message))
+                        .await
+                    {
+                        ::log::debug!("failed sending out_message to socket worker: {:#}", err);
+
+                        continue;
+                    }
+
+                    ::log::debug!("swarm worker sent OutMessage to socket worker");
+                }
+            },
+        )
+        .await;
+}
+
+/// Create current time that is updated every second
+fn create_now(server_start_instant: ServerStartInstant) -> Rc<RefCell<SecondsSinceServerStart>> {
+    let now = Rc::new(RefCell::new(server_start_instant.seconds_elapsed()));
+
+    TimerActionRepeat::repeat(enclose!((now) move || {
+        enclose!((now) move || async move {
+            *now.borrow_mut() = server_start_instant.seconds_elapsed();
+
+            Some(Duration::from_secs(1))
+        })()
+    }));
+
+    now
+}
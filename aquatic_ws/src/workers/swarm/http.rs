@@ -0,0 +1,708 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::early_announce::EarlyAnnounceAction;
+use aquatic_common::{
+    extract_filtered_response_peers, extract_response_peers, AmortizedIndexMap,
+    CanonicalSocketAddr, IndexMap, SecondsSinceServerStart, ServerStartInstant,
+};
+use aquatic_http_protocol::common::{AnnounceEvent, InfoHash, PeerId};
+use aquatic_http_protocol::request::{AnnounceRequest, ScrapeRequest};
+use aquatic_http_protocol::response::{
+    AnnounceResponse, FailureResponse, Response, ResponsePeer, ResponsePeerListV4,
+    ResponsePeerListV6, RetryIn, ScrapeResponse, ScrapeStatistics,
+};
+use futures::StreamExt;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::PeerStatus;
+
+mod persistence;
+
+pub use persistence::{load_http_torrent_maps, save_http_torrent_maps};
+
+trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash + Into<IpAddr> {}
+
+impl Ip for Ipv4Addr {}
+impl Ip for Ipv6Addr {}
+
+#[derive(Clone)]
+struct Peer<I: Ip> {
+    pub ip_address: I,
+    pub port: u16,
+    pub key: Option<Box<str>>,
+    pub status: PeerStatus,
+    pub valid_until: ValidUntil,
+    /// None for peers loaded from saved state
+    pub last_announce: Option<SecondsSinceServerStart>,
+}
+
+impl<I: Ip> Peer<I> {
+    fn to_response_peer(&self) -> ResponsePeer<I> {
+        ResponsePeer {
+            ip_address: self.ip_address,
+            port: self.port,
+        }
+    }
+}
+
+/// Since peers don't have a connection to tie them to, requests from other
+/// IP addresses than the one a peer was registered from are subject to the
+/// peer identity check
+type PeerMap<I> = IndexMap<PeerId, Peer<I>>;
+
+struct TorrentData<I: Ip> {
+    pub peers: PeerMap<I>,
+    pub num_seeders: usize,
+    /// Number of leechers, excluding partial seeds
+    pub num_leechers: usize,
+    pub num_partial_seeds: usize,
+    /// Response peers last sent to each peer. Only filled in when early
+    /// announces are answered with cached responses.
+    pub cached_response_peers: IndexMap<PeerId, Vec<ResponsePeer<I>>>,
+}
+
+impl<I: Ip> Default for TorrentData<I> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            peers: Default::default(),
+            num_seeders: 0,
+            num_leechers: 0,
+            num_partial_seeds: 0,
+            cached_response_peers: Default::default(),
+        }
+    }
+}
+
+impl<I: Ip> TorrentData<I> {
+    fn scrape_statistics(&self) -> ScrapeStatistics {
+        ScrapeStatistics {
+            complete: self.num_seeders,
+            incomplete: self.num_leechers + self.num_partial_seeds,
+            downloaded: 0,
+            downloaders: self.num_leechers,
+        }
+    }
+}
+
+type TorrentMap<I> = AmortizedIndexMap<InfoHash, TorrentData<I>>;
+
+/// Swarms of plain HTTP tracker peers, kept separately from WebTorrent swarms
+#[derive(Default)]
+pub struct HttpTorrentMaps {
+    ipv4: TorrentMap<Ipv4Addr>,
+    ipv6: TorrentMap<Ipv6Addr>,
+}
+
+impl HttpTorrentMaps {
+    pub fn clean(
+        &mut self,
+        config: &Config,
+        access_list: &Arc<AccessListArcSwap>,
+        server_start_instant: ServerStartInstant,
+    ) {
+        let mut access_list_cache = create_access_list_cache(access_list);
+        let now = server_start_instant.seconds_elapsed();
+
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now);
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now);
+    }
+
+    fn clean_torrent_map<I: Ip>(
+        config: &Config,
+        access_list_cache: &mut AccessListCache,
+        torrent_map: &mut TorrentMap<I>,
+        now: SecondsSinceServerStart,
+    ) {
+        torrent_map.retain(|info_hash, torrent_data| {
+            if !access_list_cache
+                .load()
+                .allows(config.access_list.mode, &info_hash.0)
+            {
+                return false;
+            }
+
+            let num_seeders = &mut torrent_data.num_seeders;
+            let num_leechers = &mut torrent_data.num_leechers;
+            let num_partial_seeds = &mut torrent_data.num_partial_seeds;
+
+            torrent_data.peers.retain(|_, peer| {
+                let keep = peer.valid_until.valid(now);
+
+                if !keep {
+                    match peer.status {
+                        PeerStatus::Seeding => *num_seeders -= 1,
+                        PeerStatus::Leeching => *num_leechers -= 1,
+                        PeerStatus::PartialSeeding => *num_partial_seeds -= 1,
+                        PeerStatus::Stopped => (),
+                    }
+                }
+
+                keep
+            });
+
+            if !torrent_data.cached_response_peers.is_empty() {
+                let peers = &torrent_data.peers;
+
+                torrent_data
+                    .cached_response_peers
+                    .retain(|peer_id, _| peers.contains_key(peer_id));
+            }
+
+            !torrent_data.peers.is_empty()
+        });
+
+        torrent_map.shrink_to_fit();
+    }
+}
+
+pub async fn handle_http_request_stream<S>(
+    config: Config,
+    torrents: Rc<RefCell<HttpTorrentMaps>>,
+    state: State,
+    now: Rc<RefCell<SecondsSinceServerStart>>,
+    mut stream: S,
+) where
+    S: futures_lite::Stream<Item = HttpChannelRequest> + ::std::marker::Unpin,
+{
+    let mut rng = SmallRng::from_entropy();
+
+    while let Some(request) = stream.next().await {
+        match request {
+            HttpChannelRequest::Announce {
+                request,
+                peer_addr,
+                response_sender,
+            } => {
+                let response = handle_announce_request(
+                    &config,
+                    &mut rng,
+                    &mut torrents.borrow_mut(),
+                    &state,
+                    now.borrow().to_owned(),
+                    peer_addr,
+                    request,
+                );
+
+                if let Err(err) = send_response(response_sender, response).await {
+                    ::log::error!(
+                        "swarm worker could not send http announce response: {:#}",
+                        err
+                    );
+                }
+            }
+            HttpChannelRequest::Scrape {
+                request,
+                peer_addr,
+                response_sender,
+            } => {
+                let response =
+                    handle_scrape_request(&config, &torrents.borrow(), peer_addr, request);
+
+                if let Err(err) = send_response(response_sender, response).await {
+                    ::log::error!(
+                        "swarm worker could not send http scrape response: {:#}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        async fn send_response<T>(
+            response_sender: ResponseSender<T>,
+            response: T,
+        ) -> anyhow::Result<()> {
+            response_sender
+                .send(response)
+                .map_err(|_| anyhow::anyhow!("receiver closed"))
+        }
+    } else if #[cfg(feature = "glommio")] {
+        async fn send_response<T: Send + Sized + ::std::fmt::Debug>(
+            response_sender: ResponseSender<T>,
+            response: T,
+        ) -> anyhow::Result<()> {
+            response_sender
+                .connect()
+                .await
+                .send(response)
+                .await
+                .map_err(|err| anyhow::anyhow!("{:#}", err))
+        }
+    }
+}
+
+fn handle_announce_request(
+    config: &Config,
+    rng: &mut impl Rng,
+    torrent_maps: &mut HttpTorrentMaps,
+    state: &State,
+    now: SecondsSinceServerStart,
+    peer_addr: CanonicalSocketAddr,
+    request: AnnounceRequest,
+) -> Response {
+    match peer_addr.get().ip() {
+        IpAddr::V4(ip_address) => {
+            let torrent_data = torrent_maps.ipv4.entry(request.info_hash).or_default();
+
+            if let Some(response) = check_peer_identity(config, torrent_data, ip_address, &request)
+            {
+                return response;
+            }
+
+            let (seeders, leechers, response_peers) =
+                match handle_early_announce(config, state, torrent_data, ip_address, &request, now)
+                {
+                    Some(Ok(cached)) => cached,
+                    Some(Err(response)) => return response,
+                    None => upsert_peer_and_get_response_peers(
+                        config,
+                        rng,
+                        ip_address,
+                        torrent_data,
+                        request,
+                        now,
+                    ),
+                };
+
+            let mut response = create_announce_response(config, state, seeders, leechers);
+
+            response.peers = ResponsePeerListV4(response_peers);
+
+            Response::Announce(response)
+        }
+        IpAddr::V6(ip_address) => {
+            let torrent_data = torrent_maps.ipv6.entry(request.info_hash).or_default();
+
+            if let Some(response) = check_peer_identity(config, torrent_data, ip_address, &request)
+            {
+                return response;
+            }
+
+            let (seeders, leechers, response_peers) =
+                match handle_early_announce(config, state, torrent_data, ip_address, &request, now)
+                {
+                    Some(Ok(cached)) => cached,
+                    Some(Err(response)) => return response,
+                    None => upsert_peer_and_get_response_peers(
+                        config,
+                        rng,
+                        ip_address,
+                        torrent_data,
+                        request,
+                        now,
+                    ),
+                };
+
+            let mut response = create_announce_response(config, state, seeders, leechers);
+
+            response.peers6 = ResponsePeerListV6(response_peers);
+
+            Response::Announce(response)
+        }
+    }
+}
+
+/// Create announce response without peers
+fn create_announce_response(
+    config: &Config,
+    state: &State,
+    seeders: usize,
+    leechers: usize,
+) -> AnnounceResponse {
+    AnnounceResponse {
+        complete: seeders,
+        incomplete: leechers,
+        announce_interval: config.adaptive_announce_interval.calculate(
+            config.protocol.peer_announce_interval,
+            seeders + leechers,
+            &state.request_rate,
+        ),
+        min_announce_interval: Some(config.protocol.peer_announce_min_interval)
+            .filter(|interval| *interval != 0),
+        peers: ResponsePeerListV4(vec![]),
+        peers6: ResponsePeerListV6(vec![]),
+        tracker_id: None,
+        external_ip: None,
+        warning_message: None,
+    }
+}
+
+/// Return failure response if request is for a peer_id registered from a
+/// different IP address and doesn't pass the peer identity check
+fn check_peer_identity<I: Ip>(
+    config: &Config,
+    torrent_data: &TorrentData<I>,
+    ip_address: I,
+    request: &AnnounceRequest,
+) -> Option<Response> {
+    let peer = torrent_data.peers.get(&request.peer_id)?;
+
+    if peer.ip_address == ip_address
+        || config
+            .http_tracker
+            .peer_identity_check
+            .allows_ip_change(peer.key.as_deref(), request.key.as_deref())
+    {
+        return None;
+    }
+
+    Some(Response::Failure(FailureResponse::new(
+        "Peer ID in use from other IP address",
+    )))
+}
+
+/// Number of seeders, number of leechers and response peers
+type AnnounceOutcome<I> = (usize, usize, Vec<ResponsePeer<I>>);
+
+/// Handle request if it is an announce without event from a peer that
+/// announced less than the minimum announce interval ago. Such peers are
+/// not updated. Depending on early_announce_action, return current number
+/// of seeders and leechers along with the response peers that the peer was
+/// last sent, or a failure response.
+fn handle_early_announce<I: Ip>(
+    config: &Config,
+    state: &State,
+    torrent_data: &TorrentData<I>,
+    ip_address: I,
+    request: &AnnounceRequest,
+    now: SecondsSinceServerStart,
+) -> Option<Result<AnnounceOutcome<I>, Response>> {
+    let min_interval = config.protocol.peer_announce_min_interval;
+
+    if min_interval == 0 || request.event != AnnounceEvent::Empty {
+        return None;
+    }
+
+    let peer = torrent_data
+        .peers
+        .get(&request.peer_id)
+        .filter(|peer| peer.ip_address == ip_address)?;
+
+    let seconds_since_last_announce = now.seconds_since(peer.last_announce?) as usize;
+
+    if seconds_since_last_announce >= min_interval {
+        return None;
+    }
+
+    state.early_announces.increment(&request.peer_id.0);
+
+    let result = match config.protocol.early_announce_action {
+        EarlyAnnounceAction::Cached => Ok((
+            torrent_data.num_seeders,
+            torrent_data.num_leechers + torrent_data.num_partial_seeds,
+            torrent_data
+                .cached_response_peers
+                .get(&request.peer_id)
+                .cloned()
+                .unwrap_or_default(),
+        )),
+        EarlyAnnounceAction::Failure => {
+            let seconds_left = min_interval - seconds_since_last_announce;
+
+            Err(Response::Failure(FailureResponse {
+                failure_reason: "Announcing too often".into(),
+                retry_in: Some(RetryIn::Minutes((seconds_left + 59) / 60)),
+            }))
+        }
+    };
+
+    Some(result)
+}
+
+/// Insert/update/remove peer. Return number of seeders, number of leechers
+/// (including partial seeds) and response peers
+fn upsert_peer_and_get_response_peers<I: Ip>(
+    config: &Config,
+    rng: &mut impl Rng,
+    ip_address: I,
+    torrent_data: &mut TorrentData<I>,
+    request: AnnounceRequest,
+    now: SecondsSinceServerStart,
+) -> AnnounceOutcome<I> {
+    let peer_status = match request.event {
+        AnnounceEvent::Stopped => PeerStatus::Stopped,
+        _ if request.bytes_left == 0 => PeerStatus::Seeding,
+        AnnounceEvent::Paused => PeerStatus::PartialSeeding,
+        _ => PeerStatus::Leeching,
+    };
+
+    let opt_removed_peer = if let PeerStatus::Stopped = peer_status {
+        torrent_data.peers.remove(&request.peer_id)
+    } else {
+        let peer = Peer {
+            ip_address,
+            port: request.port,
+            key: request.key.as_deref().map(Box::from),
+            status: peer_status,
+            valid_until: ValidUntil::new_with_now(now, config.cleaning.max_peer_age),
+            last_announce: Some(now),
+        };
+
+        match peer_status {
+            PeerStatus::Seeding => torrent_data.num_seeders += 1,
+            PeerStatus::Leeching => torrent_data.num_leechers += 1,
+            PeerStatus::PartialSeeding => torrent_data.num_partial_seeds += 1,
+            PeerStatus::Stopped => (),
+        }
+
+        torrent_data.peers.insert(request.peer_id, peer)
+    };
+
+    match opt_removed_peer.map(|peer| peer.status) {
+        Some(PeerStatus::Seeding) => {
+            torrent_data.num_seeders -= 1;
+        }
+        Some(PeerStatus::Leeching) => {
+            torrent_data.num_leechers -= 1;
+        }
+        Some(PeerStatus::PartialSeeding) => {
+            torrent_data.num_partial_seeds -= 1;
+        }
+        _ => {}
+    }
+
+    let response_peers = if let PeerStatus::Stopped = peer_status {
+        Vec::new()
+    } else {
+        let max_num_peers_to_take = match request.numwant {
+            Some(0) | None => config.http_tracker.max_peers,
+            Some(numwant) => numwant.min(config.http_tracker.max_peers),
+        };
+
+        if peer_status == PeerStatus::Seeding && torrent_data.num_partial_seeds != 0 {
+            // Partial seeds won't download, so they are of no use to seeders
+            extract_filtered_response_peers(
+                rng,
+                &torrent_data.peers,
+                max_num_peers_to_take,
+                request.peer_id,
+                |peer| peer.status != PeerStatus::PartialSeeding,
+                Peer::to_response_peer,
+            )
+        } else {
+            extract_response_peers(
+                rng,
+                &torrent_data.peers,
+                max_num_peers_to_take,
+                request.peer_id,
+                Peer::to_response_peer,
+            )
+        }
+    };
+
+    // Store response peers for answering early announces
+    if config.protocol.peer_announce_min_interval != 0
+        && config.protocol.early_announce_action == EarlyAnnounceAction::Cached
+    {
+        if let PeerStatus::Stopped = peer_status {
+            torrent_data.cached_response_peers.remove(&request.peer_id);
+        } else {
+            torrent_data
+                .cached_response_peers
+                .insert(request.peer_id, response_peers.clone());
+        }
+    }
+
+    (
+        torrent_data.num_seeders,
+        torrent_data.num_leechers + torrent_data.num_partial_seeds,
+        response_peers,
+    )
+}
+
+fn handle_scrape_request(
+    config: &Config,
+    torrent_maps: &HttpTorrentMaps,
+    peer_addr: CanonicalSocketAddr,
+    request: ScrapeRequest,
+) -> ScrapeResponse {
+    let mut files = BTreeMap::new();
+
+    let info_hashes = request
+        .info_hashes
+        .into_iter()
+        .take(config.protocol.max_scrape_torrents);
+
+    for info_hash in info_hashes {
+        let opt_torrent_data_stats = if peer_addr.get().is_ipv4() {
+            torrent_maps
+                .ipv4
+                .get(&info_hash)
+                .map(TorrentData::scrape_statistics)
+        } else {
+            torrent_maps
+                .ipv6
+                .get(&info_hash)
+                .map(TorrentData::scrape_statistics)
+        };
+
+        if let Some(stats) = opt_torrent_data_stats {
+            files.insert(info_hash, stats);
+        }
+    }
+
+    ScrapeResponse { files }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use aquatic_common::peer_identity::PeerIdentityCheck;
+
+    use super::*;
+
+    const INFO_HASH: InfoHash = InfoHash([0; 20]);
+
+    fn announce_request(
+        peer_index: u8,
+        bytes_left: usize,
+        event: AnnounceEvent,
+    ) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: INFO_HASH,
+            peer_id: PeerId([peer_index; 20]),
+            port: 1,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left,
+            event,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+        }
+    }
+
+    fn peer_addr(peer_index: u8) -> CanonicalSocketAddr {
+        CanonicalSocketAddr::new(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, peer_index)),
+            1,
+        ))
+    }
+
+    fn announce(
+        config: &Config,
+        torrent_maps: &mut HttpTorrentMaps,
+        peer_index: u8,
+        bytes_left: usize,
+        event: AnnounceEvent,
+    ) -> Response {
+        let request = announce_request(peer_index, bytes_left, event);
+
+        announce_from(config, torrent_maps, peer_addr(peer_index), request)
+    }
+
+    fn announce_from(
+        config: &Config,
+        torrent_maps: &mut HttpTorrentMaps,
+        peer_addr: CanonicalSocketAddr,
+        request: AnnounceRequest,
+    ) -> Response {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        handle_announce_request(
+            config,
+            &mut rng,
+            torrent_maps,
+            &State::default(),
+            ServerStartInstant::new().seconds_elapsed(),
+            peer_addr,
+            request,
+        )
+    }
+
+    #[test]
+    fn test_partial_seeds() {
+        let mut config = Config::default();
+
+        config.http_tracker.max_peers = 5;
+
+        let mut torrent_maps = HttpTorrentMaps::default();
+
+        // Partial seeds first, so that they would take up all places in
+        // response peer selection if filtered after it
+        for i in 1..=20 {
+            announce(&config, &mut torrent_maps, i, 1, AnnounceEvent::Paused);
+        }
+        for i in 21..=25 {
+            announce(&config, &mut torrent_maps, i, 1, AnnounceEvent::Started);
+        }
+
+        match announce(&config, &mut torrent_maps, 26, 0, AnnounceEvent::Started) {
+            Response::Announce(response) => {
+                assert_eq!(response.complete, 1);
+                assert_eq!(response.incomplete, 25);
+                assert_eq!(response.peers.0.len(), 5);
+                assert!(response.peers.0.iter().all(|peer| {
+                    (Ipv4Addr::new(10, 0, 0, 21)..=Ipv4Addr::new(10, 0, 0, 25))
+                        .contains(&peer.ip_address)
+                }));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_peer_identity_check() {
+        let mut config = Config::default();
+
+        for (peer_key, request_key, off, lenient, strict) in [
+            (Some("a"), Some("a"), true, true, true),
+            (Some("a"), Some("b"), true, false, false),
+            (Some("a"), None, true, false, false),
+            (None, Some("a"), true, true, false),
+            (None, None, true, true, false),
+        ] {
+            for (check, allowed) in [
+                (PeerIdentityCheck::Off, off),
+                (PeerIdentityCheck::Lenient, lenient),
+                (PeerIdentityCheck::Strict, strict),
+            ] {
+                config.http_tracker.peer_identity_check = check;
+
+                let mut torrent_maps = HttpTorrentMaps::default();
+
+                let mut request = announce_request(1, 1, AnnounceEvent::Started);
+                request.key = peer_key.map(Into::into);
+
+                announce_from(&config, &mut torrent_maps, peer_addr(1), request);
+
+                // Same peer_id from other IP address
+                let mut request = announce_request(1, 0, AnnounceEvent::Completed);
+                request.key = request_key.map(Into::into);
+
+                let response = announce_from(&config, &mut torrent_maps, peer_addr(2), request);
+
+                let peer = torrent_maps
+                    .ipv4
+                    .get(&INFO_HASH)
+                    .unwrap()
+                    .peers
+                    .get(&PeerId([1; 20]))
+                    .unwrap();
+
+                if allowed {
+                    assert!(matches!(response, Response::Announce(_)));
+                    assert_eq!(peer.ip_address, Ipv4Addr::new(10, 0, 0, 2));
+                    assert_eq!(peer.status, PeerStatus::Seeding);
+                } else {
+                    assert!(matches!(response, Response::Failure(_)));
+                    assert_eq!(peer.ip_address, Ipv4Addr::new(10, 0, 0, 1));
+                    assert_eq!(peer.status, PeerStatus::Leeching);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,636 @@
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tokio")] {
+        mod tokio;
+
+        pub use self::tokio::run_swarm_worker;
+    } else if #[cfg(feature = "glommio")] {
+        mod glommio;
+
+        pub use self::glommio::run_swarm_worker;
+    }
+}
+
+mod http;
+mod sdp;
+
+pub use http::{load_http_torrent_maps, HttpTorrentMaps};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::adaptive_interval::RequestRate;
+use aquatic_common::early_announce::EarlyAnnounceAction;
+use futures::StreamExt;
+use hashbrown::HashMap;
+use rand::rngs::SmallRng;
+use serde::{Deserialize, Serialize};
+
+use aquatic_common::{
+    extract_filtered_response_peers, extract_response_peers, AmortizedIndexMap, IndexMap,
+    SecondsSinceServerStart, ServerStartInstant,
+};
+use aquatic_ws_protocol::*;
+
+use crate::common::*;
+use crate::config::Config;
+
+use sdp::{sanitize_sdp, SdpType};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+enum PeerStatus {
+    Seeding,
+    Leeching,
+    /// Peer has stopped downloading without having the complete torrent
+    /// (BEP 21)
+    PartialSeeding,
+    Stopped,
+}
+
+impl PeerStatus {
+    /// Determine peer status from announce event and number of bytes left.
+    ///
+    /// Likely, the last branch will be taken most of the time.
+    #[inline]
+    fn from_event_and_bytes_left(event: AnnounceEvent, opt_bytes_left: Option<usize>) -> Self {
+        if let AnnounceEvent::Stopped = event {
+            Self::Stopped
+        } else if let Some(0) = opt_bytes_left {
+            Self::Seeding
+        } else if let AnnounceEvent::Paused = event {
+            Self::PartialSeeding
+        } else {
+            Self::Leeching
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Peer {
+    pub consumer_id: ConsumerId,
+    pub connection_id: ConnectionId,
+    pub status: PeerStatus,
+    pub valid_until: ValidUntil,
+    pub last_announce: SecondsSinceServerStart,
+}
+
+type PeerMap = IndexMap<PeerId, Peer>;
+
+struct TorrentData {
+    pub peers: PeerMap,
+    pub num_seeders: usize,
+    /// Number of leechers, excluding partial seeds
+    pub num_leechers: usize,
+    pub num_partial_seeds: usize,
+}
+
+impl Default for TorrentData {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            peers: Default::default(),
+            num_seeders: 0,
+            num_leechers: 0,
+            num_partial_seeds: 0,
+        }
+    }
+}
+
+impl TorrentData {
+    pub fn remove_peer(&mut self, peer_id: PeerId) {
+        if let Some(peer) = self.peers.remove(&peer_id) {
+            match peer.status {
+                PeerStatus::Seeding => self.num_seeders -= 1,
+                PeerStatus::Leeching => self.num_leechers -= 1,
+                PeerStatus::PartialSeeding => self.num_partial_seeds -= 1,
+                PeerStatus::Stopped => (),
+            }
+        }
+    }
+}
+
+type TorrentMap = AmortizedIndexMap<InfoHash, TorrentData>;
+
+#[derive(Default)]
+struct TorrentMaps {
+    pub ipv4: TorrentMap,
+    pub ipv6: TorrentMap,
+}
+
+impl TorrentMaps {
+    fn clean(
+        &mut self,
+        config: &Config,
+        access_list: &Arc<AccessListArcSwap>,
+        server_start_instant: ServerStartInstant,
+    ) {
+        let mut access_list_cache = create_access_list_cache(access_list);
+        let now = server_start_instant.seconds_elapsed();
+
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now);
+        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now);
+    }
+
+    fn clean_torrent_map(
+        config: &Config,
+        access_list_cache: &mut AccessListCache,
+        torrent_map: &mut TorrentMap,
+        now: SecondsSinceServerStart,
+    ) {
+        torrent_map.retain(|info_hash, torrent_data| {
+            if !access_list_cache
+                .load()
+                .allows(config.access_list.mode, &info_hash.0)
+            {
+                return false;
+            }
+
+            let num_seeders = &mut torrent_data.num_seeders;
+            let num_leechers = &mut torrent_data.num_leechers;
+            let num_partial_seeds = &mut torrent_data.num_partial_seeds;
+
+            torrent_data.peers.retain(|_, peer| {
+                let keep = peer.valid_until.valid(now);
+
+                if !keep {
+                    match peer.status {
+                        PeerStatus::Seeding => *num_seeders -= 1,
+                        PeerStatus::Leeching => *num_leechers -= 1,
+                        PeerStatus::PartialSeeding => *num_partial_seeds -= 1,
+                        PeerStatus::Stopped => (),
+                    }
+                }
+
+                keep
+            });
+
+            !torrent_data.peers.is_empty()
+        });
+
+        torrent_map.shrink_to_fit();
+    }
+}
+
+async fn handle_control_message_stream<S>(torrents: Rc<RefCell<TorrentMaps>>, mut stream: S)
+where
+    S: futures_lite::Stream<Item = SwarmControlMessage> + ::std::marker::Unpin,
+{
+    while let Some(message) = stream.next().await {
+        match message {
+            SwarmControlMessage::ConnectionClosed {
+                info_hash,
+                peer_id,
+                ip_version,
+            } => {
+                ::log::debug!("Removing peer from torrents because connection was closed");
+
+                if let IpVersion::V4 = ip_version {
+                    if let Some(torrent_data) = torrents.borrow_mut().ipv4.get_mut(&info_hash) {
+                        torrent_data.remove_peer(peer_id);
+                    }
+                } else {
+                    if let Some(torrent_data) = torrents.borrow_mut().ipv6.get_mut(&info_hash) {
+                        torrent_data.remove_peer(peer_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Return response if request is an announce without event or answer from
+/// a peer that announced less than the minimum announce interval ago. Such
+/// peers are not updated and their offers are not relayed.
+fn handle_early_announce(
+    config: &Config,
+    state: &State,
+    torrent_maps: &TorrentMaps,
+    now: SecondsSinceServerStart,
+    request_sender_meta: InMessageMeta,
+    request: &AnnounceRequest,
+) -> Option<OutMessage> {
+    let min_interval = config.protocol.peer_announce_min_interval;
+
+    if min_interval == 0
+        || request.answer.is_some()
+        || !matches!(request.event, None | Some(AnnounceEvent::Update))
+    {
+        return None;
+    }
+
+    let torrent_data = if let IpVersion::V4 = request_sender_meta.ip_version {
+        torrent_maps.ipv4.get(&request.info_hash)?
+    } else {
+        torrent_maps.ipv6.get(&request.info_hash)?
+    };
+
+    let peer = torrent_data.peers.get(&request.peer_id)?;
+
+    if peer.connection_id != request_sender_meta.connection_id
+        || now.seconds_since(peer.last_announce) as usize >= min_interval
+    {
+        return None;
+    }
+
+    state.early_announces.increment(&request.peer_id.0);
+
+    let out_message = match config.protocol.early_announce_action {
+        EarlyAnnounceAction::Cached => OutMessage::AnnounceResponse(AnnounceResponse {
+            action: AnnounceAction,
+            info_hash: request.info_hash,
+            complete: torrent_data.num_seeders,
+            incomplete: torrent_data.num_leechers + torrent_data.num_partial_seeds,
+            announce_interval: calculate_announce_interval(
+                config,
+                &state.request_rate,
+                torrent_data,
+            ),
+        }),
+        EarlyAnnounceAction::Failure => OutMessage::ErrorResponse(ErrorResponse {
+            failure_reason: "Announcing too often".into(),
+            action: Some(ErrorResponseAction::Announce),
+            info_hash: Some(request.info_hash),
+        }),
+    };
+
+    Some(out_message)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_announce_request(
+    config: &Config,
+    rng: &mut SmallRng,
+    torrent_maps: &mut TorrentMaps,
+    request_rate: &RequestRate,
+    out_messages: &mut Vec<(OutMessageMeta, OutMessage)>,
+    now: SecondsSinceServerStart,
+    request_sender_meta: InMessageMeta,
+    request: AnnounceRequest,
+) {
+    let torrent_data: &mut TorrentData = if let IpVersion::V4 = request_sender_meta.ip_version {
+        torrent_maps.ipv4.entry(request.info_hash).or_default()
+    } else {
+        torrent_maps.ipv6.entry(request.info_hash).or_default()
+    };
+
+    // If there is already a peer with this peer_id, check that connection id
+    // is same as that of request sender. Otherwise, ignore request. Since
+    // peers have access to each others peer_id's, they could send requests
+    // using them, causing all sorts of issues.
+    if let Some(previous_peer) = torrent_data.peers.get(&request.peer_id) {
+        if request_sender_meta.connection_id != previous_peer.connection_id {
+            return;
+        }
+    }
+
+    ::log::trace!("received request from {:?}", request_sender_meta);
+
+    let peer_status = PeerStatus::from_event_and_bytes_left(
+        request.event.unwrap_or_default(),
+        request.bytes_left,
+    );
+
+    // Insert/update/remove peer who sent this request
+    {
+        let peer = Peer {
+            connection_id: request_sender_meta.connection_id,
+            consumer_id: request_sender_meta.out_message_consumer_id,
+            status: peer_status,
+            valid_until: ValidUntil::new_with_now(now, config.cleaning.max_peer_age),
+            last_announce: now,
+        };
+
+        let opt_removed_peer = match peer_status {
+            PeerStatus::Leeching => {
+                torrent_data.num_leechers += 1;
+
+                torrent_data.peers.insert(request.peer_id, peer)
+            }
+            PeerStatus::Seeding => {
+                torrent_data.num_seeders += 1;
+
+                torrent_data.peers.insert(request.peer_id, peer)
+            }
+            PeerStatus::PartialSeeding => {
+                torrent_data.num_partial_seeds += 1;
+
+                torrent_data.peers.insert(request.peer_id, peer)
+            }
+            PeerStatus::Stopped => torrent_data.peers.remove(&request.peer_id),
+        };
+
+        match opt_removed_peer.map(|peer| peer.status) {
+            Some(PeerStatus::Leeching) => {
+                torrent_data.num_leechers -= 1;
+            }
+            Some(PeerStatus::Seeding) => {
+                torrent_data.num_seeders -= 1;
+            }
+            Some(PeerStatus::PartialSeeding) => {
+                torrent_data.num_partial_seeds -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    // If peer sent offers, send them on to random peers
+    if let Some(mut offers) = request.offers {
+        // Don't spend time sanitizing offers that won't be sent on
+        offers.truncate(config.protocol.max_offers);
+
+        offers.retain_mut(|offer| {
+            if let Err(err) = sanitize_sdp(config, &mut offer.offer, SdpType::Offer) {
+                ::log::debug!("dropping invalid offer: {:?}", err);
+
+                false
+            } else {
+                true
+            }
+        });
+
+        let max_num_peers_to_take = offers.len();
+
+        #[inline]
+        fn f(peer: &Peer) -> Peer {
+            *peer
+        }
+
+        let offer_receivers: Vec<Peer> =
+            if peer_status == PeerStatus::Seeding && torrent_data.num_partial_seeds != 0 {
+                // Partial seeds won't download, so they are of no use to seeders
+                extract_filtered_response_peers(
+                    rng,
+                    &torrent_data.peers,
+                    max_num_peers_to_take,
+                    request.peer_id,
+                    |peer| peer.status != PeerStatus::PartialSeeding,
+                    f,
+                )
+            } else {
+                extract_response_peers(
+                    rng,
+                    &torrent_data.peers,
+                    max_num_peers_to_take,
+                    request.peer_id,
+                    f,
+                )
+            };
+
+        for (offer, offer_receiver) in offers.into_iter().zip(offer_receivers) {
+            let middleman_offer = MiddlemanOfferToPeer {
+                action: AnnounceAction,
+                info_hash: request.info_hash,
+                peer_id: request.peer_id,
+                offer: offer.offer,
+                offer_id: offer.offer_id,
+            };
+
+            let meta = OutMessageMeta {
+                out_message_consumer_id: offer_receiver.consumer_id,
+                connection_id: offer_receiver.connection_id,
+                pending_scrape_id: None,
+            };
+
+            out_messages.push((meta, OutMessage::Offer(middleman_offer)));
+            ::log::trace!("sending middleman offer to {:?}", meta);
+        }
+    }
+
+    // If peer sent answer, send it on to relevant peer
+    if let (Some(mut answer), Some(answer_receiver_id), Some(offer_id)) =
+        (request.answer, request.to_peer_id, request.offer_id)
+    {
+        if let Err(err) = sanitize_sdp(config, &mut answer, SdpType::Answer) {
+            ::log::debug!("dropping invalid answer: {:?}", err);
+        } else if let Some(answer_receiver) = torrent_data.peers.get(&answer_receiver_id) {
+            let middleman_answer = MiddlemanAnswerToPeer {
+                action: AnnounceAction,
+                peer_id: request.peer_id,
+                info_hash: request.info_hash,
+                answer,
+                offer_id,
+            };
+
+            let meta = OutMessageMeta {
+                out_message_consumer_id: answer_receiver.consumer_id,
+                connection_id: answer_receiver.connection_id,
+                pending_scrape_id: None,
+            };
+
+            out_messages.push((meta, OutMessage::Answer(middleman_answer)));
+            ::log::trace!("sending middleman answer to {:?}", meta);
+        }
+    }
+
+    let out_message = OutMessage::AnnounceResponse(AnnounceResponse {
+        action: AnnounceAction,
+        info_hash: request.info_hash,
+        complete: torrent_data.num_seeders,
+        incomplete: torrent_data.num_leechers + torrent_data.num_partial_seeds,
+        announce_interval: calculate_announce_interval(config, request_rate, torrent_data),
+    });
+
+    out_messages.push((request_sender_meta.into(), out_message));
+}
+
+fn calculate_announce_interval(
+    config: &Config,
+    request_rate: &RequestRate,
+    torrent_data: &TorrentData,
+) -> usize {
+    config.adaptive_announce_interval.calculate(
+        config.protocol.peer_announce_interval,
+        torrent_data.peers.len(),
+        request_rate,
+    )
+}
+
+fn handle_scrape_request(
+    config: &Config,
+    torrent_maps: &mut TorrentMaps,
+    out_messages: &mut Vec<(OutMessageMeta, OutMessage)>,
+    meta: InMessageMeta,
+    request: ScrapeRequest,
+) {
+    let info_hashes = if let Some(info_hashes) = request.info_hashes {
+        info_hashes.as_vec()
+    } else {
+        return;
+    };
+
+    let num_to_take = info_hashes.len().min(config.protocol.max_scrape_torrents);
+
+    let mut out_message = ScrapeResponse {
+        action: ScrapeAction,
+        files: HashMap::with_capacity(num_to_take),
+    };
+
+    let torrent_map: &mut TorrentMap = if let IpVersion::V4 = meta.ip_version {
+        &mut torrent_maps.ipv4
+    } else {
+        &mut torrent_maps.ipv6
+    };
+
+    for info_hash in info_hashes.into_iter().take(num_to_take) {
+        if let Some(torrent_data) = torrent_map.get(&info_hash) {
+            let stats = ScrapeStatistics {
+                complete: torrent_data.num_seeders,
+                downloaded: 0, // No implementation planned
+                incomplete: torrent_data.num_leechers + torrent_data.num_partial_seeds,
+                downloaders: torrent_data.num_leechers,
+            };
+
+            out_message.files.insert(info_hash, stats);
+        }
+    }
+
+    out_messages.push((meta.into(), OutMessage::ScrapeResponse(out_message)));
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn announce(
+        torrent_maps: &mut TorrentMaps,
+        peer_index: u8,
+        bytes_left: usize,
+        event: AnnounceEvent,
+        num_offers: usize,
+    ) -> Vec<(OutMessageMeta, OutMessage)> {
+        let config = Config::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut out_messages = Vec::new();
+
+        let meta = InMessageMeta {
+            out_message_consumer_id: ConsumerId(0),
+            connection_id: ConnectionId(peer_index.into()),
+            ip_version: IpVersion::V4,
+            pending_scrape_id: None,
+        };
+
+        let offers = (0..num_offers)
+            .map(|i| AnnounceRequestOffer {
+                offer: JsonValue(::serde_json::Value::Null),
+                offer_id: OfferId([i as u8; 20]),
+            })
+            .collect();
+
+        let request = AnnounceRequest {
+            action: AnnounceAction,
+            info_hash: InfoHash([0; 20]),
+            peer_id: PeerId([peer_index; 20]),
+            bytes_left: Some(bytes_left),
+            event: Some(event),
+            offers: Some(offers),
+            numwant: Some(num_offers),
+            answer: None,
+            to_peer_id: None,
+            offer_id: None,
+        };
+
+        handle_announce_request(
+            &config,
+            &mut rng,
+            torrent_maps,
+            &RequestRate::default(),
+            &mut out_messages,
+            ServerStartInstant::new().seconds_elapsed(),
+            meta,
+            request,
+        );
+
+        out_messages
+    }
+
+    #[test]
+    fn test_partial_seeds() {
+        let mut torrent_maps = TorrentMaps::default();
+
+        // Partial seeds first, so that they would take up all places in
+        // offer receiver selection if filtered after it
+        for i in 1..=20 {
+            announce(&mut torrent_maps, i, 1, AnnounceEvent::Paused, 0);
+        }
+        for i in 21..=25 {
+            announce(&mut torrent_maps, i, 1, AnnounceEvent::Started, 0);
+        }
+
+        let out_messages = announce(&mut torrent_maps, 26, 0, AnnounceEvent::Started, 5);
+
+        let offer_receivers: Vec<usize> = out_messages
+            .iter()
+            .filter_map(|(meta, message)| {
+                matches!(message, OutMessage::Offer(_)).then_some(meta.connection_id.0)
+            })
+            .collect();
+
+        assert_eq!(offer_receivers.len(), 5);
+        assert!(offer_receivers.iter().all(|id| (21..=25).contains(id)));
+
+        // Leechers send offers to partial seeds too
+        let out_messages = announce(&mut torrent_maps, 27, 1, AnnounceEvent::Started, 10);
+
+        assert_eq!(
+            out_messages
+                .iter()
+                .filter(|(_, message)| matches!(message, OutMessage::Offer(_)))
+                .count(),
+            10
+        );
+
+        let torrent_data = torrent_maps.ipv4.get(&InfoHash([0; 20])).unwrap();
+
+        assert_eq!(torrent_data.num_seeders, 1);
+        assert_eq!(torrent_data.num_leechers, 6);
+        assert_eq!(torrent_data.num_partial_seeds, 20);
+
+        match out_messages.last() {
+            Some((_, OutMessage::AnnounceResponse(response))) => {
+                assert_eq!(response.complete, 1);
+                assert_eq!(response.incomplete, 26);
+            }
+            _ => panic!("expected announce response"),
+        }
+
+        let mut out_messages = Vec::new();
+
+        handle_scrape_request(
+            &Config::default(),
+            &mut torrent_maps,
+            &mut out_messages,
+            InMessageMeta {
+                out_message_consumer_id: ConsumerId(0),
+                connection_id: ConnectionId(0),
+                ip_version: IpVersion::V4,
+                pending_scrape_id: None,
+            },
+            ScrapeRequest {
+                action: ScrapeAction,
+                info_hashes: Some(ScrapeRequestInfoHashes::Single(InfoHash([0; 20]))),
+            },
+        );
+
+        match out_messages.pop() {
+            Some((_, OutMessage::ScrapeResponse(response))) => {
+                let stats = response.files.get(&InfoHash([0; 20])).unwrap();
+
+                assert_eq!(stats.complete, 1);
+                assert_eq!(stats.incomplete, 26);
+                assert_eq!(stats.downloaders, 6);
+            }
+            _ => panic!("expected scrape response"),
+        }
+
+        // Partial seed completing download becomes seeder
+        announce(&mut torrent_maps, 1, 0, AnnounceEvent::Completed, 0);
+
+        let torrent_data = torrent_maps.ipv4.get(&InfoHash([0; 20])).unwrap();
+
+        assert_eq!(torrent_data.num_seeders, 2);
+        assert_eq!(torrent_data.num_partial_seeds, 19);
+    }
+}
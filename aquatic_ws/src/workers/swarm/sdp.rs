@@ -0,0 +1,265 @@
+use std::net::IpAddr;
+
+use aquatic_ws_protocol::JsonValue;
+use serde_json::Value;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdpType {
+    Offer,
+    Answer,
+}
+
+impl SdpType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Offer => "offer",
+            Self::Answer => "answer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdpError {
+    /// Not an object with only type and sdp fields
+    InvalidStructure,
+    /// Type field doesn't match message kind
+    InvalidType,
+    /// SDP longer than configured maximum
+    TooLong,
+}
+
+/// Validate offer or answer and optionally strip ICE candidates revealing
+/// private addresses, depending on configuration
+pub fn sanitize_sdp(
+    config: &Config,
+    value: &mut JsonValue,
+    sdp_type: SdpType,
+) -> Result<(), SdpError> {
+    if config.protocol.validate_sdp {
+        validate_sdp(value, sdp_type, config.protocol.max_sdp_len)?;
+    }
+
+    if config.protocol.strip_private_ice_candidates {
+        if let Some(Value::String(sdp)) = value.0.get_mut("sdp") {
+            if let Some(stripped) = strip_private_ice_candidates(sdp) {
+                *sdp = stripped;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_sdp(value: &JsonValue, sdp_type: SdpType, max_len: usize) -> Result<(), SdpError> {
+    let object = value.0.as_object().ok_or(SdpError::InvalidStructure)?;
+
+    if object.len() != 2 {
+        return Err(SdpError::InvalidStructure);
+    }
+
+    match object.get("type") {
+        Some(Value::String(t)) if t == sdp_type.as_str() => (),
+        Some(Value::String(_)) => return Err(SdpError::InvalidType),
+        _ => return Err(SdpError::InvalidStructure),
+    }
+
+    match object.get("sdp") {
+        Some(Value::String(sdp)) if sdp.len() <= max_len => Ok(()),
+        Some(Value::String(_)) => Err(SdpError::TooLong),
+        _ => Err(SdpError::InvalidStructure),
+    }
+}
+
+/// Remove host candidates and candidates with private addresses, and mask
+/// private related addresses of remaining candidates. Returns None if SDP
+/// doesn't need to be changed.
+fn strip_private_ice_candidates(sdp: &str) -> Option<String> {
+    let mut changed = false;
+    let mut output = String::with_capacity(sdp.len());
+
+    for line in sdp.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(candidate) = content.strip_prefix("a=candidate:") {
+            match sanitize_candidate(candidate) {
+                Candidate::Keep => (),
+                Candidate::Remove => {
+                    changed = true;
+
+                    continue;
+                }
+                Candidate::Replace(candidate) => {
+                    changed = true;
+
+                    output.push_str("a=candidate:");
+                    output.push_str(&candidate);
+                    output.push_str(&line[content.len()..]);
+
+                    continue;
+                }
+            }
+        }
+
+        output.push_str(line);
+    }
+
+    changed.then_some(output)
+}
+
+enum Candidate {
+    Keep,
+    Remove,
+    Replace(String),
+}
+
+/// Candidate attribute format (RFC 8839):
+///
+/// foundation component transport priority address port typ type
+/// [raddr address rport port] *(extension-name extension-value)
+fn sanitize_candidate(candidate: &str) -> Candidate {
+    let mut parts: Vec<&str> = candidate.split(' ').collect();
+
+    if parts.len() < 8 || parts[6] != "typ" {
+        return Candidate::Keep;
+    }
+    if parts[7] == "host" || is_private_address(parts[4]) {
+        return Candidate::Remove;
+    }
+
+    let mut changed = false;
+
+    for i in (8..parts.len().saturating_sub(1)).step_by(2) {
+        match parts[i] {
+            "raddr" if parts[i + 1] != "0.0.0.0" && is_private_address(parts[i + 1]) => {
+                parts[i + 1] = "0.0.0.0";
+                changed = true;
+            }
+            "rport" if changed => {
+                parts[i + 1] = "0";
+            }
+            _ => (),
+        }
+    }
+
+    if changed {
+        Candidate::Replace(parts.join(" "))
+    } else {
+        Candidate::Keep
+    }
+}
+
+/// Is address private, loopback, link-local or an mDNS hostname?
+fn is_private_address(address: &str) -> bool {
+    if address.ends_with(".local") {
+        return true;
+    }
+
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, ..] = ip.octets();
+
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // Shared address space (RFC 6598)
+                || (a == 100 && (b & 0b1100_0000) == 64)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let first_segment = ip.segments()[0];
+
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local
+                || (first_segment & 0xfe00) == 0xfc00
+                // Link-local
+                || (first_segment & 0xffc0) == 0xfe80
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_sdp() {
+        let f = |value: Value, sdp_type| validate_sdp(&JsonValue(value), sdp_type, 8);
+
+        assert_eq!(
+            f(json!({ "type": "offer", "sdp": "v=0" }), SdpType::Offer),
+            Ok(())
+        );
+        assert_eq!(
+            f(json!({ "type": "answer", "sdp": "v=0" }), SdpType::Answer),
+            Ok(())
+        );
+        assert_eq!(
+            f(json!({ "type": "offer", "sdp": "v=0" }), SdpType::Answer),
+            Err(SdpError::InvalidType)
+        );
+        assert_eq!(
+            f(
+                json!({ "type": "offer", "sdp": "v=0 aaaaaa" }),
+                SdpType::Offer
+            ),
+            Err(SdpError::TooLong)
+        );
+        assert_eq!(
+            f(
+                json!({ "type": "offer", "sdp": "v=0", "a": 1 }),
+                SdpType::Offer
+            ),
+            Err(SdpError::InvalidStructure)
+        );
+        assert_eq!(
+            f(json!({ "type": "offer", "sdp": 1 }), SdpType::Offer),
+            Err(SdpError::InvalidStructure)
+        );
+        assert_eq!(
+            f(json!("v=0"), SdpType::Offer),
+            Err(SdpError::InvalidStructure)
+        );
+    }
+
+    #[test]
+    fn test_strip_private_ice_candidates() {
+        let sdp = "v=0\r\n\
+            a=candidate:1 1 udp 2122260223 192.168.1.2 54321 typ host generation 0\r\n\
+            a=candidate:2 1 udp 2122260223 abcd.local 54322 typ host\r\n\
+            a=candidate:3 1 udp 1686052607 203.0.113.1 54321 typ srflx raddr 192.168.1.2 rport 54321 generation 0\r\n\
+            a=candidate:4 1 udp 41885439 198.51.100.1 3478 typ relay raddr 203.0.113.1 rport 54321\r\n\
+            a=candidate:5 1 udp 1686052607 10.0.0.1 54321 typ srflx\r\n\
+            a=end-of-candidates\r\n";
+
+        let expected = "v=0\r\n\
+            a=candidate:3 1 udp 1686052607 203.0.113.1 54321 typ srflx raddr 0.0.0.0 rport 0 generation 0\r\n\
+            a=candidate:4 1 udp 41885439 198.51.100.1 3478 typ relay raddr 203.0.113.1 rport 54321\r\n\
+            a=end-of-candidates\r\n";
+
+        assert_eq!(strip_private_ice_candidates(sdp).as_deref(), Some(expected));
+        assert_eq!(strip_private_ice_candidates(expected), None);
+    }
+
+    #[test]
+    fn test_is_private_address() {
+        assert!(is_private_address("10.1.2.3"));
+        assert!(is_private_address("172.16.0.1"));
+        assert!(is_private_address("100.64.0.1"));
+        assert!(is_private_address("127.0.0.1"));
+        assert!(is_private_address("fd00::1"));
+        assert!(is_private_address("fe80::1"));
+        assert!(is_private_address(
+            "c0a6d2a3-35ab-4d6f-a45c-23ac7b7d5e9a.local"
+        ));
+
+        assert!(!is_private_address("203.0.113.1"));
+        assert!(!is_private_address("100.128.0.1"));
+        assert!(!is_private_address("2001:db8::1"));
+    }
+}
@@ -0,0 +1,243 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use rand::{rngs::SmallRng, SeedableRng};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::{spawn_local, LocalSet};
+use tokio::time::sleep;
+
+use aquatic_common::{PanicSentinel, SecondsSinceServerStart, ServerStartInstant};
+use aquatic_ws_protocol::*;
+
+use crate::common::*;
+use crate::config::Config;
+use crate::SHARED_IN_CHANNEL_SIZE;
+
+use super::http::{handle_http_request_stream, save_http_torrent_maps, HttpTorrentMaps};
+use super::{
+    handle_announce_request, handle_control_message_stream, handle_early_announce,
+    handle_scrape_request, TorrentMaps,
+};
+
+pub fn run_swarm_worker(
+    _sentinel: PanicSentinel,
+    config: Config,
+    state: State,
+    worker_index: usize,
+    http_torrents: HttpTorrentMaps,
+    control_message_receiver: Receiver<SwarmControlMessage>,
+    in_message_receiver: Receiver<(InMessageMeta, InMessage)>,
+    out_message_senders: Vec<Sender<(OutMessageMeta, OutMessage)>>,
+    http_request_receiver: Receiver<HttpChannelRequest>,
+    server_start_instant: ServerStartInstant,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+
+    LocalSet::new().block_on(
+        &runtime,
+        run_inner(
+            config,
+            state,
+            worker_index,
+            http_torrents,
+            control_message_receiver,
+            in_message_receiver,
+            out_message_senders,
+            http_request_receiver,
+            server_start_instant,
+        ),
+    );
+}
+
+async fn run_inner(
+    config: Config,
+    state: State,
+    worker_index: usize,
+    http_torrents: HttpTorrentMaps,
+    control_message_receiver: Receiver<SwarmControlMessage>,
+    in_message_receiver: Receiver<(InMessageMeta, InMessage)>,
+    out_message_senders: Vec<Sender<(OutMessageMeta, OutMessage)>>,
+    http_request_receiver: Receiver<HttpChannelRequest>,
+    server_start_instant: ServerStartInstant,
+) {
+    let out_message_senders = Rc::new(out_message_senders);
+
+    let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
+    let http_torrents = Rc::new(RefCell::new(http_torrents));
+    let access_list = state.access_list.clone();
+
+    // Periodically clean torrents
+    spawn_local({
+        let config = config.clone();
+        let torrents = torrents.clone();
+        let http_torrents = http_torrents.clone();
+
+        async move {
+            loop {
+                sleep(Duration::from_secs(
+                    config.cleaning.torrent_cleaning_interval,
+                ))
+                .await;
+
+                torrents
+                    .borrow_mut()
+                    .clean(&config, &access_list, server_start_instant);
+                http_torrents
+                    .borrow_mut()
+                    .clean(&config, &access_list, server_start_instant);
+            }
+        }
+    });
+
+    let now = create_now(server_start_instant);
+
+    let handles = [
+        spawn_local(handle_control_message_stream(
+            torrents.clone(),
+            receiver_stream(control_message_receiver),
+        )),
+        spawn_local(handle_request_stream(
+            config.clone(),
+            torrents,
+            state.clone(),
+            now.clone(),
+            out_message_senders,
+            receiver_stream(in_message_receiver),
+        )),
+        spawn_local(handle_http_request_stream(
+            config.clone(),
+            http_torrents.clone(),
+            state.clone(),
+            now,
+            receiver_stream(http_request_receiver),
+        )),
+    ];
+
+    let running_worker_guard = RunningWorkerGuard::new(state.num_running_workers.clone());
+
+    // Channels are closed when all socket workers have exited
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+    if state.shutdown.is_triggered() && config.shutdown.persist_state() {
+        if let Err(err) = save_http_torrent_maps(
+            &config,
+            worker_index,
+            &http_torrents.borrow(),
+            server_start_instant.seconds_elapsed(),
+        ) {
+            ::log::error!("couldn't save http torrent state: {:#}", err);
+        }
+    }
+
+    drop(running_worker_guard);
+}
+
+async fn handle_request_stream<S>(
+    config: Config,
+    torrents: Rc<RefCell<TorrentMaps>>,
+    state: State,
+    now: Rc<RefCell<SecondsSinceServerStart>>,
+    out_message_senders: Rc<Vec<Sender<(OutMessageMeta, OutMessage)>>>,
+    stream: S,
+) where
+    S: futures_lite::Stream<Item = (InMessageMeta, InMessage)> + ::std::marker::Unpin,
+{
+    let rng = Rc::new(RefCell::new(SmallRng::from_entropy()));
+
+    let config = &config;
+    let torrents = &torrents;
+    let state = &state;
+    let now = &now;
+    let rng = &rng;
+    let out_message_senders = &out_message_senders;
+
+    stream
+        .for_each_concurrent(
+            SHARED_IN_CHANNEL_SIZE,
+            move |(meta, in_message)| async move {
+                let mut out_messages = Vec::new();
+
+                match in_message {
+                    InMessage::AnnounceRequest(request) => {
+                        if let Some(out_message) = handle_early_announce(
+                            config,
+                            state,
+                            &torrents.borrow(),
+                            now.borrow().to_owned(),
+                            meta,
+                            &request,
+                        ) {
+                            out_messages.push((meta.into(), out_message));
+                        } else {
+                            handle_announce_request(
+                                &config,
+                                &mut rng.borrow_mut(),
+                                &mut torrents.borrow_mut(),
+                                &state.request_rate,
+                                &mut out_messages,
+                                now.borrow().to_owned(),
+                                meta,
+                                request,
+                            )
+                        }
+                    }
+                    InMessage::ScrapeRequest(request) => handle_scrape_request(
+                        &config,
+                        &mut torrents.borrow_mut(),
+                        &mut out_messages,
+                        meta,
+                        request,
+                    ),
+                };
+
+                for (meta, out_message) in out_messages.drain(..) {
+                    // Fails when socket worker has exited, e.g., during
+                    // graceful shutdown
+                    if out_message_senders[meta.out_message_consumer_id.0 as usize]
+                        .send((meta, out_message))
+                        .await
+                        .is_err()
+                    {
+                        ::log::debug!(
+                            "failed sending out_message to socket worker: receiver closed"
+                        );
+
+                        continue;
+                    }
+
+                    ::log::debug!("swarm worker sent OutMessage to socket worker");
+                }
+            },
+        )
+        .await;
+}
+
+/// Create current time that is updated every second
+fn create_now(server_start_instant: ServerStartInstant) -> Rc<RefCell<SecondsSinceServerStart>> {
+    let now = Rc::new(RefCell::new(server_start_instant.seconds_elapsed()));
+
+    spawn_local({
+        let now = now.clone();
+
+        async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+
+                *now.borrow_mut() = server_start_instant.seconds_elapsed();
+            }
+        }
+    });
+
+    now
+}
+
+fn receiver_stream<T>(mut receiver: Receiver<T>) -> impl futures_lite::Stream<Item = T> + Unpin {
+    futures::stream::poll_fn(move |cx| receiver.poll_recv(cx))
+}
@@ -176,6 +176,7 @@ mod tests {
                 complete: Arbitrary::arbitrary(g),
                 incomplete: Arbitrary::arbitrary(g),
                 downloaded: Arbitrary::arbitrary(g),
+                downloaders: Arbitrary::arbitrary(g),
             }
         }
     }
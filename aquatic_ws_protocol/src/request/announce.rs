@@ -8,6 +8,9 @@ pub enum AnnounceEvent {
     Started,
     Stopped,
     Completed,
+    /// Peer is a partial seed: it has stopped downloading without having
+    /// the complete torrent (BEP 21)
+    Paused,
     Update,
 }
 
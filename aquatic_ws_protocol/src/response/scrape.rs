@@ -14,6 +14,10 @@ pub struct ScrapeResponse {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ScrapeStatistics {
     pub complete: usize,
+    /// Number of leechers, including partial seeds
     pub incomplete: usize,
     pub downloaded: usize,
+    /// Number of leechers that are not partial seeds (BEP 21)
+    #[serde(default)]
+    pub downloaders: usize,
 }
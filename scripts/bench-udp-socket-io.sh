@@ -0,0 +1,7 @@
+#!/bin/sh
+# Compare throughput of per-datagram and batched socket I/O (recvmmsg and
+# sendmmsg on Linux)
+
+. ./scripts/env-native-cpu-without-avx-512
+
+cargo bench -p aquatic_udp --bench bench_socket_io -- --noplot $@